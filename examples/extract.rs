@@ -0,0 +1,26 @@
+//! Cut a time range out of a trace:
+//!
+//! ```text
+//! cargo run --example extract -- <input> <start_ns> <end_ns> <output>
+//! ```
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [input, start_ns, end_ns, output] = &args[..] else {
+        eprintln!("usage: extract <input> <start_ns> <end_ns> <output>");
+        return ExitCode::FAILURE;
+    };
+    let (Ok(start_ns), Ok(end_ns)) = (start_ns.parse(), end_ns.parse()) else {
+        eprintln!("extract: the times must be nanoseconds, as in the trace");
+        return ExitCode::FAILURE;
+    };
+    match tracing_perfetto::extract(input, start_ns, end_ns, output) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("extract: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Mirroring important events to the Android log.
+//!
+//! Warnings and errors are written to logcat, so they show up next to the
+//! logs of the rest of the system, and can also be recorded as
+//! `AndroidLogPacket`s, which the Perfetto UI shows in its "Android logs"
+//! panel. Less severe events only go to the trace as usual.
+
+use std::fmt::Write;
+
+use tracing::{field::Visit, Level};
+
+/// Whether events of this level are mirrored.
+pub fn is_mirrored(level: &Level) -> bool {
+    *level <= Level::WARN
+}
+
+/// The `android_LogPriority` of a level.
+pub fn priority(level: &Level) -> u32 {
+    match *level {
+        Level::TRACE => 2, // ANDROID_LOG_VERBOSE
+        Level::DEBUG => 3,
+        Level::INFO => 4,
+        Level::WARN => 5,
+        Level::ERROR => 6,
+    }
+}
+
+/// Format the fields of an event as a log line: the message, followed by
+/// the other fields as `name=value`.
+pub fn format_event(event: &tracing::Event<'_>) -> String {
+    let mut v = LogLineVisitor::default();
+    event.record(&mut v);
+    let mut line = v.message;
+    if !v.fields.is_empty() {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&v.fields);
+    }
+    line
+}
+
+#[derive(Default)]
+struct LogLineVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for LogLineVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else if !field.name().starts_with("perfetto.") {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(target_os = "android")]
+mod sys {
+    use std::os::raw::{c_char, c_int};
+
+    #[link(name = "log")]
+    extern "C" {
+        pub fn __android_log_write(prio: c_int, tag: *const c_char, text: *const c_char) -> c_int;
+    }
+}
+
+/// Write a line to logcat. Does nothing when not running on Android.
+#[cfg(target_os = "android")]
+pub fn write_logcat(prio: u32, tag: &str, message: &str) {
+    use std::ffi::CString;
+
+    // Interior nul bytes would cut the line short, so drop them.
+    let tag = CString::new(tag.replace('\0', "")).unwrap();
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    // SAFETY: both strings are valid and nul-terminated.
+    unsafe {
+        sys::__android_log_write(prio as _, tag.as_ptr(), message.as_ptr());
+    }
+}
+
+/// Write a line to logcat. Does nothing when not running on Android.
+#[cfg(not(target_os = "android"))]
+pub fn write_logcat(_prio: u32, _tag: &str, _message: &str) {}
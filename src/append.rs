@@ -0,0 +1,153 @@
+//! Continuing a trace file written by an earlier run, see
+//! [`PerfettoLayerBuilder::append`](crate::PerfettoLayerBuilder::append).
+//!
+//! Each run moves its sequence ids and track uuids up by a fixed step, so
+//! that the runs in one file don't share any. The number of the run is
+//! recovered from the sequence ids already in the file.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+};
+
+use crate::packet::{PacketData, TracePacket};
+
+/// How far the ids of each run are moved. Thread sequence ids stay below
+/// the step as long as there are fewer than 2^24 threads; the fixed
+/// sequence ids at the top of the range wrap around to just below the
+/// run's thread sequences.
+const SEQUENCE_ID_STEP: u32 = 1 << 24;
+/// Track uuids of a run, including the ones allocated from
+/// [`DYNAMIC_TRACK_UUID_BASE`](crate::ids::DYNAMIC_TRACK_UUID_BASE), stay
+/// below this.
+const TRACK_UUID_STEP: u64 = 1 << 56;
+
+/// The fixed sequence ids, counted down from `u32::MAX`.
+const FIXED_SEQUENCE_IDS: u32 = 4;
+
+/// What was found in an existing trace file.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Existing {
+    /// Length of the complete packets. A packet cut off by a crash is
+    /// dropped, so the next run's packets can be read again.
+    pub len: u64,
+    /// Number of the next run.
+    pub run: u32,
+}
+
+/// Find the complete packets in a trace file, and the runs that wrote them.
+pub(crate) fn scan(file: &File) -> io::Result<Existing> {
+    let mut reader = BufReader::new(file);
+    let mut existing = Existing { len: 0, run: 0 };
+    let mut packet = Vec::new();
+    // Trace.packet, field 1, length-delimited.
+    while let Some((0x0a, tag_len)) = read_varint(&mut reader)? {
+        let Some((len, len_len)) = read_varint(&mut reader)? else {
+            break;
+        };
+        packet.clear();
+        let read = (&mut reader).take(len).read_to_end(&mut packet)?;
+        if read as u64 != len {
+            break;
+        }
+        if let Some(sequence_id) = sequence_id(&packet) {
+            existing.run = existing.run.max(run_of(sequence_id) + 1);
+        }
+        existing.len += (tag_len + len_len) as u64 + len;
+    }
+    Ok(existing)
+}
+
+/// The run whose ids include `sequence_id`.
+fn run_of(sequence_id: u32) -> u32 {
+    sequence_id.wrapping_add(FIXED_SEQUENCE_IDS) / SEQUENCE_ID_STEP
+}
+
+/// Read a varint and its length, or `None` at the end of the file or in
+/// the middle of the varint.
+pub(crate) fn read_varint(reader: &mut impl Read) -> io::Result<Option<(u64, usize)>> {
+    let mut value = 0;
+    for i in 0..10 {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        value |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
+/// The `trusted_packet_sequence_id` of an encoded packet.
+fn sequence_id(mut packet: &[u8]) -> Option<u32> {
+    while !packet.is_empty() {
+        let (tag, _) = read_varint(&mut packet).ok()??;
+        match tag & 7 {
+            0 => {
+                let (value, _) = read_varint(&mut packet).ok()??;
+                if tag >> 3 == 10 {
+                    return Some(value as u32);
+                }
+            }
+            1 => packet = packet.get(8..)?,
+            2 => {
+                let (len, _) = read_varint(&mut packet).ok()??;
+                packet = packet.get(len as usize..)?;
+            }
+            5 => packet = packet.get(4..)?,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Move the sequence id and track uuids of `packet` to those of `run`.
+pub(crate) fn shift_ids(packet: &mut TracePacket, run: u32) {
+    let uuid_offset = (run as u64).wrapping_mul(TRACK_UUID_STEP);
+    let shift = |uuid: &mut u64| *uuid = uuid.wrapping_add(uuid_offset);
+    packet.trusted_packet_sequence_id = packet
+        .trusted_packet_sequence_id
+        .wrapping_add(run.wrapping_mul(SEQUENCE_ID_STEP));
+    if let Some(defaults) = &mut packet.trace_packet_defaults {
+        if let Some(track_event) = &mut defaults.track_event_defaults {
+            shift(&mut track_event.track_uuid);
+        }
+    }
+    match &mut packet.data {
+        PacketData::TrackEvent(event) => {
+            if let Some(uuid) = &mut event.track_uuid {
+                shift(uuid);
+            }
+            for (uuid, _) in &mut event.extra_counters {
+                shift(uuid);
+            }
+            for (uuid, _) in &mut event.extra_double_counters {
+                shift(uuid);
+            }
+        }
+        PacketData::TrackDescriptor(track) => {
+            shift(&mut track.uuid);
+            if let Some(parent) = &mut track.parent_uuid {
+                shift(parent);
+            }
+        }
+        PacketData::None | PacketData::AndroidLog(_) | PacketData::ClockSnapshot(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs() {
+        for run in 0..3 {
+            let step = run * SEQUENCE_ID_STEP;
+            assert_eq!(run_of(step + 1), run);
+            assert_eq!(run_of(step.wrapping_add(u32::MAX)), run);
+            assert_eq!(run_of(step.wrapping_add(u32::MAX - 3)), run);
+        }
+    }
+}
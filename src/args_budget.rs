@@ -0,0 +1,103 @@
+//! Bounding the bytes spent on arguments, see
+//! [`PerfettoLayerBuilder::args_budget`](crate::PerfettoLayerBuilder::args_budget).
+
+use crate::{ThreadId, Timestamp};
+
+const WINDOW_NS: Timestamp = 1_000_000_000;
+
+/// The bytes of arguments written in the current second.
+pub(crate) struct ArgsBudget {
+    bytes_per_second: u64,
+    window_start: Timestamp,
+    spent: u64,
+    omitted: u64,
+    /// Thread of the last packet whose arguments were omitted, on whose
+    /// sequence the omissions are counted.
+    thread_id: ThreadId,
+}
+
+/// A second in which arguments were omitted.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Overrun {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// Number of packets written without their arguments.
+    pub omitted: u64,
+    pub thread_id: ThreadId,
+}
+
+impl ArgsBudget {
+    pub fn new(bytes_per_second: u64) -> Self {
+        ArgsBudget {
+            bytes_per_second,
+            window_start: 0,
+            spent: 0,
+            omitted: 0,
+            thread_id: 0,
+        }
+    }
+
+    /// Move on to the second of `timestamp`, returning the second left
+    /// behind if it ran over the budget. Slightly older timestamps, e.g.
+    /// of deferred slices, count towards the current second.
+    pub fn advance(&mut self, timestamp: Timestamp) -> Option<Overrun> {
+        if timestamp < self.window_start + WINDOW_NS {
+            return None;
+        }
+        let overrun = self.finish();
+        self.window_start = timestamp - timestamp % WINDOW_NS;
+        self.spent = 0;
+        overrun
+    }
+
+    /// Spend `bytes` on the arguments of a packet of `thread_id`. Returns
+    /// false if they don't fit, in which case the packet goes without.
+    pub fn spend(&mut self, bytes: usize, thread_id: ThreadId) -> bool {
+        if self.spent + bytes as u64 <= self.bytes_per_second {
+            self.spent += bytes as u64;
+            true
+        } else {
+            self.omitted += 1;
+            self.thread_id = thread_id;
+            false
+        }
+    }
+
+    /// The current second, if it ran over the budget so far.
+    pub fn finish(&mut self) -> Option<Overrun> {
+        let omitted = std::mem::take(&mut self.omitted);
+        (omitted > 0).then_some(Overrun {
+            start: self.window_start,
+            end: self.window_start + WINDOW_NS,
+            omitted,
+            thread_id: self.thread_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let mut budget = ArgsBudget::new(100);
+        assert_eq!(budget.advance(1_500_000_000), None);
+        assert!(budget.spend(60, 1));
+        assert!(!budget.spend(60, 2));
+        assert!(budget.spend(40, 1));
+        assert_eq!(budget.advance(1_900_000_000), None);
+        assert!(!budget.spend(1, 1));
+        assert_eq!(
+            budget.advance(2_000_000_000),
+            Some(Overrun {
+                start: 1_000_000_000,
+                end: 2_000_000_000,
+                omitted: 2,
+                thread_id: 1,
+            })
+        );
+        assert!(budget.spend(100, 1));
+        assert_eq!(budget.finish(), None);
+    }
+}
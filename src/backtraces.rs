@@ -0,0 +1,71 @@
+//! Backtraces of instants, see
+//! [`PerfettoLayerBuilder::event_backtraces`](crate::PerfettoLayerBuilder::event_backtraces).
+
+use backtrace::Backtrace;
+
+use crate::packet::DebugValue;
+
+/// Crates whose frames are left out, as they are the same for every event.
+const SKIPPED_PREFIXES: &[&str] = &[
+    "backtrace::",
+    "tracing::",
+    "tracing_core::",
+    "tracing_subscriber::",
+    "tracing_perfetto::backtraces::",
+    "<tracing_core::",
+    "<tracing_subscriber::",
+    "<tracing_perfetto::",
+    "std::thread::local::",
+];
+
+/// A backtrace taken where an event was recorded. Taking it only walks the
+/// stack; the writer thread symbolizes it when it writes the event.
+#[derive(Debug, Clone)]
+pub struct EventBacktrace {
+    trace: Backtrace,
+    frames: usize,
+}
+
+impl EventBacktrace {
+    pub fn capture(frames: usize) -> Self {
+        EventBacktrace {
+            trace: Backtrace::new_unresolved(),
+            frames,
+        }
+    }
+
+    /// The innermost frames outside of tracing, as an array of strings of
+    /// the form `function (file:line)`.
+    pub fn resolve(&self) -> DebugValue {
+        let mut trace = self.trace.clone();
+        trace.resolve();
+        let frames = trace
+            .frames()
+            .iter()
+            .flat_map(|frame| frame.symbols())
+            .filter_map(|symbol| {
+                let name = format!("{:#}", symbol.name()?);
+                Some(match (symbol.filename(), symbol.lineno()) {
+                    (Some(file), Some(line)) => format!("{} ({}:{})", name, file.display(), line),
+                    _ => name,
+                })
+            })
+            .skip_while(|frame| {
+                SKIPPED_PREFIXES
+                    .iter()
+                    .any(|prefix| frame.starts_with(prefix))
+            })
+            .take(self.frames)
+            .map(DebugValue::String)
+            .collect();
+        DebugValue::Array(frames)
+    }
+}
+
+/// Backtraces are equal if they were taken at the same stack.
+impl PartialEq for EventBacktrace {
+    fn eq(&self, other: &Self) -> bool {
+        let ips = |trace: &Backtrace| -> Vec<_> { trace.frames().iter().map(|f| f.ip()).collect() };
+        self.frames == other.frames && ips(&self.trace) == ips(&other.trace)
+    }
+}
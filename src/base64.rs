@@ -0,0 +1,224 @@
+//! Writing the trace as base64 text lines, for environments where logs are
+//! the only way to get data out, e.g. locked-down containers.
+//!
+//! Each line has the form `perfetto-b64:<index>:<len>:<data>`, where `index`
+//! counts the chunks from 0, `len` is the number of decoded bytes and `data`
+//! is the base64 encoding of the chunk. Log pipelines usually prefix lines
+//! with timestamps and the like, so the decoder looks for the marker
+//! anywhere in a line and ignores lines without it.
+
+use std::{fmt, io};
+
+const MARKER: &str = "perfetto-b64:";
+
+/// Number of trace bytes per line. A multiple of 3, so full lines need no
+/// padding, and small enough for log pipelines that limit line length.
+const CHUNK_SIZE: usize = 3 * 1024;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes everything written to it as chunk lines to the inner writer.
+pub struct ChunkWriter {
+    inner: Box<dyn io::Write + Send>,
+    buf: Vec<u8>,
+    next_index: u64,
+}
+
+impl ChunkWriter {
+    pub fn new(inner: Box<dyn io::Write + Send>) -> Self {
+        ChunkWriter {
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            next_index: 0,
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut line = format!("{}{}:{}:", MARKER, self.next_index, self.buf.len());
+        encode(&self.buf, &mut line);
+        line.push('\n');
+        // Write the whole line at once, so it isn't interleaved with other
+        // output to the same stream.
+        self.inner.write_all(line.as_bytes())?;
+        self.buf.clear();
+        self.next_index += 1;
+        Ok(())
+    }
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+fn encode(data: &[u8], out: &mut String) {
+    for group in data.chunks(3) {
+        let b = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=');
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// Errors when reassembling a trace from base64 chunk lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Base64DecodeError {
+    /// A chunk line could not be parsed. Contains the chunk line.
+    Malformed(String),
+    /// A chunk is missing, or chunks are out of order.
+    MissingChunk { expected: u64, found: u64 },
+}
+
+impl fmt::Display for Base64DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Base64DecodeError::Malformed(line) => write!(f, "malformed chunk line: {}", line),
+            Base64DecodeError::MissingChunk { expected, found } => {
+                write!(f, "expected chunk {}, found chunk {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Base64DecodeError {}
+
+/// Reassemble a trace written with
+/// [`PerfettoLayerBuilder::base64_output`](crate::PerfettoLayerBuilder::base64_output)
+/// from log output. Lines without chunks are ignored.
+pub fn decode_base64_chunks(log: &str) -> Result<Vec<u8>, Base64DecodeError> {
+    let mut trace = Vec::new();
+    let mut expected = 0;
+    for line in log.lines() {
+        let Some(start) = line.find(MARKER) else {
+            continue;
+        };
+        let chunk = line[start + MARKER.len()..].trim_end();
+        let malformed = || Base64DecodeError::Malformed(chunk.to_string());
+        let mut parts = chunk.splitn(3, ':');
+        let (Some(index), Some(len), Some(data)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(malformed());
+        };
+        let index: u64 = index.parse().map_err(|_| malformed())?;
+        let len: usize = len.parse().map_err(|_| malformed())?;
+        if index != expected {
+            return Err(Base64DecodeError::MissingChunk {
+                expected,
+                found: index,
+            });
+        }
+        let bytes = decode(data).ok_or_else(malformed)?;
+        if bytes.len() != len {
+            return Err(malformed());
+        }
+        trace.extend(bytes);
+        expected += 1;
+    }
+    Ok(trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encode_matches_standard_base64() {
+        let mut out = String::new();
+        encode(b"Man", &mut out);
+        encode(b"Ma", &mut out);
+        encode(b"M", &mut out);
+        assert_eq!(out, "TWFuTWE=TQ==");
+        assert_eq!(decode("TWE=").unwrap(), b"Ma");
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        let buf = SharedBuf::default();
+        let mut writer = ChunkWriter::new(Box::new(buf.clone()));
+        writer.write_all(&data[..5]).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(&data[5..]).unwrap();
+        writer.flush().unwrap();
+
+        // Interleave the chunks with other log lines and prefixes.
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let log: String = output
+            .lines()
+            .map(|line| format!("2024-01-01T00:00:00Z app[1]: {}\nunrelated line\n", line))
+            .collect();
+        assert_eq!(decode_base64_chunks(&log).unwrap(), data);
+
+        let missing: String = log
+            .lines()
+            .filter(|line| !line.contains("perfetto-b64:1:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            decode_base64_chunks(&missing),
+            Err(Base64DecodeError::MissingChunk {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+}
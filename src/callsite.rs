@@ -0,0 +1,51 @@
+//! Identifiers of callsites that stay the same across builds, see
+//! [`PerfettoLayerBuilder::source_locations`](crate::PerfettoLayerBuilder::source_locations).
+
+/// A hash of the target, name and file of a callsite.
+///
+/// Unlike [`tracing::callsite::Identifier`], which is an address, the hash
+/// is the same in every build of the same code, so tools can match slices
+/// of different traces. The line is left out, so that edits elsewhere in
+/// the file keep the hash; events without an explicit name still change
+/// it when they move, as tracing puts the line into their name.
+pub fn callsite_hash(metadata: &tracing::Metadata<'_>) -> u64 {
+    // FNV-1a, which unlike `DefaultHasher` is fixed across Rust versions.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let parts = [
+        metadata.target(),
+        metadata.name(),
+        metadata.file().unwrap_or(""),
+    ];
+    for part in parts {
+        // The separator keeps ("ab", "c") and ("a", "bc") apart.
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable() {
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let span = tracing::info_span!("stable");
+        let metadata = span.metadata().unwrap();
+        assert_eq!(callsite_hash(metadata), callsite_hash(metadata));
+        // Another span of the same name, in another line.
+        let other = tracing::info_span!("stable");
+        assert_eq!(
+            callsite_hash(metadata),
+            callsite_hash(other.metadata().unwrap())
+        );
+        let renamed = tracing::info_span!("unstable");
+        assert_ne!(
+            callsite_hash(metadata),
+            callsite_hash(renamed.metadata().unwrap())
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Timestamps for trace events.
+
+use std::time::{Instant, SystemTime};
+
+use crate::packet::ClockId;
+
+/// What trace timestamps count from, see
+/// [`PerfettoLayerBuilder::timestamp_origin`](crate::PerfettoLayerBuilder::timestamp_origin).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Origin {
+    /// Timestamps start at zero when the layer is built.
+    #[default]
+    ProcessStart,
+    /// Timestamps are those of `CLOCK_BOOTTIME`, so traces of processes on
+    /// the same machine line up. Only on Linux; elsewhere timestamps count
+    /// from process start.
+    SystemBoot,
+    /// Timestamps are those of `CLOCK_MONOTONIC`, the clock of ftrace and
+    /// of most other tracers on Linux, which stops while the system is
+    /// suspended. Only on Linux; elsewhere timestamps count from process
+    /// start.
+    Monotonic,
+    /// Timestamps are nanoseconds since the Unix epoch, so traces from
+    /// different machines line up as far as their clocks agree.
+    UnixEpochNs,
+    /// Timestamps are `offset_ns` plus the time since `start`, the time
+    /// base of a host application, see
+    /// [`PerfettoLayerBuilder::time_base`](crate::PerfettoLayerBuilder::time_base).
+    TimeBase { start: Instant, offset_ns: u64 },
+}
+
+/// Source of trace timestamps: nanoseconds since the clock was created,
+/// plus the offset of the origin.
+///
+/// `Instant` uses `CLOCK_MONOTONIC` on Linux, which stops while the system
+/// is suspended. A trace spanning a laptop sleep would then be squashed
+/// together, and drift away from the boottime clock we declare for our
+/// packets. So on Linux we read `CLOCK_BOOTTIME` directly, which keeps
+/// counting during suspend.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    start: Instant,
+    boottime_start: Option<u64>,
+    offset: u64,
+    clock_id: ClockId,
+}
+
+impl Clock {
+    pub fn new(origin: Origin) -> Self {
+        let boottime_start = boottime_ns();
+        let (offset, clock_id) = match origin {
+            Origin::ProcessStart => (0, ClockId::Boottime),
+            Origin::SystemBoot => (boottime_start.unwrap_or(0), ClockId::Boottime),
+            Origin::Monotonic => {
+                // `Instant` is `CLOCK_MONOTONIC` on Linux, so only its start
+                // has to be read.
+                return Clock {
+                    start: Instant::now(),
+                    boottime_start: None,
+                    offset: monotonic_ns().unwrap_or(0),
+                    clock_id: ClockId::Monotonic,
+                };
+            }
+            Origin::UnixEpochNs => {
+                let since_epoch = SystemTime::UNIX_EPOCH.elapsed().unwrap_or_default();
+                (since_epoch.as_nanos() as u64, ClockId::Realtime)
+            }
+            Origin::TimeBase { start, offset_ns } => {
+                // Counting with `Instant` like the host does, even across a
+                // suspend.
+                return Clock {
+                    start,
+                    boottime_start: None,
+                    offset: offset_ns,
+                    clock_id: ClockId::Boottime,
+                };
+            }
+        };
+        Clock {
+            start: Instant::now(),
+            boottime_start,
+            offset,
+            clock_id,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        if let Some(start) = self.boottime_start {
+            if let Some(now) = boottime_ns() {
+                return now.saturating_sub(start) + self.offset;
+            }
+        }
+        self.start.elapsed().as_nanos() as u64 + self.offset
+    }
+
+    /// The clock that the timestamps are declared in.
+    pub fn clock_id(&self) -> ClockId {
+        self.clock_id
+    }
+
+    /// The readings of the system clocks at `now`, a timestamp of this
+    /// clock, for a clock snapshot that lets Perfetto convert between them.
+    /// The clock of the timestamps comes first; system clocks it stands in
+    /// for are left out.
+    pub fn snapshot(&self, now: u64) -> Vec<(ClockId, u64)> {
+        let since_epoch = SystemTime::UNIX_EPOCH
+            .elapsed()
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let readings = [
+            (ClockId::Boottime, boottime_ns()),
+            (ClockId::Monotonic, monotonic_ns()),
+            (ClockId::Realtime, Some(since_epoch)),
+        ];
+        let mut clocks = vec![(self.clock_id, now)];
+        for (clock_id, reading) in readings {
+            if let Some(reading) = reading.filter(|_| clock_id != self.clock_id) {
+                clocks.push((clock_id, reading));
+            }
+        }
+        clocks
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(Origin::ProcessStart)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boottime_ns() -> Option<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boottime_ns() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn monotonic_ns() -> Option<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn monotonic_ns() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins() {
+        let clock = Clock::new(Origin::ProcessStart);
+        assert_eq!(clock.clock_id(), ClockId::Boottime);
+        assert!(clock.now() < 1_000_000_000);
+
+        let before = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos() as u64;
+        let clock = Clock::new(Origin::UnixEpochNs);
+        let now = clock.now();
+        let after = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos() as u64;
+        assert_eq!(clock.clock_id(), ClockId::Realtime);
+        assert!(before <= now && now <= after + 1_000_000);
+
+        let clock = Clock::new(Origin::SystemBoot);
+        assert_eq!(clock.clock_id(), ClockId::Boottime);
+        if let Some(boottime) = boottime_ns() {
+            assert!(clock.now() >= boottime);
+        }
+    }
+
+    #[test]
+    fn monotonic() {
+        let before = monotonic_ns();
+        let clock = Clock::new(Origin::Monotonic);
+        assert_eq!(clock.clock_id(), ClockId::Monotonic);
+        let now = clock.now();
+        if let (Some(before), Some(after)) = (before, monotonic_ns()) {
+            assert!(before <= now && now <= after);
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let clock = Clock::new(Origin::Monotonic);
+        let now = clock.now();
+        let clocks = clock.snapshot(now);
+        assert_eq!(clocks[0], (ClockId::Monotonic, now));
+        let ids: Vec<_> = clocks.iter().map(|(id, _)| *id).collect();
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                ids,
+                [ClockId::Monotonic, ClockId::Boottime, ClockId::Realtime]
+            );
+        }
+
+        // Process start timestamps stand in for the boottime clock.
+        let clock = Clock::new(Origin::ProcessStart);
+        let clocks = clock.snapshot(5);
+        assert_eq!(clocks[0], (ClockId::Boottime, 5));
+        assert_eq!(
+            clocks
+                .iter()
+                .filter(|(id, _)| *id == ClockId::Boottime)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn time_base() {
+        let start = Instant::now();
+        let offset_ns = 5_000_000_000;
+        let clock = Clock::new(Origin::TimeBase { start, offset_ns });
+        let now = clock.now();
+        let elapsed = start.elapsed().as_nanos() as u64;
+        assert!(offset_ns <= now && now <= offset_ns + elapsed);
+    }
+}
@@ -0,0 +1,165 @@
+//! Compressing the trace with zstd, optionally with a dictionary, see
+//! [`PerfettoLayerBuilder::zstd_compression`](crate::PerfettoLayerBuilder::zstd_compression).
+//!
+//! Each chunk of the trace is compressed as a zstd frame of its own, and
+//! written as a frame of [`transform_chunks`](crate::PerfettoLayerBuilder::transform_chunks).
+//! Small chunks compress poorly without a dictionary, as each starts from
+//! scratch; a dictionary trained on similar traces gives them the common
+//! strings and packet layouts up front.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::framing::{decode_frames, ChunkTransform};
+
+/// Size of the chunks the writer hands to the transform, and of the samples
+/// taken from trace files to train a dictionary.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// The dictionary to compress the trace with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZstdDictionary {
+    /// Compress each chunk on its own.
+    None,
+    /// Use a dictionary trained beforehand, e.g. with
+    /// [`train_zstd_dictionary`] on earlier traces.
+    Use(Vec<u8>),
+    /// Train a dictionary of up to `max_size` bytes on the first `samples`
+    /// chunks of the trace, which are compressed without it, and use it for
+    /// the rest of the trace, including later files when rotating. The
+    /// dictionary is written next to the trace, with `.zdict` appended to
+    /// its path.
+    Train { max_size: usize, samples: usize },
+}
+
+/// Train a dictionary of up to `max_size` bytes on existing, uncompressed
+/// trace files.
+///
+/// This fails if the traces are too small to train on; zstd wants about a
+/// hundred times `max_size` bytes of samples.
+pub fn train_zstd_dictionary<P: AsRef<Path>>(traces: &[P], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut samples = Vec::new();
+    for trace in traces {
+        let data = fs::read(trace)?;
+        samples.extend(data.chunks(SAMPLE_SIZE).map(<[u8]>::to_vec));
+    }
+    zstd::dict::from_samples(&samples, max_size)
+}
+
+/// Get back a trace written with
+/// [`PerfettoLayerBuilder::zstd_compression`](crate::PerfettoLayerBuilder::zstd_compression),
+/// given the dictionary it was compressed with, if any. An incomplete last
+/// frame is ignored.
+pub fn decode_zstd(data: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let dictionary = dictionary.unwrap_or_default();
+    decode_frames(data, |frame| {
+        // Chunks compressed before a dictionary was trained don't refer to
+        // it, so they decode with it all the same.
+        let mut chunk = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(frame, dictionary)?.read_to_end(&mut chunk)?;
+        Ok(chunk)
+    })
+}
+
+/// The transform compressing the trace, which writes a trained dictionary
+/// to `dictionary_path`, if any.
+///
+/// An invalid level or dictionary fails the first write, like any other
+/// error of the output.
+pub(crate) fn zstd_transform(
+    level: i32,
+    dictionary: ZstdDictionary,
+    dictionary_path: Option<PathBuf>,
+) -> ChunkTransform {
+    let (mut dictionary, mut training) = match dictionary {
+        ZstdDictionary::None => (Vec::new(), None),
+        ZstdDictionary::Use(dictionary) => (dictionary, None),
+        ZstdDictionary::Train { max_size, samples } => {
+            (Vec::new(), Some((max_size, samples, Vec::new())))
+        }
+    };
+    let mut compressor = None;
+    Box::new(move |chunk| {
+        let compressed = match &mut compressor {
+            Some(compressor) => compressor,
+            None => compressor.insert(zstd::bulk::Compressor::with_dictionary(level, &dictionary)?),
+        }
+        .compress(chunk)?;
+        let Some((max_size, wanted, samples)) = &mut training else {
+            return Ok(compressed);
+        };
+        samples.push(chunk.to_vec());
+        if samples.len() >= *wanted {
+            // Keep compressing without a dictionary if the samples are
+            // too alike or too few to train one.
+            if let Ok(trained) = zstd::dict::from_samples(samples, *max_size) {
+                if let Some(path) = &dictionary_path {
+                    fs::write(path, &trained)?;
+                }
+                dictionary = trained;
+                compressor = None;
+            }
+            training = None;
+        }
+        Ok(compressed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write as _;
+
+    use super::*;
+    use crate::framing::FrameWriter;
+
+    /// Chunks that share most of their strings, like those of a trace.
+    fn chunks() -> Vec<Vec<u8>> {
+        (0..200)
+            .map(|i| {
+                let mut chunk = String::new();
+                for j in 0..20 {
+                    let _ = write!(
+                        chunk,
+                        "slice request_{} handler=http::serve status={} ",
+                        (i * 7 + j) % 13,
+                        200 + (i + j) % 5
+                    );
+                }
+                chunk.into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn train_round_trip() {
+        let dir = std::env::temp_dir().join(format!("zstd-train-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dictionary_path = dir.join("trace.zdict");
+        let transform = zstd_transform(
+            3,
+            ZstdDictionary::Train {
+                max_size: 1024,
+                samples: 100,
+            },
+            Some(dictionary_path.clone()),
+        );
+        let mut writer = FrameWriter::new(Vec::new(), transform);
+        let mut expected = Vec::new();
+        for chunk in chunks() {
+            io::Write::write_all(&mut writer, &chunk).unwrap();
+            expected.extend(chunk);
+        }
+
+        let dictionary = fs::read(&dictionary_path).unwrap();
+        assert_eq!(
+            decode_zstd(&writer.inner, Some(&dictionary)).unwrap(),
+            expected
+        );
+        // Chunks after training can't be decoded without the dictionary.
+        assert!(decode_zstd(&writer.inner, None).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
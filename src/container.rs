@@ -0,0 +1,116 @@
+//! Information about the container the process runs in.
+
+use crate::packet::DebugAnnotation;
+
+/// Host name, cgroup and container of the process.
+#[derive(Debug, Clone)]
+pub struct ContainerInfo {
+    pub hostname: String,
+    /// Path of the process's cgroup, e.g.
+    /// `/system.slice/docker-<id>.scope`.
+    pub cgroup: Option<String>,
+    /// Id of the container, guessed from the cgroup path or the mounts.
+    pub container_id: Option<String>,
+}
+
+impl ContainerInfo {
+    /// Query the info of the current process.
+    ///
+    /// Returns `None` on platforms where we don't know how to get it.
+    #[cfg(target_os = "linux")]
+    pub fn current() -> Option<ContainerInfo> {
+        let read = |path| std::fs::read_to_string(path).ok();
+        let hostname = read("/proc/sys/kernel/hostname")?.trim().to_string();
+        let cgroups = read("/proc/self/cgroup").unwrap_or_default();
+        // Inside a cgroup namespace the path is just "/", but the files
+        // docker mounts into the container still mention the id.
+        let container_id = container_id(&cgroups).or_else(|| {
+            let mounts = read("/proc/self/mountinfo")?;
+            mounts.lines().find_map(container_id)
+        });
+        Some(ContainerInfo {
+            hostname,
+            cgroup: cgroup_path(&cgroups).map(str::to_string),
+            container_id,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn current() -> Option<ContainerInfo> {
+        None
+    }
+
+    pub fn debug_annotations(&self) -> Vec<DebugAnnotation> {
+        let mut annotations = vec![DebugAnnotation::new("hostname", self.hostname.as_str())];
+        if let Some(cgroup) = &self.cgroup {
+            annotations.push(DebugAnnotation::new("cgroup", cgroup.as_str()));
+        }
+        if let Some(id) = &self.container_id {
+            annotations.push(DebugAnnotation::new("container_id", id.as_str()));
+        }
+        annotations
+    }
+}
+
+/// The path of the cgroup v2 hierarchy (`0::<path>`) in the contents of
+/// `/proc/<pid>/cgroup`, or else that of the first v1 hierarchy.
+fn cgroup_path(cgroups: &str) -> Option<&str> {
+    let paths = cgroups
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2).map(|path| (line, path)));
+    let mut first = None;
+    for (line, path) in paths {
+        if line.starts_with("0::") {
+            return Some(path);
+        }
+        first = first.or(Some(path));
+    }
+    first
+}
+
+/// Find a container id in paths like `/docker/<id>`,
+/// `/system.slice/docker-<id>.scope` or
+/// `/var/lib/docker/containers/<id>/hostname`, as used by docker, podman,
+/// containerd and Kubernetes: a path segment of 64 hex digits, possibly with
+/// the runtime as prefix.
+fn container_id(text: &str) -> Option<String> {
+    text.split(['/', '\n', ' ']).find_map(|segment| {
+        let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+        let id = segment.rsplit('-').next()?;
+        let is_id = id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit());
+        is_id.then(|| id.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cgroup_path, container_id};
+
+    const ID: &str = "3f4e2b1a0c9d8e7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f";
+
+    #[test]
+    fn cgroup_paths() {
+        assert_eq!(cgroup_path(""), None);
+        assert_eq!(cgroup_path("0::/user.slice\n"), Some("/user.slice"));
+        assert_eq!(
+            cgroup_path("12:memory:/docker/abc\n1:name=systemd:/init.scope\n"),
+            Some("/docker/abc")
+        );
+    }
+
+    #[test]
+    fn container_ids() {
+        assert_eq!(container_id("0::/user.slice/user-1000.slice"), None);
+        for path in [
+            format!("12:memory:/docker/{}", ID),
+            format!("0::/system.slice/docker-{}.scope", ID),
+            format!("0::/kubepods/burstable/pod1234/cri-containerd-{}.scope", ID),
+            format!(
+                "480 470 0:45 /var/lib/docker/containers/{}/hostname /etc/hostname rw",
+                ID
+            ),
+        ] {
+            assert_eq!(container_id(&path).as_deref(), Some(ID), "{}", path);
+        }
+    }
+}
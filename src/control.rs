@@ -0,0 +1,60 @@
+//! Switching recording on and off at runtime, see
+//! [`PerfettoLayer::control_handle`](crate::PerfettoLayer::control_handle).
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+#[derive(Debug)]
+struct Switch {
+    enabled: AtomicBool,
+    /// Set once recording was disabled, after which the layer has to track
+    /// which span entries it skipped.
+    toggled: AtomicBool,
+}
+
+/// Turns recording of a [`PerfettoLayer`](crate::PerfettoLayer) on and off,
+/// e.g. from an admin endpoint, while the trace keeps running. Cloning the
+/// controller gives another handle to the same switch.
+///
+/// While recording is disabled, the layer does little more than load a
+/// flag: new spans, events and recorded fields are ignored, and only the
+/// threads entering a span are noted, so their exits aren't recorded
+/// either. Spans created while disabled have no arguments when entered
+/// after enabling again.
+#[derive(Debug, Clone)]
+pub struct TracingController(Arc<Switch>);
+
+impl TracingController {
+    pub(crate) fn new(enabled: bool) -> Self {
+        TracingController(Arc::new(Switch {
+            enabled: AtomicBool::new(enabled),
+            toggled: AtomicBool::new(!enabled),
+        }))
+    }
+
+    pub fn enable(&self) {
+        self.set_enabled(true);
+    }
+
+    pub fn disable(&self) {
+        self.set_enabled(false);
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if !enabled {
+            self.0.toggled.store(true, Ordering::Relaxed);
+        }
+        self.0.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether recording was ever disabled.
+    pub(crate) fn toggled(&self) -> bool {
+        self.0.toggled.load(Ordering::Relaxed)
+    }
+}
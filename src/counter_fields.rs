@@ -0,0 +1,123 @@
+//! Recording numeric fields of events as counters, see
+//! [`PerfettoLayerBuilder::unit_hint`](crate::PerfettoLayerBuilder::unit_hint)
+//! and [`PerfettoLayerBuilder::counter_field`](crate::PerfettoLayerBuilder::counter_field).
+//!
+//! Counters are nested below the process track, or below the track of the
+//! thread that recorded them, see
+//! [`PerfettoLayerBuilder::counter_scope`](crate::PerfettoLayerBuilder::counter_scope).
+
+use tracing::field::{Field, Visit};
+
+use crate::packet::CounterUnit;
+
+/// The unit of a numeric field. Perfetto shows counters in these units
+/// with a suitable scale, e.g. "1.5 ms" or "3 MiB".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    DurationNs,
+    DurationUs,
+    DurationMs,
+    Bytes,
+    Count,
+}
+
+impl Unit {
+    /// The unit in the trace, and the factor that converts values to it.
+    pub(crate) fn trace_unit(self) -> (CounterUnit, i64) {
+        match self {
+            Unit::DurationNs => (CounterUnit::TimeNs, 1),
+            Unit::DurationUs => (CounterUnit::TimeNs, 1_000),
+            Unit::DurationMs => (CounterUnit::TimeNs, 1_000_000),
+            Unit::Bytes => (CounterUnit::SizeBytes, 1),
+            Unit::Count => (CounterUnit::Count, 1),
+        }
+    }
+}
+
+/// Whether a counter has one track for the process, or one for each
+/// thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CounterScope {
+    /// One track, below the process track, e.g. for the size of a cache.
+    #[default]
+    Process,
+    /// A track below the track of each thread that records the counter,
+    /// e.g. for the allocation rate of each thread.
+    Thread,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterValue {
+    Int(i64),
+    Double(f64),
+}
+
+impl CounterValue {
+    pub(crate) fn scaled(self, factor: i64) -> CounterValue {
+        match self {
+            CounterValue::Int(value) => CounterValue::Int(value.saturating_mul(factor)),
+            CounterValue::Double(value) => CounterValue::Double(value * factor as f64),
+        }
+    }
+}
+
+/// Collects the values of the fields that are recorded as counters.
+pub(crate) struct CounterVisitor {
+    fields: Vec<&'static str>,
+    pub samples: Vec<(&'static str, CounterValue)>,
+}
+
+impl CounterVisitor {
+    pub fn new(fields: Vec<&'static str>) -> Self {
+        CounterVisitor {
+            fields,
+            samples: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, field: &Field, value: CounterValue) {
+        if self.fields.contains(&field.name()) {
+            self.samples.push((field.name(), value));
+        }
+    }
+}
+
+impl Visit for CounterVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, CounterValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, CounterValue::Int(value.min(i64::MAX as u64) as i64));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, CounterValue::Double(value));
+    }
+
+    /// Fields that are not numbers are left to the other visitors.
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        let (unit, factor) = Unit::DurationMs.trace_unit();
+        assert_eq!(unit, CounterUnit::TimeNs);
+        assert_eq!(
+            CounterValue::Int(3).scaled(factor),
+            CounterValue::Int(3_000_000)
+        );
+        assert_eq!(
+            CounterValue::Double(1.5).scaled(factor),
+            CounterValue::Double(1_500_000.0)
+        );
+        assert_eq!(
+            CounterValue::Int(i64::MAX).scaled(factor),
+            CounterValue::Int(i64::MAX)
+        );
+    }
+}
@@ -1,3 +1,26 @@
+use std::fmt;
+
+/// Errors while encoding a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmitError {
+    /// A nested message does not fit into the space reserved for its length.
+    MessageTooLarge { size: usize, max_size: usize },
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::MessageTooLarge { size, max_size } => write!(
+                f,
+                "nested message of {} bytes exceeds maximum size of {} bytes",
+                size, max_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
 pub struct ProtoEmitter {
     data: Vec<u8>,
 }
@@ -20,7 +43,6 @@ impl ProtoEmitter {
         self.data.extend(data.as_bytes());
     }
 
-    #[allow(unused)]
     pub fn bytes_field(&mut self, field_id: u32, data: &[u8]) {
         Self::check_valid_field_id(field_id);
         self.push_varint(((field_id << 3) | LENGTH_DELIMITED) as u64);
@@ -29,6 +51,10 @@ impl ProtoEmitter {
     }
 
     pub fn double_field(&mut self, field_id: u32, data: f64) {
+        self.fixed64_field(field_id, data.to_bits())
+    }
+
+    pub fn fixed64_field(&mut self, field_id: u32, data: u64) {
         Self::check_valid_field_id(field_id);
         self.push_varint(((field_id << 3) | FIXED_LENGTH_8) as u64);
         let bytes: [u8; 8] = data.to_le_bytes();
@@ -50,37 +76,40 @@ impl ProtoEmitter {
 
     // TODO: Optimize via SIMD
     fn push_varint(&mut self, mut val: u64) {
-        //    dbg!(val);
         loop {
             let byte = (val & 0x7f) as u8;
             val >>= 7;
             if val > 0 {
-                //dbg!(byte | 0x80);
                 self.data.push(byte | 0x80);
             } else {
-                //dbg!(byte);
                 self.data.push(byte);
                 return;
             }
         }
     }
 
-    fn write_size3(&mut self, offset: usize, size: u32) {
-        assert!(size < (1 << 21));
+    fn write_size3(&mut self, offset: usize, size: usize) -> Result<(), EmitError> {
+        check_size(size, 1 << 21)?;
         self.data[offset] = ((size & 0x7f) as u8) | 0x80;
         self.data[offset + 1] = (((size >> 7) & 0x7f) as u8) | 0x80;
         self.data[offset + 2] = ((size >> 14) & 0x7f) as u8;
+        Ok(())
     }
 
-    fn write_size2(&mut self, offset: usize, size: u32) {
-        assert!(size < (1 << 14));
+    fn write_size2(&mut self, offset: usize, size: usize) -> Result<(), EmitError> {
+        check_size(size, 1 << 14)?;
         self.data[offset] = ((size & 0x7f) as u8) | 0x80;
         self.data[offset + 1] = ((size >> 7) & 0x7f) as u8;
+        Ok(())
     }
 
-    pub fn nested<F>(&mut self, field_id: u32, mut build: F)
+    /// Emit a nested message, built by `build`.
+    ///
+    /// On error, the emitter contains a partial message and should be
+    /// cleared.
+    pub fn nested<F>(&mut self, field_id: u32, build: F) -> Result<(), EmitError>
     where
-        F: FnMut(&mut ProtoEmitter) -> (),
+        F: FnOnce(&mut ProtoEmitter) -> Result<(), EmitError>,
     {
         Self::check_valid_field_id(field_id);
         self.push_varint(((field_id << 3) | LENGTH_DELIMITED) as u64);
@@ -91,14 +120,15 @@ impl ProtoEmitter {
         }
         // Get current write offset
         let ofs = self.data.len();
-        build(self);
+        build(self)?;
         let size = self.data.len() - ofs;
-        self.write_size3(ofs - 3, size as u32);
+        self.write_size3(ofs - 3, size)
     }
 
-    pub fn nested_small<F>(&mut self, field_id: u32, mut build: F)
+    /// Like [`nested`](Self::nested), but for messages smaller than 16KiB.
+    pub fn nested_small<F>(&mut self, field_id: u32, build: F) -> Result<(), EmitError>
     where
-        F: FnMut(&mut ProtoEmitter) -> (),
+        F: FnOnce(&mut ProtoEmitter) -> Result<(), EmitError>,
     {
         Self::check_valid_field_id(field_id);
         self.push_varint(((field_id << 3) | LENGTH_DELIMITED) as u64);
@@ -109,11 +139,47 @@ impl ProtoEmitter {
         }
         // Get current write offset
         let ofs = self.data.len();
-        build(self);
+        build(self)?;
         let size = self.data.len() - ofs;
-        self.write_size2(ofs - 2, size as u32);
+        self.write_size2(ofs - 2, size)
+    }
+}
+
+fn check_size(size: usize, max_size: usize) -> Result<(), EmitError> {
+    if size < max_size {
+        Ok(())
+    } else {
+        Err(EmitError::MessageTooLarge { size, max_size })
     }
 }
 
 const LENGTH_DELIMITED: u32 = 2;
 const FIXED_LENGTH_8: u32 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_too_large() {
+        let mut out = ProtoEmitter::new();
+        let big = "x".repeat(1 << 14);
+        assert_eq!(
+            out.nested_small(1, |out| {
+                out.string_field(1, &big);
+                Ok(())
+            }),
+            Err(EmitError::MessageTooLarge {
+                size: big.len() + 4,
+                max_size: 1 << 14
+            })
+        );
+        out.clear();
+        assert!(out
+            .nested(1, |out| {
+                out.string_field(1, &big);
+                Ok(())
+            })
+            .is_ok());
+    }
+}
@@ -0,0 +1,447 @@
+//! Emitting the trace as ETW events on Windows.
+//!
+//! Instead of writing a Perfetto trace, this backend turns slices into
+//! TraceLogging start and stop events and instants into info events, so
+//! they show up in Windows Performance Analyzer next to kernel data.
+//! TraceLogging events describe their own fields, so no manifest needs to be
+//! registered. Start and stop events of a slice share an activity id, with
+//! the enclosing slice as the related activity.
+//!
+//! The events are written by the backend thread, so ETW records that
+//! thread, and the time of writing, which lags slightly behind the time
+//! the span was entered. Every event therefore carries the traced thread and
+//! the original timestamp as fields.
+//!
+//! On other platforms, the events are dropped.
+
+use std::{collections::HashMap, fs::File, ops::Deref, sync::Arc};
+
+use crossbeam_channel::Receiver;
+
+use crate::{
+    packet::{DebugAnnotation, DebugValue, IString},
+    writer::WriterError,
+    Message, ThreadId,
+};
+
+/// The ETW provider the events are written to.
+#[derive(Debug, Clone)]
+pub struct EtwProvider {
+    pub name: String,
+    /// The provider GUID, e.g. `0x5f1b2e7c_1d3a_4b6e_9c8d_0a1b2c3d4e5f`.
+    pub id: u128,
+}
+
+const OPCODE_INFO: u8 = 0;
+const OPCODE_START: u8 = 1;
+const OPCODE_STOP: u8 = 2;
+
+// TraceLogging field types.
+const IN_ANSISTRING: u8 = 2;
+const IN_INT64: u8 = 9;
+const IN_UINT64: u8 = 10;
+const IN_DOUBLE: u8 = 12;
+const IN_BOOL32: u8 = 13;
+const OUT_UTF8: u8 = 35;
+/// Set on the in-type if an out-type follows.
+const CHAIN_FLAG: u8 = 0x80;
+
+/// The in-memory layout of a GUID, which stores the first three groups in
+/// little endian.
+fn guid_bytes(id: u128) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..4].copy_from_slice(&((id >> 96) as u32).to_le_bytes());
+    bytes[4..6].copy_from_slice(&((id >> 80) as u16).to_le_bytes());
+    bytes[6..8].copy_from_slice(&((id >> 64) as u16).to_le_bytes());
+    bytes[8..].copy_from_slice(&(id as u64).to_be_bytes());
+    bytes
+}
+
+/// Append a nul-terminated string, dropping interior nul bytes.
+fn push_str(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.bytes().filter(|&b| b != 0));
+    out.push(0);
+}
+
+/// Set the size prefix of a metadata blob.
+fn finish_metadata(mut metadata: Vec<u8>) -> Vec<u8> {
+    let size = metadata.len() as u16;
+    metadata[..2].copy_from_slice(&size.to_le_bytes());
+    metadata
+}
+
+fn provider_metadata(name: &str) -> Vec<u8> {
+    let mut metadata = vec![0, 0];
+    push_str(&mut metadata, name);
+    finish_metadata(metadata)
+}
+
+/// A TraceLogging event: the metadata describing its name and fields, and
+/// the field values.
+struct Event {
+    metadata: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Event {
+    fn new(name: &str) -> Self {
+        // Size, to be filled in, and tags.
+        let mut metadata = vec![0, 0, 0];
+        push_str(&mut metadata, name);
+        Event {
+            metadata,
+            data: Vec::new(),
+        }
+    }
+
+    fn field(&mut self, name: &str, value: &DebugValue) {
+        push_str(&mut self.metadata, name);
+        match value {
+            DebugValue::Bool(b) => {
+                self.metadata.push(IN_BOOL32);
+                self.data.extend((*b as u32).to_le_bytes());
+            }
+            DebugValue::Uint(n) => {
+                self.metadata.push(IN_UINT64);
+                self.data.extend(n.to_le_bytes());
+            }
+            DebugValue::Int(n) => {
+                self.metadata.push(IN_INT64);
+                self.data.extend(n.to_le_bytes());
+            }
+            DebugValue::Double(d) => {
+                self.metadata.push(IN_DOUBLE);
+                self.data.extend(d.to_le_bytes());
+            }
+            DebugValue::String(s) => self.string_value(s),
+            other => self.string_value(&format!("{:?}", other)),
+        }
+    }
+
+    fn string_value(&mut self, s: &str) {
+        self.metadata.extend([IN_ANSISTRING | CHAIN_FLAG, OUT_UTF8]);
+        push_str(&mut self.data, s);
+    }
+
+    fn annotations(&mut self, annotations: &[DebugAnnotation]) {
+        for annotation in annotations {
+            if let IString::Plain(name) = &annotation.name {
+                self.field(name, &annotation.value);
+            }
+        }
+    }
+
+    fn finish(self) -> Self {
+        Event {
+            metadata: finish_metadata(self.metadata),
+            data: self.data,
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    pub struct EventDescriptor {
+        pub id: u16,
+        pub version: u8,
+        pub channel: u8,
+        pub level: u8,
+        pub opcode: u8,
+        pub task: u16,
+        pub keyword: u64,
+    }
+
+    #[repr(C)]
+    pub struct EventDataDescriptor {
+        pub ptr: u64,
+        pub size: u32,
+        /// 2 for provider metadata, 1 for event metadata, 0 for data.
+        pub kind: u32,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        pub fn EventRegister(
+            provider_id: *const [u8; 16],
+            callback: *const c_void,
+            context: *const c_void,
+            handle: *mut u64,
+        ) -> u32;
+        pub fn EventUnregister(handle: u64) -> u32;
+        pub fn EventWriteTransfer(
+            handle: u64,
+            descriptor: *const EventDescriptor,
+            activity_id: *const [u8; 16],
+            related_activity_id: *const [u8; 16],
+            count: u32,
+            data: *const EventDataDescriptor,
+        ) -> u32;
+    }
+}
+
+/// A registered provider. Only used on Windows.
+#[cfg_attr(not(windows), allow(dead_code))]
+struct Provider {
+    handle: u64,
+    metadata: Vec<u8>,
+}
+
+impl Provider {
+    #[cfg(windows)]
+    fn register(provider: &EtwProvider) -> Self {
+        let id = guid_bytes(provider.id);
+        let mut handle = 0;
+        // SAFETY: `id` and `handle` are valid for the duration of the call.
+        // If registration fails, the handle stays 0 and writes are ignored.
+        unsafe {
+            sys::EventRegister(&id, std::ptr::null(), std::ptr::null(), &mut handle);
+        }
+        Provider {
+            handle,
+            metadata: provider_metadata(&provider.name),
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn register(provider: &EtwProvider) -> Self {
+        Provider {
+            handle: 0,
+            metadata: provider_metadata(&provider.name),
+        }
+    }
+
+    #[cfg(windows)]
+    fn write(
+        &self,
+        opcode: u8,
+        event: &Event,
+        activity: Option<&[u8; 16]>,
+        related: Option<&[u8; 16]>,
+    ) {
+        let descriptor = sys::EventDescriptor {
+            id: 0,
+            version: 0,
+            channel: 11, // TraceLogging
+            level: 5,    // verbose
+            opcode,
+            task: 0,
+            keyword: 0,
+        };
+        let data =
+            [(&self.metadata, 2), (&event.metadata, 1), (&event.data, 0)].map(|(bytes, kind)| {
+                sys::EventDataDescriptor {
+                    ptr: bytes.as_ptr() as u64,
+                    size: bytes.len() as u32,
+                    kind,
+                }
+            });
+        let as_ptr = |id: Option<&[u8; 16]>| id.map_or(std::ptr::null(), |id| id as *const _);
+        // SAFETY: all pointers are valid for the duration of the call.
+        unsafe {
+            sys::EventWriteTransfer(
+                self.handle,
+                &descriptor,
+                as_ptr(activity),
+                as_ptr(related),
+                data.len() as u32,
+                data.as_ptr(),
+            );
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn write(
+        &self,
+        _opcode: u8,
+        _event: &Event,
+        _activity: Option<&[u8; 16]>,
+        _related: Option<&[u8; 16]>,
+    ) {
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Provider {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from `EventRegister`.
+        unsafe {
+            sys::EventUnregister(self.handle);
+        }
+    }
+}
+
+/// Consumes the messages of the layer like the writer thread, but writes
+/// them as ETW events.
+pub(crate) fn etw_thread(
+    rx: Receiver<Message>,
+    provider: EtwProvider,
+) -> Result<Option<File>, WriterError> {
+    let mut backend = Backend {
+        provider: Provider::register(&provider),
+        thread_names: HashMap::new(),
+        activities: HashMap::new(),
+        next_activity: (std::process::id() as u128) << 64,
+    };
+    for msg in rx.iter() {
+        if let Message::Drop = msg {
+            break;
+        }
+        backend.handle_message(msg);
+    }
+    Ok(None)
+}
+
+/// A thread, and the custom track if the slice is not on the thread track.
+type TrackKey = (ThreadId, Option<Arc<str>>);
+
+struct Backend {
+    provider: Provider,
+    thread_names: HashMap<ThreadId, String>,
+    /// Activity ids of the open slices of each thread and track.
+    activities: HashMap<TrackKey, Vec<[u8; 16]>>,
+    next_activity: u128,
+}
+
+impl Backend {
+    fn event(&self, name: &str, thread_id: ThreadId, timestamp: u64) -> Event {
+        let mut event = Event::new(name);
+        let thread = match self.thread_names.get(&thread_id) {
+            Some(name) => name.clone(),
+            None => format!("thread {}", thread_id),
+        };
+        event.field("thread", &DebugValue::String(thread));
+        event.field("timestamp_ns", &DebugValue::Uint(timestamp));
+        event
+    }
+
+    fn handle_message(&mut self, msg: Message) {
+        match msg {
+            Message::NewThread(thread_id, name, _) => {
+                self.thread_names.insert(thread_id, name);
+            }
+            Message::ThreadExit(thread_id, _) => {
+                self.thread_names.remove(&thread_id);
+                self.activities.retain(|(id, _), _| *id != thread_id);
+            }
+            Message::Enter {
+                timestamp,
+                name,
+                args,
+                thread_id,
+                track,
+                ..
+            } => {
+                let mut event = self.event(&name, thread_id, timestamp);
+                if let Some(args) = args {
+                    event.annotations(args.deref());
+                }
+                self.next_activity += 1;
+                let activity = guid_bytes(self.next_activity);
+                let stack = self.activities.entry((thread_id, track)).or_default();
+                self.provider
+                    .write(OPCODE_START, &event.finish(), Some(&activity), stack.last());
+                stack.push(activity);
+            }
+            Message::Exit {
+                timestamp,
+                name,
+                thread_id,
+                track,
+                ..
+            } => {
+                let event = self.event(&name, thread_id, timestamp).finish();
+                let activity = self
+                    .activities
+                    .get_mut(&(thread_id, track))
+                    .and_then(Vec::pop);
+                self.provider
+                    .write(OPCODE_STOP, &event, activity.as_ref(), None);
+            }
+            Message::Event {
+                timestamp,
+                name,
+                args,
+                thread_id,
+                track,
+                ..
+            } => {
+                let mut event = self.event(&name, thread_id, timestamp);
+                if let Some(args) = args {
+                    event.annotations(args.deref());
+                }
+                let activity = self
+                    .activities
+                    .get(&(thread_id, track))
+                    .and_then(|stack| stack.last());
+                self.provider
+                    .write(OPCODE_INFO, &event.finish(), activity, None);
+            }
+            // There is no reservoir here, so slices are written right away.
+            Message::Slice { enter, end, .. } => {
+                if let Message::Enter {
+                    ref name,
+                    thread_id,
+                    ref track,
+                    ..
+                } = *enter
+                {
+                    let exit = Message::Exit {
+                        timestamp: end,
+                        name: name.clone(),
+                        thread_id,
+                        track: track.clone(),
+                        perf_count: None,
+                        args: None,
+                        unwound: false,
+                    };
+                    self.handle_message(*enter);
+                    self.handle_message(exit);
+                }
+            }
+            // Already shown next to the kernel data.
+            Message::AndroidLog { .. }
+            | Message::Counters { .. }
+            | Message::FlushHint
+            | Message::Flush
+            | Message::Rotate
+            | Message::State { .. }
+            | Message::Region { .. }
+            | Message::Snapshot { .. }
+            | Message::Dump { .. } => {}
+            Message::Drop => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guid_layout() {
+        assert_eq!(
+            guid_bytes(0x00112233_4455_6677_8899_aabbccddeeff),
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+    }
+
+    #[test]
+    fn event_metadata() {
+        let mut event = Event::new("query");
+        event.field("rows", &DebugValue::Uint(3));
+        event.field("table", &DebugValue::String("users".to_string()));
+        let event = event.finish();
+        assert_eq!(
+            event.metadata,
+            b"\x17\x00\x00query\0rows\0\x0atable\0\x82\x23".to_vec()
+        );
+        assert_eq!(event.data, b"\x03\0\0\0\0\0\0\0users\0".to_vec());
+        assert_eq!(provider_metadata("app"), b"\x06\x00app\0".to_vec());
+    }
+}
@@ -0,0 +1,313 @@
+//! Cutting a time range out of a trace, see [`extract`].
+//!
+//! The trace is read twice: once to find the slices that overlap the
+//! range, and once to copy the packets. Only the slices still open are
+//! held in memory, so traces of any size can be cut.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{append::read_varint, emit::ProtoEmitter, Timestamp, SEQ_INCREMENTAL_STATE_CLEARED};
+
+/// `TrackEvent.Type`
+const SLICE_BEGIN: u64 = 1;
+const SLICE_END: u64 = 2;
+
+/// Copy the part of the trace in `input` between `start_ns` and `end_ns`
+/// to `output`, e.g. the seconds around a problem out of a trace of hours.
+///
+/// The times are those of the trace, as trace_processor reports them, not
+/// relative to its start. The excerpt has the track events and log
+/// messages of the range, and of the rest of the trace everything needed to
+/// show them: the track descriptors, clock snapshots and interned names.
+/// Slices that overlap the range keep both ends, so they don't show up as
+/// unfinished. The input has to be a plain trace, i.e. not one written with
+/// [`PerfettoLayerBuilder::transform_chunks`](crate::PerfettoLayerBuilder::transform_chunks)
+/// or as base64.
+pub fn extract(
+    input: impl AsRef<Path>,
+    start_ns: Timestamp,
+    end_ns: Timestamp,
+    output: impl AsRef<Path>,
+) -> io::Result<()> {
+    if start_ns > end_ns {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the range ends before it starts",
+        ));
+    }
+    let input = input.as_ref();
+    let range = start_ns..=end_ns;
+    let overlapping =
+        overlapping_slices(&mut BufReader::new(File::open(input)?), start_ns, end_ns)?;
+
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut packet = Vec::new();
+    let mut out = ProtoEmitter::new();
+    let mut index = 0;
+    while read_packet(&mut reader, &mut packet)? {
+        let info = PacketInfo::parse(&packet);
+        let in_range = !info.timed
+            || info
+                .timestamp
+                .is_none_or(|timestamp| range.contains(&timestamp))
+            || overlapping.contains(&index);
+        out.clear();
+        if in_range {
+            out.bytes_field(1, &packet);
+        } else if info.incremental {
+            // Later packets of the sequence may refer to its interned
+            // data, so only the event goes.
+            out.bytes_field(1, &without_data(&packet));
+        }
+        writer.write_all(out.as_bytes())?;
+        index += 1;
+    }
+    writer.flush()
+}
+
+/// The indices of the begin and end packets of the slices that overlap the
+/// range.
+fn overlapping_slices(
+    reader: &mut impl Read,
+    start_ns: Timestamp,
+    end_ns: Timestamp,
+) -> io::Result<HashSet<usize>> {
+    let mut default_tracks = HashMap::new();
+    // The begin packets and timestamps of the open slices of each track.
+    let mut open: HashMap<u64, Vec<(usize, Timestamp)>> = HashMap::new();
+    let mut overlapping = HashSet::new();
+    let mut packet = Vec::new();
+    let mut index = 0;
+    while read_packet(reader, &mut packet)? {
+        let info = PacketInfo::parse(&packet);
+        if let Some(track) = info.default_track {
+            default_tracks.insert(info.sequence_id, track);
+        }
+        let track = info
+            .track
+            .or_else(|| default_tracks.get(&info.sequence_id).copied());
+        if let (Some(track), Some(timestamp)) = (track, info.timestamp) {
+            match info.event_type {
+                Some(SLICE_BEGIN) => open.entry(track).or_default().push((index, timestamp)),
+                Some(SLICE_END) => {
+                    let begin = open.get_mut(&track).and_then(Vec::pop);
+                    if let Some((begin, begin_ts)) = begin {
+                        if begin_ts <= end_ns && timestamp >= start_ns {
+                            overlapping.extend([begin, index]);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        index += 1;
+    }
+    // Slices that never end overlap the range if they begin before its end.
+    for &(begin, begin_ts) in open.values().flatten() {
+        if begin_ts <= end_ns {
+            overlapping.insert(begin);
+        }
+    }
+    Ok(overlapping)
+}
+
+/// What [`extract`] needs to know about a packet.
+#[derive(Debug, Default)]
+struct PacketInfo {
+    timestamp: Option<Timestamp>,
+    sequence_id: u64,
+    /// Whether the packet is a track event or log messages, which are only
+    /// copied if they are in the range.
+    timed: bool,
+    /// Whether the packet sets up the incremental state of its sequence.
+    incremental: bool,
+    /// `TrackEventDefaults.track_uuid`.
+    default_track: Option<u64>,
+    /// `TrackEvent.type` and `track_uuid`.
+    event_type: Option<u64>,
+    track: Option<u64>,
+}
+
+impl PacketInfo {
+    fn parse(packet: &[u8]) -> PacketInfo {
+        let mut info = PacketInfo::default();
+        for (field, value, _) in fields(packet) {
+            match (field, value) {
+                (8, Value::Varint(timestamp)) => info.timestamp = Some(timestamp),
+                (10, Value::Varint(sequence_id)) => info.sequence_id = sequence_id,
+                (13, Value::Varint(flags)) => {
+                    info.incremental |= flags & SEQ_INCREMENTAL_STATE_CLEARED as u64 != 0;
+                }
+                (11, Value::Bytes(event)) => {
+                    info.timed = true;
+                    for (field, value, _) in fields(event) {
+                        match (field, value) {
+                            (9, Value::Varint(event_type)) => info.event_type = Some(event_type),
+                            (11, Value::Varint(track)) => info.track = Some(track),
+                            _ => {}
+                        }
+                    }
+                }
+                (39, _) => info.timed = true,
+                (12, _) => info.incremental = true,
+                (59, Value::Bytes(defaults)) => {
+                    info.incremental = true;
+                    info.default_track = nested_varint(defaults, 11, 11);
+                }
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
+/// The varint `inner` of the message `outer` in `message`.
+fn nested_varint(message: &[u8], outer: u64, inner: u64) -> Option<u64> {
+    fields(message).find_map(|(field, value, _)| match value {
+        Value::Bytes(nested) if field == outer => {
+            fields(nested).find_map(|(field, value, _)| match value {
+                Value::Varint(value) if field == inner => Some(value),
+                _ => None,
+            })
+        }
+        _ => None,
+    })
+}
+
+/// The packet without its track event or log messages.
+fn without_data(packet: &[u8]) -> Vec<u8> {
+    fields(packet)
+        .filter(|(field, _, _)| *field != 11 && *field != 39)
+        .flat_map(|(_, _, encoded)| encoded)
+        .copied()
+        .collect()
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// The fields of an encoded message, as field number, value and the
+/// encoded field. Stops at the first malformed field.
+fn fields(mut message: &[u8]) -> impl Iterator<Item = (u64, Value<'_>, &[u8])> {
+    std::iter::from_fn(move || {
+        let field_start = message;
+        let (tag, _) = read_varint(&mut message).ok()??;
+        let value = match tag & 7 {
+            0 => Value::Varint(read_varint(&mut message).ok()??.0),
+            1 => {
+                message = message.get(8..)?;
+                Value::Fixed
+            }
+            2 => {
+                let (len, _) = read_varint(&mut message).ok()??;
+                let (bytes, rest) = message.split_at_checked(len as usize)?;
+                message = rest;
+                Value::Bytes(bytes)
+            }
+            5 => {
+                message = message.get(4..)?;
+                Value::Fixed
+            }
+            _ => return None,
+        };
+        let encoded = &field_start[..field_start.len() - message.len()];
+        Some((tag >> 3, value, encoded))
+    })
+}
+
+/// Read the next packet of a trace, returning false at the end of the
+/// trace or at a packet that was cut off.
+fn read_packet(reader: &mut impl Read, packet: &mut Vec<u8>) -> io::Result<bool> {
+    // Trace.packet, field 1, length-delimited.
+    let Some((0x0a, _)) = read_varint(reader)? else {
+        return Ok(false);
+    };
+    let Some((len, _)) = read_varint(reader)? else {
+        return Ok(false);
+    };
+    packet.clear();
+    let read = reader.take(len).read_to_end(packet)?;
+    Ok(read as u64 == len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(trace: &mut ProtoEmitter, timestamp: Timestamp, event_type: u64, name: &str) {
+        let mut event = ProtoEmitter::new();
+        event.varint_field(9, event_type);
+        event.string_field(23, name);
+        let mut packet = ProtoEmitter::new();
+        packet.varint_field(8, timestamp);
+        packet.varint_field(10, 1);
+        packet.bytes_field(11, event.as_bytes());
+        trace.bytes_field(1, packet.as_bytes());
+    }
+
+    #[test]
+    fn range() {
+        let mut trace = ProtoEmitter::new();
+        let mut defaults = ProtoEmitter::new();
+        defaults.bytes_field(11, &[11 << 3, 7]);
+        let mut header = ProtoEmitter::new();
+        header.varint_field(10, 1);
+        header.varint_field(13, SEQ_INCREMENTAL_STATE_CLEARED as u64);
+        header.bytes_field(59, defaults.as_bytes());
+        trace.bytes_field(1, header.as_bytes());
+        event(&mut trace, 100, SLICE_BEGIN, "outer");
+        event(&mut trace, 200, SLICE_BEGIN, "before");
+        event(&mut trace, 300, SLICE_END, "");
+        // An event outside the range whose interned data is needed later.
+        let mut interned = ProtoEmitter::new();
+        interned.varint_field(8, 400);
+        interned.varint_field(10, 1);
+        interned.bytes_field(11, &[9 << 3, 3]);
+        interned.string_field(12, "interned");
+        trace.bytes_field(1, interned.as_bytes());
+        event(&mut trace, 1000, 3, "inside");
+        event(&mut trace, 1400, SLICE_BEGIN, "ending later");
+        event(&mut trace, 2000, SLICE_END, "");
+        event(&mut trace, 2100, SLICE_END, "");
+        event(&mut trace, 3000, 3, "after");
+
+        let input = std::env::temp_dir().join("tracing-perfetto-extract-in");
+        let output = std::env::temp_dir().join("tracing-perfetto-extract-out");
+        std::fs::write(&input, trace.as_bytes()).unwrap();
+        extract(&input, 500, 1500, &output).unwrap();
+        let excerpt = std::fs::read(&output).unwrap();
+        let mut reader = &excerpt[..];
+        let mut packet = Vec::new();
+        let mut packets = Vec::new();
+        while read_packet(&mut reader, &mut packet).unwrap() {
+            let info = PacketInfo::parse(&packet);
+            packets.push((info.timestamp, info.event_type));
+        }
+        assert_eq!(
+            packets,
+            [
+                (None, None),
+                (Some(100), Some(SLICE_BEGIN)),
+                (Some(400), None),
+                (Some(1000), Some(3)),
+                (Some(1400), Some(SLICE_BEGIN)),
+                (Some(2000), Some(SLICE_END)),
+                (Some(2100), Some(SLICE_END)),
+            ]
+        );
+        let contains = |needle: &[u8]| excerpt.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"interned"));
+        assert!(!contains(b"before"));
+        assert!(!contains(b"after"));
+        assert!(extract(&input, 2, 1, &output).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Ids of flows, which connect slices and instants, e.g. a request span on
+//! one thread with its continuation on another.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A flow id for the `perfetto.flow` and `perfetto.terminating_flow`
+/// fields of spans and events.
+///
+/// The ids are unique among the processes on a machine, and unlike the
+/// small ids users tend to pick for their own flows. A span of queued work
+/// can take the id of the span that queued it, e.g.
+/// `info_span!("job", perfetto.terminating_flow = job.flow_id)`.
+pub fn new_flow_id() -> u64 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    (std::process::id() as u64) << 32 | NEXT.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique() {
+        let first = new_flow_id();
+        assert_ne!(first, new_flow_id());
+        assert_eq!(first >> 32, std::process::id() as u64);
+    }
+}
@@ -0,0 +1,95 @@
+//! Passing the trace through a user-supplied transform before it is
+//! written, e.g. to encrypt it, see
+//! [`PerfettoLayerBuilder::transform_chunks`](crate::PerfettoLayerBuilder::transform_chunks).
+//!
+//! Each chunk of the trace is transformed on its own and written as a
+//! frame: the length of the transformed bytes as a 4-byte little-endian
+//! number, followed by the bytes. A trace that was cut off, e.g. by a
+//! crash, can still be decoded up to its last complete frame.
+
+use std::io;
+
+/// See [`PerfettoLayerBuilder::transform_chunks`](crate::PerfettoLayerBuilder::transform_chunks).
+pub(crate) type ChunkTransform = Box<dyn FnMut(&[u8]) -> io::Result<Vec<u8>> + Send>;
+
+/// Writes everything written to it as transformed frames to the inner
+/// writer.
+pub struct FrameWriter<W> {
+    pub inner: W,
+    transform: ChunkTransform,
+}
+
+impl<W> FrameWriter<W> {
+    pub fn new(inner: W, transform: ChunkTransform) -> Self {
+        FrameWriter { inner, transform }
+    }
+}
+
+impl<W: io::Write> io::Write for FrameWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let frame = (self.transform)(data)?;
+        let len = u32::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame too large"))?;
+        // Write the frame at once, so the file holds as few partial frames
+        // as possible.
+        let mut out = Vec::with_capacity(4 + frame.len());
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&frame);
+        self.inner.write_all(&out)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Get back the trace written with
+/// [`PerfettoLayerBuilder::transform_chunks`](crate::PerfettoLayerBuilder::transform_chunks),
+/// given the inverse of the transform. An incomplete last frame is
+/// ignored.
+pub fn decode_frames<F>(data: &[u8], mut inverse: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(&[u8]) -> io::Result<Vec<u8>>,
+{
+    let mut trace = Vec::new();
+    let mut rest = data;
+    while let Some((len, after)) = rest.split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        let Some(frame) = after.get(..len) else {
+            break;
+        };
+        trace.extend(inverse(frame)?);
+        rest = &after[len..];
+    }
+    Ok(trace)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn xor(data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.iter().map(|b| b ^ 0x5a).collect())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = FrameWriter::new(Vec::new(), Box::new(xor));
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"world").unwrap();
+        let mut framed = writer.inner;
+        assert_eq!(framed.len(), 4 + 6 + 4 + 5);
+        assert_eq!(decode_frames(&framed, xor).unwrap(), b"hello world");
+
+        // A cut off frame is dropped.
+        framed.truncate(framed.len() - 1);
+        assert_eq!(decode_frames(&framed, xor).unwrap(), b"hello ");
+    }
+}
@@ -0,0 +1,140 @@
+//! The sequence ids and track uuids of the trace, and how to leave some of
+//! them to another producer writing into the same file, see
+//! [`PerfettoLayerBuilder::reserve_ids`](crate::PerfettoLayerBuilder::reserve_ids).
+
+use std::ops::{Range, RangeInclusive};
+
+use crate::ThreadId;
+
+/// Sequence id of the packets describing the "process info" track. Thread
+/// sequences start at 1, so this will not collide.
+pub(crate) const PROCESS_INFO_SEQUENCE_ID: u32 = u32::MAX;
+pub(crate) const PROCESS_INFO_TRACK_UUID: u64 = 8764;
+
+/// Sequence of the packets that every file starts with, and the track of
+/// the process, see [`Writer::write_header`](crate::writer::Writer::write_header).
+pub(crate) const HEADER_SEQUENCE_ID: u32 = u32::MAX - 2;
+pub(crate) const PROCESS_TRACK_UUID: u64 = 8760;
+
+/// Sequence of the packets of state tracks, see
+/// [`PerfettoLayer::state_track`](crate::PerfettoLayer::state_track).
+/// The lowest of the fixed sequence ids.
+pub(crate) const STATE_SEQUENCE_ID: u32 = u32::MAX - 3;
+
+/// Sequence and tracks of the writer's own activity, see
+/// [`PerfettoLayerBuilder::trace_writer`](crate::PerfettoLayerBuilder::trace_writer).
+pub(crate) const WRITER_SEQUENCE_ID: u32 = u32::MAX - 1;
+pub(crate) const WRITER_TRACK_UUID: u64 = 8763;
+pub(crate) const WRITER_BYTES_TRACK_UUID: u64 = 8762;
+pub(crate) const WRITER_QUEUE_TRACK_UUID: u64 = 8761;
+
+/// Custom tracks and tracks of recycled thread ids are numbered from here,
+/// well above any thread track uuid.
+pub(crate) const DYNAMIC_TRACK_UUID_BASE: u64 = 1 << 48;
+
+/// The highest thread id whose sequence stays below the fixed ones.
+const MAX_THREAD_ID: ThreadId = STATE_SEQUENCE_ID - 2;
+
+pub(crate) fn thread_track_uuid(thread_id: ThreadId) -> u64 {
+    8765 * (thread_id as u64 + 1)
+}
+
+pub(crate) fn thread_sequence_id(thread_id: ThreadId) -> u32 {
+    1 + thread_id
+}
+
+/// The sequence ids and track uuids the layer may write, see
+/// [`PerfettoLayerBuilder::id_ranges`](crate::PerfettoLayerBuilder::id_ranges).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdRanges {
+    /// The sequences of the threads, and the fixed sequences at the top of
+    /// the range.
+    pub sequence_ids: [RangeInclusive<u32>; 2],
+    /// The tracks of the process and the threads, and the tracks numbered
+    /// as they come up, e.g. custom tracks.
+    pub track_uuids: [RangeInclusive<u64>; 2],
+}
+
+impl IdRanges {
+    /// The ranges used with the given thread limit, where `None` is
+    /// unlimited.
+    pub(crate) fn new(max_threads: Option<ThreadId>) -> Self {
+        // Threads beyond the limit share the id after the last thread.
+        let last_thread = max_threads.map_or(MAX_THREAD_ID, |max| max.min(MAX_THREAD_ID));
+        IdRanges {
+            sequence_ids: [
+                thread_sequence_id(0)..=thread_sequence_id(last_thread),
+                STATE_SEQUENCE_ID..=u32::MAX,
+            ],
+            track_uuids: [
+                PROCESS_TRACK_UUID..=thread_track_uuid(last_thread),
+                DYNAMIC_TRACK_UUID_BASE..=u64::MAX,
+            ],
+        }
+    }
+
+    pub fn contains_sequence_id(&self, id: u32) -> bool {
+        self.sequence_ids.iter().any(|range| range.contains(&id))
+    }
+
+    pub fn contains_track_uuid(&self, uuid: u64) -> bool {
+        self.track_uuids.iter().any(|range| range.contains(&uuid))
+    }
+}
+
+/// The thread limit that keeps the ids of the threads below the reserved
+/// ranges.
+///
+/// # Panics
+///
+/// If the ranges overlap the ids that don't depend on the number of
+/// threads.
+pub(crate) fn max_threads_below(sequence_ids: &Range<u32>, track_uuids: &Range<u64>) -> ThreadId {
+    let mut max = MAX_THREAD_ID;
+    if !sequence_ids.is_empty() {
+        assert!(
+            sequence_ids.start > thread_sequence_id(0) && sequence_ids.end <= STATE_SEQUENCE_ID,
+            "sequence ids {:?} are used by tracing-perfetto",
+            sequence_ids
+        );
+        max = max.min(sequence_ids.start - 2);
+    }
+    if !track_uuids.is_empty() {
+        assert!(
+            track_uuids.start > thread_track_uuid(0) && track_uuids.end <= DYNAMIC_TRACK_UUID_BASE,
+            "track uuids {:?} are used by tracing-perfetto",
+            track_uuids
+        );
+        let below = (track_uuids.start - 1) / thread_track_uuid(0) - 1;
+        max = max.min(below as ThreadId);
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_ranges_are_free() {
+        let sequence_ids = 100..200;
+        let track_uuids = 1_000_000..2_000_000;
+        let max = max_threads_below(&sequence_ids, &track_uuids);
+        assert_eq!(max, 98);
+        let ranges = IdRanges::new(Some(max));
+        assert!(sequence_ids
+            .into_iter()
+            .all(|id| !ranges.contains_sequence_id(id)));
+        assert!(track_uuids
+            .into_iter()
+            .all(|uuid| !ranges.contains_track_uuid(uuid)));
+        assert!(ranges.contains_sequence_id(thread_sequence_id(max)));
+        assert!(ranges.contains_track_uuid(thread_track_uuid(max)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn fixed_ids_cannot_be_reserved() {
+        max_threads_below(&(u32::MAX - 10..u32::MAX), &(0..0));
+    }
+}
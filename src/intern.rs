@@ -1,34 +1,80 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, hash::Hash};
 
-pub struct Interned {
-    event_names: HashMap<String, u64>,
-    next_name_iid: u64,
+/// Assigns interning ids to values of one kind.
+pub struct InternTable<K> {
+    iids: HashMap<K, u64>,
+    next_iid: u64,
 }
 
-impl Interned {
+impl<K: Hash + Eq> InternTable<K> {
     pub fn new() -> Self {
-        Interned {
-            event_names: HashMap::new(),
-            next_name_iid: 1,
+        InternTable {
+            iids: HashMap::new(),
+            next_iid: 1,
         }
     }
 
-    pub fn event_name(&mut self, name: &str) -> (u64, bool) {
-        match self.event_names.get(name) {
+    /// Get the interning id of `key`, and whether it was newly added (and
+    /// thus needs to be emitted as interned data).
+    pub fn intern<Q>(&mut self, key: &Q) -> (u64, bool)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.iids.get(key) {
             Some(iid) => (*iid, false),
             None => {
-                self.event_names
-                    .insert(name.to_string(), self.next_name_iid);
-                let iid = self.next_name_iid;
-                self.next_name_iid += 1;
+                let iid = self.next_iid;
+                self.iids.insert(key.to_owned(), iid);
+                self.next_iid += 1;
                 (iid, true)
             }
         }
     }
+
+    /// The interned values, in the order they were added.
+    pub fn values(&self) -> Vec<&K> {
+        let mut values: Vec<_> = self.iids.iter().collect();
+        values.sort_by_key(|(_, iid)| **iid);
+        values.into_iter().map(|(value, _)| value).collect()
+    }
 }
 
-impl Default for Interned {
+impl<K: Hash + Eq> Default for InternTable<K> {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Interning state of one sequence. Each kind of interned data has its own
+/// id space.
+#[derive(Default)]
+pub struct Interned {
+    pub event_names: InternTable<String>,
+    pub debug_annotation_names: InternTable<String>,
+    /// Keyed by file and line.
+    pub source_locations: InternTable<(&'static str, u32)>,
+    pub event_categories: InternTable<String>,
+}
+
+impl Interned {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn event_name(&mut self, name: &str) -> (u64, bool) {
+        self.event_names.intern(name)
+    }
+
+    pub fn debug_annotation_name(&mut self, name: &str) -> (u64, bool) {
+        self.debug_annotation_names.intern(name)
+    }
+
+    pub fn source_location(&mut self, file: &'static str, line: u32) -> (u64, bool) {
+        self.source_locations.intern(&(file, line))
+    }
+
+    pub fn event_category(&mut self, name: &str) -> (u64, bool) {
+        self.event_categories.intern(name)
+    }
+}
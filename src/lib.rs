@@ -1,556 +1,5672 @@
 use std::{
-    cell::RefCell,
+    any::TypeId,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
     fs::File,
-    io::{BufWriter, Write},
+    io,
     marker::PhantomData,
-    ops::Deref,
+    ops::Range,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
     },
     thread::JoinHandle,
-    time::Instant,
+    time::Duration,
 };
 
-use crossbeam_channel::{Receiver, Sender};
-use intern::Interned;
-use packet::{
-    DebugAnnotation, TracePacketDefaults, TrackDescriptor, TrackEventDefaults,
-    SEQ_INCREMENTAL_STATE_CLEARED,
-};
+use crossbeam_channel::Sender;
+use packet::{DebugAnnotation, ThreadDescriptor};
 use tracing::{field::Visit, span, Subscriber};
-use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
+};
+
+pub use base64::{decode_base64_chunks, Base64DecodeError};
+pub use callsite::callsite_hash;
+pub use clock::Origin;
+#[cfg(feature = "zstd")]
+pub use compress::{decode_zstd, train_zstd_dictionary, ZstdDictionary};
+pub use control::TracingController;
+pub use counter_fields::{CounterScope, Unit};
+#[cfg(feature = "etw")]
+pub use etw::EtwProvider;
+pub use extract::extract;
+pub use flow::new_flow_id;
+pub use framing::decode_frames;
+pub use ids::IdRanges;
+pub use packet::{ClockId, SEQ_INCREMENTAL_STATE_CLEARED, SEQ_NEEDS_INCREMENTAL_STATE};
+pub use perf_counter::PerfCounter;
+pub use profile::Profile;
+pub use regions::{region, RegionGuard};
+pub use rotate::SyncPolicy;
+pub use routing::Rule;
+pub use snapshot::SnapshotTrigger;
+pub use span_context::{ParseSpanContextError, SpanContext, SPAN_CONTEXT_ARG, SPAN_CONTEXT_ENV};
+pub use state_track::StateTrack;
+pub use stats::{StatsHandle, TraceStats};
+pub use summary::SummaryFormat;
+pub use trigger::{Trigger, TriggerHandle};
+
+/// The packets of the Perfetto trace format as the writer builds them, e.g.
+/// to assert on in tests, or to build debug annotations programmatically.
+pub mod raw {
+    pub use crate::packet::{
+        AndroidLogEvent, AndroidLogPacket, ClockSnapshot, CounterUnit, DebugAnnotation,
+        DebugAnnotationName, DebugAnnotationValueTypeName, DebugValue, EventCategory, EventName,
+        EventType, IString, InternedData, InternedString, LogMessageBody, PacketData,
+        ProcessDescriptor, SourceLocation, ThreadDescriptor, TracePacket, TracePacketDefaults,
+        TrackDescriptor, TrackEvent, TrackEventDefaults,
+    };
+}
 
 use crate::{
-    emit::ProtoEmitter,
-    packet::{
-        Emit, EventName, InternedData, PacketData, TracePacket, TrackEvent,
-        SEQ_NEEDS_INCREMENTAL_STATE,
-    },
+    clock::Clock,
+    counter_fields::{CounterValue, CounterVisitor},
+    stats::Counters,
+    trigger::StartTrigger,
+    writer::{writer_thread, Output, WriterConfig, WriterError},
 };
 
+#[cfg(feature = "android-log")]
+mod android_log;
+mod append;
+mod args_budget;
+#[cfg(feature = "backtrace")]
+mod backtraces;
+mod base64;
+mod callsite;
+mod clock;
+#[cfg(feature = "zstd")]
+mod compress;
+mod container;
+mod control;
+mod counter_fields;
 mod emit;
+#[cfg(feature = "etw")]
+mod etw;
+mod extract;
+mod flow;
+mod framing;
+mod ids;
 mod intern;
+#[cfg(feature = "opentelemetry")]
+mod otel;
 mod packet;
-// mod thread_local;
+mod perf_counter;
+mod profile;
+mod regions;
+mod report;
+mod ring;
+mod rotate;
+mod routing;
+mod sanitize;
+mod sched;
+mod slowest;
+mod snapshot;
+mod span_context;
+mod spill;
+mod state_track;
+#[cfg(feature = "static-config")]
+pub mod static_config;
+mod stats;
+mod strict;
+mod summary;
+mod syslog;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test;
+#[cfg(feature = "tokio")]
+mod tokio_tasks;
+mod trace_marker;
+mod trigger;
+mod writer;
 
 thread_local! {
-        //static OUT: RefCell<Option<Sender<Message>>> = RefCell::new(None);
-    static THREAD_ID: RefCell<Option<u32>>  = RefCell::new(None);
+    /// The state of the current thread for each layer it has recorded to.
+    /// There is usually just one, but scoped subscribers (e.g. in tests)
+    /// come and go.
+    static THREAD_STATES: RefCell<Vec<ThreadState>> = const { RefCell::new(Vec::new()) };
+    /// Set on writer threads, which can't wait for room in the queue.
+    pub(crate) static ON_WRITER_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Per-thread state of a traced thread.
+struct ThreadState {
+    id: ThreadId,
+    /// The layer the id belongs to.
+    layer: Weak<()>,
+    sender: Sender<Message>,
+    counters: Arc<Counters>,
+    clock: Clock,
+    /// Where to return the thread id for reuse, if recycling is enabled.
+    free_thread_ids: Option<Arc<Mutex<Vec<ThreadId>>>>,
+    /// Whether the id is shared with other threads, see
+    /// [`PerfettoLayerBuilder::max_threads`].
+    shared: bool,
+    /// Whether the id is the one given by
+    /// [`PerfettoLayerBuilder::thread_ids`] rather than the thread's own.
+    provided: bool,
+    /// Number of spans the thread is in, see
+    /// [`PerfettoLayerBuilder::max_span_depth`].
+    depth: u32,
+}
+
+impl Drop for ThreadState {
+    // Runs when the thread exits, so the writer knows that it won't get any
+    // more data for this thread.
+    fn drop(&mut self) {
+        if self.shared {
+            return;
+        }
+        let timestamp = self.clock.now();
+        queue_message(
+            &self.sender,
+            &self.counters,
+            Message::ThreadExit(self.id, timestamp),
+        );
+        // Only hand out the id again after the exit message is queued, so the
+        // writer sees the messages of the old and new thread in order.
+        if let Some(free_thread_ids) = &self.free_thread_ids {
+            free_thread_ids.lock().unwrap().push(self.id);
+        }
+    }
+}
+
+/// Hint that the calling thread is at a quiet point, e.g. the end of a frame
+/// or a batch, so it is a good time to get the trace onto disk.
+///
+/// The writer flushes whenever it has caught up with the traced threads, so
+/// this only makes a difference under sustained load: the writers of the
+/// layers this thread records to then flush their output buffer if it is
+/// fuller than the threshold set with
+/// [`PerfettoLayerBuilder::flush_hint_threshold`]. The hint is cheap, as
+/// the check is left to the writer thread.
+pub fn flush_hint() {
+    THREAD_STATES.with(|states| {
+        for state in states.borrow().iter() {
+            if state.layer.strong_count() > 0 {
+                queue_message(&state.sender, &state.counters, Message::FlushHint);
+            }
+        }
+    });
+}
+
+/// Queue a message for the writer thread.
+///
+/// The queue is only bounded with the `static-config` feature. When it is
+/// full, the message waits for the writer to catch up, unless it comes from
+/// a writer thread, e.g. an `on_rotate` callback that logs, which would wait
+/// for itself. It is then dropped and counted instead.
+pub(crate) fn queue_message(sender: &Sender<Message>, counters: &Counters, msg: Message) {
+    if !ON_WRITER_THREAD.with(Cell::get) {
+        // Fails if the writer has already finished.
+        let _ignore_send_err = sender.send(msg);
+    } else if let Err(crossbeam_channel::TrySendError::Full(_)) = sender.try_send(msg) {
+        counters.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 pub struct PerfettoLayer<S> {
     sender: crossbeam_channel::Sender<Message>,
-    start: Instant,
+    /// Identifies the layer in the state of the threads recording to it,
+    /// which can tell from their weak references when it is gone.
+    alive: Arc<()>,
+    clock: Clock,
+    /// Shared with the writer, which tells when it has stopped.
+    counters: Arc<Counters>,
+    /// Found by [`region`] through the dispatcher.
+    regions: regions::RegionSink,
+    /// Found by [`TraceController::from_dispatch`].
+    controller: TraceController,
+    start_trigger: Option<StartTrigger>,
+    /// See [`PerfettoLayer::control_handle`].
+    control: TracingController,
+    max_span_depth: Option<u32>,
+    snapshot_trigger: Option<SnapshotTrigger>,
     next_thread_id: AtomicU32,
+    /// Ids of exited threads, if they should be reused.
+    free_thread_ids: Option<Arc<Mutex<Vec<ThreadId>>>>,
+    /// Name prefixes of pooled threads and the free ids of each pool.
+    thread_pools: Vec<(String, Arc<Mutex<Vec<ThreadId>>>)>,
+    max_threads: Option<ThreadId>,
+    /// Whether the track shared by threads over the limit was named yet.
+    other_threads_named: AtomicBool,
+    thread_ids: Option<ThreadIdProvider>,
+    task_ids: Option<TaskIdProvider>,
+    perf_counter: Option<PerfCounter>,
+    /// Ids from `thread_ids` whose track has been named.
+    provided_thread_ids: Mutex<HashMap<u32, ThreadId>>,
     include_args: bool,
+    include_thread_info: bool,
+    os_thread_ids: bool,
+    inherit_tracks: bool,
+    inherited_fields: Vec<String>,
+    events_on_span_tracks: bool,
+    async_tracks: bool,
+    root_offsets: bool,
+    parent_span_context: Option<SpanContext>,
+    message_policy: MessagePolicy,
+    target_message_policies: Vec<(String, MessagePolicy)>,
+    event_naming: EventNaming,
+    record_kinds: Kinds,
+    span_budgets: HashMap<String, Duration>,
+    unit_hints: HashMap<String, Unit>,
+    counter_scopes: HashMap<String, CounterScope>,
+    /// Target prefixes and names of the fields recorded as counters.
+    counter_fields: Vec<(String, String)>,
+    router: Option<routing::Router>,
+    min_span_duration: Option<Duration>,
+    slowest_spans: Option<usize>,
+    trace_marker: Option<trace_marker::TraceMarker>,
+    syslog: Option<syslog::Syslog>,
+    /// Path of the trace file being written, for the syslog lines.
+    current_path: syslog::CurrentPath,
+    #[cfg(feature = "android-log")]
+    android_log_packets: bool,
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry_context: bool,
+    #[cfg(feature = "backtrace")]
+    event_backtraces: Option<(tracing::Level, usize)>,
+    #[cfg(feature = "opentelemetry")]
+    span_export: Option<otel::SpanExport>,
     _marker: PhantomData<S>,
 }
 
 pub struct PerfettoLayerBuilder<S> {
-    output_file: Option<PathBuf>,
+    output: Option<Output>,
+    append: bool,
     include_args: bool,
+    include_thread_info: bool,
+    os_thread_ids: bool,
+    include_container_info: bool,
+    recycle_thread_ids: bool,
+    thread_pools: Vec<String>,
+    max_threads: Option<ThreadId>,
+    /// Sequence ids and track uuids left to another producer.
+    reserved_ids: Option<(Range<u32>, Range<u64>)>,
+    thread_ids: Option<ThreadIdProvider>,
+    task_ids: Option<TaskIdProvider>,
+    perf_counter: Option<PerfCounter>,
+    inherit_tracks: bool,
+    inherited_fields: Vec<String>,
+    events_on_span_tracks: bool,
+    async_tracks: bool,
+    root_offsets: bool,
+    parent_span_context: Option<SpanContext>,
+    message_policy: MessagePolicy,
+    target_message_policies: Vec<(String, MessagePolicy)>,
+    event_naming: EventNaming,
+    record_kinds: Kinds,
+    span_budgets: HashMap<String, Duration>,
+    unit_hints: HashMap<String, Unit>,
+    counter_scopes: HashMap<String, CounterScope>,
+    /// Target prefixes and names of the fields recorded as counters.
+    counter_fields: Vec<(String, String)>,
+    rules: Vec<Rule>,
+    min_span_duration: Option<Duration>,
+    slowest_spans: Option<usize>,
+    trace_marker: bool,
+    syslog_errors: bool,
+    trace_writer: bool,
+    flush_hint_threshold: u8,
+    stop_after_bytes: Option<u64>,
+    stop_after: Option<Duration>,
+    write_stall_threshold: Duration,
+    start_trigger: Option<Trigger>,
+    enabled: bool,
+    max_span_depth: Option<u32>,
+    /// The trigger of snapshots, and the time before and after it to keep.
+    snapshot: Option<(SnapshotTrigger, Duration, Duration)>,
+    strict: bool,
+    sync_policy: SyncPolicy,
+    span_summary: Option<(PathBuf, SummaryFormat)>,
+    source_locations: bool,
+    #[cfg(feature = "android-log")]
+    android_log_packets: bool,
+    process_info: Option<ProcessInfo>,
+    incremental_state_interval: Option<Duration>,
+    intern_seed: Vec<String>,
+    export_intern_table: Option<PathBuf>,
+    timestamp_origin: Origin,
+    limits: sanitize::Limits,
+    args_budget: Option<u64>,
+    spill: Option<(PathBuf, usize)>,
+    ring_buffer: Option<usize>,
+    max_file_size: Option<u64>,
+    on_rotate: Option<rotate::RotateCallback>,
+    on_error: writer::ErrorHook,
+    shutdown_report: bool,
+    transform: Option<framing::ChunkTransform>,
+    #[cfg(feature = "zstd")]
+    zstd: Option<(i32, ZstdDictionary)>,
+    #[cfg(feature = "opentelemetry")]
+    opentelemetry_context: bool,
+    #[cfg(feature = "backtrace")]
+    event_backtraces: Option<(tracing::Level, usize)>,
+    #[cfg(feature = "opentelemetry")]
+    span_export: Option<otel::SpanExport>,
+    #[cfg(feature = "etw")]
+    etw: Option<EtwProvider>,
     _marker: PhantomData<S>,
 }
 
+impl<S> Default for PerfettoLayerBuilder<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<S> PerfettoLayerBuilder<S> {
     pub fn new() -> Self {
         PerfettoLayerBuilder {
-            output_file: None,
+            output: None,
+            append: false,
             include_args: false,
+            include_thread_info: false,
+            os_thread_ids: false,
+            include_container_info: false,
+            recycle_thread_ids: false,
+            thread_pools: Vec::new(),
+            max_threads: None,
+            reserved_ids: None,
+            thread_ids: None,
+            task_ids: None,
+            perf_counter: None,
+            inherit_tracks: false,
+            inherited_fields: Vec::new(),
+            events_on_span_tracks: false,
+            async_tracks: false,
+            root_offsets: false,
+            parent_span_context: None,
+            message_policy: MessagePolicy::Annotation,
+            target_message_policies: Vec::new(),
+            event_naming: EventNaming::Default,
+            record_kinds: Kinds::ALL,
+            span_budgets: HashMap::new(),
+            unit_hints: HashMap::new(),
+            counter_scopes: HashMap::new(),
+            counter_fields: Vec::new(),
+            rules: Vec::new(),
+            min_span_duration: None,
+            slowest_spans: None,
+            trace_marker: false,
+            syslog_errors: false,
+            trace_writer: false,
+            flush_hint_threshold: 50,
+            stop_after_bytes: None,
+            stop_after: None,
+            write_stall_threshold: Duration::from_millis(100),
+            start_trigger: None,
+            enabled: true,
+            max_span_depth: None,
+            snapshot: None,
+            strict: false,
+            sync_policy: SyncPolicy::Never,
+            span_summary: None,
+            source_locations: false,
+            #[cfg(feature = "android-log")]
+            android_log_packets: false,
+            process_info: None,
+            incremental_state_interval: None,
+            intern_seed: Vec::new(),
+            export_intern_table: None,
+            timestamp_origin: Origin::ProcessStart,
+            limits: sanitize::Limits::default(),
+            args_budget: None,
+            spill: None,
+            ring_buffer: None,
+            max_file_size: None,
+            on_rotate: None,
+            on_error: writer::ErrorHook::default(),
+            shutdown_report: false,
+            transform: None,
+            #[cfg(feature = "zstd")]
+            zstd: None,
+            #[cfg(feature = "opentelemetry")]
+            opentelemetry_context: false,
+            #[cfg(feature = "backtrace")]
+            event_backtraces: None,
+            #[cfg(feature = "opentelemetry")]
+            span_export: None,
+            #[cfg(feature = "etw")]
+            etw: None,
             _marker: PhantomData,
         }
     }
 
+    /// Set the options of `profile`, a combination of them for a common use,
+    /// e.g. `.profile(Profile::LowOverhead)` for tracing in production.
+    ///
+    /// Options set after this override those of the profile.
+    pub fn profile(self, profile: Profile) -> Self {
+        profile.apply(self)
+    }
+
     /// Set the path of the output trace file.
     ///
     /// Defaults to `trace-<unixepoch>.perfetto-trace`.
     pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
         let path = PathBuf::from(path.as_ref());
-        self.output_file = Some(path);
+        self.output = Some(Output::Path(path));
         self
     }
 
-    pub fn include_args(mut self, include: bool) -> Self {
-        self.include_args = include;
+    /// Continue the trace in the file set with [`file`](Self::file) if it
+    /// exists, e.g. when a supervisor restarts a crashed process, so all
+    /// runs end up on one timeline.
+    ///
+    /// Each run gets sequence ids and track uuids of its own, and starts
+    /// with a "restart" instant on the process track. A packet cut off by
+    /// a crash is removed first. Timestamps count from system boot, unless
+    /// [`timestamp_origin`](Self::timestamp_origin) is
+    /// [`Origin::UnixEpochNs`]. With [`max_file_size`](Self::max_file_size)
+    /// the highest numbered file is continued, and later files are numbered
+    /// after it.
+    ///
+    /// # Panics
+    ///
+    /// In [`build`](Self::build), if the trace is transformed, e.g. with
+    /// [`transform_chunks`](Self::transform_chunks), as a transformed file
+    /// can't be scanned for the runs in it.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
         self
     }
 
-    pub fn build(self) -> (PerfettoLayer<S>, FlushGuard) {
-        PerfettoLayer::new(self)
+    /// Split the trace into files of about `max_file_size` bytes.
+    ///
+    /// The files are named after the path set with [`file`], with a file
+    /// index inserted before the extension, e.g. `trace.0.perfetto-trace`,
+    /// `trace.1.perfetto-trace`, and so on. Each file can be opened on its
+    /// own. Slices that are open while switching files are cut in two.
+    ///
+    /// Only applies when writing to a path.
+    ///
+    /// [`file`]: Self::file
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = Some(max_file_size);
+        self
     }
-}
-
-type ThreadId = u32;
-type Timestamp = u64;
-
-#[derive(Debug)]
-pub enum Message {
-    NewThread(ThreadId, String),
-    Enter(
-        Timestamp,
-        &'static str,
-        Option<Arc<Vec<DebugAnnotation>>>,
-        ThreadId,
-    ),
-    Exit(Timestamp, &'static str, ThreadId),
-    Event(
-        Timestamp,
-        &'static str,
-        Option<Arc<Vec<DebugAnnotation>>>,
-        ThreadId,
-    ),
-    Drop,
-}
 
-impl<S> PerfettoLayer<S> {
-    fn new(builder: PerfettoLayerBuilder<S>) -> (Self, FlushGuard) {
-        let (tx, rx) = crossbeam_channel::unbounded();
-        let worker = std::thread::spawn(move || writer_thread(rx, builder.output_file));
+    /// Call `on_error` with each error of the trace, instead of printing it
+    /// to stderr.
+    ///
+    /// The error that ends the trace, e.g. when the trace file can't be
+    /// created on a read-only file system or the disk is full, is passed
+    /// as is, on the writer thread. The layer then stops recording, so the
+    /// rest of the application keeps running without a trace. Errors that
+    /// only affect part of the trace, like a packet that is too large to
+    /// encode, a shutdown report that can't be written, or a `trace_marker`
+    /// or syslog that can't be opened, mention what was affected in their
+    /// message.
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where
+        F: FnMut(&io::Error) + Send + 'static,
+    {
+        self.on_error = writer::ErrorHook::new(on_error);
+        self
+    }
 
-        let start = Instant::now();
+    /// Call `on_rotate` on the writer thread with the path of each trace
+    /// file once it is complete, including the last one when the trace
+    /// ends.
+    ///
+    /// Use this with [`max_file_size`] to upload (and delete) finished files
+    /// while the trace keeps running. The writer thread does not write while
+    /// the callback runs, so expensive work should be handed off to another
+    /// thread.
+    ///
+    /// [`max_file_size`]: Self::max_file_size
+    pub fn on_rotate<F>(mut self, on_rotate: F) -> Self
+    where
+        F: FnMut(&Path) + Send + 'static,
+    {
+        self.on_rotate = Some(Box::new(on_rotate));
+        self
+    }
 
-        (
-            PerfettoLayer {
-                sender: tx.clone(),
-                start,
-                next_thread_id: AtomicU32::new(0),
-                include_args: builder.include_args,
-                _marker: PhantomData,
-            },
-            FlushGuard {
-                handle: Some(worker),
-                sender: tx,
-            },
-        )
+    /// Pass the trace through `transform` before it is written, e.g. to
+    /// encrypt it at rest.
+    ///
+    /// The trace is transformed in chunks of up to the writer's buffer
+    /// size, and each result is written as a frame prefixed with its
+    /// length, so a trace that was cut off can still be decoded up to its
+    /// last complete frame with [`decode_frames`]. Each chunk must be
+    /// decodable on its own, e.g. encrypted with a fresh nonce. Applies to
+    /// every file when rotating, and to [`base64_output`].
+    ///
+    /// [`base64_output`]: Self::base64_output
+    pub fn transform_chunks<F>(mut self, transform: F) -> Self
+    where
+        F: FnMut(&[u8]) -> io::Result<Vec<u8>> + Send + 'static,
+    {
+        self.transform = Some(Box::new(transform));
+        self
     }
 
-    fn send_message(&self, msg: Message) {
-        let _ignore_send_err = self.sender.send(msg);
+    /// Compress the trace with zstd at `level`, with the given
+    /// `dictionary`, e.g. to store many small traces that compress poorly
+    /// on their own.
+    ///
+    /// The chunks are compressed and framed as with [`transform_chunks`],
+    /// which this replaces, and [`decode_zstd`] gets back the trace. A
+    /// dictionary trained with [`ZstdDictionary::Train`] is written next
+    /// to the path set with [`file`](Self::file).
+    ///
+    /// [`transform_chunks`]: Self::transform_chunks
+    #[cfg(feature = "zstd")]
+    pub fn zstd_compression(mut self, level: i32, dictionary: ZstdDictionary) -> Self {
+        self.zstd = Some((level, dictionary));
+        self
     }
 
-    fn get_timestamp(&self) -> u64 {
-        self.start.elapsed().as_nanos() as u64
+    /// Set when the writer makes sure the trace file is on disk, with
+    /// `File::sync_data`. A flush only hands the data to the operating
+    /// system, which may lose it in a power failure.
+    ///
+    /// Defaults to [`SyncPolicy::Never`]. Files are synced before they are
+    /// passed to [`on_rotate`]. Only applies when writing to a file.
+    ///
+    /// [`on_rotate`]: Self::on_rotate
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
     }
 
-    fn get_thread_id(&self) -> (ThreadId, Option<String>) {
-        THREAD_ID.with(|value| {
-            let thread_id = *value.borrow();
-            match thread_id {
-                Some(thread_id) => (thread_id, None),
-                None => {
-                    let id = self.next_thread_id.fetch_add(1, Ordering::SeqCst);
-                    value.replace(Some(id));
-                    let thread_name = if let Some(name) = std::thread::current().name() {
-                        format!("{} {}", name, id)
-                    } else {
-                        format!("thread {}", id)
-                    };
-                    (id, Some(thread_name))
-                }
-            }
-        })
+    /// Also write the count, total, mean and 95th percentile of the
+    /// durations of each span name to `path` when the trace ends, e.g. to
+    /// check the numbers in CI without opening the trace.
+    ///
+    /// The statistics cover the span entries written to the trace, and are
+    /// collected while writing it. The percentile is accurate to about 9%.
+    pub fn span_summary<P: AsRef<Path>>(mut self, path: P, format: SummaryFormat) -> Self {
+        self.span_summary = Some((path.as_ref().to_path_buf(), format));
+        self
     }
 
-    fn init_thread(&self, id: ThreadId, name: String) {
-        self.send_message(Message::NewThread(id, name));
+    /// Write a JSON report to `<trace>.meta.json` when the trace ends, with
+    /// the number of packets and events written, what was dropped, how
+    /// long the trace ran, the files it was split into and the error that
+    /// ended it, if any. This lets a CI job check that a trace is complete
+    /// without parsing it.
+    ///
+    /// Only applies when writing to a path. Defaults to `false`.
+    pub fn shutdown_report(mut self, enabled: bool) -> Self {
+        self.shutdown_report = enabled;
+        self
     }
-}
 
-impl<S> Drop for PerfettoLayer<S> {
-    fn drop(&mut self) {
-        println!("Dropping layer, TODO: flush buffers")
+    /// Attach the file, line and module of each span and event as an
+    /// interned source location, and a `callsite` argument with its
+    /// [`callsite_hash`], which stays the same across builds. This lets
+    /// tools match the slices of two traces, e.g. to compare runs.
+    ///
+    /// Defaults to `false`.
+    pub fn source_locations(mut self, enabled: bool) -> Self {
+        self.source_locations = enabled;
+        self
     }
-}
 
-impl<S> Layer<S> for PerfettoLayer<S>
-where
-    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
-{
-    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
-        if self.include_args {
-            let mut v = DebugAnnotationVisitor { infos: Vec::new() };
-            attrs.record(&mut v);
-            //println!("{:?}", &v.infos);
-            ctx.span(id).unwrap().extensions_mut().insert(DebugInfoExt {
-                info: Arc::new(v.infos),
-            });
-        }
+    /// Write the trace as base64 text lines to `out`, e.g.
+    /// `std::io::stderr()`, instead of a file.
+    ///
+    /// This allows capturing traces through log pipelines when writing
+    /// files is not an option. Use [`decode_base64_chunks`] on the captured
+    /// log to get the trace back.
+    pub fn base64_output<W: io::Write + Send + 'static>(mut self, out: W) -> Self {
+        self.output = Some(Output::Base64(Box::new(out)));
+        self
     }
 
-    // for handling `Span::record` events
-    // fn on_record(&self, _span: &span::Id, _values: &span::Record<'_>, _ctx: Context<'_, S>) {
+    /// Stream the trace to `out` instead of a file, e.g. a socket, a
+    /// compressing encoder or `std::io::stdout()`.
+    ///
+    /// `out` is dropped once the trace is complete, which finishes e.g. an
+    /// encoder. Options that work on files, such as
+    /// [`max_file_size`](Self::max_file_size) and [`append`](Self::append),
+    /// don't apply.
+    pub fn writer<W: io::Write + Send + 'static>(mut self, out: W) -> Self {
+        self.output = Some(Output::Writer(Box::new(out)));
+        self
+    }
 
-    // }
+    /// Write the trace to an already opened file, instead of creating one.
+    ///
+    /// The trace is written from the current position of `file`. Use
+    /// [`FlushGuard::into_inner`] to get the file back once the trace is
+    /// complete.
+    pub fn file_handle(mut self, file: File) -> Self {
+        self.output = Some(Output::File(file));
+        self
+    }
 
-    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id);
-        let span_name: Option<&'static str> = span.as_ref().map(|s| s.name());
-        //let fields = span.map(|s| s.fields())
+    pub fn include_args(mut self, include: bool) -> Self {
+        self.include_args = include;
+        self
+    }
 
-        let (thread_id, new_thread) = self.get_thread_id();
-        if let Some(name) = new_thread {
-            self.init_thread(thread_id, name);
-        }
+    /// Record scheduling policy, priority and CPU affinity of each thread.
+    ///
+    /// The values are captured when a thread first emits a trace event and
+    /// are attached as arguments to a "thread info" instant on the thread's
+    /// track. Only supported on Linux; ignored elsewhere.
+    pub fn include_thread_info(mut self, include: bool) -> Self {
+        self.include_thread_info = include;
+        self
+    }
 
-        let arg_info = if let Some(span_ref) = span {
-            if let Some(info) = span_ref.extensions().get::<DebugInfoExt>() {
-                Some(info.info.clone())
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+    /// Describe the track of each thread with its OS thread id and name, as
+    /// shown by `perf`, `gdb` or `top -H`, so that Perfetto lists it under
+    /// the thread of that id, along with the data of other tools.
+    ///
+    /// Only tracks that belong to a single OS thread are described, i.e.
+    /// not the tracks of thread pools or of ids from
+    /// [`thread_ids`](Self::thread_ids). Only supported on Linux and
+    /// Windows; ignored elsewhere.
+    pub fn os_thread_ids(mut self, enable: bool) -> Self {
+        self.os_thread_ids = enable;
+        self
+    }
 
-        // println!("on_enter: id={:?}, span_name={:?}, ", id, span_name);
-        let msg = Message::Enter(
-            self.get_timestamp(),
-            span_name.unwrap_or(""),
-            arg_info,
-            thread_id,
-        );
-        self.send_message(msg);
+    /// Record the host name, cgroup and container id of the process.
+    ///
+    /// The values are captured when the layer is built and are attached as
+    /// arguments to a "container info" instant on the process track at the
+    /// start of each trace file. The container id is a guess based on the
+    /// paths container runtimes use. Only supported on Linux; ignored
+    /// elsewhere.
+    pub fn include_container_info(mut self, include: bool) -> Self {
+        self.include_container_info = include;
+        self
     }
 
-    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id);
-        let span_name = span.map(|s| s.name());
+    /// Reuse the ids of exited threads for new threads.
+    ///
+    /// Each thread that records anything gets its own id, which determines
+    /// its packet sequence. Services that keep creating short-lived threads
+    /// would otherwise use up more and more sequences. Threads with a
+    /// recycled id still get a track of their own.
+    pub fn recycle_thread_ids(mut self, recycle: bool) -> Self {
+        self.recycle_thread_ids = recycle;
+        self
+    }
 
-        let (thread_id, new_thread) = self.get_thread_id();
-        if let Some(name) = new_thread {
-            self.init_thread(thread_id, name);
-        }
+    /// Let threads whose name starts with `prefix` share a pool of tracks.
+    ///
+    /// A thread of the pool takes over the track (and packet sequence) of a
+    /// thread of the same pool that has exited, so a thread-per-request
+    /// server only gets as many tracks as it ever had threads running at the
+    /// same time. The tracks are named after the prefix, and a "thread
+    /// exited" instant marks where one thread ends and the next begins.
+    pub fn pool_threads<T: Into<String>>(mut self, prefix: T) -> Self {
+        self.thread_pools.push(prefix.into());
+        self
+    }
 
-        // println!("on_exit: id={:?}, span_name={:?}, ", id, span_name);
-        let msg = Message::Exit(self.get_timestamp(), span_name.unwrap_or(""), thread_id);
-        self.send_message(msg);
+    /// Give at most `max_threads` threads a track of their own.
+    ///
+    /// Threads beyond the limit share a single "other threads" track. As
+    /// their spans would overlap on it, only their events are recorded there
+    /// (spans on custom tracks are recorded as usual). Ids returned by
+    /// exited threads, see [`recycle_thread_ids`] and [`pool_threads`], do
+    /// not count towards the limit.
+    ///
+    /// [`recycle_thread_ids`]: Self::recycle_thread_ids
+    /// [`pool_threads`]: Self::pool_threads
+    pub fn max_threads(mut self, max_threads: u32) -> Self {
+        self.max_threads = Some(max_threads);
+        self
     }
 
-    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
-        let name = event.metadata().name();
+    /// Leave sequence ids and track uuids to another Perfetto producer in
+    /// the process, e.g. one using the C++ SDK, whose data goes into the
+    /// same file. The layer writes none of them.
+    ///
+    /// Threads whose ids would reach the reserved ranges share a track, as
+    /// with [`max_threads`](Self::max_threads). Pick ranges above the ids
+    /// of the threads you expect, see [`id_ranges`](Self::id_ranges).
+    ///
+    /// # Panics
+    ///
+    /// If the ranges overlap the ids the layer always uses: sequence ids up
+    /// to 1 and from `u32::MAX - 3`, track uuids up to 8765 and from
+    /// `1 << 48`.
+    pub fn reserve_ids(mut self, sequence_ids: Range<u32>, track_uuids: Range<u64>) -> Self {
+        ids::max_threads_below(&sequence_ids, &track_uuids);
+        self.reserved_ids = Some((sequence_ids, track_uuids));
+        self
+    }
 
-        let (thread_id, new_thread) = self.get_thread_id();
-        if let Some(name) = new_thread {
-            self.init_thread(thread_id, name);
-        }
+    /// The sequence ids and track uuids the layer may write with the
+    /// current settings. Another producer writing into the same file must
+    /// stay clear of them.
+    pub fn id_ranges(&self) -> IdRanges {
+        IdRanges::new(self.thread_limit())
+    }
 
-        let arg_info = if self.include_args {
-            let mut v = DebugAnnotationVisitor { infos: Vec::new() };
-            event.record(&mut v);
-            if !v.infos.is_empty() {
-                Some(Arc::new(v.infos))
-            } else {
-                None
-            }
-        } else {
-            None
+    /// The thread limit, given [`max_threads`](Self::max_threads), the
+    /// reserved ids and the static configuration.
+    fn thread_limit(&self) -> Option<ThreadId> {
+        let reserved = self
+            .reserved_ids
+            .as_ref()
+            .map(|(sequence_ids, track_uuids)| ids::max_threads_below(sequence_ids, track_uuids));
+        let limit = match (self.max_threads, reserved) {
+            (Some(max), Some(reserved)) => Some(max.min(reserved)),
+            (max, reserved) => max.or(reserved),
         };
-
-        let msg = Message::Event(self.get_timestamp(), name, arg_info, thread_id);
-        self.send_message(msg);
+        #[cfg(feature = "static-config")]
+        let limit = Some(static_config::max_threads(limit));
+        limit
     }
-}
 
-struct DebugInfoExt {
-    info: Arc<Vec<DebugAnnotation>>,
-}
+    /// Key tracks by ids of your own, e.g. shard numbers, instead of by
+    /// thread.
+    ///
+    /// `thread_ids` is called once on each thread, the first time it records
+    /// anything. A thread for which it returns an id records onto the track
+    /// named `worker <id>`, which a later thread returning the same id
+    /// continues. Two threads must not use the same id at the same time, as
+    /// their spans would get mixed up. Threads for which it returns `None`
+    /// are numbered as usual. Each of your ids takes up a thread id of its
+    /// own, so they count towards [`max_threads`].
+    ///
+    /// [`max_threads`]: Self::max_threads
+    pub fn thread_ids<F>(mut self, thread_ids: F) -> Self
+    where
+        F: Fn() -> Option<u32> + Send + Sync + 'static,
+    {
+        self.thread_ids = Some(Box::new(thread_ids));
+        self
+    }
 
-pub struct FlushGuard {
-    handle: Option<JoinHandle<()>>, // An option, so we can `take`
-    sender: Sender<Message>,
-}
+    /// Group the spans of async tasks onto a track per task, for executors
+    /// other than tokio (see the `tokio` feature for that).
+    ///
+    /// `task_id` is called whenever a span is entered or exited, and should
+    /// return the id of the task being polled on the current thread, if
+    /// any. Spans entered by a task go onto the track named `task <id>`,
+    /// unless they have a custom track; other spans stay on the thread
+    /// track. A span must be exited by the same task that entered it.
+    pub fn task_id_provider<F>(mut self, task_id: F) -> Self
+    where
+        F: Fn() -> Option<u64> + Send + Sync + 'static,
+    {
+        self.task_ids = Some(Box::new(task_id));
+        self
+    }
 
-impl Drop for FlushGuard {
-    fn drop(&mut self) {
-        // Tell writer thread to stop. Sending will fail if thread is already
-        // stopped. We can ignore that.
-        let _ignore_err = self.sender.send(crate::Message::Drop);
-        if let Some(handle) = self.handle.take() {
-            if handle.join().is_err() {
-                eprintln!("tracing_perfetto: writer thread panicked");
-            }
-        }
+    /// Sample a hardware counter of the thread, e.g. the instructions
+    /// retired, whenever a span is entered or exited, so slices can be
+    /// compared by their cost rather than their wall time.
+    ///
+    /// The samples go onto a counter track per thread, named after the
+    /// counter, at the begin and end of each slice; the difference is what
+    /// the slice cost. Only user space is counted. Linux only; elsewhere,
+    /// or if the kernel refuses to open the counter, nothing is sampled.
+    pub fn perf_counter(mut self, counter: PerfCounter) -> Self {
+        self.perf_counter = Some(counter);
+        self
     }
-}
 
-// TODO: Use custom type here with `&'static str` for name, and custom enum for
-// values. Then interning can be handled in the writer.
-#[derive(Debug)]
-struct DebugAnnotationVisitor {
-    infos: Vec<DebugAnnotation>,
-}
+    /// Put child spans of a span on a custom track onto the same track.
+    ///
+    /// A span is put on a custom track by giving it a `perfetto.track`
+    /// field, e.g. `info_span!("query", perfetto.track = "db")`. With this
+    /// option, its descendants end up on that track as well, unless they
+    /// specify a track of their own.
+    pub fn inherit_tracks(mut self, inherit: bool) -> Self {
+        self.inherit_tracks = inherit;
+        self
+    }
 
-impl Visit for DebugAnnotationVisitor {
-    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::String(format!("{:?}", value)),
-        })
+    /// Copy the given fields of a span to the slices of its descendants.
+    ///
+    /// E.g. with `inherit_fields(["request_id"])`, every slice nested in a
+    /// span with a `request_id` field shows that `request_id` as an
+    /// argument, unless it has a `request_id` of its own. Inherited fields
+    /// are recorded even if [`include_args`] is disabled.
+    ///
+    /// [`include_args`]: Self::include_args
+    pub fn inherit_fields<I, T>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.inherited_fields
+            .extend(fields.into_iter().map(Into::into));
+        self
     }
 
-    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::Bool(value),
-        })
+    /// Put events onto the custom track of the span they occur in.
+    ///
+    /// By default, instants are always shown on the thread track. With this
+    /// option, an event inside a span on a custom track is shown on that
+    /// track instead. Individual events can override this with a
+    /// `perfetto.instant_scope` field set to `"track"` or `"thread"`.
+    pub fn events_on_span_tracks(mut self, enable: bool) -> Self {
+        self.events_on_span_tracks = enable;
+        self
     }
 
-    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::Uint(value),
-        })
+    /// Give every span tree a track of its own, for async code whose spans
+    /// move between the threads polling them.
+    ///
+    /// A span without a parent, such as the span of a request or of a
+    /// spawned future, gets the track named `<name> (span <id>)`, and its
+    /// descendants and the events inside them go onto that track too. The
+    /// slices of a task polled by several threads thus show up as one
+    /// continuous slice instead of pieces nested into whatever the threads
+    /// were doing. Spans and events outside of any span stay on the thread
+    /// track.
+    ///
+    /// A span without a parent that
+    /// [follows from](tracing::Span::follows_from) another one, e.g. a
+    /// retry, continues on that span's track instead. The relation has to
+    /// be recorded before the span is entered, and the span should only be
+    /// entered once the one it follows from is done, as slices on a track
+    /// have to nest.
+    pub fn async_tracks(mut self, enable: bool) -> Self {
+        self.async_tracks = enable;
+        self
     }
 
-    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::String(value.to_owned()),
-        })
+    /// Give each slice of a span with a parent a `root_offset_ns` argument:
+    /// the time since the root of its span tree was first entered.
+    ///
+    /// This makes it easy to look at the first milliseconds of an
+    /// operation in trace_processor without joining slices to their roots,
+    /// e.g. `SELECT * FROM slice JOIN args USING (arg_set_id) WHERE key =
+    /// 'debug.root_offset_ns' AND int_value < 2000000`.
+    pub fn root_offsets(mut self, enable: bool) -> Self {
+        self.root_offsets = enable;
+        self
     }
 
-    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::Int(value),
-        })
+    /// Link the root slices of this process to a span of the process that
+    /// spawned it, with a flow starting where the parent called
+    /// [`SpanContext::export`].
+    pub fn parent_span_context(mut self, context: SpanContext) -> Self {
+        self.parent_span_context = Some(context);
+        self
     }
 
-    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.infos.push(DebugAnnotation {
-            name: packet::IString::Plain(field.name().to_string()),
-            value: packet::DebugValue::Double(value),
-        })
+    /// Set how the `message` field of events is recorded.
+    ///
+    /// Defaults to [`MessagePolicy::Annotation`].
+    pub fn message_policy(mut self, policy: MessagePolicy) -> Self {
+        self.message_policy = policy;
+        self
     }
-}
 
-//fn fields_to_debug_attrs()
+    /// Set how the `message` field of events is recorded for targets
+    /// starting with `target_prefix`.
+    ///
+    /// If several prefixes match, the longest one wins. Targets that match
+    /// no prefix use the policy set with [`message_policy`].
+    ///
+    /// [`message_policy`]: Self::message_policy
+    pub fn target_message_policy<T: Into<String>>(
+        mut self,
+        target_prefix: T,
+        policy: MessagePolicy,
+    ) -> Self {
+        self.target_message_policies
+            .push((target_prefix.into(), policy));
+        self
+    }
 
-// pub fn init_thread()
+    /// Set how events without an explicit name are named.
+    ///
+    /// Defaults to [`EventNaming::Default`]. A message recorded with
+    /// [`MessagePolicy::Name`] still takes precedence.
+    pub fn event_naming(mut self, naming: EventNaming) -> Self {
+        self.event_naming = naming;
+        self
+    }
 
-fn writer_thread(rx: Receiver<Message>, path: Option<PathBuf>) {
-    let filename = if let Some(path) = path {
-        path
-    } else {
-        PathBuf::from(format!(
-            "trace-{}.perfetto-trace",
-            std::time::SystemTime::UNIX_EPOCH
-                .elapsed()
-                .unwrap()
-                .as_secs()
-        ))
-    };
+    /// Only record spans or only events, e.g. `Kinds::SPANS` for a pure
+    /// span profile. The callbacks for the other kind return right away.
+    ///
+    /// Counters recorded from event fields count as events. Defaults to
+    /// [`Kinds::ALL`].
+    pub fn record_kinds(mut self, kinds: Kinds) -> Self {
+        self.record_kinds = kinds;
+        self
+    }
 
-    let file = File::create(filename).unwrap();
-    let mut writer = BufWriter::with_capacity(64 * 1024, file);
-
-    let mut em = ProtoEmitter::new();
-    let trusted_uid = 42;
-    //    let trusted_packet_sequence_id = 1;
-    let mut names: Vec<Interned> = vec![Interned::new()];
-
-    for msg in rx {
-        em.clear();
-        match msg {
-            Message::NewThread(thread_id, thread_name) => {
-                names.resize_with((thread_id + 1) as usize, || Interned::new());
-
-                // This packet is needed so we can use string interning. It also
-                // defines the default track uuid for this thread. Because we
-                // use one trusted sequence id per thread, we should never have
-                // to override the track uuid in a packet.
-                let msg0 = TracePacket {
-                    timestamp: 1,
-                    data: PacketData::None,
-                    sequence_flags: SEQ_INCREMENTAL_STATE_CLEARED,
-                    trusted_uid,
-                    trusted_packet_sequence_id: 1 + thread_id as u32,
-                    interned_data: None,
-                    trace_packet_defaults: Some(TracePacketDefaults {
-                        timestamp_clock_id: 6, // boottime?
-                        track_event_defaults: Some(TrackEventDefaults {
-                            track_uuid: 8765 * (thread_id as u64 + 1),
-                        }),
-                    }),
-                };
+    /// Cut off slice, event and argument names after this many bytes.
+    ///
+    /// Control characters such as newlines in names and string arguments
+    /// are always escaped. Defaults to 1024.
+    pub fn max_name_len(mut self, max_len: usize) -> Self {
+        self.limits.max_name_len = max_len;
+        self
+    }
 
-                // thread track descriptor. defines track uuid and track name
-                // (= thread name)
-                let msg1 = TracePacket {
-                    timestamp: 1,
-                    data: PacketData::TrackDescriptor(TrackDescriptor {
-                        uuid: 8765 * (thread_id as u64 + 1),
-                        name: thread_name,
-                    }),
-                    sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
-                    trusted_uid,
-                    trusted_packet_sequence_id: 1 + thread_id as u32,
-                    interned_data: None,
-                    trace_packet_defaults: None,
+    /// Cut off string argument values after this many bytes.
+    ///
+    /// Defaults to 64KiB.
+    pub fn max_arg_len(mut self, max_len: usize) -> Self {
+        self.limits.max_value_len = max_len;
+        self
+    }
+
+    /// Spend at most `bytes_per_second` bytes per second of trace time on
+    /// the arguments of slices and instants, see [`include_args`].
+    ///
+    /// Capturing arguments can blow up the size of a trace under load.
+    /// Once a second's budget is used up, slices and instants are written
+    /// without their arguments until the next second. The number of them
+    /// is plotted on an "omitted args" counter track, and counted in
+    /// [`TraceStats::args_omitted`], so a reader knows what is missing.
+    ///
+    /// [`include_args`]: Self::include_args
+    pub fn args_budget(mut self, bytes_per_second: u64) -> Self {
+        self.args_budget = Some(bytes_per_second);
+        self
+    }
+
+    /// Record the values of numeric event fields named `field` as a counter
+    /// in `unit`.
+    ///
+    /// The values are plotted on a counter track named after the field, e.g.
+    /// `info!(latency_ms = 12, "request done")` with
+    /// `.unit_hint("latency_ms", Unit::DurationMs)` puts a sample of 12ms on
+    /// the "latency_ms" track, which Perfetto draws as a graph with a time
+    /// axis. The event itself is recorded as usual.
+    pub fn unit_hint<T: Into<String>>(mut self, field: T, unit: Unit) -> Self {
+        self.unit_hints.insert(field.into(), unit);
+        self
+    }
+
+    /// Record the values of numeric fields named `field` of events with a
+    /// target starting with `target_prefix` as a counter.
+    ///
+    /// This makes existing instrumentation graphable, e.g.
+    /// `.counter_field("my_crate::net", "bytes_sent")`. The counter goes on
+    /// a track named after the field, and has the unit set with
+    /// [`unit_hint`], if any. The counters of one event share a single
+    /// packet, so a sampler can record related values, e.g.
+    /// `info!(target: "my_crate::sampler", rss, cpu, fds)`, cheaply.
+    ///
+    /// [`unit_hint`]: Self::unit_hint
+    pub fn counter_field<T: Into<String>, F: Into<String>>(
+        mut self,
+        target_prefix: T,
+        field: F,
+    ) -> Self {
+        self.counter_fields
+            .push((target_prefix.into(), field.into()));
+        self
+    }
+
+    /// Put the counter of fields named `field` on a track of each thread
+    /// that records it, with [`CounterScope::Thread`], rather than on a
+    /// single track of the process.
+    ///
+    /// This applies to the counters of [`unit_hint`] and [`counter_field`],
+    /// and of routing rules. Defaults to [`CounterScope::Process`].
+    ///
+    /// [`unit_hint`]: Self::unit_hint
+    /// [`counter_field`]: Self::counter_field
+    pub fn counter_scope<F: Into<String>>(mut self, field: F, scope: CounterScope) -> Self {
+        self.counter_scopes.insert(field.into(), scope);
+        self
+    }
+
+    /// Add a rule deciding where matching spans and events go: onto a
+    /// custom track, into a category, into counters, or nowhere at all.
+    ///
+    /// This moves instrumentation around without touching it, e.g.
+    /// `.route(Rule::new().target("hyper").level(Level::DEBUG).discard())`.
+    /// Rules are checked in the order they were added; of the tracks and
+    /// categories of the matching rules, the first one wins, and a single
+    /// matching [`discard`](Rule::discard) drops the span or event. The
+    /// rules only look at callsites, so they are evaluated once per
+    /// callsite.
+    pub fn route(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Give the slices and instants of targets starting with
+    /// `target_prefix` the category `category`, e.g.
+    /// `.target_category("my_crate::db", "db")`, to tell subsystems apart.
+    ///
+    /// This is short for
+    /// `.route(Rule::new().target(target_prefix).category(category))`, so
+    /// of several matching prefixes, the first one added wins. See
+    /// [`Rule::category`] for what the Perfetto UI does with categories.
+    pub fn target_category<T: Into<String>>(self, target_prefix: T, category: &str) -> Self {
+        self.route(Rule::new().target(target_prefix).category(category))
+    }
+
+    /// Declare how long a single entry into spans named `span_name` may
+    /// take.
+    ///
+    /// Whenever such a span is exited after more than `budget` since it was
+    /// entered, a "budget exceeded" instant is recorded at that point, with
+    /// the span name, the budget and the overage in nanoseconds as
+    /// arguments. The instant is recorded even if [`include_args`] is
+    /// disabled.
+    ///
+    /// [`include_args`]: Self::include_args
+    pub fn span_budget<T: Into<String>>(mut self, span_name: T, budget: Duration) -> Self {
+        self.span_budgets.insert(span_name.into(), budget);
+        self
+    }
+
+    /// Only record span entries that last at least `min_duration`.
+    ///
+    /// Useful to keep traces of hot code paths small when only slow calls
+    /// are of interest. The beginning of a slice is held back until the span
+    /// is exited, so slices show up in the trace only once they are
+    /// complete, and spans that are still entered when the trace ends are
+    /// missing entirely.
+    pub fn min_span_duration(mut self, min_duration: Duration) -> Self {
+        self.min_span_duration = Some(min_duration);
+        self
+    }
+
+    /// Only record the `n` slowest entries of each span callsite.
+    ///
+    /// Meant for long soak tests, where a full trace would be far too large.
+    /// The kept slices, with all their arguments, are written when the trace
+    /// ends. For each callsite with more entries, a "dropped entries"
+    /// instant on the "span summaries" track records how many entries were
+    /// dropped and their total duration. Can be combined with
+    /// [`min_span_duration`], in which case shorter entries are not counted
+    /// at all.
+    ///
+    /// [`min_span_duration`]: Self::min_span_duration
+    pub fn slowest_spans(mut self, n: usize) -> Self {
+        self.slowest_spans = Some(n);
+        self
+    }
+
+    /// Also write begin and end markers for every span entry to the ftrace
+    /// `trace_marker` file, so the spans show up in system traces recorded
+    /// with Perfetto or trace-cmd, next to the kernel's scheduling data.
+    ///
+    /// Needs write access to tracefs (usually root). Only supported on
+    /// Linux; ignored elsewhere.
+    pub fn trace_marker(mut self, enable: bool) -> Self {
+        self.trace_marker = enable;
+        self
+    }
+
+    /// Log every `ERROR` event to syslog (and thus the systemd journal) in a
+    /// single line with the path of the trace file, so that alerts on the
+    /// log lead to the trace capturing the incident.
+    ///
+    /// The path is only known if the trace is written to a path. Only
+    /// supported on Unix; ignored elsewhere.
+    pub fn syslog_errors(mut self, enable: bool) -> Self {
+        self.syslog_errors = enable;
+        self
+    }
+
+    /// Record what the writer thread is doing on a "tracing-perfetto"
+    /// track: a slice for each batch of messages it processes, from the
+    /// first message until the output is flushed, and counters of the
+    /// queued messages and the bytes written.
+    ///
+    /// This shows the overhead of tracing, and whether the writer keeps up.
+    pub fn trace_writer(mut self, enable: bool) -> Self {
+        self.trace_writer = enable;
+        self
+    }
+
+    /// Set how full, in percent, the output buffer must be for
+    /// [`flush_hint`] to flush it. Defaults to 50.
+    pub fn flush_hint_threshold(mut self, percent: u8) -> Self {
+        self.flush_hint_threshold = percent.min(100);
+        self
+    }
+
+    /// Finish the trace once about `bytes` bytes have been written. The
+    /// layer then stops recording, while the application keeps running.
+    ///
+    /// The packet that crosses the limit is still written, so the trace
+    /// can be slightly larger.
+    pub fn stop_after_bytes(mut self, bytes: u64) -> Self {
+        self.stop_after_bytes = Some(bytes);
+        self
+    }
+
+    /// Finish the trace `duration` after the layer was built. The layer
+    /// then stops recording, while the application keeps running.
+    pub fn stop_after(mut self, duration: Duration) -> Self {
+        self.stop_after = Some(duration);
+        self
+    }
+
+    /// Report writes to the trace file that take at least `threshold`, as
+    /// "write stall" instants on the writer's track and in
+    /// [`TraceStats::write_stalls`]. This tells a slow disk apart from
+    /// overhead in the traced code.
+    ///
+    /// Defaults to 100ms. Use `Duration::MAX` to turn it off.
+    pub fn write_stall_threshold(mut self, threshold: Duration) -> Self {
+        self.write_stall_threshold = threshold;
+        self
+    }
+
+    /// Write packets to a temporary file in `dir` while more than
+    /// `max_queued` messages wait for the writer, and copy them to the
+    /// trace once it has caught up.
+    ///
+    /// This bounds memory use while the output is slow, e.g. during a
+    /// rotation to slow storage, by trading it for space on a faster disk.
+    /// Files are not rotated while packets are spilled, so they can grow
+    /// past [`max_file_size`]. The bytes spilled are counted in
+    /// [`TraceStats::bytes_spilled`].
+    ///
+    /// [`max_file_size`]: Self::max_file_size
+    pub fn spill_to_disk<P: Into<PathBuf>>(mut self, dir: P, max_queued: usize) -> Self {
+        self.spill = Some((dir.into(), max_queued));
+        self
+    }
+
+    /// Keep only the most recent `max_bytes` of the trace in memory, like a
+    /// flight recorder, instead of writing it as it is recorded.
+    ///
+    /// The output is only written when the trace finishes, including when
+    /// the [`FlushGuard`] is dropped by a panic, and
+    /// [`FlushGuard::dump_now`] writes what the buffer holds at any time.
+    /// Besides the packets, the buffer keeps the latest descriptor of each
+    /// track, so slices stay on their tracks after their thread started
+    /// long ago. Packets in the oldest quarter of the buffer may refer to
+    /// names interned in evicted packets, which trace processors skip.
+    pub fn ring_buffer(mut self, max_bytes: usize) -> Self {
+        self.ring_buffer = Some(max_bytes);
+        self
+    }
+
+    /// Only start recording once `trigger` fires, e.g. to skip a noisy
+    /// startup phase. Until then, spans and events are not recorded.
+    pub fn start_trigger(mut self, trigger: Trigger) -> Self {
+        self.start_trigger = Some(trigger);
+        self
+    }
+
+    /// Whether to record from the start. With `false`, nothing is recorded
+    /// until recording is enabled through the
+    /// [`control_handle`](PerfettoLayer::control_handle) of the layer.
+    ///
+    /// Defaults to `true`.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Only record spans nested at most `depth` levels deep on their
+    /// thread, e.g. to keep the top levels of a deep call graph. Deeper
+    /// spans are skipped when they are entered, which saves both overhead
+    /// and trace size.
+    pub fn max_span_depth(mut self, depth: u32) -> Self {
+        self.max_span_depth = Some(depth);
+        self
+    }
+
+    /// Record like a black box: keep the last `before` of the trace in
+    /// memory, and only write it out when an event matches `trigger`,
+    /// followed by everything in the `after` that follows.
+    ///
+    /// Slices that began before the written window have no beginning in
+    /// the trace.
+    pub fn snapshot(mut self, trigger: SnapshotTrigger, before: Duration, after: Duration) -> Self {
+        self.snapshot = Some((trigger, before, after));
+        self
+    }
+
+    /// Check the trace as it is written, to find instrumentation bugs that
+    /// would otherwise just make the trace look odd: slices that end
+    /// without beginning or never end, timestamps going backwards on a
+    /// thread, and interned names used before they are defined.
+    ///
+    /// Each violation goes to [`on_error`](Self::on_error), or stderr by
+    /// default, and is counted in [`TraceStats::violations`], and a summary
+    /// follows when the trace is finished. The checks cost some time on the
+    /// writer thread, so this is meant for tests and debugging. Slices that
+    /// are written once the span is exited, see [`min_span_duration`] and
+    /// [`slowest_spans`], are not checked for going backwards.
+    ///
+    /// [`min_span_duration`]: Self::min_span_duration
+    /// [`slowest_spans`]: Self::slowest_spans
+    pub fn strict(mut self, enable: bool) -> Self {
+        self.strict = enable;
+        self
+    }
+
+    /// Show build information about the traced binary on a dedicated
+    /// "process info" track.
+    ///
+    /// The track contains a single slice that lasts for the whole trace and
+    /// carries the version, git hash and build profile as arguments. Use the
+    /// [`process_info!`] macro to fill in the values for the calling crate.
+    pub fn process_info(mut self, info: ProcessInfo) -> Self {
+        self.process_info = Some(info);
+        self
+    }
+
+    /// Periodically clear the interning state of each thread's sequence.
+    ///
+    /// Interned names are normally emitted only once per sequence, so a
+    /// trace can only be parsed from the start. With this option, each
+    /// sequence starts over at most once per `interval` (in trace time),
+    /// re-emitting the names it uses afterwards. Tools can then start
+    /// reading at any of these points, at the cost of a slightly larger
+    /// trace.
+    pub fn incremental_state_interval(mut self, interval: Duration) -> Self {
+        self.incremental_state_interval = Some(interval);
+        self
+    }
+
+    /// Intern these event names at the start of each thread's sequence,
+    /// e.g. the names exported by [`export_intern_table`] in an earlier
+    /// run. They are then announced once in the sequence header, rather
+    /// than spread over the first packets of each thread. To carry the
+    /// table from run to run, pass the lines of the exported file here and
+    /// export to the same path again.
+    ///
+    /// [`export_intern_table`]: Self::export_intern_table
+    pub fn intern_seed<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.intern_seed = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// When the trace is finished, write the event names interned in it to
+    /// `path`, one per line, for [`intern_seed`](Self::intern_seed).
+    pub fn export_intern_table<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.export_intern_table = Some(path.into());
+        self
+    }
+
+    /// Set what timestamps count from. By default, they start at zero when
+    /// the layer is built, which keeps them small but means that traces of
+    /// different processes can't be lined up. With [`Origin::SystemBoot`],
+    /// [`Origin::Monotonic`] or [`Origin::UnixEpochNs`], they are declared
+    /// in the boottime, monotonic or realtime clock respectively, so
+    /// Perfetto shows traces loaded together on a common timeline, e.g.
+    /// with an ftrace recording of the system.
+    ///
+    /// Each file starts with a snapshot of the system clocks, from which
+    /// Perfetto converts between them.
+    pub fn timestamp_origin(mut self, origin: Origin) -> Self {
+        self.timestamp_origin = origin;
+        self
+    }
+
+    /// Share the time base of a host application, e.g. of its own
+    /// profiler: timestamps are `offset_ns` plus the nanoseconds since
+    /// `start`, so slices line up with the data the host exports.
+    ///
+    /// This is [`timestamp_origin`](Self::timestamp_origin) with
+    /// [`Origin::TimeBase`]. Unlike the other origins, the time is read
+    /// from [`Instant`](std::time::Instant) as the host does, so it stops
+    /// while the system is suspended.
+    pub fn time_base(mut self, start: std::time::Instant, offset_ns: u64) -> Self {
+        self.timestamp_origin = Origin::TimeBase { start, offset_ns };
+        self
+    }
+
+    /// Record the trace and span id of the active OpenTelemetry span as
+    /// `otel.trace_id` and `otel.span_id` arguments of each slice.
+    ///
+    /// Slices belonging to the same OpenTelemetry trace are also connected
+    /// by a flow, so they can be followed across threads in the UI.
+    #[cfg(feature = "opentelemetry")]
+    pub fn opentelemetry_context(mut self, enable: bool) -> Self {
+        self.opentelemetry_context = enable;
+        self
+    }
+
+    /// Attach the innermost `frames` frames of the call stack to events at
+    /// `level` or a more severe one, as a `backtrace` argument.
+    ///
+    /// Walking the stack is cheap enough for warnings and errors, but not for
+    /// every event; the frames are symbolized on the writer thread.
+    #[cfg(feature = "backtrace")]
+    pub fn event_backtraces(mut self, level: tracing::Level, frames: usize) -> Self {
+        self.event_backtraces = Some((level, frames));
+        self
+    }
+
+    /// Also send spans that stay open for at least `min_duration` to an
+    /// OpenTelemetry tracer, e.g. one from an OTLP exporter pipeline.
+    ///
+    /// The trace file keeps every span, while the tracer only sees the
+    /// coarse ones, with their fields as attributes. Exported spans are
+    /// children of the OpenTelemetry context that was active when they were
+    /// created.
+    #[cfg(feature = "opentelemetry")]
+    pub fn export_spans<T>(mut self, tracer: T, min_duration: Duration) -> Self
+    where
+        T: opentelemetry::trace::Tracer + Send + Sync + 'static,
+        T::Span: Send + Sync + 'static,
+    {
+        self.span_export = Some(otel::SpanExport {
+            tracer: opentelemetry::global::BoxedTracer::new(Box::new(tracer)),
+            min_duration,
+        });
+        self
+    }
+
+    /// Also record the warnings and errors that are mirrored to logcat as
+    /// Android log packets, which the Perfetto UI lists in its "Android
+    /// logs" panel.
+    ///
+    /// This works on any platform, not just on Android.
+    #[cfg(feature = "android-log")]
+    pub fn android_log_packets(mut self, enable: bool) -> Self {
+        self.android_log_packets = enable;
+        self
+    }
+
+    /// Write the spans and events as ETW events of the given provider,
+    /// instead of writing a Perfetto trace, so they can be recorded with
+    /// WPR and viewed in Windows Performance Analyzer.
+    ///
+    /// Options about the output file and the Perfetto format are ignored.
+    /// On other platforms, nothing is recorded.
+    #[cfg(feature = "etw")]
+    pub fn etw(mut self, provider: EtwProvider) -> Self {
+        self.etw = Some(provider);
+        self
+    }
+
+    pub fn build(self) -> (PerfettoLayer<S>, FlushGuard) {
+        #[cfg(feature = "zstd")]
+        let transformed = self.transform.is_some() || self.zstd.is_some();
+        #[cfg(not(feature = "zstd"))]
+        let transformed = self.transform.is_some();
+        assert!(
+            !(self.append && transformed),
+            "a transformed trace can't be appended to"
+        );
+        PerfettoLayer::new(self)
+    }
+}
+
+/// What to do with the `message` field of an event, i.e., the formatted
+/// text of `info!("text {}", x)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePolicy {
+    /// Record the message as a `message` argument if
+    /// [`PerfettoLayerBuilder::include_args`] is enabled.
+    Annotation,
+    /// Use the message as the name of the instant. This works even if
+    /// arguments are not included.
+    Name,
+    /// Like `Annotation`, but keep at most this many characters.
+    Truncate(usize),
+    /// Don't record the message.
+    Drop,
+}
+
+/// The kinds of data to record, see [`PerfettoLayerBuilder::record_kinds`].
+/// Combine them with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kinds(u8);
+
+impl Kinds {
+    pub const SPANS: Kinds = Kinds(1);
+    pub const EVENTS: Kinds = Kinds(2);
+    pub const ALL: Kinds = Kinds(3);
+
+    pub fn contains(self, other: Kinds) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Kinds {
+    type Output = Kinds;
+
+    fn bitor(self, other: Kinds) -> Kinds {
+        Kinds(self.0 | other.0)
+    }
+}
+
+/// How to name events that have no explicit `name:`, which tracing calls
+/// `"event src/foo/bar.rs:42"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventNaming {
+    /// Keep tracing's name, e.g. `"event src/foo/bar.rs:42"`.
+    Default,
+    /// The file name and line, e.g. `"bar.rs:42"`.
+    FileLine,
+    /// The module path, e.g. `"my_crate::foo::bar"`.
+    ModulePath,
+}
+
+impl EventNaming {
+    fn name(self, metadata: &'static tracing::Metadata<'static>) -> Cow<'static, str> {
+        let name = metadata.name();
+        if self == EventNaming::Default || !name.starts_with("event ") {
+            return Cow::Borrowed(name);
+        }
+        match (self, metadata.file(), metadata.line()) {
+            (EventNaming::FileLine, Some(file), Some(line)) => {
+                let file_name = file.rsplit(['/', '\\']).next().unwrap_or(file);
+                Cow::Owned(format!("{}:{}", file_name, line))
+            }
+            (EventNaming::ModulePath, ..) => {
+                Cow::Borrowed(metadata.module_path().unwrap_or(metadata.target()))
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// Build information about the traced binary.
+///
+/// Usually created with [`process_info!`], which picks up the package name
+/// and version of the crate it is invoked from.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub name: String,
+    pub version: String,
+    /// Git revision the binary was built from, if known.
+    pub git_hash: Option<String>,
+    /// Build profile, e.g. `"debug"` or `"release"`.
+    pub profile: String,
+}
+
+/// Create a [`ProcessInfo`] describing the crate this macro is invoked from.
+///
+/// The git hash is taken from the `GIT_HASH` environment variable at compile
+/// time, e.g. set from a build script or CI.
+#[macro_export]
+macro_rules! process_info {
+    () => {
+        $crate::ProcessInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("GIT_HASH").map(|hash| hash.to_string()),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_string(),
+        }
+    };
+}
+
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+/// Create an info-level span and enter it, with fields written as
+/// `{ key = value, .. }`, e.g. `perfetto_span!("flush", { rows = 12 })`.
+///
+/// Evaluates to the [`EnteredSpan`](tracing::span::EnteredSpan), which
+/// exits the span when dropped. Use [`perfetto_exit_args!`] to add
+/// arguments to the end of its slice.
+#[macro_export]
+macro_rules! perfetto_span {
+    ($name:literal $(,)?) => {
+        $crate::__tracing::info_span!($name).entered()
+    };
+    ($name:literal, { $($fields:tt)* } $(,)?) => {
+        $crate::__tracing::info_span!($name, $($fields)*).entered()
+    };
+}
+
+/// Add arguments to the end of the slice of the current span, e.g. the
+/// result of the work it covers: `perfetto_exit_args!{ rows = 12 }`.
+///
+/// Unlike `Span::record`, the fields don't have to be declared when the
+/// span is created. The values are kept until the span is exited, and
+/// recorded even if [`PerfettoLayerBuilder::include_args`] is disabled.
+/// Only works with the [`Registry`](tracing_subscriber::Registry) of
+/// `tracing_subscriber`; elsewhere, the arguments are dropped.
+#[macro_export]
+macro_rules! perfetto_exit_args {
+    ($($key:ident = $value:expr),* $(,)?) => {
+        $crate::__stash_exit_args(::std::vec![
+            $($crate::raw::DebugAnnotation::new(::std::stringify!($key), $value)),*
+        ])
+    };
+}
+
+/// See [`perfetto_exit_args!`].
+#[doc(hidden)]
+pub fn __stash_exit_args(args: Vec<DebugAnnotation>) {
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let Some(registry) = dispatch.downcast_ref::<tracing_subscriber::Registry>() else {
+            return;
+        };
+        let Some(span) = registry.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<RecordedArgsExt>() {
+            Some(ext) => merge_args(&mut ext.args, args),
+            None => extensions.insert(RecordedArgsExt { args }),
+        }
+    });
+}
+
+type ThreadId = u32;
+
+/// See [`PerfettoLayerBuilder::thread_ids`].
+type ThreadIdProvider = Box<dyn Fn() -> Option<ThreadId> + Send + Sync>;
+
+/// See [`PerfettoLayerBuilder::task_id_provider`].
+type TaskIdProvider = Box<dyn Fn() -> Option<u64> + Send + Sync>;
+type Timestamp = u64;
+
+#[derive(Debug)]
+pub enum Message {
+    /// A thread and the name of its track, and the OS thread the track
+    /// belongs to if it is described by its OS thread id.
+    NewThread(ThreadId, String, Option<ThreadDescriptor>),
+    /// The thread has exited and won't send any more messages.
+    ThreadExit(ThreadId, Timestamp),
+    Enter {
+        timestamp: Timestamp,
+        /// The name of the span, or one set with a `perfetto.name` field.
+        name: Cow<'static, str>,
+        args: Option<Args>,
+        thread_id: ThreadId,
+        /// Name of the custom track, if the slice is not on the thread track.
+        track: Option<Arc<str>>,
+        /// Flows connecting the slice to related slices.
+        flow_ids: Vec<u64>,
+        /// Flows that end at the slice.
+        terminating_flow_ids: Vec<u64>,
+        /// The callsite, if the slice comes from a span.
+        location: Option<&'static tracing::Metadata<'static>>,
+        /// See [`Rule::category`].
+        category: Option<Arc<str>>,
+        /// See [`PerfettoLayerBuilder::perf_counter`].
+        perf_count: Option<u64>,
+    },
+    Exit {
+        timestamp: Timestamp,
+        name: Cow<'static, str>,
+        thread_id: ThreadId,
+        track: Option<Arc<str>>,
+        perf_count: Option<u64>,
+        /// Fields recorded while the slice was open, which are added to its
+        /// arguments.
+        args: Option<Args>,
+        /// Whether the span was exited by a panic unwinding the stack.
+        unwound: bool,
+    },
+    /// A complete span entry, of which the writer only keeps the slowest,
+    /// see [`PerfettoLayerBuilder::slowest_spans`].
+    Slice {
+        callsite: tracing::callsite::Identifier,
+        /// The `Enter` message beginning the slice.
+        enter: Box<Message>,
+        end: Timestamp,
+        end_perf_count: Option<u64>,
+    },
+    Event {
+        timestamp: Timestamp,
+        name: Cow<'static, str>,
+        args: Option<Args>,
+        thread_id: ThreadId,
+        track: Option<Arc<str>>,
+        /// Flows connecting the instant to related slices.
+        flow_ids: Vec<u64>,
+        /// Flows that end at the instant.
+        terminating_flow_ids: Vec<u64>,
+        /// The callsite, if the instant comes from an event.
+        location: Option<&'static tracing::Metadata<'static>>,
+        /// See [`Rule::category`].
+        category: Option<Arc<str>>,
+    },
+    /// Samples of the fields of an event that are recorded as counters.
+    Counters {
+        timestamp: Timestamp,
+        samples: Vec<(&'static str, Option<Unit>, CounterScope, CounterValue)>,
+        thread_id: ThreadId,
+    },
+    /// A log line for the Android log panel.
+    AndroidLog {
+        timestamp: Timestamp,
+        prio: u32,
+        tag: String,
+        message: String,
+        thread_id: ThreadId,
+    },
+    /// See [`flush_hint`].
+    FlushHint,
+    /// See [`TraceController::flush`].
+    Flush,
+    /// See [`TraceController::rotate`].
+    Rotate,
+    /// See [`StateTrack`]; `None` ends the current state.
+    State {
+        timestamp: Timestamp,
+        track: Arc<str>,
+        state: Option<String>,
+    },
+    /// Begin (with a name) or end a region, see [`region`].
+    Region {
+        timestamp: Timestamp,
+        id: u64,
+        name: Option<String>,
+    },
+    /// Write the buffered messages, see [`PerfettoLayerBuilder::snapshot`].
+    Snapshot {
+        timestamp: Timestamp,
+    },
+    /// See [`FlushGuard::dump_now`].
+    Dump {
+        path: PathBuf,
+        done: Sender<io::Result<()>>,
+    },
+    Drop,
+}
+
+impl<S> PerfettoLayer<S> {
+    fn new(builder: PerfettoLayerBuilder<S>) -> (Self, FlushGuard) {
+        let max_threads = builder.thread_limit();
+        #[cfg(not(feature = "static-config"))]
+        let (tx, rx) = crossbeam_channel::unbounded();
+        #[cfg(feature = "static-config")]
+        let (tx, rx) = crossbeam_channel::bounded(static_config::QUEUE_CAPACITY);
+        let counters = Arc::new(Counters::default());
+        let current_path = syslog::CurrentPath::default();
+        // Runs of an appended trace need a common timeline.
+        let origin = match builder.timestamp_origin {
+            Origin::ProcessStart if builder.append => Origin::SystemBoot,
+            origin => origin,
+        };
+        let clock = Clock::new(origin);
+        #[cfg(feature = "zstd")]
+        let transform = match builder.zstd {
+            Some((level, dictionary)) => {
+                let dictionary_path = match &builder.output {
+                    Some(Output::Path(path)) => {
+                        let mut path = path.clone().into_os_string();
+                        path.push(".zdict");
+                        Some(path.into())
+                    }
+                    _ => None,
                 };
+                Some(compress::zstd_transform(level, dictionary, dictionary_path))
+            }
+            None => builder.transform,
+        };
+        #[cfg(not(feature = "zstd"))]
+        let transform = builder.transform;
+        let config = WriterConfig {
+            output: builder.output,
+            counters: counters.clone(),
+            process_info: builder.process_info,
+            container_info: if builder.include_container_info {
+                container::ContainerInfo::current()
+            } else {
+                None
+            },
+            incremental_state_interval: builder.incremental_state_interval,
+            clock,
+            limits: builder.limits,
+            max_file_size: builder.max_file_size,
+            on_rotate: builder.on_rotate,
+            transform,
+            slowest_spans: builder.slowest_spans,
+            current_path: current_path.clone(),
+            self_trace: builder.trace_writer,
+            flush_hint_threshold: builder.flush_hint_threshold,
+            stop_after_bytes: builder.stop_after_bytes,
+            stop_after: builder.stop_after,
+            write_stall_threshold: builder.write_stall_threshold,
+            snapshot: builder
+                .snapshot
+                .as_ref()
+                .map(|(_, before, after)| (*before, *after)),
+            strict: builder.strict,
+            defers_slices: builder.min_span_duration.is_some() || builder.slowest_spans.is_some(),
+            sync_policy: builder.sync_policy,
+            span_summary: builder.span_summary,
+            source_locations: builder.source_locations,
+            perf_counter: builder.perf_counter,
+            intern_seed: builder.intern_seed,
+            export_intern_table: builder.export_intern_table,
+            append: builder.append,
+            args_budget: builder.args_budget,
+            spill: builder.spill,
+            ring_buffer: builder.ring_buffer,
+            on_error: builder.on_error.clone(),
+            shutdown_report: builder.shutdown_report,
+        };
+        #[cfg(feature = "etw")]
+        let worker = match builder.etw {
+            Some(provider) => std::thread::spawn(move || etw::etw_thread(rx, provider)),
+            None => std::thread::spawn(move || writer_thread(rx, config)),
+        };
+        #[cfg(not(feature = "etw"))]
+        let worker = std::thread::spawn(move || writer_thread(rx, config));
+
+        (
+            PerfettoLayer {
+                sender: tx.clone(),
+                alive: Arc::new(()),
+                clock,
+                counters: counters.clone(),
+                regions: regions::RegionSink {
+                    sender: tx.clone(),
+                    clock,
+                    counters: counters.clone(),
+                },
+                controller: TraceController {
+                    stats: StatsHandle {
+                        counters: counters.clone(),
+                        queue: tx.clone(),
+                    },
+                },
+                start_trigger: builder
+                    .start_trigger
+                    .map(|trigger| StartTrigger::new(trigger, &clock)),
+                control: TracingController::new(builder.enabled),
+                max_span_depth: builder.max_span_depth,
+                snapshot_trigger: builder.snapshot.map(|(trigger, _, _)| trigger),
+                next_thread_id: AtomicU32::new(0),
+                free_thread_ids: if builder.recycle_thread_ids {
+                    Some(Arc::new(Mutex::new(Vec::new())))
+                } else {
+                    None
+                },
+                thread_pools: builder
+                    .thread_pools
+                    .into_iter()
+                    .map(|prefix| (prefix, Arc::new(Mutex::new(Vec::new()))))
+                    .collect(),
+                max_threads,
+                other_threads_named: AtomicBool::new(false),
+                thread_ids: builder.thread_ids,
+                task_ids: builder.task_ids,
+                perf_counter: builder.perf_counter,
+                provided_thread_ids: Mutex::new(HashMap::new()),
+                include_args: builder.include_args,
+                include_thread_info: builder.include_thread_info,
+                os_thread_ids: builder.os_thread_ids,
+                inherit_tracks: builder.inherit_tracks,
+                inherited_fields: builder.inherited_fields,
+                events_on_span_tracks: builder.events_on_span_tracks,
+                async_tracks: builder.async_tracks,
+                root_offsets: builder.root_offsets,
+                parent_span_context: builder.parent_span_context,
+                message_policy: builder.message_policy,
+                event_naming: builder.event_naming,
+                record_kinds: builder.record_kinds,
+                target_message_policies: builder.target_message_policies,
+                span_budgets: builder.span_budgets,
+                unit_hints: builder.unit_hints,
+                counter_scopes: builder.counter_scopes,
+                counter_fields: builder.counter_fields,
+                router: routing::Router::new(builder.rules),
+                min_span_duration: builder.min_span_duration,
+                slowest_spans: builder.slowest_spans,
+                trace_marker: if builder.trace_marker {
+                    trace_marker::TraceMarker::open(&builder.on_error)
+                } else {
+                    None
+                },
+                syslog: if builder.syslog_errors {
+                    syslog::Syslog::connect(&builder.on_error)
+                } else {
+                    None
+                },
+                current_path,
+                #[cfg(feature = "android-log")]
+                android_log_packets: builder.android_log_packets,
+                #[cfg(feature = "opentelemetry")]
+                opentelemetry_context: builder.opentelemetry_context,
+                #[cfg(feature = "backtrace")]
+                event_backtraces: builder.event_backtraces,
+                #[cfg(feature = "opentelemetry")]
+                span_export: builder.span_export,
+                _marker: PhantomData,
+            },
+            FlushGuard {
+                handle: Some(worker),
+                on_error: builder.on_error,
+                controller: TraceController {
+                    stats: StatsHandle {
+                        counters,
+                        queue: tx,
+                    },
+                },
+            },
+        )
+    }
+
+    fn send_message(&self, msg: Message) {
+        queue_message(&self.sender, &self.counters, msg);
+    }
+
+    /// Get the track named `name` for the states of a component, e.g. a
+    /// connection going from "idle" to "connecting" to "active".
+    ///
+    /// Each state is shown as a slice that lasts until the next state is
+    /// set. Create the tracks before adding the layer to a subscriber, and
+    /// hand them to the components; getting the same name twice gives the
+    /// same track.
+    pub fn state_track(&self, name: &str) -> StateTrack {
+        StateTrack {
+            name: name.into(),
+            sender: self.sender.clone(),
+            clock: self.clock,
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Get a handle to control the trace, the same one
+    /// [`FlushGuard::controller`] gives.
+    pub fn controller(&self) -> TraceController {
+        self.controller.clone()
+    }
+
+    /// Get a handle to switch recording on and off at runtime, e.g. to
+    /// ship the layer in production and only record while an operator
+    /// looks into a problem. The trace file stays open meanwhile.
+    pub fn control_handle(&self) -> TracingController {
+        self.control.clone()
+    }
+
+    /// The layer of `dispatch`, if it has one added to a subscriber of type
+    /// `S`, e.g. to get a [`state_track`](Self::state_track) in code that
+    /// only has the dispatcher.
+    ///
+    /// `S` has to be the exact type of the subscriber below the layer, such
+    /// as `Layered<EnvFilter, Registry>`. Where that is hard to name,
+    /// [`TraceController::from_dispatch`] finds the trace regardless.
+    pub fn from_dispatch(dispatch: &tracing::Dispatch) -> Option<&Self>
+    where
+        S: 'static,
+    {
+        dispatch.downcast_ref::<Self>()
+    }
+
+    fn get_timestamp(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Id of the current thread, and if it is new, the name of its track
+    /// and whether the id is provided, see [`ThreadState::provided`].
+    fn get_thread_id(&self) -> (ThreadId, Option<(String, bool)>) {
+        THREAD_STATES.with(|states| {
+            let layer = Arc::as_ptr(&self.alive);
+            let thread_id = states
+                .borrow()
+                .iter()
+                .find(|state| state.layer.as_ptr() == layer)
+                .map(|state| state.id);
+            if let Some(thread_id) = thread_id {
+                return (thread_id, None);
+            }
+            let (state, thread_name) = self.register_thread();
+            let id = state.id;
+            let new_thread = thread_name.map(|name| (name, state.provided));
+            let mut states = states.borrow_mut();
+            // Forget the layers that are gone, e.g. scoped subscribers of
+            // earlier tests on this thread.
+            states.retain(|state| state.layer.strong_count() > 0);
+            states.push(state);
+            (id, new_thread)
+        })
+    }
+
+    /// The state of a thread that has not recorded anything yet, and the
+    /// name of its track if it needs one.
+    fn register_thread(&self) -> (ThreadState, Option<String>) {
+        let state = |id, free_thread_ids, shared, provided| ThreadState {
+            id,
+            layer: Arc::downgrade(&self.alive),
+            sender: self.sender.clone(),
+            counters: self.counters.clone(),
+            clock: self.clock,
+            free_thread_ids,
+            shared,
+            provided,
+            depth: 0,
+        };
+        if let Some(provided) = self.provided_thread_id() {
+            let mut ids = self.provided_thread_ids.lock().unwrap();
+            if let Some(&id) = ids.get(&provided) {
+                return (state(id, None, true, true), None);
+            }
+            if let Some(id) = self.allocate_thread_id() {
+                ids.insert(provided, id);
+                return (
+                    state(id, None, true, true),
+                    Some(format!("worker {}", provided)),
+                );
+            }
+            let (id, thread_name) = self.other_threads();
+            return (state(id, None, true, true), thread_name);
+        }
+        let current = std::thread::current();
+        let pool = current.name().and_then(|name| {
+            self.thread_pools
+                .iter()
+                .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+        });
+        let mut free_thread_ids = match pool {
+            Some((_, ids)) => Some(ids.clone()),
+            None => self.free_thread_ids.clone(),
+        };
+        let recycled = free_thread_ids
+            .as_ref()
+            .and_then(|ids| ids.lock().unwrap().pop());
+        let mut shared = false;
+        let (id, thread_name) = match (recycled, pool) {
+            // The track of a pool keeps its name.
+            (Some(id), Some(_)) => (id, None),
+            (Some(id), None) => (id, Some(thread_track_name(current.name(), id))),
+            (None, _) => match self.allocate_thread_id() {
+                Some(id) => {
+                    let name = match pool {
+                        Some((prefix, _)) => format!("{} {}", prefix, id),
+                        None => thread_track_name(current.name(), id),
+                    };
+                    (id, Some(name))
+                }
+                None => {
+                    shared = true;
+                    free_thread_ids = None;
+                    self.other_threads()
+                }
+            },
+        };
+        (state(id, free_thread_ids, shared, false), thread_name)
+    }
+
+    /// The id shared by the threads over the limit, and the name of its
+    /// track if it needs one.
+    fn other_threads(&self) -> (ThreadId, Option<String>) {
+        let named = self.other_threads_named.swap(true, Ordering::SeqCst);
+        let id = self.max_threads.unwrap();
+        (id, (!named).then(|| OTHER_THREADS_NAME.to_string()))
+    }
+
+    /// The id of the current thread given by
+    /// [`PerfettoLayerBuilder::thread_ids`], if any.
+    fn provided_thread_id(&self) -> Option<u32> {
+        (self.thread_ids.as_ref()?)()
+    }
+
+    /// The OS thread that the track of `id` belongs to, if it is to be
+    /// described by it, see [`PerfettoLayerBuilder::os_thread_ids`].
+    fn os_thread(
+        &self,
+        id: ThreadId,
+        track_name: &str,
+        provided: bool,
+    ) -> Option<ThreadDescriptor> {
+        if !self.os_thread_ids || self.is_shared(id) || provided {
+            return None;
+        }
+        let current = std::thread::current();
+        let in_pool = current.name().is_some_and(|name| {
+            self.thread_pools
+                .iter()
+                .any(|(prefix, _)| name.starts_with(prefix.as_str()))
+        });
+        if in_pool {
+            return None;
+        }
+        Some(ThreadDescriptor {
+            pid: std::process::id(),
+            tid: sched::os_thread_id()?,
+            thread_name: current.name().unwrap_or(track_name).to_string(),
+        })
+    }
+
+    /// A fresh thread id, or `None` if the limit on threads is reached.
+    fn allocate_thread_id(&self) -> Option<ThreadId> {
+        let max_threads = self.max_threads.unwrap_or(ThreadId::MAX);
+        self.next_thread_id
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |id| {
+                (id < max_threads).then_some(id + 1)
+            })
+            .ok()
+    }
+
+    /// Whether the start trigger has fired, if there is one.
+    fn started(&self) -> bool {
+        self.start_trigger
+            .as_ref()
+            .is_none_or(|trigger| trigger.started(&self.clock))
+    }
+
+    /// The track of the task being polled, see
+    /// [`PerfettoLayerBuilder::task_id_provider`].
+    fn current_task_track(&self) -> Option<Arc<str>> {
+        let task_id = self.task_ids.as_ref()?()?;
+        Some(format!("task {}", task_id).into())
+    }
+
+    fn read_perf_counter(&self) -> Option<u64> {
+        perf_counter::read(self.perf_counter?)
+    }
+
+    /// Whether slices are only sent once the span is exited.
+    fn defers_slices(&self) -> bool {
+        self.min_span_duration.is_some() || self.slowest_spans.is_some()
+    }
+
+    /// Whether the thread id is the one shared by threads over the limit.
+    fn is_shared(&self, thread_id: ThreadId) -> bool {
+        self.max_threads == Some(thread_id)
+    }
+
+    /// Id of the current thread, registering the thread with the writer if
+    /// this is the first time we see it.
+    fn current_thread_id(&self) -> ThreadId {
+        let (thread_id, new_thread) = self.get_thread_id();
+        if let Some((name, provided)) = new_thread {
+            self.init_thread(thread_id, name, provided);
+        }
+        thread_id
+    }
+
+    /// Count the thread entering (or exiting) a span, and return the depth
+    /// of that span, 1 for a span on no other span.
+    fn span_depth(&self, enter: bool) -> u32 {
+        self.current_thread_id();
+        THREAD_STATES.with(|states| {
+            let layer = Arc::as_ptr(&self.alive);
+            let mut states = states.borrow_mut();
+            let Some(state) = states
+                .iter_mut()
+                .find(|state| state.layer.as_ptr() == layer)
+            else {
+                return 0;
+            };
+            if enter {
+                state.depth += 1;
+                state.depth
+            } else {
+                let depth = state.depth;
+                state.depth = depth.saturating_sub(1);
+                depth
+            }
+        })
+    }
+
+    fn message_policy(&self, target: &str) -> MessagePolicy {
+        self.target_message_policies
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.message_policy, |(_, policy)| *policy)
+    }
+
+    /// Whether the field of events with the given target is recorded as a
+    /// counter.
+    fn is_counter_field(&self, target: &str, field: &str) -> bool {
+        self.unit_hints.contains_key(field)
+            || self
+                .counter_fields
+                .iter()
+                .any(|(prefix, name)| name == field && target.starts_with(prefix.as_str()))
+    }
+
+    /// What the rules say about a callsite, if there are any.
+    fn route(&self, metadata: &'static tracing::Metadata<'static>) -> Option<Arc<routing::Route>> {
+        self.router.as_ref().map(|router| router.route(metadata))
+    }
+
+    fn is_inherited(&self, annotation: &DebugAnnotation) -> bool {
+        match &annotation.name {
+            packet::IString::Plain(name) => self.inherited_fields.iter().any(|field| field == name),
+            packet::IString::Interned(_) => false,
+        }
+    }
+
+    fn init_thread(&self, id: ThreadId, name: String, provided: bool) {
+        let os_thread = self.os_thread(id, &name, provided);
+        self.send_message(Message::NewThread(id, name, os_thread));
+        if self.include_thread_info && !self.is_shared(id) {
+            if let Some(info) = sched::SchedInfo::current() {
+                self.send_message(Message::Event {
+                    timestamp: self.get_timestamp(),
+                    name: Cow::Borrowed("thread info"),
+                    args: Args::new(info.debug_annotations()),
+                    thread_id: id,
+                    track: None,
+                    flow_ids: Vec::new(),
+                    terminating_flow_ids: Vec::new(),
+                    location: None,
+                    category: None,
+                });
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for PerfettoLayer<S>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if self.counters.stopped()
+            || !self.control.is_enabled()
+            || !self.record_kinds.contains(Kinds::SPANS)
+        {
+            return;
+        }
+        let route = self.route(attrs.metadata());
+        if route.as_ref().is_some_and(|route| route.discard) {
+            return;
+        }
+        let span = ctx.span(id).unwrap();
+        if self.include_args || !self.inherited_fields.is_empty() {
+            // Field-less callsites are common, and cost the same as without
+            // arguments unless there are fields to inherit.
+            let mut infos = Vec::new();
+            if !attrs.metadata().fields().is_empty() {
+                let mut v = DebugAnnotationVisitor::new(MessagePolicy::Annotation);
+                attrs.record(&mut v);
+                infos = v.infos;
+                if !self.include_args {
+                    infos.retain(|ann| self.is_inherited(ann));
+                }
+            }
+            let parent = if self.inherited_fields.is_empty() {
+                None
+            } else {
+                span.parent()
+            };
+            if let Some(parent) = parent {
+                if let Some(parent_info) = parent.extensions().get::<DebugInfoExt>() {
+                    for ann in parent_info.info.iter() {
+                        let overridden = infos.iter().any(|own| own.name == ann.name);
+                        if self.is_inherited(ann) && !overridden {
+                            infos.push(ann.clone());
+                        }
+                    }
+                }
+            }
+            // Most spans have no fields at all.
+            if let Some(info) = Args::new(infos) {
+                span.extensions_mut().insert(DebugInfoExt { info });
+            }
+        }
+
+        let mut track = None;
+        if attrs
+            .metadata()
+            .fields()
+            .iter()
+            .any(|field| is_control_field(field.name()))
+        {
+            let mut v = ControlFieldVisitor::default();
+            attrs.record(&mut v);
+            track = v.track;
+            if let Some(name) = v.name {
+                span.extensions_mut().insert(SliceNameExt { name });
+            }
+            if v.flow.is_some() || v.terminating_flow.is_some() {
+                span.extensions_mut().insert(FlowExt {
+                    flow: v.flow,
+                    terminating_flow: v.terminating_flow,
+                });
+            }
+        }
+        if track.is_none() {
+            track = route.and_then(|route| route.track.clone());
+        }
+        #[cfg(feature = "tokio")]
+        if track.is_none() {
+            if let Some(task_track) = tokio_tasks::task_track(attrs) {
+                let msg = Message::Enter {
+                    timestamp: self.get_timestamp(),
+                    name: Cow::Borrowed(tokio_tasks::TASK_ALIVE_NAME),
+                    args: None,
+                    thread_id: self.current_thread_id(),
+                    track: Some(task_track.clone()),
+                    flow_ids: Vec::new(),
+                    terminating_flow_ids: Vec::new(),
+                    location: None,
+                    category: None,
+                    perf_count: None,
+                };
+                // The task's slice only ends if it began.
+                if self.started() {
+                    self.send_message(msg);
+                    span.extensions_mut().insert(tokio_tasks::TaskExt);
+                }
+                track = Some(task_track);
+            }
+        }
+        if track.is_none() && self.inherit_tracks {
+            track = span.parent().and_then(|parent| {
+                parent
+                    .extensions()
+                    .get::<CustomTrackExt>()
+                    .map(|ext| ext.name.clone())
+            });
+        }
+        if track.is_none() && self.async_tracks {
+            track = match span.parent() {
+                Some(parent) => parent
+                    .extensions()
+                    .get::<CustomTrackExt>()
+                    .map(|ext| ext.name.clone()),
+                None => {
+                    span.extensions_mut().insert(AsyncRootExt);
+                    let name = format!("{} (span {})", attrs.metadata().name(), id.into_u64());
+                    Some(name.into())
+                }
+            };
+        }
+        if let Some(name) = track {
+            span.extensions_mut().insert(CustomTrackExt { name });
+        }
+
+        if let Some(budget) = self.span_budgets.get(attrs.metadata().name()) {
+            span.extensions_mut().insert(BudgetExt {
+                budget: budget.as_nanos() as u64,
+                entered_at: None,
+            });
+        }
+
+        #[cfg(feature = "opentelemetry")]
+        if self.span_export.is_some() {
+            let mut v = DebugAnnotationVisitor::new(MessagePolicy::Annotation);
+            attrs.record(&mut v);
+            span.extensions_mut().insert(otel::ExportExt::new(v.infos));
+        }
+    }
+
+    fn on_follows_from(&self, id: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
+        if !self.async_tracks || !self.control.is_enabled() {
+            return;
+        }
+        let (Some(span), Some(follows)) = (ctx.span(id), ctx.span(follows)) else {
+            return;
+        };
+        let Some(name) = follows
+            .extensions()
+            .get::<CustomTrackExt>()
+            .map(|ext| ext.name.clone())
+        else {
+            return;
+        };
+        // Only a span that got a track of its own moves, not one whose
+        // track was given or inherited.
+        let mut extensions = span.extensions_mut();
+        if extensions.remove::<AsyncRootExt>().is_some() {
+            extensions.replace(CustomTrackExt { name });
+        }
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == TypeId::of::<regions::RegionSink>() {
+            Some(&self.regions as *const regions::RegionSink as *const ())
+        } else if id == TypeId::of::<TraceController>() {
+            Some(&self.controller as *const TraceController as *const ())
+        } else {
+            None
+        }
+    }
+
+    #[cfg(any(feature = "tokio", feature = "opentelemetry"))]
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if self.counters.stopped() || !self.record_kinds.contains(Kinds::SPANS) {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+
+        #[cfg(feature = "opentelemetry")]
+        if let Some(export) = &self.span_export {
+            if let Some(ext) = extensions.get::<otel::ExportExt>() {
+                export.span_closed(span.name(), ext);
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        if extensions.get::<tokio_tasks::TaskExt>().is_some() {
+            let msg = Message::Exit {
+                timestamp: self.get_timestamp(),
+                name: Cow::Borrowed(tokio_tasks::TASK_ALIVE_NAME),
+                thread_id: self.current_thread_id(),
+                track: extensions
+                    .get::<CustomTrackExt>()
+                    .map(|ext| ext.name.clone()),
+                perf_count: None,
+                args: None,
+                unwound: false,
+            };
+            self.send_message(msg);
+        }
+    }
+
+    // Fields declared as `field::Empty` are left out until they are
+    // recorded, and then go on the end of the open slice and the slices of
+    // later entries.
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if !self.control.is_enabled() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if self.include_args || !self.inherited_fields.is_empty() {
+            let mut v = DebugAnnotationVisitor::new(MessagePolicy::Annotation);
+            values.record(&mut v);
+            let mut recorded = v.infos;
+            if !self.include_args {
+                recorded.retain(|ann| self.is_inherited(ann));
+            }
+            if !recorded.is_empty() {
+                let mut extensions = span.extensions_mut();
+                // The slice of the current entry has already begun, so they
+                // go on its end as well.
+                match extensions.get_mut::<RecordedArgsExt>() {
+                    Some(ext) => merge_args(&mut ext.args, recorded.clone()),
+                    None => extensions.insert(RecordedArgsExt {
+                        args: recorded.clone(),
+                    }),
+                }
+                let mut infos = extensions
+                    .get_mut::<DebugInfoExt>()
+                    .map_or_else(Vec::new, |ext| ext.info.to_vec());
+                merge_args(&mut infos, recorded);
+                if let Some(info) = Args::new(infos) {
+                    extensions.replace(DebugInfoExt { info });
+                }
+            }
+        }
+
+        // Names and flows are often only known once the span's work is
+        // under way or handed off.
+        let mut v = ControlFieldVisitor::default();
+        values.record(&mut v);
+        if let Some(name) = v.name {
+            span.extensions_mut().replace(SliceNameExt { name });
+        }
+        if v.flow.is_none() && v.terminating_flow.is_none() {
+            return;
+        }
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<FlowExt>() {
+            Some(ext) => {
+                ext.flow = v.flow.or(ext.flow);
+                ext.terminating_flow = v.terminating_flow.or(ext.terminating_flow);
+            }
+            None => extensions.insert(FlowExt {
+                flow: v.flow,
+                terminating_flow: v.terminating_flow,
+            }),
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if self.counters.stopped() || !self.record_kinds.contains(Kinds::SPANS) {
+            return;
+        }
+        let route = ctx.metadata(id).and_then(|metadata| self.route(metadata));
+        if route.as_ref().is_some_and(|route| route.discard) {
+            return;
+        }
+        let span = ctx.span(id);
+        let location = span.as_ref().map(|s| s.metadata());
+        let is_root = span.as_ref().is_some_and(|s| s.parent().is_none());
+
+        let thread_id = self.current_thread_id();
+
+        if !self.started() || !self.control.is_enabled() {
+            if let Some(span) = &span {
+                let mut extensions = span.extensions_mut();
+                if extensions.get_mut::<SkippedEntryExt>().is_none() {
+                    extensions.insert(SkippedEntryExt::default());
+                }
+                let ext = extensions.get_mut::<SkippedEntryExt>().unwrap();
+                ext.threads.push(thread_id);
+            }
+            return;
+        }
+
+        if let Some(max_depth) = self.max_span_depth {
+            if self.span_depth(true) > max_depth {
+                return;
+            }
+        }
+
+        let name = span.as_ref().map_or(Cow::Borrowed(""), slice_name);
+        if let Some(marker) = &self.trace_marker {
+            marker.begin(&name);
+        }
+
+        let timestamp = self.get_timestamp();
+        let mut root_offset = None;
+        #[allow(unused_mut)]
+        let (mut arg_info, track, flows) = if let Some(span_ref) = span {
+            if let Some(ext) = span_ref.extensions_mut().get_mut::<BudgetExt>() {
+                ext.entered_at = Some(timestamp);
+            }
+            if self.root_offsets {
+                if let Some(root) = span_ref.scope().from_root().next() {
+                    if root.id() == span_ref.id() {
+                        let mut extensions = root.extensions_mut();
+                        if extensions.get_mut::<RootEnteredExt>().is_none() {
+                            extensions.insert(RootEnteredExt(timestamp));
+                        }
+                    } else if let Some(ext) = root.extensions().get::<RootEnteredExt>() {
+                        root_offset = Some(timestamp.saturating_sub(ext.0));
+                    }
+                }
+            }
+            // The arguments of this slice include everything recorded so far.
+            span_ref.extensions_mut().remove::<RecordedArgsExt>();
+            let extensions = span_ref.extensions();
+            (
+                extensions
+                    .get::<DebugInfoExt>()
+                    .map(|info| info.info.clone()),
+                extensions
+                    .get::<CustomTrackExt>()
+                    .map(|ext| ext.name.clone()),
+                extensions.get::<FlowExt>().copied().unwrap_or_default(),
+            )
+        } else {
+            (None, None, FlowExt::default())
+        };
+        let track = track.or_else(|| self.current_task_track());
+        if let Some(offset) = root_offset {
+            let mut args = arg_info.as_deref().map(<[_]>::to_vec).unwrap_or_default();
+            args.push(DebugAnnotation::new("root_offset_ns", offset));
+            arg_info = Args::new(args);
+        }
+
+        #[allow(unused_mut)]
+        let mut flow_ids: Vec<u64> = flows.flow.into_iter().collect();
+        if let Some(context) = self.parent_span_context.filter(|_| is_root) {
+            flow_ids.push(context.flow_id);
+        }
+        #[cfg(feature = "opentelemetry")]
+        if self.opentelemetry_context {
+            if let Some(ids) = otel::OtelIds::current() {
+                let mut args = arg_info.as_deref().map(<[_]>::to_vec).unwrap_or_default();
+                args.extend(ids.debug_annotations());
+                arg_info = Args::new(args);
+                flow_ids.push(ids.flow_id());
+            }
+        }
+
+        // Overlapping spans of different threads would mess up the shared
+        // track.
+        if track.is_none() && self.is_shared(thread_id) {
+            return;
+        }
+
+        let msg = Message::Enter {
+            timestamp,
+            name,
+            args: arg_info,
+            thread_id,
+            track,
+            flow_ids,
+            terminating_flow_ids: flows.terminating_flow.into_iter().collect(),
+            location,
+            category: route.and_then(|route| route.category.clone()),
+            perf_count: self.read_perf_counter(),
+        };
+        if self.defers_slices() {
+            if let Some(span) = ctx.span(id) {
+                let mut extensions = span.extensions_mut();
+                if extensions.get_mut::<DeferredEnterExt>().is_none() {
+                    extensions.insert(DeferredEnterExt::default());
+                }
+                let ext = extensions.get_mut::<DeferredEnterExt>().unwrap();
+                ext.entries.push((thread_id, timestamp, msg));
+                return;
+            }
+        }
+        self.send_message(msg);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if self.counters.stopped() || !self.record_kinds.contains(Kinds::SPANS) {
+            return;
+        }
+        if ctx
+            .metadata(id)
+            .and_then(|metadata| self.route(metadata))
+            .is_some_and(|route| route.discard)
+        {
+            return;
+        }
+        if self.start_trigger.is_some() || self.control.toggled() {
+            let thread_id = self.current_thread_id();
+            let skipped_entry = ctx.span(id).is_some_and(|span| {
+                let mut extensions = span.extensions_mut();
+                let Some(ext) = extensions.get_mut::<SkippedEntryExt>() else {
+                    return false;
+                };
+                match ext.threads.iter().rposition(|entry| *entry == thread_id) {
+                    Some(index) => {
+                        ext.threads.remove(index);
+                        true
+                    }
+                    None => false,
+                }
+            });
+            if skipped_entry {
+                return;
+            }
+        }
+        if let Some(max_depth) = self.max_span_depth {
+            if self.span_depth(false) > max_depth {
+                return;
+            }
+        }
+        if let Some(marker) = &self.trace_marker {
+            marker.end();
+        }
+        let span = ctx.span(id);
+        let name = span.as_ref().map_or(Cow::Borrowed(""), slice_name);
+        let timestamp = self.get_timestamp();
+        let perf_count = self.read_perf_counter();
+        // The guard of an instrumented function is dropped while a panic
+        // unwinds it.
+        let unwound = std::thread::panicking();
+        let mut overage = None;
+        let mut recorded = None;
+        let track = span.and_then(|s| {
+            recorded = s
+                .extensions_mut()
+                .remove::<RecordedArgsExt>()
+                .and_then(|ext| Args::new(ext.args));
+            if let Some(ext) = s.extensions_mut().get_mut::<BudgetExt>() {
+                if let Some(entered_at) = ext.entered_at.take() {
+                    let elapsed = timestamp.saturating_sub(entered_at);
+                    if elapsed > ext.budget {
+                        overage = Some((ext.budget, elapsed - ext.budget));
+                    }
+                }
+            }
+            s.extensions()
+                .get::<CustomTrackExt>()
+                .map(|ext| ext.name.clone())
+        });
+        let track = track.or_else(|| self.current_task_track());
+
+        let thread_id = self.current_thread_id();
+
+        let mut record = track.is_some() || !self.is_shared(thread_id);
+        if self.defers_slices() {
+            record = false;
+            let entry = ctx.span(id).and_then(|span| {
+                let entry = span
+                    .extensions_mut()
+                    .get_mut::<DeferredEnterExt>()
+                    .and_then(|ext| ext.take(thread_id));
+                entry.map(|(entered_at, enter)| (span.metadata().callsite(), entered_at, enter))
+            });
+            let min_duration = self
+                .min_span_duration
+                .map_or(0, |min_duration| min_duration.as_nanos() as u64);
+            match entry {
+                Some((callsite, entered_at, enter))
+                    if timestamp.saturating_sub(entered_at) >= min_duration =>
+                {
+                    if self.slowest_spans.is_some() {
+                        self.send_message(Message::Slice {
+                            callsite,
+                            enter: Box::new(enter),
+                            end: timestamp,
+                            end_perf_count: perf_count,
+                        });
+                    } else {
+                        self.send_message(enter);
+                        record = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if record {
+            let msg = Message::Exit {
+                timestamp,
+                name: name.clone(),
+                thread_id,
+                track: track.clone(),
+                perf_count,
+                args: recorded,
+                unwound,
+            };
+            self.send_message(msg);
+        }
+
+        if unwound {
+            self.send_message(Message::Event {
+                timestamp,
+                name: Cow::Borrowed(UNWOUND_NAME),
+                args: Args::new(vec![DebugAnnotation::new("span", name.as_ref())]),
+                thread_id,
+                track: track.clone(),
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                location: None,
+                category: None,
+            });
+        }
+
+        if let Some((budget, overage)) = overage {
+            let args = vec![
+                DebugAnnotation::new("span", name.into_owned()),
+                DebugAnnotation::new("budget_ns", budget),
+                DebugAnnotation::new("overage_ns", overage),
+            ];
+            self.send_message(Message::Event {
+                timestamp,
+                name: Cow::Borrowed(BUDGET_EXCEEDED_NAME),
+                args: Args::new(args),
+                thread_id,
+                track,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                location: None,
+                category: None,
+            });
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        if self.counters.stopped() || !self.control.is_enabled() {
+            return;
+        }
+        if let Some(trigger) = &self.start_trigger {
+            trigger.on_event(event.metadata().name());
+            if !self.started() {
+                return;
+            }
+        }
+        if !self.record_kinds.contains(Kinds::EVENTS) {
+            return;
+        }
+        let route = self.route(event.metadata());
+        if route.as_ref().is_some_and(|route| route.discard) {
+            return;
+        }
+        let mut on_span_track = self.events_on_span_tracks || self.async_tracks;
+        let mut flows = FlowExt::default();
+        let mut custom_name = None;
+        if event
+            .metadata()
+            .fields()
+            .iter()
+            .any(|field| is_control_field(field.name()))
+        {
+            let mut v = ControlFieldVisitor::default();
+            event.record(&mut v);
+            match v.instant_scope.as_deref() {
+                Some("track") => on_span_track = true,
+                Some("thread") => on_span_track = false,
+                _ => {}
+            }
+            flows = FlowExt {
+                flow: v.flow,
+                terminating_flow: v.terminating_flow,
+            };
+            custom_name = v.name;
+        }
+        let track = if on_span_track {
+            ctx.event_span(event).and_then(|span| {
+                span.extensions()
+                    .get::<CustomTrackExt>()
+                    .map(|ext| ext.name.clone())
+            })
+        } else {
+            None
+        };
+        let track = track.or_else(|| route.as_ref().and_then(|route| route.track.clone()));
+
+        let thread_id = self.current_thread_id();
+
+        let message_policy = self.message_policy(event.metadata().target());
+        let mut name = self.event_naming.name(event.metadata());
+        let has_fields = !event.metadata().fields().is_empty();
+        #[allow(unused_mut)]
+        let mut arg_info =
+            if has_fields && (self.include_args || message_policy == MessagePolicy::Name) {
+                let mut v = DebugAnnotationVisitor::new(message_policy);
+                event.record(&mut v);
+                if let Some(message) = v.message {
+                    name = Cow::Owned(message);
+                }
+                if self.include_args {
+                    Args::new(v.infos)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+        #[cfg(feature = "backtrace")]
+        if let Some((level, frames)) = self.event_backtraces {
+            if event.metadata().level() <= &level {
+                let mut args = arg_info.as_deref().map(<[_]>::to_vec).unwrap_or_default();
+                args.push(DebugAnnotation::new(
+                    "backtrace",
+                    packet::DebugValue::Backtrace(backtraces::EventBacktrace::capture(frames)),
+                ));
+                arg_info = Args::new(args);
+            }
+        }
+        if let Some(custom_name) = custom_name {
+            name = Cow::Owned(custom_name);
+        }
+
+        let timestamp = self.get_timestamp();
+        let msg = Message::Event {
+            timestamp,
+            name,
+            args: arg_info,
+            thread_id,
+            track,
+            flow_ids: flows.flow.into_iter().collect(),
+            terminating_flow_ids: flows.terminating_flow.into_iter().collect(),
+            location: Some(event.metadata()),
+            category: route.as_ref().and_then(|route| route.category.clone()),
+        };
+        self.send_message(msg);
+        if let Some(trigger) = &self.snapshot_trigger {
+            if trigger.matches(event.metadata()) {
+                self.send_message(Message::Snapshot { timestamp });
+            }
+        }
+
+        let routed_counters = route.as_ref().map_or(&[][..], |route| &route.counters);
+        if !self.unit_hints.is_empty()
+            || !self.counter_fields.is_empty()
+            || !routed_counters.is_empty()
+        {
+            let target = event.metadata().target();
+            let fields: Vec<_> = event
+                .metadata()
+                .fields()
+                .iter()
+                .map(|field| field.name())
+                .filter(|field| {
+                    self.is_counter_field(target, field) || routed_counters.contains(field)
+                })
+                .collect();
+            if !fields.is_empty() {
+                let mut v = CounterVisitor::new(fields);
+                event.record(&mut v);
+                if !v.samples.is_empty() {
+                    let samples = v
+                        .samples
+                        .into_iter()
+                        .map(|(field, value)| {
+                            let unit = self.unit_hints.get(field).copied();
+                            let scope = self.counter_scopes.get(field).copied().unwrap_or_default();
+                            (field, unit, scope, value)
+                        })
+                        .collect();
+                    self.send_message(Message::Counters {
+                        timestamp,
+                        samples,
+                        thread_id,
+                    });
+                }
+            }
+        }
+
+        if let Some(syslog) = &self.syslog {
+            if *event.metadata().level() == tracing::Level::ERROR {
+                let path = self.current_path.lock().unwrap().clone();
+                syslog.error(event, path.as_deref());
+            }
+        }
+
+        #[cfg(feature = "android-log")]
+        if android_log::is_mirrored(event.metadata().level()) {
+            let prio = android_log::priority(event.metadata().level());
+            let tag = event.metadata().target();
+            let line = android_log::format_event(event);
+            android_log::write_logcat(prio, tag, &line);
+            if self.android_log_packets {
+                self.send_message(Message::AndroidLog {
+                    timestamp,
+                    prio,
+                    tag: tag.to_string(),
+                    message: line,
+                    thread_id,
+                });
+            }
+        }
+    }
+}
+
+struct DebugInfoExt {
+    info: Args,
+}
+
+/// Fields recorded since the span was last entered.
+struct RecordedArgsExt {
+    args: Vec<DebugAnnotation>,
+}
+
+/// Add `recorded` to `args`, replacing earlier values of the same fields.
+fn merge_args(args: &mut Vec<DebugAnnotation>, recorded: Vec<DebugAnnotation>) {
+    for ann in recorded {
+        match args.iter_mut().find(|own| own.name == ann.name) {
+            Some(own) => *own = ann,
+            None => args.push(ann),
+        }
+    }
+}
+
+/// The arguments of a slice or instant.
+///
+/// Most spans have just a field or two, which are kept inline rather than
+/// in a vector of their own; only longer lists are shared between the
+/// messages of a span's entries.
+#[derive(Debug, Clone)]
+pub enum Args {
+    One(DebugAnnotation),
+    Two([DebugAnnotation; 2]),
+    Many(Arc<Vec<DebugAnnotation>>),
+}
+
+impl Args {
+    /// `None` if there are no arguments.
+    fn new(mut args: Vec<DebugAnnotation>) -> Option<Args> {
+        match args.len() {
+            0 => None,
+            1 => args.pop().map(Args::One),
+            2 => {
+                let second = args.pop()?;
+                let first = args.pop()?;
+                Some(Args::Two([first, second]))
+            }
+            _ => Some(Args::Many(Arc::new(args))),
+        }
+    }
+}
+
+impl std::ops::Deref for Args {
+    type Target = [DebugAnnotation];
+
+    fn deref(&self) -> &[DebugAnnotation] {
+        match self {
+            Args::One(arg) => std::slice::from_ref(arg),
+            Args::Two(args) => args,
+            Args::Many(args) => args,
+        }
+    }
+}
+
+/// Span field that puts the span's slice onto a custom track of that name,
+/// instead of the track of the thread it is entered on.
+const TRACK_FIELD: &str = "perfetto.track";
+
+/// Event field that selects where an instant is put: `"track"` for the
+/// custom track of the current span (if any), `"thread"` for the thread track.
+const INSTANT_SCOPE_FIELD: &str = "perfetto.instant_scope";
+
+/// Span or event field that replaces the name of the span's slices (or the
+/// event's instant), e.g. with a name only known at runtime.
+const NAME_FIELD: &str = "perfetto.name";
+
+/// Span or event field with a flow id, which connects the span's slices
+/// (or the event's instant) with all other slices of the same flow.
+const FLOW_FIELD: &str = "perfetto.flow";
+
+/// Span or event field with a flow id that ends at the span's slices (or
+/// the event's instant), so the UI does not connect them with later slices
+/// of that flow.
+const TERMINATING_FLOW_FIELD: &str = "perfetto.terminating_flow";
+
+/// Whether a field controls how a span or event is recorded, rather than
+/// being an argument.
+fn is_control_field(name: &str) -> bool {
+    matches!(
+        name,
+        TRACK_FIELD | INSTANT_SCOPE_FIELD | NAME_FIELD | FLOW_FIELD | TERMINATING_FLOW_FIELD
+    )
+}
+
+struct CustomTrackExt {
+    name: Arc<str>,
+}
+
+/// Marks a span on the track it got from
+/// [`PerfettoLayerBuilder::async_tracks`] for having no parent.
+struct AsyncRootExt;
+
+/// The name set with a control field of a span.
+struct SliceNameExt {
+    name: String,
+}
+
+/// The name of the slices of a span: its own, or the one set with a
+/// `perfetto.name` field. Names set at runtime are interned by the writer
+/// like any other, so they cost no more than static ones in the trace.
+fn slice_name<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>) -> Cow<'static, str> {
+    match span.extensions().get::<SliceNameExt>() {
+        Some(ext) => Cow::Owned(ext.name.clone()),
+        None => Cow::Borrowed(span.name()),
+    }
+}
+
+/// The flows set with control fields of a span or event.
+#[derive(Default, Clone, Copy)]
+struct FlowExt {
+    flow: Option<u64>,
+    terminating_flow: Option<u64>,
+}
+
+/// Name of the track shared by threads over the limit on threads.
+const OTHER_THREADS_NAME: &str = "other threads";
+
+fn thread_track_name(thread_name: Option<&str>, id: ThreadId) -> String {
+    match thread_name {
+        Some(name) => format!("{} {}", name, id),
+        None => format!("thread {}", id),
+    }
+}
+
+/// Name of the instant marking a span exited by a panic, whose slice has an
+/// `unwound` argument.
+const UNWOUND_NAME: &str = "unwound";
+
+/// Name of the instant marking a span entry that took longer than its
+/// budget.
+const BUDGET_EXCEEDED_NAME: &str = "budget exceeded";
+
+/// The duration budget of a span, see
+/// [`PerfettoLayerBuilder::span_budget`].
+struct BudgetExt {
+    budget: u64,
+    /// When the span was last entered.
+    entered_at: Option<Timestamp>,
+}
+
+/// When a root span was first entered, see
+/// [`PerfettoLayerBuilder::root_offsets`].
+struct RootEnteredExt(Timestamp);
+
+/// Threads that entered the span while recording was off, before the
+/// [`start_trigger`](PerfettoLayerBuilder::start_trigger) fired or while
+/// [disabled](PerfettoLayer::control_handle). Their exits are not recorded
+/// either.
+#[derive(Default)]
+struct SkippedEntryExt {
+    threads: Vec<ThreadId>,
+}
+
+/// Span entries whose slice is not recorded yet, see
+/// [`PerfettoLayerBuilder::min_span_duration`].
+///
+/// A span can be entered on several threads at once, so there is one entry
+/// per thread.
+#[derive(Default)]
+struct DeferredEnterExt {
+    entries: Vec<(ThreadId, Timestamp, Message)>,
+}
+
+impl DeferredEnterExt {
+    /// Remove the latest entry of the thread, returning when it was entered
+    /// and the message beginning its slice.
+    fn take(&mut self, thread_id: ThreadId) -> Option<(Timestamp, Message)> {
+        let index = self
+            .entries
+            .iter()
+            .rposition(|(entry_thread, _, _)| *entry_thread == thread_id)?;
+        let (_, timestamp, msg) = self.entries.remove(index);
+        Some((timestamp, msg))
+    }
+}
+
+/// Extracts the values of the control fields of a span or event.
+#[derive(Default)]
+struct ControlFieldVisitor {
+    track: Option<Arc<str>>,
+    instant_scope: Option<String>,
+    name: Option<String>,
+    flow: Option<u64>,
+    terminating_flow: Option<u64>,
+}
+
+impl ControlFieldVisitor {
+    fn record(&mut self, field: &tracing::field::Field, value: String) {
+        match field.name() {
+            TRACK_FIELD => self.track = Some(value.into()),
+            INSTANT_SCOPE_FIELD => self.instant_scope = Some(value),
+            NAME_FIELD => self.name = Some(value),
+            FLOW_FIELD => self.flow = value.parse().ok(),
+            TERMINATING_FLOW_FIELD => self.terminating_flow = value.parse().ok(),
+            _ => {}
+        }
+    }
+}
+
+impl Visit for ControlFieldVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if is_control_field(field.name()) {
+            self.record(field, format!("{:?}", value));
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if is_control_field(field.name()) {
+            self.record(field, value.to_string());
+        }
+    }
+}
+
+/// Finishes the trace when dropped. Use a [`TraceController`] to manage
+/// the trace while it is running.
+pub struct FlushGuard {
+    handle: Option<JoinHandle<Result<Option<File>, WriterError>>>, // An option, so we can `take`
+    controller: TraceController,
+    on_error: writer::ErrorHook,
+}
+
+impl FlushGuard {
+    /// Get a handle to control the trace while it is being written, which
+    /// can be cloned and handed to other parts of a service.
+    pub fn controller(&self) -> TraceController {
+        self.controller.clone()
+    }
+
+    /// Get a handle to poll the size of the trace and other counters while
+    /// it is being written.
+    pub fn stats_handle(&self) -> StatsHandle {
+        self.controller.stats.clone()
+    }
+
+    /// Write the most recent part of the trace kept in memory to `path`,
+    /// and keep recording. Only applies to a
+    /// [`ring_buffer`](PerfettoLayerBuilder::ring_buffer); the trace can be
+    /// dumped any number of times.
+    ///
+    /// Waits for the writer to catch up with the messages recorded so far,
+    /// so the dump includes them.
+    pub fn dump_now<P: Into<PathBuf>>(&self, path: P) -> io::Result<()> {
+        let (done, result) = crossbeam_channel::bounded(1);
+        self.controller.send(Message::Dump {
+            path: path.into(),
+            done,
+        });
+        result
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("the trace has finished")))
+    }
+
+    /// Finish the trace and return the file it was written to.
+    ///
+    /// Unlike dropping the guard, this reports errors of the writer thread.
+    /// Messages sent by the layer after this are discarded.
+    pub fn into_inner(mut self) -> io::Result<File> {
+        self.controller.stop();
+        let handle = self.handle.take().expect("writer thread already joined");
+        match handle.join() {
+            Ok(Ok(Some(file))) => Ok(file),
+            Ok(Ok(None)) => Err(io::Error::other("the trace was not written to a file")),
+            Ok(Err(WriterError::Io(err))) => Err(err),
+            Ok(Err(err)) => Err(io::Error::other(err.to_string())),
+            Err(_) => Err(io::Error::other("writer thread panicked")),
+        }
+    }
+
+    /// Finish the trace, but give up waiting for the writer after
+    /// `timeout`, e.g. if the disk hangs, so the process can still exit.
+    ///
+    /// Returns the final stats, or those at the time of giving up. An
+    /// abandoned writer keeps running in the background, and the trace
+    /// may be incomplete.
+    pub fn finish_timeout(mut self, timeout: Duration) -> Result<TraceStats, FlushError> {
+        self.controller.stop();
+        let handle = self.handle.take().expect("writer thread already joined");
+        let deadline = std::time::Instant::now() + timeout;
+        while !handle.is_finished() {
+            if std::time::Instant::now() >= deadline {
+                let stats = self.stats_handle().stats();
+                self.on_error.report_partial(
+                    "abandoning the trace",
+                    io::ErrorKind::TimedOut,
+                    &format_args!("writer did not finish within {:?}", timeout),
+                );
+                return Err(FlushError::Timeout(stats));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let stats = self.stats_handle().stats();
+        match handle.join() {
+            Ok(Ok(_)) => Ok(stats),
+            Ok(Err(WriterError::Io(err))) => Err(FlushError::Writer(err)),
+            Ok(Err(err)) => Err(FlushError::Writer(io::Error::other(err.to_string()))),
+            Err(_) => Err(FlushError::Writer(io::Error::other(
+                "writer thread panicked",
+            ))),
+        }
+    }
+}
+
+/// Controls a running trace. Unlike the [`FlushGuard`], it can be cloned,
+/// and dropping it does not end the trace.
+///
+/// Requests are queued behind the messages recorded so far, and carried out
+/// by the writer thread in order.
+#[derive(Debug, Clone)]
+pub struct TraceController {
+    stats: StatsHandle,
+}
+
+impl TraceController {
+    /// The controller of the [`PerfettoLayer`] of `dispatch`, if it has
+    /// one, e.g. for a tool that flushes the trace on a signal without
+    /// holding on to the [`FlushGuard`]. Unlike
+    /// [`PerfettoLayer::from_dispatch`], this doesn't need the type of the
+    /// subscriber. If `dispatch` has several layers, the outermost one is
+    /// found.
+    pub fn from_dispatch(dispatch: &tracing::Dispatch) -> Option<TraceController> {
+        dispatch.downcast_ref::<TraceController>().cloned()
+    }
+
+    /// The controller of the current default subscriber, see
+    /// [`from_dispatch`](Self::from_dispatch).
+    pub fn current() -> Option<TraceController> {
+        tracing::dispatcher::get_default(Self::from_dispatch)
+    }
+
+    /// Write everything recorded so far to the output.
+    pub fn flush(&self) {
+        self.send(Message::Flush);
+    }
+
+    /// Finish the trace. Anything recorded after this is discarded. The
+    /// writer thread is only joined by the [`FlushGuard`].
+    pub fn stop(&self) {
+        self.send(Message::Drop);
+    }
+
+    /// Finish the current file and continue in the next one. Only applies
+    /// if the trace is split into files, see
+    /// [`PerfettoLayerBuilder::max_file_size`].
+    pub fn rotate(&self) {
+        self.send(Message::Rotate);
+    }
+
+    /// The size of the trace and other counters.
+    pub fn stats(&self) -> TraceStats {
+        self.stats.stats()
+    }
+
+    fn send(&self, msg: Message) {
+        // Fails if the writer has already finished.
+        let _ignore_err = self.stats.queue.send(msg);
+    }
+}
+
+/// Why [`FlushGuard::finish_timeout`] could not finish the trace.
+#[derive(Debug)]
+pub enum FlushError {
+    /// The writer did not finish in time. Contains the stats when we gave
+    /// up.
+    Timeout(TraceStats),
+    /// The writer failed.
+    Writer(io::Error),
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushError::Timeout(stats) => write!(
+                f,
+                "writer did not finish in time ({} of {} bytes written)",
+                stats.file_size, stats.bytes_written
+            ),
+            FlushError::Writer(err) => write!(f, "writer failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FlushError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FlushError::Timeout(_) => None,
+            FlushError::Writer(err) => Some(err),
+        }
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        // Tell writer thread to stop. Sending will fail if thread is already
+        // stopped. We can ignore that.
+        self.controller.stop();
+        if let Some(handle) = self.handle.take() {
+            match handle.join() {
+                // Errors were reported by the writer thread.
+                Ok(_) => {}
+                Err(_) => self.on_error.report_partial(
+                    "writer thread failed",
+                    io::ErrorKind::Other,
+                    &"the writer thread panicked",
+                ),
+            }
+        }
+    }
+}
+
+// TODO: Use custom type here with `&'static str` for name, and custom enum for
+// values. Then interning can be handled in the writer.
+#[derive(Debug)]
+struct DebugAnnotationVisitor {
+    infos: Vec<DebugAnnotation>,
+    message_policy: MessagePolicy,
+    /// The `message` field, if the policy is [`MessagePolicy::Name`].
+    message: Option<String>,
+}
+
+impl DebugAnnotationVisitor {
+    fn new(message_policy: MessagePolicy) -> Self {
+        DebugAnnotationVisitor {
+            infos: Vec::new(),
+            message_policy,
+            message: None,
+        }
+    }
+
+    fn push(&mut self, field: &tracing::field::Field, mut value: packet::DebugValue) {
+        // Fields that control how the span is recorded are not arguments.
+        if is_control_field(field.name()) {
+            return;
+        }
+        if field.name() == "message" {
+            match self.message_policy {
+                MessagePolicy::Annotation => {}
+                MessagePolicy::Name => {
+                    if let packet::DebugValue::String(message) = value {
+                        self.message = Some(message);
+                    }
+                    return;
+                }
+                MessagePolicy::Truncate(max_chars) => {
+                    if let packet::DebugValue::String(message) = &mut value {
+                        if let Some((end, _)) = message.char_indices().nth(max_chars) {
+                            message.truncate(end);
+                        }
+                    }
+                }
+                MessagePolicy::Drop => return,
+            }
+        }
+        self.infos.push(DebugAnnotation {
+            name: packet::IString::Plain(field.name().to_string()),
+            value,
+        })
+    }
+}
+
+impl Visit for DebugAnnotationVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.push(field, packet::DebugValue::String(format!("{:?}", value)))
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.push(field, packet::DebugValue::Bool(value))
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.push(field, packet::DebugValue::Uint(value))
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.push(field, packet::DebugValue::String(value.to_owned()))
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.push(field, packet::DebugValue::Int(value))
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.push(field, packet::DebugValue::Double(value))
+    }
+}
+
+#[test]
+fn basic() {
+    use tracing::info_span;
+    use tracing_subscriber::prelude::*;
+
+    let (perfetto_layer, _handle) = PerfettoLayerBuilder::new()
+        .file("test-basic.perfetto-trace")
+        .build();
+    let _default = tracing_subscriber::registry()
+        .with(perfetto_layer)
+        .set_default();
+
+    let span = info_span!("hello world").entered();
+    println!("blah");
+    span.exit();
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::instrument;
+    //use tracing_subscriber::prelude::*;
+
+    use crate::PerfettoLayerBuilder;
+
+    /// The number of times `needle` occurs in `bytes`, for what
+    /// [`crate::test::Trace`] doesn't decode, such as track descriptors.
+    fn count(bytes: &[u8], needle: &(impl AsRef<[u8]> + ?Sized)) -> usize {
+        let needle = needle.as_ref();
+        bytes
+            .windows(needle.len())
+            .filter(|window| *window == needle)
+            .count()
+    }
+
+    fn contains(bytes: &[u8], needle: &(impl AsRef<[u8]> + ?Sized)) -> bool {
+        count(bytes, needle) > 0
+    }
+
+    #[instrument]
+    fn fibonacci(n: usize) -> usize {
+        if n < 2 {
+            n
+        } else {
+            fibonacci(n - 1) + fibonacci(n - 2)
+        }
+    }
+
+    #[test]
+    fn fib() {
+        use tracing_subscriber::prelude::*;
+
+        let (perfetto_layer, _handle) = PerfettoLayerBuilder::new()
+            .file("test-fib.perfetto-trace")
+            .build();
+        let _default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+
+        fibonacci(6);
+    }
+
+    #[test]
+    fn process_info_track() {
+        let builder = PerfettoLayerBuilder::new().process_info(crate::process_info!());
+        let trace = crate::test::capture_bytes(builder, || {
+            fibonacci(2);
+        });
+        assert!(contains(&trace, "process info"));
+        assert!(contains(
+            &trace,
+            concat!("tracing-perfetto ", env!("CARGO_PKG_VERSION"))
+        ));
+    }
+
+    #[test]
+    fn trace_writer() {
+        let builder = PerfettoLayerBuilder::new().trace_writer(true);
+        let trace = crate::test::capture_with(builder, || {
+            fibonacci(5);
+        });
+        assert_eq!(trace.slice("process messages").track, "tracing-perfetto");
+        let counters: Vec<_> = trace
+            .counters
+            .iter()
+            .map(|sample| sample.track.as_str())
+            .collect();
+        assert!(counters.contains(&"bytes written"), "{:?}", counters);
+        assert!(counters.contains(&"queued messages"), "{:?}", counters);
+    }
+
+    #[test]
+    fn custom_tracks() {
+        use tracing::info_span;
+
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .inherit_tracks(true);
+        let trace = crate::test::capture_with(builder, || {
+            let _outer = info_span!("request", perfetto.track = "db-pool").entered();
+            let _inner = info_span!("query").entered();
+        });
+        // No debug annotation for the control field.
+        assert_eq!(trace.slice("request").track, "db-pool");
+        assert_eq!(trace.slice("request").arg("perfetto.track"), None);
+        assert_eq!(trace.slice("query").track, "db-pool");
+    }
+
+    #[test]
+    fn events_on_span_tracks() {
+        use tracing::{info, info_span};
+
+        let record = || {
+            let _span = info_span!("request", perfetto.track = "db-pool").entered();
+            info!(perfetto.name = "default");
+            info!(
+                perfetto.name = "on thread",
+                perfetto.instant_scope = "thread"
+            );
+            info!(perfetto.name = "on track", perfetto.instant_scope = "track");
+        };
+        let tracks = |trace: crate::test::Trace| {
+            trace
+                .instants
+                .into_iter()
+                .map(|instant| (instant.name, instant.track == "db-pool"))
+                .collect::<Vec<_>>()
+        };
+
+        let builder = PerfettoLayerBuilder::new().events_on_span_tracks(true);
+        assert_eq!(
+            tracks(crate::test::capture_with(builder, record)),
+            [
+                ("default".to_string(), true),
+                ("on thread".to_string(), false),
+                ("on track".to_string(), true)
+            ]
+        );
+        let builder = PerfettoLayerBuilder::new();
+        assert_eq!(
+            tracks(crate::test::capture_with(builder, record)),
+            [
+                ("default".to_string(), false),
+                ("on thread".to_string(), false),
+                ("on track".to_string(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn async_tracks() {
+        let builder = PerfettoLayerBuilder::new().async_tracks(true);
+        let trace = crate::test::capture_with(builder, || {
+            let request = tracing::info_span!("request");
+            request.in_scope(|| tracing::info!(name: "received", "received"));
+            let retry = tracing::info_span!(parent: None, "retry");
+            retry.follows_from(&request);
+            // The task moves to another thread, as if stolen by another worker.
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    request.in_scope(|| tracing::info_span!("poll").in_scope(|| {}));
+                    drop(request);
+                    retry.in_scope(|| {});
+                });
+            })
+            .join()
+            .unwrap();
+            tracing::info_span!("other").in_scope(|| {});
+            tracing::info!(name: "outside", "outside");
+        });
+
+        let request_track = trace.slice("request").track.clone();
+        assert!(
+            request_track.starts_with("request (span "),
+            "{}",
+            request_track
+        );
+        let tracks: Vec<_> = trace
+            .slices_named("request")
+            .chain(trace.slices_named("poll"))
+            .chain(trace.slices_named("retry"))
+            .map(|slice| slice.track.as_str())
+            .collect();
+        assert_eq!(tracks, [request_track.as_str(); 4]);
+        assert_eq!(trace.instant("received").track, request_track);
+        assert!(trace.slice("other").track.starts_with("other (span "));
+        assert!(!trace.instant("outside").track.contains("(span "));
+    }
+
+    #[test]
+    fn incremental_state_interval() {
+        use tracing::info_span;
+
+        let builder =
+            PerfettoLayerBuilder::new().incremental_state_interval(std::time::Duration::ZERO);
+        let trace = crate::test::capture_bytes(builder, || {
+            for _ in 0..3 {
+                let _span = info_span!("repeated").entered();
+            }
+        });
+        // Every begin and end packet clears the state and re-interns the name.
+        assert_eq!(count(&trace, b"repeated"), 6);
+    }
+
+    #[test]
+    fn message_policy() {
+        use crate::MessagePolicy;
+
+        let builder = PerfettoLayerBuilder::new()
+            .message_policy(MessagePolicy::Name)
+            .target_message_policy("noisy", MessagePolicy::Drop);
+        let bytes = crate::test::capture_bytes(builder, || {
+            tracing::info!("cache miss storm");
+            tracing::info!(target: "noisy::module", "should not appear");
+        });
+        let trace = crate::test::Trace::parse(&bytes);
+        trace.instant("cache miss storm");
+        assert!(!contains(&bytes, "should not appear"));
+    }
+
+    #[test]
+    fn include_args() {
+        for include in [true, false] {
+            let builder = PerfettoLayerBuilder::new().include_args(include);
+            let trace = crate::test::capture_with(builder, || {
+                tracing::info_span!("load", retry = true, shard = -3, rows = 12_u64, ratio = 0.5)
+                    .in_scope(
+                        || tracing::info!(name: "cache", table = "users", ?include, "missed"),
+                    );
+            });
+            let slice = trace.slice("load");
+            let instant = trace.instant("cache");
+            if !include {
+                // Both are still recorded, just without their fields.
+                assert!(slice.args.is_empty());
+                assert!(instant.args.is_empty());
+                continue;
+            }
+            slice
+                .assert_arg("retry", true)
+                .assert_arg("shard", -3)
+                .assert_arg("rows", 12_u64)
+                .assert_arg("ratio", 0.5);
+            instant
+                .assert_arg("message", "missed")
+                .assert_arg("table", "users")
+                .assert_arg("include", "true");
+            assert_eq!(instant.track, slice.track);
+        }
+    }
+
+    #[test]
+    fn event_naming() {
+        use crate::EventNaming;
+
+        let builder = PerfettoLayerBuilder::new().event_naming(EventNaming::FileLine);
+        let line = line!() + 2;
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info!("cache miss storm");
+            tracing::info!(name: "cache_refilled", "cache refilled");
+        });
+        let names: Vec<_> = trace
+            .instants
+            .iter()
+            .map(|instant| instant.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [format!("lib.rs:{}", line).as_str(), "cache_refilled"]
+        );
+    }
+
+    #[test]
+    fn record_kinds() {
+        use crate::Kinds;
+
+        for kinds in [Kinds::SPANS, Kinds::EVENTS] {
+            let builder = PerfettoLayerBuilder::new()
+                .include_args(true)
+                .record_kinds(kinds);
+            let trace = crate::test::capture_with(builder, || {
+                tracing::info_span!("compaction").in_scope(|| {
+                    tracing::info!("cache miss storm");
+                });
+            });
+            let spans: Vec<_> = trace.slices.iter().map(|slice| &slice.name).collect();
+            let events: Vec<_> = trace
+                .instants
+                .iter()
+                .filter_map(|instant| instant.arg("message"))
+                .collect();
+            if kinds == Kinds::SPANS {
+                assert_eq!(spans, ["compaction"]);
+                assert!(events.is_empty());
+            } else {
+                assert!(spans.is_empty());
+                assert_eq!(events, [&"cache miss storm".into()]);
+            }
+        }
+        assert!(Kinds::ALL.contains(Kinds::SPANS | Kinds::EVENTS));
+        assert!(!Kinds::SPANS.contains(Kinds::EVENTS));
+    }
+
+    #[test]
+    fn flow_fields() {
+        let builder = PerfettoLayerBuilder::new().include_args(true);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("enqueue", perfetto.flow = 42_u64).in_scope(|| {});
+            tracing::info_span!("handle", perfetto.terminating_flow = 42_u64).in_scope(|| {});
+        });
+        let enqueue = trace.slice("enqueue");
+        assert_eq!(enqueue.flows, [42]);
+        assert!(enqueue.args.is_empty());
+        let handle = trace.slice("handle");
+        assert_eq!(handle.terminating_flows, [42]);
+        assert!(handle.args.is_empty());
+    }
+
+    #[test]
+    fn event_and_recorded_flows() {
+        let queued = crate::new_flow_id();
+        let handed_off = crate::new_flow_id();
+        let trace = crate::test::capture_with(PerfettoLayerBuilder::new(), || {
+            tracing::info!(name: "queued", { perfetto.flow = queued }, "queued");
+            let request = tracing::info_span!("request", perfetto.flow = tracing::field::Empty);
+            request.record("perfetto.flow", handed_off);
+            request.in_scope(|| {});
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    tracing::info_span!("continue", perfetto.terminating_flow = handed_off).in_scope(
+                        || tracing::info!(name: "dequeued", { perfetto.terminating_flow = queued }, "dequeued"),
+                    );
+                });
+            })
+            .join()
+            .unwrap();
+        });
+        assert_eq!(trace.instant("queued").flows, [queued]);
+        assert_eq!(trace.slice("request").flows, [handed_off]);
+        assert_eq!(trace.slice("continue").terminating_flows, [handed_off]);
+        assert_eq!(trace.instant("dequeued").terminating_flows, [queued]);
+    }
+
+    #[test]
+    fn recorded_fields() {
+        #[tracing::instrument(skip_all, fields(rows = tracing::field::Empty, table = "users"))]
+        fn query(rows: u64) {
+            tracing::Span::current().record("rows", rows);
+        }
+
+        let builder = PerfettoLayerBuilder::new().include_args(true);
+        let bytes = crate::test::capture_bytes(builder, || {
+            let span = tracing::info_span!("batch", rows = tracing::field::Empty, table = "orders");
+            span.in_scope(|| {});
+            span.record("rows", 7_u64);
+            span.record("table", "archive");
+            span.in_scope(|| {});
+            query(3);
+        });
+        // Neither an empty value nor the field's name before it is set.
+        assert_eq!(count(&bytes, b"Empty"), 0);
+        let trace = crate::test::Trace::parse(&bytes);
+        let batches: Vec<_> = trace.slices_named("batch").collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].arg("rows"), None);
+        batches[0].assert_arg("table", "orders");
+        // Replaced by the recorded values on the second entry.
+        batches[1]
+            .assert_arg("rows", 7_u64)
+            .assert_arg("table", "archive");
+        // Recorded while the only entry of `query` was open, so on its end.
+        trace
+            .slice("query")
+            .assert_arg("rows", 3_u64)
+            .assert_arg("table", "users");
+    }
+
+    #[test]
+    fn exit_args() {
+        let builder = PerfettoLayerBuilder::new().include_args(true);
+        let trace = crate::test::capture_with(builder, || {
+            {
+                let _span = crate::perfetto_span!("flush", { table = "orders" });
+                crate::perfetto_exit_args! { rows = 12_u64, compacted = true };
+            }
+            let _span = crate::perfetto_span!("idle");
+        });
+        trace
+            .slice("flush")
+            .assert_arg("table", "orders")
+            .assert_arg("rows", 12_u64)
+            .assert_arg("compacted", true);
+        assert!(trace.slice("idle").args.is_empty());
+    }
+
+    #[test]
+    fn dynamic_names() {
+        let bytes = crate::test::capture_bytes(PerfettoLayerBuilder::new(), || {
+            for table in ["users", "orders"] {
+                tracing::info_span!("query", perfetto.name = %format_args!("select {}", table))
+                    .in_scope(|| {});
+            }
+            let job = tracing::info_span!("job", perfetto.name = tracing::field::Empty);
+            job.record("perfetto.name", "nightly backup");
+            job.in_scope(|| {});
+            tracing::info!(perfetto.name = "checkpoint 3", "checkpoint");
+        });
+        let trace = crate::test::Trace::parse(&bytes);
+        let names: Vec<_> = trace.slices.iter().map(|slice| &slice.name).collect();
+        assert_eq!(names, ["select users", "select orders", "nightly backup"]);
+        trace.instant("checkpoint 3");
+        for name in ["select users", "select orders", "nightly backup"] {
+            // Interned by the begin of the slice, and reused by its end.
+            assert_eq!(count(&bytes, name), 1);
+        }
+        assert_eq!(count(&bytes, "perfetto.name"), 0);
+    }
+
+    #[test]
+    fn task_id_provider() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static CURRENT_TASK: Cell<Option<u64>> = const { Cell::new(None) };
+        }
+
+        let builder = PerfettoLayerBuilder::new().task_id_provider(|| CURRENT_TASK.with(Cell::get));
+        let trace = crate::test::capture_with(builder, || {
+            CURRENT_TASK.with(|task| task.set(Some(17)));
+            tracing::info_span!("poll").in_scope(|| {});
+            CURRENT_TASK.with(|task| task.set(None));
+            tracing::info_span!("idle").in_scope(|| {});
+        });
+        assert_eq!(trace.slice("poll").track, "task 17");
+        assert_ne!(trace.slice("idle").track, "task 17");
+    }
+
+    #[test]
+    fn strict() {
+        use std::{
+            io,
+            sync::{Arc, Mutex},
+        };
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .strict(true)
+            .on_error({
+                let errors = errors.clone();
+                move |err| errors.lock().unwrap().push((err.kind(), err.to_string()))
+            });
+        let mut controller = None;
+        let mut unfinished = None;
+        crate::test::capture_with(builder, || {
+            controller = crate::TraceController::current();
+            tracing::info_span!("outer", n = 1).in_scope(|| {
+                tracing::info_span!("inner").in_scope(|| tracing::info!(kind = "miss", "lookup"));
+            });
+            // Left open when the trace ends.
+            unfinished = Some(tracing::info_span!("unfinished").entered());
+        });
+        drop(unfinished);
+
+        assert_eq!(controller.unwrap().stats().violations, 1);
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|(kind, _)| *kind == io::ErrorKind::InvalidData));
+        assert!(
+            errors[0].1.contains("slices never ended"),
+            "{}",
+            errors[0].1
+        );
+        assert_eq!(errors[1].1, "strict: 1 violations in the trace");
+    }
+
+    #[test]
+    fn strict_deferred_slices() {
+        use std::time::Duration;
+
+        let builder = PerfettoLayerBuilder::new()
+            .min_span_duration(Duration::from_millis(1))
+            .strict(true);
+        let mut controller = None;
+        crate::test::capture_with(builder, || {
+            controller = crate::TraceController::current();
+            // The slice is written after the event it contains.
+            tracing::info_span!("slow").in_scope(|| {
+                tracing::info!("inside");
+                std::thread::sleep(Duration::from_millis(2));
+            });
+            tracing::info!("after");
+        });
+        assert_eq!(controller.unwrap().stats().violations, 0);
+    }
+
+    #[test]
+    fn sync_policy() {
+        use crate::SyncPolicy;
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-sync-policy.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file(path)
+            .sync_policy(SyncPolicy::EveryNBytes(1))
+            .build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("durable").in_scope(|| {});
+        drop(default);
+        let file = handle.into_inner().unwrap();
+
+        let trace = std::fs::read(path).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), trace.len() as u64);
+        assert!(contains(&trace, "durable"));
+    }
+
+    #[test]
+    fn trace_controller() {
+        use tracing_subscriber::prelude::*;
+
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file("test-controller.perfetto-trace")
+            .max_file_size(1 << 30)
+            .build();
+        let controller = handle.controller();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("before rotation").in_scope(|| {});
+        controller.rotate();
+        tracing::info_span!("after rotation").in_scope(|| {});
+        let remote = controller.clone();
+        std::thread::spawn(move || remote.flush()).join().unwrap();
+        controller.stop();
+        drop(default);
+        drop(handle);
+        assert!(controller.stats().packets_written > 0);
+
+        let first = std::fs::read("test-controller.0.perfetto-trace").unwrap();
+        let second = std::fs::read("test-controller.1.perfetto-trace").unwrap();
+        assert!(contains(&first, "before rotation"));
+        assert!(!contains(&first, "after rotation"));
+        assert!(contains(&second, "after rotation"));
+    }
+
+    #[test]
+    fn state_track() {
+        let builder = PerfettoLayerBuilder::new().strict(true);
+        let mut controller = None;
+        let trace = crate::test::capture_with(builder, || {
+            controller = crate::TraceController::current();
+            let connection = tracing::dispatcher::get_default(|dispatch| {
+                crate::PerfettoLayer::<tracing_subscriber::Registry>::from_dispatch(dispatch)
+                    .unwrap()
+                    .state_track("conn-42")
+            });
+            for state in ["Idle", "Connecting", "Active"] {
+                connection.set_state(state);
+            }
+            connection.clear();
+            connection.set_state("Closed");
+        });
+        let states: Vec<_> = trace
+            .slices
+            .iter()
+            .map(|slice| {
+                (
+                    slice.track.as_str(),
+                    slice.name.as_str(),
+                    slice.end.is_some(),
+                )
+            })
+            .collect();
+        // The last state is still open when the trace ends.
+        assert_eq!(
+            states,
+            [
+                ("conn-42", "Idle", true),
+                ("conn-42", "Connecting", true),
+                ("conn-42", "Active", true),
+                ("conn-42", "Closed", false),
+            ]
+        );
+        assert_eq!(controller.unwrap().stats().violations, 1);
+    }
+
+    #[test]
+    fn span_summary() {
+        use crate::SummaryFormat;
+
+        let summary_path = "test-span-summary.csv";
+        let builder = PerfettoLayerBuilder::new().span_summary(summary_path, SummaryFormat::Csv);
+        crate::test::capture_with(builder, || {
+            for _ in 0..3 {
+                tracing::info_span!("compaction").in_scope(|| {});
+            }
+        });
+
+        let summary = std::fs::read_to_string(summary_path).unwrap();
+        let mut lines = summary.lines();
+        assert_eq!(lines.next(), Some("name,count,total_ns,mean_ns,p95_ns"));
+        assert!(lines.next().unwrap().starts_with("compaction,3,"));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn shutdown_report() {
+        use tracing_subscriber::prelude::*;
+
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file("test-shutdown-report.perfetto-trace")
+            .shutdown_report(true)
+            .build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("work").in_scope(|| tracing::info!("done"));
+        drop(default);
+        drop(handle);
+
+        let report =
+            std::fs::read_to_string("test-shutdown-report.perfetto-trace.meta.json").unwrap();
+        assert!(report.contains("\"complete\": true,\n"));
+        assert!(report.contains("\"error\": null,\n"));
+        assert!(report.contains("\"files\": [\"test-shutdown-report.perfetto-trace\"],\n"));
+        // The slice's begin and end, and the instant.
+        assert!(report.contains("\"events\": 3,\n"));
+        assert!(report.contains("\"messages_dropped\": 0,\n"));
+    }
+
+    #[test]
+    fn ring_buffer() {
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-ring-buffer.perfetto-trace";
+        let dump = "test-ring-buffer-dump.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file(path)
+            .include_args(true)
+            .ring_buffer(16 * 1024)
+            .build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("startup").in_scope(|| {});
+        for i in 0..2000 {
+            tracing::info!(name: "tick", i);
+        }
+        handle.dump_now(dump).unwrap();
+        // Nothing is written to the output until the trace finishes.
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 0);
+        tracing::info!(name: "after dump", "");
+        drop(default);
+        drop(handle);
+
+        let dumped = std::fs::read(dump).unwrap();
+        assert!(dumped.len() < 20 * 1024, "{} bytes", dumped.len());
+        let trace = crate::test::Trace::parse(&dumped);
+        assert_eq!(trace.slices_named("startup").count(), 0);
+        assert!(trace
+            .instants
+            .iter()
+            .all(|instant| instant.name != "after dump"));
+        let newest = trace.instants.last().unwrap();
+        assert_eq!(newest.name, "tick");
+        newest.assert_arg("i", 1999);
+        // The thread's track is still named, although its descriptor was
+        // written long before.
+        assert!(!newest.track.is_empty());
+
+        let trace = crate::test::Trace::parse(&std::fs::read(path).unwrap());
+        trace.instant("after dump");
+    }
+
+    #[test]
+    fn source_locations() {
+        let builder = PerfettoLayerBuilder::new().source_locations(true);
+        let mut hash = 0;
+        let bytes = crate::test::capture_bytes(builder, || {
+            let span = tracing::info_span!("located");
+            hash = crate::callsite_hash(span.metadata().unwrap());
+            span.in_scope(|| {});
+        });
+        assert!(contains(&bytes, file!().as_bytes()));
+        assert!(contains(&bytes, module_path!().as_bytes()));
+        let mut callsite = crate::emit::ProtoEmitter::new();
+        callsite.varint_field(3, hash);
+        assert!(contains(&bytes, callsite.as_bytes()));
+    }
+
+    #[test]
+    fn perf_counter() {
+        let builder = PerfettoLayerBuilder::new().perf_counter(crate::PerfCounter::Instructions);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("counted").in_scope(|| {
+                std::hint::black_box((0..10_000u64).sum::<u64>());
+            });
+        });
+        trace.slice("counted");
+        let samples: Vec<_> = trace
+            .counters
+            .iter()
+            .filter(|sample| sample.track == "instructions")
+            .map(|sample| sample.value)
+            .collect();
+        // Containers and VMs often have no PMU, in which case the slices
+        // are written without samples.
+        if crate::perf_counter::read(crate::PerfCounter::Instructions).is_some() {
+            // At the begin and end of the slice, which retired the loop.
+            assert_eq!(samples.len(), 2);
+            assert!(samples[1] - samples[0] >= 10_000.0, "{:?}", samples);
+        } else {
+            assert!(samples.is_empty());
+        }
+    }
+
+    #[test]
+    fn container_info() {
+        let builder = PerfettoLayerBuilder::new().include_container_info(true);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info!("hello");
+        });
+        if let Some(info) = crate::container::ContainerInfo::current() {
+            trace
+                .instant("container info")
+                .assert_arg("hostname", info.hostname.as_str());
+        }
+    }
+
+    #[test]
+    fn transform_chunks() {
+        fn xor(data: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        let builder = PerfettoLayerBuilder::new().transform_chunks(xor);
+        let framed = crate::test::capture_bytes(builder, || {
+            tracing::info_span!("secret").in_scope(|| {});
+        });
+        assert!(!contains(&framed, b"secret"));
+        let trace = crate::test::Trace::parse(&crate::decode_frames(&framed, xor).unwrap());
+        trace.slice("secret");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_compression() {
+        use crate::ZstdDictionary;
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-zstd-compression.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file(path)
+            .include_args(true)
+            .zstd_compression(
+                3,
+                ZstdDictionary::Train {
+                    max_size: 4096,
+                    samples: 8,
+                },
+            )
+            .build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        for i in 0..20_000 {
+            tracing::info_span!("request", i).in_scope(|| {});
+        }
+        drop(default);
+        drop(handle);
+
+        let dictionary = std::fs::read("test-zstd-compression.perfetto-trace.zdict").unwrap();
+        let compressed = std::fs::read(path).unwrap();
+        let bytes = crate::decode_zstd(&compressed, Some(&dictionary)).unwrap();
+        assert!(compressed.len() < bytes.len());
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slices_named("request").count(), 20_000);
+    }
+
+    #[test]
+    fn root_offsets() {
+        let builder = PerfettoLayerBuilder::new().root_offsets(true);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("parse").in_scope(|| {
+                    tracing::info_span!("lex").in_scope(|| {});
+                });
+            });
+        });
+        let offset = |name| match trace.slice(name).arg("root_offset_ns") {
+            Some(crate::test::Value::Uint(offset)) => *offset,
+            other => panic!("{}: {:?}", name, other),
+        };
+        assert_eq!(trace.slice("request").arg("root_offset_ns"), None);
+        assert!(offset("parse") <= offset("lex"));
+    }
+
+    #[test]
+    fn write_stalls() {
+        use std::time::Duration;
+
+        let builder = PerfettoLayerBuilder::new()
+            .write_stall_threshold(Duration::from_millis(5))
+            // A disk that takes its time.
+            .transform_chunks(|data| {
+                std::thread::sleep(Duration::from_millis(10));
+                Ok(data.to_vec())
+            });
+        let mut controller = None;
+        let framed = crate::test::capture_bytes(builder, || {
+            controller = crate::TraceController::current();
+            tracing::info_span!("slow disk").in_scope(|| {});
+            std::thread::sleep(Duration::from_millis(50));
+            tracing::info_span!("more").in_scope(|| {});
+        });
+        assert!(controller.unwrap().stats().write_stalls > 0);
+
+        let bytes = crate::decode_frames(&framed, |frame| Ok(frame.to_vec())).unwrap();
+        let trace = crate::test::Trace::parse(&bytes);
+        assert!(trace.instants_named("write stall").count() > 0);
+    }
+
+    #[test]
+    fn intern_seed() {
+        let names = "test-intern-seed.txt";
+        let builder = PerfettoLayerBuilder::new().export_intern_table(names);
+        crate::test::capture_with(builder, || {
+            tracing::info_span!("seeded").in_scope(|| {});
+        });
+        let exported = std::fs::read_to_string(names).unwrap();
+        assert!(
+            exported.lines().any(|name| name == "seeded"),
+            "{}",
+            exported
+        );
+
+        let builder = PerfettoLayerBuilder::new().intern_seed(exported.lines().chain(["unused"]));
+        let bytes = crate::test::capture_bytes(builder, || {
+            tracing::info_span!("seeded").in_scope(|| {});
+            tracing::info_span!("seeded").in_scope(|| {});
+        });
+        // Both names are interned in the sequence header only.
+        assert_eq!(count(&bytes, b"seeded"), 1);
+        assert_eq!(count(&bytes, b"unused"), 1);
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slices_named("seeded").count(), 2);
+    }
+
+    #[test]
+    fn previous_packet_dropped() {
+        use std::sync::mpsc;
+
+        let (tx, rx) = mpsc::channel();
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .max_arg_len(usize::MAX)
+            .on_error(move |err| tx.send((err.kind(), err.to_string())).unwrap());
+        let bytes = crate::test::capture_bytes(builder, || {
+            // Too large to encode.
+            let huge = "x".repeat(3 << 20);
+            tracing::info_span!("huge", payload = huge.as_str()).in_scope(|| {});
+            tracing::info_span!("after").in_scope(|| {});
+        });
+
+        // Reported, but the trace goes on.
+        let (kind, message) = rx.try_recv().unwrap();
+        assert_eq!(kind, std::io::ErrorKind::InvalidData);
+        assert!(message.starts_with("dropping packet: "));
+        assert!(rx.try_recv().is_err());
+
+        assert!(bytes.len() < 1024);
+        // The end of the slice is marked, and clears the incremental state.
+        assert_eq!(count(&bytes, &[0xd0, 0x02, 0x01]), 1);
+        crate::test::Trace::parse(&bytes).slice("after");
+    }
+
+    #[test]
+    fn append() {
+        use std::io::Write;
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-append.perfetto-trace";
+        let _ = std::fs::remove_file(path);
+        for run in 0..2 {
+            let (perfetto_layer, handle) =
+                PerfettoLayerBuilder::new().file(path).append(true).build();
+            let default = tracing_subscriber::registry()
+                .with(perfetto_layer)
+                .set_default();
+            let span = match run {
+                0 => tracing::info_span!("first run"),
+                _ => tracing::info_span!("second run"),
+            };
+            span.in_scope(|| {});
+            drop(default);
+            drop(handle);
+            // A crash in the middle of a packet.
+            let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(&[0x0a, 0x40, 0x08]).unwrap();
+        }
+
+        let trace = std::fs::read(path).unwrap();
+        assert_eq!(count(&trace, b"first run"), 1);
+        assert_eq!(count(&trace, b"second run"), 1);
+        assert_eq!(count(&trace, b"restart"), 1);
+        let file = std::fs::File::open(path).unwrap();
+        let existing = crate::append::scan(&file).unwrap();
+        assert_eq!(existing.run, 2);
+        // Only the cut off packet at the end.
+        assert_eq!(existing.len, trace.len() as u64 - 3);
+    }
+
+    #[test]
+    fn append_rotated() {
+        use tracing_subscriber::prelude::*;
+
+        let dir = std::path::Path::new("test-append-rotated");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let mut files = Vec::new();
+        for run in 0..2 {
+            let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+                .file(dir.join("trace.perfetto-trace"))
+                .max_file_size(512)
+                .append(true)
+                .build();
+            let default = tracing_subscriber::registry()
+                .with(perfetto_layer)
+                .set_default();
+            for i in 0..20 {
+                tracing::info_span!("rotating", run, i).in_scope(|| {});
+            }
+            drop(default);
+            drop(handle);
+            files.push(std::fs::read_dir(dir).unwrap().count());
+        }
+
+        // The second run continued the last file of the first, and wrote
+        // the rest after it.
+        assert!(files[1] > files[0]);
+        let first = std::fs::read(dir.join("trace.0.perfetto-trace")).unwrap();
+        assert_eq!(count(&first, b"restart"), 0);
+        let last = files[0] - 1;
+        let continued = std::fs::read(dir.join(format!("trace.{}.perfetto-trace", last))).unwrap();
+        assert_eq!(count(&continued, b"restart"), 1);
+        let file = std::fs::File::open(dir.join(format!("trace.{}.perfetto-trace", files[1] - 1)));
+        assert_eq!(crate::append::scan(&file.unwrap()).unwrap().run, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "a transformed trace can't be appended to")]
+    fn append_transformed() {
+        let _ = PerfettoLayerBuilder::<tracing_subscriber::Registry>::new()
+            .file("test-append-transformed.perfetto-trace")
+            .append(true)
+            .transform_chunks(|chunk| Ok(chunk.to_vec()))
+            .build();
+    }
+
+    #[test]
+    fn span_context() {
+        let mut context = None;
+        let parent = crate::test::capture_with(PerfettoLayerBuilder::new(), || {
+            context = Some(tracing::info_span!("spawn").in_scope(crate::SpanContext::export));
+        });
+        let context = context.unwrap();
+
+        let builder = PerfettoLayerBuilder::new().parent_span_context(context);
+        let child = crate::test::capture_with(builder, || {
+            tracing::info_span!("child").in_scope(|| tracing::info_span!("nested").in_scope(|| {}));
+        });
+
+        // The export and the child's root slice share the flow.
+        assert_eq!(
+            parent.slice("span context exported").flows,
+            [context.flow_id]
+        );
+        assert_eq!(child.slice("child").flows, [context.flow_id]);
+        assert!(child.slice("nested").flows.is_empty());
+    }
+
+    #[test]
+    fn regions() {
+        // Without a layer, nothing happens.
+        drop(crate::region("unrecorded"));
+
+        let trace = crate::test::capture_with(PerfettoLayerBuilder::new(), || {
+            let load = crate::region("load");
+            let reproduce = crate::region("reproduce");
+            drop(load);
+            drop(reproduce);
+            drop(crate::region("later"));
+        });
+        let regions: Vec<_> = trace
+            .slices
+            .iter()
+            .map(|slice| (slice.name.as_str(), slice.track.as_str()))
+            .collect();
+        // The overlapping region is on a track of its own.
+        assert_eq!(
+            regions,
+            [
+                ("load", "Regions"),
+                ("reproduce", "Regions 2"),
+                ("later", "Regions")
+            ]
+        );
+    }
+
+    #[test]
+    fn from_dispatch() {
+        use tracing_subscriber::{filter::LevelFilter, prelude::*, Registry};
+
+        let unrelated = tracing::Dispatch::new(tracing_subscriber::registry());
+        assert!(crate::TraceController::from_dispatch(&unrelated).is_none());
+
+        let trace = crate::test::capture_with(PerfettoLayerBuilder::new(), || {
+            tracing::dispatcher::get_default(|dispatch| {
+                assert!(crate::PerfettoLayer::<Registry>::from_dispatch(dispatch).is_some());
+            });
+            tracing::info_span!("before_stop").in_scope(|| {});
+            crate::TraceController::current().unwrap().stop();
+            tracing::info_span!("after_stop").in_scope(|| {});
+        });
+        trace.slice("before_stop");
+        trace.assert_no_slice("after_stop");
+
+        // Found behind a filter too, where the type of the layer is hard to
+        // name.
+        let (perfetto_layer, _handle) = PerfettoLayerBuilder::new().writer(std::io::sink()).build();
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry().with(perfetto_layer.with_filter(LevelFilter::INFO)),
+        );
+        assert!(crate::TraceController::from_dispatch(&dispatch).is_some());
+    }
+
+    #[test]
+    fn target_categories() {
+        let builder = PerfettoLayerBuilder::new()
+            .target_category("app::db", "database")
+            .target_category("app", "application");
+        let bytes = crate::test::capture_bytes(builder, || {
+            tracing::info_span!(target: "app::db::pool", "query").in_scope(|| {
+                tracing::info!(name: "request", target: "app::http", "handled");
+            });
+            tracing::info_span!(target: "other", "uncategorized").in_scope(|| {});
+        });
+
+        // Each category is interned once.
+        assert_eq!(count(&bytes, "database"), 1);
+        assert_eq!(count(&bytes, "application"), 1);
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slice("query").categories, ["database"]);
+        assert_eq!(trace.instant("request").categories, ["application"]);
+        assert!(trace.slice("uncategorized").categories.is_empty());
+    }
+
+    #[test]
+    fn routing_rules() {
+        let builder = PerfettoLayerBuilder::new()
+            .route(crate::Rule::new().target("noisy").discard())
+            .route(
+                crate::Rule::new()
+                    .field("shard")
+                    .to_track("shards")
+                    .category("storage"),
+            )
+            .route(
+                crate::Rule::new()
+                    .kinds(crate::Kinds::EVENTS)
+                    .counter("queue_len"),
+            );
+        let trace = crate::test::capture_with(builder, || {
+            for shard in 0..3 {
+                tracing::info_span!("write", shard).in_scope(|| {
+                    tracing::info_span!(target: "noisy", "noisy_span").in_scope(|| {
+                        tracing::info!(target: "noisy", "noisy_event");
+                    });
+                });
+                tracing::info!(queue_len = shard, "sampled");
+            }
+        });
+        trace.assert_no_slice("noisy_span");
+        // Only the sampled events, none of the noisy ones.
+        assert_eq!(trace.instants.len(), 3);
+        for write in trace.slices_named("write") {
+            assert_eq!(write.track, "shards");
+            assert_eq!(write.categories, ["storage"]);
+        }
+        let samples: Vec<_> = trace
+            .counters
+            .iter()
+            .map(|sample| (sample.track.as_str(), sample.value))
+            .collect();
+        assert_eq!(
+            samples,
+            [("queue_len", 0.0), ("queue_len", 1.0), ("queue_len", 2.0)]
+        );
+    }
+
+    #[test]
+    fn args_budget() {
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .args_budget(200);
+        let mut controller = None;
+        let trace = crate::test::capture_with(builder, || {
+            controller = crate::TraceController::current();
+            for _ in 0..50 {
+                tracing::info!(payload = "0123456789abcdef0123456789abcdef", "sampled");
+            }
+        });
+        let kept = trace
+            .instants
+            .iter()
+            .filter(|instant| instant.arg("payload").is_some())
+            .count();
+        assert!((1..50).contains(&kept), "{} payloads", kept);
+        assert_eq!(controller.unwrap().stats().args_omitted, 50 - kept as u64);
+        assert!(trace
+            .counters
+            .iter()
+            .any(|sample| sample.track == "omitted args"));
+    }
+
+    #[test]
+    fn spill_to_disk() {
+        let dir = "test-spill-to-disk";
+        std::fs::create_dir_all(dir).unwrap();
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .spill_to_disk(dir, 0);
+        let trace = crate::test::capture_with(builder, || {
+            for _ in 0..2000 {
+                tracing::info!(payload = "spilled payload", "event");
+            }
+        });
+        // Whether spilled or not, every event is in the trace, in order of
+        // the sequence.
+        let payloads = trace
+            .instants
+            .iter()
+            .filter(|instant| instant.arg("payload") == Some(&"spilled payload".into()))
+            .count();
+        assert_eq!(payloads, 2000);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn os_thread_ids() {
+        let builder = PerfettoLayerBuilder::new()
+            .pool_threads("pool")
+            .os_thread_ids(true);
+        let mut tid = None;
+        let trace = crate::test::capture_bytes(builder, || {
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            let spawn = |name: &str| {
+                let dispatch = dispatch.clone();
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn(move || {
+                        tracing::dispatcher::with_default(&dispatch, || fibonacci(1));
+                        crate::sched::os_thread_id()
+                    })
+                    .unwrap()
+                    .join()
+                    .unwrap()
+            };
+            tid = spawn("worker");
+            spawn("pool-1");
+        });
+
+        // The track keeps its name, and only the worker's track is
+        // described by its OS thread.
+        assert!(contains(&trace, b"\x12\x08worker 0"));
+        assert!(!contains(&trace, b"\x2a\x06pool-1"));
+        let Some(tid) = tid else { return };
+        // `ThreadDescriptor.tid` and `thread_name`.
+        let mut descriptor = vec![0x10];
+        let mut value = tid;
+        while value >= 0x80 {
+            descriptor.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        descriptor.push(value as u8);
+        descriptor.extend(b"\x2a\x06worker");
+        assert!(contains(&trace, &descriptor));
+    }
+
+    #[test]
+    fn unwound_spans() {
+        #[tracing::instrument]
+        fn fails() {
+            panic!("expected");
+        }
+
+        let trace = crate::test::capture(|| {
+            tracing::info_span!("fine").in_scope(|| {});
+            assert!(std::panic::catch_unwind(fails).is_err());
+        });
+
+        // The end of the failed slice has an `unwound = true` argument, and
+        // is followed by an "unwound" instant naming the span.
+        trace.slice("fails").assert_arg("unwound", true);
+        assert_eq!(trace.slice("fine").arg("unwound"), None);
+        assert_eq!(trace.instants_named("unwound").count(), 1);
+        trace.instant("unwound").assert_arg("span", "fails");
+    }
 
-                em.nested(1, |out| msg0.emit(out));
-                em.nested(1, |out| msg1.emit(out));
-                writer.write(em.as_bytes()).unwrap();
+    #[test]
+    fn short_lived_thread_flushed() {
+        use std::time::{Duration, Instant};
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-short-lived-thread.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new().file(path).build();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(perfetto_layer));
+        let worker = dispatch.clone();
+        std::thread::Builder::new()
+            .name("short-lived".to_string())
+            .spawn(move || tracing::dispatcher::with_default(&worker, || fibonacci(1)))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        // The thread's data is on disk once it exited, while the trace
+        // keeps running.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut bytes = std::fs::read(path).unwrap();
+        while !contains(&bytes, "thread exited") {
+            assert!(
+                Instant::now() < deadline,
+                "the thread's data was not flushed"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+            bytes = std::fs::read(path).unwrap();
+        }
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slice("fibonacci").track, "short-lived 0");
+        trace.slice("fibonacci").assert_duration(..);
+        drop(dispatch);
+        drop(handle);
+    }
+
+    #[test]
+    fn recycle_thread_ids() {
+        let builder = PerfettoLayerBuilder::new().recycle_thread_ids(true);
+        let trace = crate::test::capture_with(builder, || {
+            for _ in 0..2 {
+                let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+                std::thread::Builder::new()
+                    .name("short-lived".to_string())
+                    .spawn(move || tracing::dispatcher::with_default(&dispatch, || fibonacci(1)))
+                    .unwrap()
+                    .join()
+                    .unwrap();
             }
+        });
 
-            Message::Enter(timestamp, name, debug_info, thread_id) => {
-                let (name_iid, added) = names[thread_id as usize].event_name(&name);
-                let interned_data = if added {
-                    Some(InternedData {
-                        event_names: vec![EventName {
-                            iid: name_iid,
-                            name: name.to_string(),
-                        }],
+        // Both threads got id 0, each with its own track and exit marker.
+        let tracks: Vec<_> = trace
+            .slices_named("fibonacci")
+            .map(|slice| &slice.track)
+            .collect();
+        assert_eq!(tracks, ["short-lived 0", "short-lived 0"]);
+        assert_eq!(trace.instants_named("thread exited").count(), 2);
+    }
+
+    #[test]
+    fn pool_and_max_threads() {
+        let builder = PerfettoLayerBuilder::new()
+            .pool_threads("request")
+            .max_threads(2);
+        let trace = crate::test::capture_with(builder, || {
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            let spawn = |name: String| {
+                let dispatch = dispatch.clone();
+                std::thread::Builder::new()
+                    .name(name)
+                    .spawn(move || {
+                        tracing::dispatcher::with_default(&dispatch, || {
+                            tracing::info_span!("work").in_scope(|| tracing::info!("working"))
+                        })
                     })
-                } else {
-                    None
-                };
+                    .unwrap()
+                    .join()
+                    .unwrap();
+            };
+            for i in 0..3 {
+                spawn(format!("request-{}", i));
+            }
+            for i in 0..3 {
+                spawn(format!("other-{}", i));
+            }
+        });
 
-                let msg = TracePacket {
-                    timestamp,
-                    sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
-                    data: PacketData::TrackEvent(TrackEvent {
-                        event_type: packet::EventType::SliceBegin,
-                        name: packet::IString::Interned(name_iid),
-                        debug_annotations: if let Some(info) = debug_info {
-                            info.deref().to_vec()
-                        } else {
-                            Vec::new()
-                        }, // debug_annotations: vec![DebugAnnotation {
-                           //     name: packet::IString::Plain("hello".to_string()),
-                           //     value: packet::DebugValue::Uint(42),
-                           // }]
-                           // debug_annotations: vec![DebugAnnotation {
-                           //     name: packet::IString::Plain("blah".to_string()),
-                           //     value: packet::DebugValue::Array(vec![
-                           //         packet::DebugValue::Int(32)
-                           //     ])
-                           // }]
-                    }),
-                    trusted_uid,
-                    trusted_packet_sequence_id: 1 + thread_id,
-                    interned_data,
-                    trace_packet_defaults: None,
-                };
+        // All requests ran on the one track of the pool. The first other
+        // thread got the last track, the rest share one, where only their
+        // events are recorded.
+        let slices: Vec<_> = trace
+            .slices_named("work")
+            .map(|slice| &slice.track)
+            .collect();
+        assert_eq!(slices, ["request 0", "request 0", "request 0", "other-0 1"]);
+        let events: Vec<_> = trace
+            .instants
+            .iter()
+            .filter(|instant| instant.name.starts_with("event "))
+            .map(|instant| &instant.track)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "request 0",
+                "request 0",
+                "request 0",
+                "other-0 1",
+                "other threads",
+                "other threads"
+            ]
+        );
+        // Threads on the shared track aren't marked as exited.
+        let exits: Vec<_> = trace
+            .instants_named("thread exited")
+            .map(|instant| &instant.track)
+            .collect();
+        assert_eq!(exits, ["request 0", "request 0", "request 0", "other-0 1"]);
+    }
+
+    #[test]
+    fn provided_thread_ids() {
+        thread_local! {
+            static SHARD: std::cell::Cell<Option<u32>> = const { std::cell::Cell::new(None) };
+        }
 
-                em.nested(1, |out| msg.emit(out));
-                writer.write(em.as_bytes()).unwrap();
-            }
-            Message::Exit(timestamp, name, thread_id) => {
-                let (name_iid, added) = names[thread_id as usize].event_name(&name);
-                let interned_data = if added {
-                    Some(InternedData {
-                        event_names: vec![EventName {
-                            iid: name_iid,
-                            name: name.to_string(),
-                        }],
+        let builder = PerfettoLayerBuilder::new().thread_ids(|| SHARD.with(|shard| shard.get()));
+        let trace = crate::test::capture_with(builder, || {
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            let spawn = |shard: Option<u32>| {
+                let dispatch = dispatch.clone();
+                std::thread::Builder::new()
+                    .name("pool".to_string())
+                    .spawn(move || {
+                        SHARD.with(|cell| cell.set(shard));
+                        tracing::dispatcher::with_default(&dispatch, || {
+                            tracing::info_span!("task").in_scope(|| {})
+                        })
                     })
-                } else {
-                    None
-                };
+                    .unwrap()
+                    .join()
+                    .unwrap();
+            };
+            spawn(Some(10));
+            spawn(Some(10));
+            spawn(Some(11));
+            spawn(None);
+            // Your ids don't coincide with the ids of other threads.
+            spawn(Some(2));
+        });
 
-                let msg = TracePacket {
-                    timestamp,
-                    sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
-                    data: PacketData::TrackEvent(TrackEvent {
-                        event_type: packet::EventType::SliceEnd,
-                        name: packet::IString::Interned(name_iid), // packet::IString::Plain(name),
-                        debug_annotations: Vec::new(),
-                    }),
-                    trusted_uid,
-                    trusted_packet_sequence_id: 1 + thread_id as u32,
-                    interned_data,
-                    trace_packet_defaults: None,
-                };
+        // Both threads of shard 10 recorded onto one track, without a
+        // thread exit in between. Only the thread without a shard exited.
+        let tracks: Vec<_> = trace
+            .slices_named("task")
+            .map(|slice| slice.track.as_str())
+            .collect();
+        assert_eq!(
+            tracks,
+            ["worker 10", "worker 10", "worker 11", "pool 2", "worker 2"]
+        );
+        let exits: Vec<_> = trace
+            .instants_named("thread exited")
+            .map(|instant| instant.track.as_str())
+            .collect();
+        assert_eq!(exits, ["pool 2"]);
+    }
+
+    #[test]
+    fn unit_hints() {
+        let builder = PerfettoLayerBuilder::new()
+            .unit_hint("latency_ms", crate::Unit::DurationMs)
+            .unit_hint("payload", crate::Unit::Bytes);
+        let bytes = crate::test::capture_bytes(builder, || {
+            tracing::info!(latency_ms = 12, payload = 1.5, other = 7, "request done");
+            tracing::info!(latency_ms = 3, "request done");
+            tracing::info!(latency_ms = "n/a", "request failed");
+        });
+
+        // A track per hinted field, with the unit in its descriptor.
+        let descriptor = |unit: u8| [8 << 3 | 2, 0x82, 0, 3 << 3, unit];
+        assert_eq!(count(&bytes, &descriptor(1)), 1);
+        assert_eq!(count(&bytes, &descriptor(3)), 1);
+        // The samples are scaled to the unit of the trace, and the payload
+        // rides along on the latency's.
+        let trace = crate::test::Trace::parse(&bytes);
+        let samples: Vec<_> = trace
+            .counters
+            .iter()
+            .map(|sample| (sample.track.as_str(), sample.value))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                ("latency_ms", 12_000_000.0),
+                ("payload", 1.5),
+                ("latency_ms", 3_000_000.0)
+            ]
+        );
+        assert_eq!(trace.counters[0].timestamp, trace.counters[1].timestamp);
+    }
+
+    #[test]
+    fn counter_fields() {
+        let builder = PerfettoLayerBuilder::new()
+            .counter_field("my_crate::net", "bytes_sent")
+            .counter_field("my_crate::net", "latency")
+            .unit_hint("latency", crate::Unit::DurationNs);
+        let bytes = crate::test::capture_bytes(builder, || {
+            tracing::info!(target: "my_crate::net::tcp", bytes_sent = 1500, "sent");
+            tracing::info!(target: "my_crate::net", latency = 250, "acked");
+            tracing::info!(target: "my_crate::disk", written_bytes = 4096, "written");
+        });
+
+        let trace = crate::test::Trace::parse(&bytes);
+        let samples: Vec<_> = trace
+            .counters
+            .iter()
+            .map(|sample| (sample.track.as_str(), sample.value))
+            .collect();
+        assert_eq!(samples, [("bytes_sent", 1500.0), ("latency", 250.0)]);
+        // A plain counter without a unit hint, and one with.
+        let descriptor = |name: &str, counter: &[u8]| {
+            [&[2 << 3 | 2, name.len() as u8], name.as_bytes(), counter].concat()
+        };
+        assert!(contains(
+            &bytes,
+            &descriptor("bytes_sent", &[8 << 3 | 2, 0])
+        ));
+        assert!(contains(
+            &bytes,
+            &descriptor("latency", &[8 << 3 | 2, 0x82, 0, 3 << 3, 1])
+        ));
+    }
+
+    #[test]
+    fn counter_scopes() {
+        use crate::ids::{thread_track_uuid, PROCESS_TRACK_UUID};
 
-                em.nested(1, |out| msg.emit(out));
-                writer.write(em.as_bytes()).unwrap();
+        let builder = PerfettoLayerBuilder::new()
+            .counter_field("alloc", "allocated")
+            .counter_field("alloc", "cache_size")
+            .counter_scope("allocated", crate::CounterScope::Thread);
+        let record = || tracing::info!(target: "alloc", allocated = 4096, cache_size = 10);
+        let trace = crate::test::capture_bytes(builder, || {
+            record();
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::spawn(move || tracing::dispatcher::with_default(&dispatch, record))
+                .join()
+                .unwrap();
+            record();
+        });
+
+        // `TrackDescriptor.parent_uuid` followed by the name.
+        let descriptor = |parent: u64, name: &str| {
+            let mut bytes = vec![5 << 3];
+            let mut value = parent;
+            while value >= 0x80 {
+                bytes.push(value as u8 | 0x80);
+                value >>= 7;
             }
+            bytes.push(value as u8);
+            bytes.extend([2 << 3 | 2, name.len() as u8]);
+            bytes.extend(name.as_bytes());
+            bytes
+        };
+        assert_eq!(count(&trace, b"cache_size"), 1);
+        assert_eq!(
+            count(&trace, &descriptor(PROCESS_TRACK_UUID, "cache_size")),
+            1
+        );
+        assert_eq!(count(&trace, b"allocated"), 2);
+        for thread_id in 0..2 {
+            assert_eq!(
+                count(
+                    &trace,
+                    &descriptor(thread_track_uuid(thread_id), "allocated")
+                ),
+                1
+            );
+        }
+    }
 
-            Message::Event(timestamp, name, debug_info, thread_id) => {
-                let (name_iid, added) = names[thread_id as usize].event_name(&name);
-                let interned_data = if added {
-                    Some(InternedData {
-                        event_names: vec![EventName {
-                            iid: name_iid,
-                            name: name.to_string(),
-                        }],
-                    })
-                } else {
-                    None
-                };
+    #[test]
+    fn counters_share_packet() {
+        let builder = PerfettoLayerBuilder::new()
+            .counter_field("sampler", "rss")
+            .counter_field("sampler", "cpu")
+            .counter_field("sampler", "fds");
+        let trace = crate::test::capture_bytes(builder, || {
+            tracing::info!(target: "sampler", rss = 1 << 20, cpu = 0.5, fds = 12);
+        });
 
-                let msg = TracePacket {
-                    timestamp,
-                    sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
-                    data: PacketData::TrackEvent(TrackEvent {
-                        event_type: packet::EventType::Instant,
-                        name: packet::IString::Interned(name_iid),
-                        debug_annotations: if let Some(info) = debug_info {
-                            info.deref().to_vec()
-                        } else {
-                            Vec::new()
-                        },
-                    }),
-                    trusted_uid,
-                    trusted_packet_sequence_id: 1 + thread_id,
-                    interned_data,
-                    trace_packet_defaults: None,
-                };
+        // One counter event, with an extra integer and double counter.
+        assert_eq!(count(&trace, &[9 << 3, 4]), 1);
+        assert_eq!(count(&trace, &[0xf8, 0x01]), 1);
+        assert_eq!(count(&trace, &[0xe8, 0x02]), 1);
+    }
+
+    #[test]
+    fn control_handle() {
+        use tracing_subscriber::Registry;
+
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .enabled(false);
+        let trace = crate::test::capture_with(builder, || {
+            let control = tracing::dispatcher::get_default(|dispatch| {
+                crate::PerfettoLayer::<Registry>::from_dispatch(dispatch)
+                    .unwrap()
+                    .control_handle()
+            });
+            tracing::info!(name: "off", "not recorded");
+            tracing::info_span!("outer").in_scope(|| {
+                control.enable();
+                tracing::info_span!("inner", n = 1).in_scope(|| {
+                    tracing::info!(name: "on", "recorded");
+                });
+            });
+            tracing::info_span!("straddle").in_scope(|| control.disable());
+            tracing::info_span!("disabled").in_scope(|| {});
+            assert!(!control.is_enabled());
+        });
+
+        let names: Vec<_> = trace.slices.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["inner", "straddle"]);
+        assert!(trace.slices.iter().all(|slice| slice.end.is_some()));
+        trace.slice("inner").assert_arg("n", 1);
+        let names: Vec<_> = trace.instants.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["on"]);
+    }
+
+    #[test]
+    fn start_triggers() {
+        use crate::{Trigger, TriggerHandle};
+
+        let trigger = TriggerHandle::new();
+        let builder = PerfettoLayerBuilder::new().start_trigger(Trigger::Manual(trigger.clone()));
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("startup").in_scope(|| {
+                tracing::info_span!("loading").in_scope(|| {});
+                trigger.fire();
+                tracing::info_span!("serving").in_scope(|| {});
+            });
+        });
+        // The exit of the span entered before the start has no slice end.
+        let names: Vec<_> = trace.slices.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["serving"]);
+        trace.slice("serving").assert_duration(..);
+
+        let builder = PerfettoLayerBuilder::new()
+            .start_trigger(Trigger::OnEventNamed("warmup_done".to_string()));
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("preheat").in_scope(|| {});
+            tracing::info!(name: "warmup_done", "ready");
+            tracing::info_span!("request").in_scope(|| {});
+        });
+        trace.assert_no_slice("preheat");
+        trace.instant("warmup_done");
+        trace.slice("request");
+    }
+
+    #[test]
+    fn snapshots() {
+        use crate::SnapshotTrigger;
+        use std::time::Duration;
+
+        let builder = PerfettoLayerBuilder::new().include_args(true).snapshot(
+            SnapshotTrigger::Level(tracing::Level::ERROR),
+            Duration::from_millis(50),
+            Duration::from_millis(50),
+        );
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("alpha").in_scope(|| {});
+            std::thread::sleep(Duration::from_millis(200));
+            tracing::info_span!("bravo").in_scope(|| {});
+            tracing::warn!("not yet");
+            tracing::error!("boom");
+            tracing::info_span!("charlie").in_scope(|| {});
+            std::thread::sleep(Duration::from_millis(200));
+            tracing::info_span!("delta").in_scope(|| {});
+        });
+
+        let names: Vec<_> = trace.slices.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["bravo", "charlie"]);
+        let messages: Vec<_> = trace
+            .instants
+            .iter()
+            .map(|instant| instant.arg("message").unwrap())
+            .collect();
+        assert_eq!(messages, [&"not yet".into(), &"boom".into()]);
+    }
+
+    #[test]
+    fn finish_with_timeout() {
+        use crate::FlushError;
+        use std::io;
+        use std::time::Duration;
+        use tracing_subscriber::prelude::*;
 
-                em.nested(1, |out| msg.emit(out));
-                writer.write(em.as_bytes()).unwrap();
+        let path = "test-finish-timeout.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new().file(path).build();
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("work").in_scope(|| {});
+        });
+        let stats = handle.finish_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(stats.file_size, std::fs::metadata(path).unwrap().len());
+
+        /// An output that hangs like a stuck disk.
+        struct Stuck;
+
+        impl io::Write for Stuck {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                std::thread::sleep(Duration::from_secs(1));
+                Ok(buf.len())
             }
 
-            Message::Drop => break,
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
         }
-        writer.flush().unwrap();
+
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new().base64_output(Stuck).build();
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("work").in_scope(|| {});
+        });
+        let start = std::time::Instant::now();
+        let result = handle.finish_timeout(Duration::from_millis(50));
+        assert!(matches!(result, Err(FlushError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_millis(900));
     }
-}
 
-#[test]
-fn basic() {
-    use tracing::info_span;
-    use tracing_subscriber::prelude::*;
+    #[test]
+    fn stop_after_budget() {
+        use std::time::Duration;
+        use tracing_subscriber::prelude::*;
 
-    let (perfetto_layer, _handle) = PerfettoLayerBuilder::new()
-        .file("test-basic.perfetto-trace")
-        .build();
-    tracing_subscriber::registry().with(perfetto_layer).init();
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .stop_after_bytes(2000);
+        let bytes = crate::test::capture_bytes(builder, || {
+            for i in 0..1000 {
+                tracing::info!(i, "{}", format!("message {:04}", i));
+            }
+        });
+        assert!(bytes.len() < 2100, "{} bytes", bytes.len());
+        let trace = crate::test::Trace::parse(&bytes);
+        trace.instants[0].assert_arg("message", "message 0000");
+        assert!(trace.instants.len() < 1000);
 
-    let span = info_span!("hello world").entered();
-    println!("blah");
-    span.exit();
-}
+        let path = "test-stop-after.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file(path)
+            .stop_after(Duration::from_millis(50))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(perfetto_layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("early").in_scope(|| {});
+            std::thread::sleep(Duration::from_millis(300));
+            tracing::info_span!("late").in_scope(|| {});
+        });
+        let file = handle.into_inner().unwrap();
+        assert!(file.metadata().unwrap().len() > 0);
+        let trace = std::fs::read(path).unwrap();
+        assert!(contains(&trace, "early"));
+        assert!(!contains(&trace, "late"));
+    }
 
-#[cfg(test)]
-mod tests {
-    use tracing::instrument;
-    //use tracing_subscriber::prelude::*;
+    #[test]
+    fn flush_hints() {
+        // Threads that don't record to a layer ignore the hint.
+        crate::flush_hint();
 
-    use crate::PerfettoLayerBuilder;
+        let builder = PerfettoLayerBuilder::new().flush_hint_threshold(0);
+        let bytes = crate::test::capture_bytes(builder, || {
+            for frame in 0..100 {
+                tracing::info_span!("frame", frame).in_scope(|| {});
+                crate::flush_hint();
+            }
+        });
 
-    #[instrument]
-    fn fibonacci(n: usize) -> usize {
-        if n < 2 {
-            n
-        } else {
-            fibonacci(n - 1) + fibonacci(n - 2)
+        // Flushing in between doesn't disturb the trace, nor the interned
+        // names.
+        assert_eq!(count(&bytes, "frame"), 1);
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slices_named("frame").count(), 100);
+        assert!(trace.slices.iter().all(|slice| slice.end.is_some()));
+    }
+
+    #[test]
+    fn scoped_subscribers() {
+        use crate::test::{capture_with, Trace};
+
+        let builder = || PerfettoLayerBuilder::new().include_args(true);
+        let recorded = |trace: &Trace| {
+            trace
+                .instants
+                .iter()
+                .map(|instant| instant.arg("subscriber").unwrap().clone())
+                .collect::<Vec<_>>()
+        };
+        // One subscriber after another on the same thread, and one nested
+        // in another.
+        let first = capture_with(builder(), || tracing::info!(subscriber = "first"));
+        let mut inner = None;
+        let second = capture_with(builder(), || {
+            tracing::info!(subscriber = "second");
+            inner = Some(capture_with(builder(), || {
+                tracing::info!(subscriber = "inner")
+            }));
+            tracing::info!(subscriber = "second again");
+        });
+
+        assert_eq!(recorded(&first), ["first".into()]);
+        assert_eq!(recorded(&second), ["second".into(), "second again".into()]);
+        assert_eq!(recorded(&inner.unwrap()), ["inner".into()]);
+    }
+
+    #[test]
+    fn span_entered_on_other_threads() {
+        let builder = PerfettoLayerBuilder::new().include_args(true);
+        let trace = crate::test::capture_with(builder, || {
+            let span = tracing::info_span!("shared span", answer = 42);
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            let workers: Vec<_> = (0..4)
+                .map(|i| {
+                    let dispatch = dispatch.clone();
+                    let span = span.clone();
+                    std::thread::Builder::new()
+                        .name(format!("worker-{}", i))
+                        .spawn(move || {
+                            tracing::dispatcher::with_default(&dispatch, || {
+                                let _entered = span.enter();
+                            })
+                        })
+                        .unwrap()
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        });
+
+        // A slice on the track of each thread that entered the span.
+        let mut tracks: Vec<_> = trace
+            .slices_named("shared span")
+            .map(|slice| {
+                slice.assert_arg("answer", 42);
+                slice.track.split(' ').next().unwrap()
+            })
+            .collect();
+        tracks.sort();
+        assert_eq!(tracks, ["worker-0", "worker-1", "worker-2", "worker-3"]);
+    }
+
+    #[test]
+    fn inherit_fields() {
+        let builder = PerfettoLayerBuilder::new().inherit_fields(["request_id"]);
+        let trace = crate::test::capture_with(builder, || {
+            let _request =
+                tracing::info_span!("request", request_id = "req-7", user = "alice").entered();
+            let _handler = tracing::info_span!("handler").entered();
+            let _query = tracing::info_span!("query").entered();
+            let _other = tracing::info_span!("other request", request_id = "req-8").entered();
+        });
+
+        // The selected fields are recorded on the span and its descendants,
+        // and only they without `include_args`.
+        for name in ["request", "handler", "query"] {
+            trace.slice(name).assert_arg("request_id", "req-7");
         }
+        trace
+            .slice("other request")
+            .assert_arg("request_id", "req-8");
+        assert_eq!(trace.slice("request").arg("user"), None);
     }
 
     #[test]
-    fn fib() {
+    fn span_budget() {
+        use std::time::Duration;
+        let builder = PerfettoLayerBuilder::new()
+            .span_budget("db.query", Duration::from_millis(1))
+            .span_budget("cache.get", Duration::from_secs(10));
+        let trace = crate::test::capture_with(builder, || {
+            let query = tracing::info_span!("db.query");
+            for _ in 0..2 {
+                let _entered = query.enter();
+                std::thread::sleep(Duration::from_millis(3));
+            }
+            tracing::info_span!("cache.get").in_scope(|| {});
+            drop(query);
+        });
+
+        let exceeded: Vec<_> = trace.instants_named("budget exceeded").collect();
+        assert_eq!(exceeded.len(), 2);
+        for instant in exceeded {
+            instant.assert_arg("span", "db.query");
+            assert!(instant.arg("overage_ns").is_some());
+        }
+    }
+
+    #[test]
+    fn min_span_duration() {
+        use std::time::Duration;
+        let builder = PerfettoLayerBuilder::new().min_span_duration(Duration::from_millis(5));
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("slow").in_scope(|| {
+                for _ in 0..10 {
+                    tracing::info_span!("fast").in_scope(|| {});
+                }
+                tracing::info_span!("slow child").in_scope(|| {
+                    std::thread::sleep(Duration::from_millis(10));
+                });
+            });
+        });
+
+        trace
+            .slice("slow")
+            .assert_duration(Duration::from_millis(10)..);
+        trace.slice("slow child");
+        trace.assert_no_slice("fast");
+    }
+
+    #[test]
+    fn slowest_spans() {
+        use std::time::Duration;
+        let builder = PerfettoLayerBuilder::new()
+            .include_args(true)
+            .slowest_spans(2);
+        let trace = crate::test::capture_with(builder, || {
+            for i in [0, 5, 1, 4, 2] {
+                let label = format!("run {}", i);
+                tracing::info_span!("soak", label = label.as_str()).in_scope(|| {
+                    std::thread::sleep(Duration::from_millis(3 * i));
+                });
+            }
+        });
+
+        // Only the two slowest runs are kept, and the rest are summed up.
+        let labels: Vec<_> = trace
+            .slices_named("soak")
+            .map(|slice| slice.arg("label").unwrap())
+            .collect();
+        assert_eq!(labels.len(), 2);
+        for kept in ["run 4", "run 5"] {
+            assert!(labels.contains(&&kept.into()));
+        }
+        trace
+            .instant("dropped entries")
+            .assert_arg("span", "soak")
+            .assert_arg("dropped", 3u64);
+        assert_eq!(trace.instant("dropped entries").track, "span summaries");
+    }
+
+    #[test]
+    fn file_handle() {
+        use std::io::{Read, Seek, SeekFrom};
         use tracing_subscriber::prelude::*;
 
-        let (perfetto_layer, _handle) = PerfettoLayerBuilder::new()
-            .file("test-fib.perfetto-trace")
+        let path = "test-file-handle.perfetto-trace";
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new().file_handle(file).build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("handed over").in_scope(|| {});
+        drop(default);
+        let mut file = handle.into_inner().unwrap();
+
+        let mut trace = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut trace).unwrap();
+        assert!(contains(&trace, "handed over"));
+    }
+
+    #[test]
+    fn writer() {
+        use std::{
+            io,
+            sync::{Arc, Mutex},
+        };
+        use tracing_subscriber::prelude::*;
+
+        /// Records what is written, and whether it was dropped.
+        struct Stream(Arc<Mutex<(Vec<u8>, bool)>>);
+
+        impl io::Write for Stream {
+            fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().0.extend_from_slice(data);
+                Ok(data.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl Drop for Stream {
+            fn drop(&mut self) {
+                self.0.lock().unwrap().1 = true;
+            }
+        }
+
+        let stream = Arc::new(Mutex::new((Vec::new(), false)));
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .writer(Stream(stream.clone()))
             .build();
-        tracing_subscriber::registry().with(perfetto_layer).init();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        tracing::info_span!("streamed").in_scope(|| {});
+        drop(default);
+        drop(handle);
 
-        fibonacci(6);
+        let (trace, dropped) = &*stream.lock().unwrap();
+        assert!(dropped);
+        // Trace.packet
+        assert_eq!(trace[0], 0x0a);
+        assert!(contains(trace, "streamed"));
+    }
+
+    #[test]
+    fn unwritable_output() {
+        use std::sync::{mpsc, Arc};
+        use tracing_subscriber::prelude::*;
+
+        let (tx, rx) = mpsc::channel();
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file("no-such-dir/test-unwritable.perfetto-trace")
+            .on_error(move |err| tx.send(err.kind()).unwrap())
+            .build();
+        let counters = Arc::clone(&perfetto_layer.counters);
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        let kind = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(kind, std::io::ErrorKind::NotFound);
+        // The layer is disabled, and keeps the application running.
+        assert!(counters.stopped());
+        tracing::info_span!("not recorded").in_scope(|| tracing::info!("nor this"));
+        drop(default);
+        drop(handle);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn max_file_size() {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::prelude::*;
+
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file("test-rotate.perfetto-trace")
+            .max_file_size(512)
+            .on_rotate({
+                let finished = finished.clone();
+                move |path| finished.lock().unwrap().push(path.to_path_buf())
+            })
+            .build();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        for i in 0..100 {
+            tracing::info_span!("rotating", i).in_scope(|| {});
+        }
+        drop(default);
+        drop(handle);
+
+        let finished = finished.lock().unwrap();
+        assert!(finished.len() > 1);
+        for (i, path) in finished.iter().enumerate() {
+            assert_eq!(
+                path.to_str().unwrap(),
+                format!("test-rotate.{}.perfetto-trace", i)
+            );
+            // Every file describes the thread track again.
+            let trace = std::fs::read(path).unwrap();
+            assert!(contains(&trace, "max_file_size"));
+        }
+    }
+
+    #[cfg(feature = "static-config")]
+    #[test]
+    fn full_queue_drops() {
+        use std::sync::{Arc, OnceLock};
+        use tracing_subscriber::prelude::*;
+
+        // Logging on the writer thread can't wait for the writer.
+        let dispatch = Arc::new(OnceLock::<tracing::Dispatch>::new());
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+            .file(std::env::temp_dir().join("test-full-queue.perfetto-trace"))
+            .max_file_size(512)
+            .on_rotate({
+                let dispatch = dispatch.clone();
+                move |_| {
+                    if let Some(dispatch) = dispatch.get() {
+                        tracing::dispatcher::with_default(dispatch, || {
+                            for i in 0..2 * crate::static_config::QUEUE_CAPACITY {
+                                tracing::info!(i, "rotated");
+                            }
+                        });
+                    }
+                }
+            })
+            .build();
+        let _ = dispatch.set(tracing_subscriber::registry().with(perfetto_layer).into());
+        tracing::dispatcher::with_default(dispatch.get().unwrap(), || {
+            for i in 0..100 {
+                tracing::info_span!("rotating", i).in_scope(|| {});
+            }
+        });
+        let stats = handle.stats_handle();
+        drop(handle);
+        assert!(stats.stats().messages_dropped > 0);
+    }
+
+    #[test]
+    fn events_before_new_thread() {
+        use crate::{
+            stats::Counters,
+            writer::{writer_thread, Output, WriterConfig},
+            Message,
+        };
+        use std::sync::Arc;
+
+        let out = crate::test::SharedBuffer::default();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let config = WriterConfig {
+            output: Some(Output::Writer(Box::new(out.clone()))),
+            counters: Arc::new(Counters::default()),
+            process_info: None,
+            container_info: None,
+            incremental_state_interval: None,
+            clock: Default::default(),
+            limits: Default::default(),
+            max_file_size: None,
+            on_rotate: None,
+            transform: None,
+            slowest_spans: None,
+            current_path: Default::default(),
+            self_trace: false,
+            flush_hint_threshold: 50,
+            stop_after_bytes: None,
+            stop_after: None,
+            write_stall_threshold: std::time::Duration::from_millis(100),
+            snapshot: None,
+            strict: false,
+            defers_slices: false,
+            sync_policy: crate::SyncPolicy::Never,
+            span_summary: None,
+            source_locations: false,
+            perf_counter: None,
+            intern_seed: Vec::new(),
+            export_intern_table: None,
+            append: false,
+            args_budget: None,
+            spill: None,
+            ring_buffer: None,
+            on_error: crate::writer::ErrorHook::default(),
+            shutdown_report: false,
+        };
+        let writer = std::thread::spawn(move || writer_thread(rx, config));
+        let messages = [
+            Message::Enter {
+                timestamp: 10,
+                name: "early".into(),
+                args: None,
+                thread_id: 3,
+                track: None,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                location: None,
+                category: None,
+                perf_count: None,
+            },
+            Message::NewThread(3, "late thread".to_string(), None),
+            Message::Exit {
+                timestamp: 20,
+                name: "early".into(),
+                thread_id: 3,
+                track: None,
+                perf_count: None,
+                args: None,
+                unwound: false,
+            },
+            Message::ThreadExit(5, 30),
+            Message::Drop,
+        ];
+        for msg in messages {
+            tx.send(msg).unwrap();
+        }
+        writer.join().unwrap().unwrap();
+
+        let bytes = out.0.lock().unwrap().clone();
+        // Thread 3 is described before its slice, and renamed once known.
+        assert!(contains(&bytes, "thread 3"));
+        let trace = crate::test::Trace::parse(&bytes);
+        assert_eq!(trace.slice("early").track, "late thread");
+        trace.slice("early").assert_duration(..);
+        assert_eq!(trace.instant("thread exited").track, "thread 5");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_task_tracks() {
+        let builder = PerfettoLayerBuilder::new();
+        let trace = crate::test::capture_with(builder, || {
+            // What tokio creates for `tokio::task::Builder::new().name("conn").spawn(..)`.
+            let task = tracing::trace_span!(
+                target: "tokio::task",
+                "runtime.spawn",
+                kind = "task",
+                task.name = "conn",
+                task.id = 7u64,
+            );
+            for _ in 0..2 {
+                let _poll = task.enter();
+            }
+            drop(task);
+        });
+
+        // The task has its own track, with a slice for its lifetime and one
+        // for each poll.
+        trace.slice("task alive").assert_duration(..);
+        let tracks: Vec<_> = trace.slices.iter().map(|slice| &slice.track).collect();
+        assert_eq!(tracks, ["conn (task 7)"; 3]);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn event_backtraces() {
+        use crate::test::Value;
+
+        #[inline(never)]
+        fn cache_miss_storm() {
+            tracing::warn!("cache misses");
+            tracing::info!("cache refilled");
+        }
+
+        let builder = PerfettoLayerBuilder::new().event_backtraces(tracing::Level::WARN, 4);
+        let trace = crate::test::capture_with(builder, cache_miss_storm);
+
+        // Only the warning has a backtrace, starting in the caller.
+        let Some(Value::Array(frames)) = trace.instants[0].arg("backtrace") else {
+            panic!("no backtrace in {:?}", trace.instants[0]);
+        };
+        let frames: Vec<_> = frames
+            .iter()
+            .map(|frame| match frame {
+                Value::String(frame) => frame.as_str(),
+                other => panic!("unexpected frame {:?}", other),
+            })
+            .collect();
+        let in_caller = frames
+            .iter()
+            .filter(|frame| frame.contains("tests::event_backtraces::cache_miss_storm ("))
+            .count();
+        assert_eq!(in_caller, 1, "{:?}", frames);
+        assert!(!frames
+            .iter()
+            .any(|frame| frame.contains("get_default") || frame.contains("on_event")));
+        assert_eq!(frames.len(), 4);
+        assert_eq!(trace.instants[1].arg("backtrace"), None);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn opentelemetry_context() {
+        use opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+        let builder = PerfettoLayerBuilder::new().opentelemetry_context(true);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("outside otel").in_scope(|| {});
+            let span_context = SpanContext::new(
+                TraceId::from(0x0af7651916cd43dd8448eb211c80319c),
+                SpanId::from(0xb7ad6b7169203331),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            );
+            let otel_guard = opentelemetry::Context::new()
+                .with_remote_span_context(span_context)
+                .attach();
+            tracing::info_span!("inside otel").in_scope(|| {});
+            drop(otel_guard);
+        });
+
+        assert_eq!(trace.slice("outside otel").arg("otel.trace_id"), None);
+        trace
+            .slice("inside otel")
+            .assert_arg("otel.trace_id", "0af7651916cd43dd8448eb211c80319c")
+            .assert_arg("otel.span_id", "b7ad6b7169203331");
+        // The flow joins the slices of the same OpenTelemetry trace.
+        assert_eq!(trace.slice("inside otel").flows, [0x8448eb211c80319c]);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn export_spans() {
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+        use std::time::Duration;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let builder = PerfettoLayerBuilder::new().export_spans(
+            provider.tracer("tracing-perfetto"),
+            Duration::from_millis(20),
+        );
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("slow", rows = 3u64).in_scope(|| {
+                std::thread::sleep(Duration::from_millis(25));
+                tracing::info_span!("fast").in_scope(|| {});
+            });
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "slow");
+        assert!(spans[0]
+            .attributes
+            .contains(&opentelemetry::KeyValue::new("rows", 3)));
+
+        // The trace still has all spans.
+        trace.slice("slow");
+        trace.slice("fast");
+    }
+
+    #[cfg(feature = "android-log")]
+    #[test]
+    fn android_log_packets() {
+        let builder = PerfettoLayerBuilder::new().android_log_packets(true);
+        let trace = crate::test::capture_bytes(builder, || {
+            tracing::info!(target: "app::net", "connected to peer");
+            tracing::warn!(target: "app::net", retries = 3, "slow handshake");
+        });
+
+        assert!(contains(&trace, "slow handshake retries=3"));
+        assert!(contains(&trace, "app::net"));
+        assert!(!contains(&trace, "connected to peer"));
+    }
+
+    #[test]
+    fn max_span_depth() {
+        let builder = PerfettoLayerBuilder::new().max_span_depth(2);
+        let trace = crate::test::capture_with(builder, || {
+            tracing::info_span!("level one").in_scope(|| {
+                tracing::info_span!("level two").in_scope(|| {
+                    tracing::info_span!("level three").in_scope(|| {
+                        tracing::info_span!("level four").in_scope(|| {});
+                    });
+                });
+                tracing::info_span!("second level two").in_scope(|| {});
+            });
+        });
+
+        // Each recorded slice ends.
+        for name in ["level one", "level two", "second level two"] {
+            trace.slice(name).assert_duration(..);
+        }
+        assert_eq!(trace.slices.len(), 3);
+    }
+
+    #[test]
+    fn empty_trace() {
+        let trace = crate::test::capture_bytes(PerfettoLayerBuilder::new(), || {});
+
+        let exe = std::env::current_exe().unwrap();
+        let name = exe.file_name().unwrap().to_str().unwrap();
+        assert!(contains(&trace, name.as_bytes()));
+        // A clock snapshot with the boottime and realtime clocks.
+        assert!(contains(&trace, &[0x08, 0x06, 0x10]));
+        assert!(contains(&trace, &[0x08, 0x01, 0x10]));
+    }
+
+    #[test]
+    fn stats_handle() {
+        use tracing_subscriber::prelude::*;
+
+        let path = "test-stats.perfetto-trace";
+        let (perfetto_layer, handle) = PerfettoLayerBuilder::new().file(path).build();
+        let stats = handle.stats_handle();
+        let default = tracing_subscriber::registry()
+            .with(perfetto_layer)
+            .set_default();
+        fibonacci(3);
+        drop(default);
+        drop(handle);
+
+        let stats = stats.stats();
+        assert_eq!(stats.file_size, std::fs::metadata(path).unwrap().len());
+        assert_eq!(stats.bytes_written, stats.file_size);
+        // File header (3 packets), thread header (2 packets), 5 spans with
+        // begin and end each.
+        assert_eq!(stats.packets_written, 15);
+        assert_eq!(stats.messages_queued, 0);
     }
 }
@@ -0,0 +1,125 @@
+//! Integration with OpenTelemetry.
+//!
+//! When a slice begins while an OpenTelemetry span is active, we record its
+//! trace and span ids as arguments, and connect all slices of the same
+//! distributed trace with a flow. Both `tracing-opentelemetry` (which
+//! activates the context of a span while it is entered) and code that
+//! attaches contexts manually are supported.
+//!
+//! We can also export long spans to an OpenTelemetry tracer, so only the
+//! Perfetto layer is needed to get both a detailed local trace and coarse
+//! distributed traces.
+
+use std::time::{Duration, SystemTime};
+
+use opentelemetry::{
+    global::BoxedTracer,
+    trace::{Span, SpanBuilder, TraceContextExt, TraceId, Tracer},
+    Context, KeyValue, Value,
+};
+
+use crate::packet::{DebugAnnotation, DebugValue, IString};
+
+pub const TRACE_ID_NAME: &str = "otel.trace_id";
+pub const SPAN_ID_NAME: &str = "otel.span_id";
+
+/// Ids of the active OpenTelemetry span.
+pub struct OtelIds {
+    pub trace_id: TraceId,
+    pub span_id: opentelemetry::SpanId,
+}
+
+impl OtelIds {
+    /// The ids of the span of the current OpenTelemetry context, if any.
+    pub fn current() -> Option<Self> {
+        let context = Context::current();
+        let span = context.span();
+        let span_context = span.span_context();
+        if !span_context.is_valid() {
+            return None;
+        }
+        Some(OtelIds {
+            trace_id: span_context.trace_id(),
+            span_id: span_context.span_id(),
+        })
+    }
+
+    pub fn debug_annotations(&self) -> [DebugAnnotation; 2] {
+        [
+            DebugAnnotation {
+                name: IString::Plain(TRACE_ID_NAME.to_string()),
+                value: DebugValue::String(self.trace_id.to_string()),
+            },
+            DebugAnnotation {
+                name: IString::Plain(SPAN_ID_NAME.to_string()),
+                value: DebugValue::String(self.span_id.to_string()),
+            },
+        ]
+    }
+
+    /// Flow id shared by all slices of the trace.
+    ///
+    /// Trace ids are random, so the low 64 bits are as good as the whole id.
+    pub fn flow_id(&self) -> u64 {
+        let bytes = self.trace_id.to_bytes();
+        u64::from_be_bytes(bytes[8..].try_into().unwrap())
+    }
+}
+
+/// Sends spans that took at least `min_duration` to an OpenTelemetry tracer,
+/// e.g. one exporting to an OTLP endpoint.
+pub struct SpanExport {
+    pub tracer: BoxedTracer,
+    pub min_duration: Duration,
+}
+
+/// What we need to know about a span to export it when it is closed.
+pub struct ExportExt {
+    start: SystemTime,
+    parent_cx: Context,
+    fields: Vec<DebugAnnotation>,
+}
+
+impl ExportExt {
+    pub fn new(fields: Vec<DebugAnnotation>) -> Self {
+        ExportExt {
+            start: SystemTime::now(),
+            parent_cx: Context::current(),
+            fields,
+        }
+    }
+}
+
+impl SpanExport {
+    /// Export a span that has just been closed, if it is long enough.
+    pub fn span_closed(&self, name: &'static str, ext: &ExportExt) {
+        let end = SystemTime::now();
+        let duration = end.duration_since(ext.start).unwrap_or_default();
+        if duration < self.min_duration {
+            return;
+        }
+        let attributes = ext.fields.iter().filter_map(|ann| match &ann.name {
+            IString::Plain(key) => Some(KeyValue::new(key.clone(), attribute_value(&ann.value))),
+            IString::Interned(_) => None,
+        });
+        let builder = SpanBuilder::from_name(name)
+            .with_start_time(ext.start)
+            .with_attributes(attributes);
+        let mut span = self.tracer.build_with_context(builder, &ext.parent_cx);
+        span.end_with_timestamp(end);
+    }
+}
+
+fn attribute_value(value: &DebugValue) -> Value {
+    match value {
+        DebugValue::Bool(b) => Value::Bool(*b),
+        DebugValue::Uint(n) => match i64::try_from(*n) {
+            Ok(n) => Value::I64(n),
+            Err(_) => Value::String(n.to_string().into()),
+        },
+        DebugValue::Int(n) => Value::I64(*n),
+        DebugValue::Double(d) => Value::F64(*d),
+        DebugValue::String(s) => Value::String(s.clone().into()),
+        other => Value::String(format!("{:?}", other).into()),
+    }
+}
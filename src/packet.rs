@@ -1,5 +1,8 @@
-use crate::emit::ProtoEmitter;
+use std::fmt;
 
+use crate::emit::{EmitError, ProtoEmitter};
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TracePacket {
     pub timestamp: u64,
     pub data: PacketData,
@@ -10,31 +13,137 @@ pub struct TracePacket {
     pub trace_packet_defaults: Option<TracePacketDefaults>, // = 59
 }
 
+/// Sequence flag of a packet after which the interning tables and defaults
+/// of its sequence start over, so readers can start parsing there.
 pub const SEQ_INCREMENTAL_STATE_CLEARED: u32 = 1;
+/// Sequence flag of a packet that refers to the interning tables or
+/// defaults of its sequence.
 pub const SEQ_NEEDS_INCREMENTAL_STATE: u32 = 2;
 
+/// A clock that packet timestamps can be taken from, see Perfetto's
+/// `BuiltinClock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    Realtime,
+    Monotonic,
+    /// `CLOCK_BOOTTIME`, which keeps counting while the system is suspended.
+    /// Used for the packets written by this crate, unless timestamps count
+    /// from the Unix epoch.
+    Boottime,
+    /// A clock defined by the trace, with ids 64 to 127 being scoped to the
+    /// packet sequence.
+    Custom(u32),
+}
+
+impl ClockId {
+    /// The id used in the trace.
+    pub fn id(self) -> u32 {
+        match self {
+            ClockId::Realtime => 1,
+            ClockId::Monotonic => 3,
+            ClockId::Boottime => 6,
+            ClockId::Custom(id) => id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PacketData {
     TrackEvent(TrackEvent),           // 11
     TrackDescriptor(TrackDescriptor), // 60
+    AndroidLog(AndroidLogPacket),     // 39
+    ClockSnapshot(ClockSnapshot),     // 6
     None,
 }
 
-#[derive(Debug, Clone)]
+/// The readings of several clocks at the same moment, which lets readers
+/// convert between them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockSnapshot {
+    pub clocks: Vec<(ClockId, u64)>,
+    /// The clock that timestamps are shown in.
+    pub primary_trace_clock: ClockId,
+}
+
+impl Emit for ClockSnapshot {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        for (clock_id, timestamp) in &self.clocks {
+            out.nested_small(1, |out| {
+                out.varint_field(1, clock_id.id() as u64);
+                out.varint_field(2, *timestamp);
+                Ok(())
+            })?;
+        }
+        out.varint_field(2, self.primary_trace_clock.id() as u64);
+        Ok(())
+    }
+}
+
+/// The process that a track belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessDescriptor {
+    pub pid: u32,
+    pub process_name: String,
+}
+
+impl Emit for ProcessDescriptor {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.pid as u64);
+        out.string_field(6, &self.process_name);
+        Ok(())
+    }
+}
+
+/// The OS thread that a track belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadDescriptor {
+    pub pid: u32,
+    pub tid: u32,
+    pub thread_name: String,
+}
+
+impl Emit for ThreadDescriptor {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.pid as u64);
+        out.varint_field(2, self.tid as u64);
+        out.string_field(5, &self.thread_name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IString {
     Plain(String),
     Interned(u64),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TrackEvent {
     pub event_type: EventType,
     pub name: IString,
     pub debug_annotations: Vec<DebugAnnotation>,
+    /// Overrides the track from the sequence's `TrackEventDefaults`.
+    pub track_uuid: Option<u64>, // 11
+    pub category_iids: Vec<u64>,          // 3
+    pub source_location_iid: Option<u64>, // 34
+    /// Connects this slice with all other slices with the same flow id.
+    pub flow_ids: Vec<u64>, // 47
+    /// Like `flow_ids`, but ends the flow at this slice.
+    pub terminating_flow_ids: Vec<u64>, // 48
+    /// The value of a `Counter` event.
+    pub counter_value: Option<i64>, // 30
+    pub double_counter_value: Option<f64>, // 44
+    /// Samples of further counter tracks at the same time, by track uuid.
+    pub extra_counters: Vec<(u64, i64)>, // 31, 12
+    pub extra_double_counters: Vec<(u64, f64)>, // 45, 46
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
     Instant,
     SliceBegin,
     SliceEnd,
+    Counter,
 }
 
 impl EventType {
@@ -43,69 +152,272 @@ impl EventType {
             EventType::Instant => 3,
             EventType::SliceBegin => 1,
             EventType::SliceEnd => 2,
+            EventType::Counter => 4,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TracePacketDefaults {
-    pub timestamp_clock_id: u32,                          // 58
+    pub timestamp_clock_id: ClockId,                      // 58
     pub track_event_defaults: Option<TrackEventDefaults>, // 11
 }
 
 impl Emit for TracePacketDefaults {
-    fn emit(&self, out: &mut ProtoEmitter) {
-        out.varint_field(58, self.timestamp_clock_id as u64);
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(58, self.timestamp_clock_id.id() as u64);
         if let Some(defaults) = self.track_event_defaults.as_ref() {
-            out.nested(11, |out| defaults.emit(out));
+            out.nested(11, |out| defaults.emit(out))?;
         }
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TrackEventDefaults {
     pub track_uuid: u64, // 11
 }
 
 impl Emit for TrackEventDefaults {
-    fn emit(&self, out: &mut ProtoEmitter) {
-        out.varint_field(11, self.track_uuid)
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(11, self.track_uuid);
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndroidLogPacket {
+    pub events: Vec<AndroidLogEvent>, // 1
+}
+
+impl Emit for AndroidLogPacket {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        for event in &self.events {
+            out.nested(1, |out| event.emit(out))?;
+        }
+        Ok(())
+    }
+}
+
+/// A logcat entry. `log_id` is always the main buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndroidLogEvent {
+    pub pid: i32,        // 2
+    pub timestamp: u64,  // 5
+    pub tag: String,     // 6
+    pub prio: u32,       // 7, AndroidLogPriority
+    pub message: String, // 8
+}
+
+impl Emit for AndroidLogEvent {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, 0); // LID_DEFAULT
+        out.varint_field(2, self.pid as u32 as u64);
+        out.varint_field(5, self.timestamp);
+        out.string_field(6, &self.tag);
+        out.varint_field(7, self.prio as u64);
+        out.string_field(8, &self.message);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TrackDescriptor {
     pub uuid: u64,
+    /// Nests the track below another one in the UI.
+    pub parent_uuid: Option<u64>, // 5
     pub name: String,
+    /// The unit of a counter track; `None` for a track of slices.
+    pub counter: Option<CounterUnit>, // 8
+    pub process: Option<ProcessDescriptor>, // 3
+    pub thread: Option<ThreadDescriptor>,   // 4
+}
+
+/// `CounterDescriptor.Unit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterUnit {
+    Unspecified,
+    TimeNs,
+    Count,
+    SizeBytes,
+}
+
+impl CounterUnit {
+    fn id(self) -> u64 {
+        match self {
+            CounterUnit::Unspecified => 0,
+            CounterUnit::TimeNs => 1,
+            CounterUnit::Count => 2,
+            CounterUnit::SizeBytes => 3,
+        }
+    }
 }
 
 impl Emit for TrackDescriptor {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         out.varint_field(1, self.uuid);
+        if let Some(parent_uuid) = self.parent_uuid {
+            out.varint_field(5, parent_uuid);
+        }
         out.string_field(2, &self.name);
+        if let Some(process) = &self.process {
+            out.nested(3, |out| process.emit(out))?;
+        }
+        if let Some(thread) = &self.thread {
+            out.nested(4, |out| thread.emit(out))?;
+        }
+        match self.counter {
+            None => {}
+            // An empty `CounterDescriptor` gives a plain counter.
+            Some(CounterUnit::Unspecified) => out.bytes_field(8, &[]),
+            Some(unit) => out.nested_small(8, |out| {
+                out.varint_field(3, unit.id());
+                Ok(())
+            })?,
+        }
+        Ok(())
     }
 }
 
+/// The interning sections that are relevant for track events. Perfetto also
+/// supports interning profiling and GPU data, which we never emit.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct InternedData {
-    pub event_names: Vec<EventName>,
+    pub event_categories: Vec<EventCategory>,             // 1
+    pub event_names: Vec<EventName>,                      // 2
+    pub debug_annotation_names: Vec<DebugAnnotationName>, // 3
+    pub source_locations: Vec<SourceLocation>,            // 4
+    pub log_message_body: Vec<LogMessageBody>,            // 20
+    pub debug_annotation_value_type_names: Vec<DebugAnnotationValueTypeName>, // 27
+    pub debug_annotation_string_values: Vec<InternedString>, // 29
+}
+
+impl InternedData {
+    pub fn is_empty(&self) -> bool {
+        self.event_categories.is_empty()
+            && self.event_names.is_empty()
+            && self.debug_annotation_names.is_empty()
+            && self.source_locations.is_empty()
+            && self.log_message_body.is_empty()
+            && self.debug_annotation_value_type_names.is_empty()
+            && self.debug_annotation_string_values.is_empty()
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventCategory {
+    pub iid: u64,
+    pub name: String,
+}
+
+impl Emit for EventCategory {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.iid);
+        out.string_field(2, &self.name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct EventName {
     pub iid: u64,
     pub name: String,
 }
 
 impl Emit for EventName {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.iid);
+        out.string_field(2, &self.name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub iid: u64,
+    pub file_name: String,
+    pub function_name: Option<String>,
+    pub line_number: u32,
+}
+
+impl Emit for SourceLocation {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.iid);
+        out.string_field(2, &self.file_name);
+        if let Some(function_name) = &self.function_name {
+            out.string_field(3, function_name);
+        }
+        out.varint_field(4, self.line_number as u64);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogMessageBody {
+    pub iid: u64,
+    pub body: String,
+}
+
+impl Emit for LogMessageBody {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.iid);
+        out.string_field(2, &self.body);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugAnnotationValueTypeName {
+    pub iid: u64,
+    pub name: String,
+}
+
+impl Emit for DebugAnnotationValueTypeName {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         out.varint_field(1, self.iid);
         out.string_field(2, &self.name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternedString {
+    pub iid: u64,
+    pub str: String,
+}
+
+impl Emit for InternedString {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        out.varint_field(1, self.iid);
+        out.bytes_field(2, self.str.as_bytes());
+        Ok(())
     }
 }
 
 impl Emit for InternedData {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
+        for category in &self.event_categories {
+            out.nested_small(1, |out| category.emit(out))?;
+        }
         for event_name in &self.event_names {
-            out.nested_small(2, |out| {
-                event_name.emit(out);
-            });
+            out.nested_small(2, |out| event_name.emit(out))?;
+        }
+        for name in &self.debug_annotation_names {
+            out.nested_small(3, |out| name.emit(out))?;
         }
+        for location in &self.source_locations {
+            out.nested_small(4, |out| location.emit(out))?;
+        }
+        for body in &self.log_message_body {
+            out.nested(20, |out| body.emit(out))?;
+        }
+        for name in &self.debug_annotation_value_type_names {
+            out.nested_small(27, |out| name.emit(out))?;
+        }
+        for value in &self.debug_annotation_string_values {
+            out.nested(29, |out| value.emit(out))?;
+        }
+        Ok(())
     }
 }
 
@@ -119,24 +431,60 @@ impl Emit for InternedData {
 // }
 
 pub trait Emit {
-    fn emit(&self, out: &mut ProtoEmitter);
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError>;
 }
 
 impl Emit for TrackEvent {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         out.varint_field(9, self.event_type.id());
+        if let Some(track_uuid) = self.track_uuid {
+            out.varint_field(11, track_uuid);
+        }
+        for iid in &self.category_iids {
+            out.varint_field(3, *iid);
+        }
+        if let Some(iid) = self.source_location_iid {
+            out.varint_field(34, iid);
+        }
         match &self.name {
+            // Counter samples go without a name.
+            IString::Plain(s) if s.is_empty() => {}
             IString::Plain(s) => out.string_field(23, s),
             IString::Interned(iid) => out.varint_field(10, *iid),
         }
         for debug_ann in &self.debug_annotations {
-            out.nested(4, |out| debug_ann.emit(out));
+            out.nested(4, |out| debug_ann.emit(out))?;
+        }
+        for flow_id in &self.flow_ids {
+            out.fixed64_field(47, *flow_id);
+        }
+        for flow_id in &self.terminating_flow_ids {
+            out.fixed64_field(48, *flow_id);
+        }
+        if let Some(value) = self.counter_value {
+            out.varint_field(30, value as u64);
+        }
+        if let Some(value) = self.double_counter_value {
+            out.double_field(44, value);
         }
+        for (track_uuid, _) in &self.extra_counters {
+            out.varint_field(31, *track_uuid);
+        }
+        for (_, value) in &self.extra_counters {
+            out.varint_field(12, *value as u64);
+        }
+        for (track_uuid, _) in &self.extra_double_counters {
+            out.varint_field(45, *track_uuid);
+        }
+        for (_, value) in &self.extra_double_counters {
+            out.double_field(46, *value);
+        }
+        Ok(())
     }
 }
 
 impl Emit for TracePacket {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         //let mut buf = ProtoEmitter::new();
         out.varint_field(8, self.timestamp);
         out.varint_field(3, self.trusted_uid as u32 as u64); // not sint32, so no zigzag
@@ -145,28 +493,35 @@ impl Emit for TracePacket {
         match &self.data {
             PacketData::None => (),
             PacketData::TrackEvent(ev) => {
-                out.nested(11, |out| ev.emit(out));
+                out.nested(11, |out| ev.emit(out))?;
                 // ev.emit(&mut buf);
                 // out.bytes_field(11, buf.as_bytes());
             }
+            PacketData::AndroidLog(log) => {
+                out.nested(39, |out| log.emit(out))?;
+            }
+            PacketData::ClockSnapshot(snapshot) => {
+                out.nested(6, |out| snapshot.emit(out))?;
+            }
             PacketData::TrackDescriptor(ev) => {
-                out.nested(60, |out| ev.emit(out));
+                out.nested(60, |out| ev.emit(out))?;
                 // ev.emit(&mut buf);
                 // out.bytes_field(60, buf.as_bytes());
             }
         }
         if let Some(interned_data) = self.interned_data.as_ref() {
-            out.nested(12, |out| interned_data.emit(out));
+            out.nested(12, |out| interned_data.emit(out))?;
             // buf.clear();
             // interned_data.emit(&mut buf);
             // out.bytes_field(12, buf.as_bytes());
         }
         if let Some(defaults) = self.trace_packet_defaults.as_ref() {
-            out.nested(59, |out| defaults.emit(out))
+            out.nested(59, |out| defaults.emit(out))?;
             // buf.clear();
             // defaults.emit(&mut buf);
             // out.bytes_field(59, buf.as_bytes());
         }
+        Ok(())
     }
 }
 
@@ -182,62 +537,394 @@ impl Emit for TracePacket {
 //     zigzag_encode_i32(val) as u64
 // }
 
-#[derive(Debug, Clone)]
-
+#[derive(Debug, Clone, PartialEq)]
 pub struct DebugAnnotation {
     pub name: IString,
     pub value: DebugValue,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct DebugAnnotationName {
     pub iid: u64,
     pub name: String,
 }
 
 impl Emit for DebugAnnotationName {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         out.varint_field(1, self.iid);
         out.string_field(2, &self.name);
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DebugValue {
     Bool(bool),
     Uint(u64),
     Int(i64),
     Double(f64),
     String(String),
+    /// Interning id of a string in `debug_annotation_string_values`.
+    InternedString(u64),
     Dict(Vec<DebugAnnotation>),
     Array(Vec<DebugValue>),
+    /// Symbolized into an array of frames by the writer.
+    #[cfg(feature = "backtrace")]
+    Backtrace(crate::backtraces::EventBacktrace),
 }
 
 impl Emit for DebugAnnotation {
-    fn emit(&self, out: &mut ProtoEmitter) {
+    fn emit(&self, out: &mut ProtoEmitter) -> Result<(), EmitError> {
         match &self.name {
             IString::Plain(s) => out.string_field(10, s),
             IString::Interned(n) => out.varint_field(1, *n),
         }
-        emit_value(&self.value, out);
+        emit_value(&self.value, out)
+    }
+}
+
+impl DebugAnnotation {
+    /// An annotation with a plain, not interned, name.
+    pub fn new(name: impl Into<String>, value: impl Into<DebugValue>) -> Self {
+        DebugAnnotation {
+            name: IString::Plain(name.into()),
+            value: value.into(),
+        }
     }
 }
 
-fn emit_value(value: &DebugValue, out: &mut ProtoEmitter) {
+macro_rules! debug_value_from {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$ty> for DebugValue {
+                fn from(value: $ty) -> Self {
+                    DebugValue::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+debug_value_from! {
+    bool => Bool,
+    u8 => Uint,
+    u16 => Uint,
+    u32 => Uint,
+    u64 => Uint,
+    i8 => Int,
+    i16 => Int,
+    i32 => Int,
+    i64 => Int,
+    f32 => Double,
+    f64 => Double,
+    String => String,
+    &str => String,
+}
+
+impl From<usize> for DebugValue {
+    fn from(value: usize) -> Self {
+        DebugValue::Uint(value as u64)
+    }
+}
+
+impl From<isize> for DebugValue {
+    fn from(value: isize) -> Self {
+        DebugValue::Int(value as i64)
+    }
+}
+
+impl<T: Into<DebugValue>> From<Vec<T>> for DebugValue {
+    fn from(values: Vec<T>) -> Self {
+        DebugValue::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Vec<DebugAnnotation>> for DebugValue {
+    fn from(entries: Vec<DebugAnnotation>) -> Self {
+        DebugValue::Dict(entries)
+    }
+}
+
+/// Interned strings show as `#<iid>`.
+impl fmt::Display for IString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IString::Plain(s) => f.write_str(s),
+            IString::Interned(iid) => write!(f, "#{}", iid),
+        }
+    }
+}
+
+/// Shows the annotation as `name=value`.
+impl fmt::Display for DebugAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Strings are quoted, dicts and arrays are shown like in Rust.
+impl fmt::Display for DebugValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugValue::Bool(b) => write!(f, "{}", b),
+            DebugValue::Uint(n) => write!(f, "{}", n),
+            DebugValue::Int(n) => write!(f, "{}", n),
+            DebugValue::Double(d) => write!(f, "{}", d),
+            DebugValue::String(s) => write!(f, "{:?}", s),
+            DebugValue::InternedString(iid) => write!(f, "#{}", iid),
+            DebugValue::Dict(entries) => {
+                f.write_str("{")?;
+                for (i, entry) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", entry.name, entry.value)?;
+                }
+                f.write_str("}")
+            }
+            DebugValue::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            #[cfg(feature = "backtrace")]
+            DebugValue::Backtrace(_) => f.write_str("<backtrace>"),
+        }
+    }
+}
+
+fn emit_value(value: &DebugValue, out: &mut ProtoEmitter) -> Result<(), EmitError> {
     match value {
         DebugValue::Bool(b) => out.varint_field(2, *b as u64),
         DebugValue::Uint(n) => out.varint_field(3, *n),
         DebugValue::Int(n) => out.varint_field(4, *n as u64),
         DebugValue::Double(d) => out.double_field(5, *d),
         DebugValue::String(s) => out.string_field(6, s),
+        DebugValue::InternedString(iid) => out.varint_field(17, *iid),
         DebugValue::Dict(anns) => {
             for ann in anns {
-                out.nested_small(11, |out| ann.emit(out));
+                out.nested_small(11, |out| ann.emit(out))?;
             }
         }
         DebugValue::Array(vals) => {
             for val in vals {
-                out.nested_small(12, |out| emit_value(val, out));
+                out.nested_small(12, |out| emit_value(val, out))?;
+            }
+        }
+        #[cfg(feature = "backtrace")]
+        DebugValue::Backtrace(trace) => emit_value(&trace.resolve(), out)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode a varint at the start of `data`, returning it and its length.
+    fn varint(data: &[u8]) -> (u64, usize) {
+        let mut value = 0;
+        for (i, byte) in data.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (value, i + 1);
+            }
+        }
+        panic!("truncated varint");
+    }
+
+    /// Field numbers of the top-level fields of an encoded message.
+    fn field_numbers(mut data: &[u8]) -> Vec<u64> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let (tag, len) = varint(data);
+            data = &data[len..];
+            fields.push(tag >> 3);
+            match tag & 7 {
+                0 => data = &data[varint(data).1..],
+                1 => data = &data[8..],
+                2 => {
+                    let (size, len) = varint(data);
+                    data = &data[len + size as usize..];
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
             }
         }
+        fields
+    }
+
+    #[test]
+    fn interned_data_field_numbers() {
+        let data = InternedData {
+            event_categories: vec![EventCategory {
+                iid: 1,
+                name: "cat".to_string(),
+            }],
+            event_names: vec![EventName {
+                iid: 1,
+                name: "name".to_string(),
+            }],
+            debug_annotation_names: vec![DebugAnnotationName {
+                iid: 1,
+                name: "arg".to_string(),
+            }],
+            source_locations: vec![SourceLocation {
+                iid: 1,
+                file_name: "lib.rs".to_string(),
+                function_name: None,
+                line_number: 42,
+            }],
+            log_message_body: vec![LogMessageBody {
+                iid: 1,
+                body: "hello".to_string(),
+            }],
+            debug_annotation_value_type_names: vec![DebugAnnotationValueTypeName {
+                iid: 1,
+                name: "u32".to_string(),
+            }],
+            debug_annotation_string_values: vec![InternedString {
+                iid: 1,
+                str: "value".to_string(),
+            }],
+        };
+        let mut out = ProtoEmitter::new();
+        data.emit(&mut out).unwrap();
+        assert_eq!(field_numbers(out.as_bytes()), vec![1, 2, 3, 4, 20, 27, 29]);
+    }
+
+    #[test]
+    fn track_event_field_numbers() {
+        let event = TrackEvent {
+            event_type: EventType::SliceBegin,
+            name: IString::Interned(1),
+            debug_annotations: vec![DebugAnnotation {
+                name: IString::Interned(1),
+                value: DebugValue::InternedString(2),
+            }],
+            track_uuid: Some(7),
+            category_iids: vec![1, 2],
+            source_location_iid: Some(3),
+            flow_ids: vec![5],
+            terminating_flow_ids: vec![6],
+            counter_value: Some(-1),
+            double_counter_value: Some(0.5),
+            extra_counters: vec![(8, 1), (9, 2)],
+            extra_double_counters: vec![(10, 0.25)],
+        };
+        let mut out = ProtoEmitter::new();
+        event.emit(&mut out).unwrap();
+        assert_eq!(
+            field_numbers(out.as_bytes()),
+            vec![9, 11, 3, 3, 34, 10, 4, 47, 48, 30, 44, 31, 31, 12, 12, 45, 46]
+        );
+
+        let mut out = ProtoEmitter::new();
+        event.debug_annotations[0].emit(&mut out).unwrap();
+        assert_eq!(field_numbers(out.as_bytes()), vec![1, 17]);
+    }
+
+    #[test]
+    fn thread_track_descriptor() {
+        let descriptor = TrackDescriptor {
+            uuid: 2,
+            parent_uuid: None,
+            name: "main 0".to_string(),
+            counter: None,
+            process: None,
+            thread: Some(ThreadDescriptor {
+                pid: 7,
+                tid: 9,
+                thread_name: "main".to_string(),
+            }),
+        };
+        let mut out = ProtoEmitter::new();
+        descriptor.emit(&mut out).unwrap();
+        assert_eq!(field_numbers(out.as_bytes()), vec![1, 2, 4]);
+        assert!(out.as_bytes().ends_with(&[
+            1 << 3,
+            7,
+            2 << 3,
+            9,
+            5 << 3 | 2,
+            4,
+            b'm',
+            b'a',
+            b'i',
+            b'n'
+        ]));
+    }
+
+    #[test]
+    fn counter_track_descriptor() {
+        let descriptor = TrackDescriptor {
+            uuid: 2,
+            parent_uuid: Some(1),
+            name: "bytes".to_string(),
+            counter: Some(CounterUnit::SizeBytes),
+            process: None,
+            thread: None,
+        };
+        let mut out = ProtoEmitter::new();
+        descriptor.emit(&mut out).unwrap();
+        assert_eq!(field_numbers(out.as_bytes()), vec![1, 5, 2, 8]);
+        // The `CounterDescriptor` holds just the unit.
+        assert!(out.as_bytes().ends_with(&[3 << 3, 3]));
+
+        let descriptor = TrackDescriptor {
+            counter: Some(CounterUnit::Unspecified),
+            ..descriptor
+        };
+        let mut out = ProtoEmitter::new();
+        descriptor.emit(&mut out).unwrap();
+        assert!(out.as_bytes().ends_with(&[8 << 3 | 2, 0]));
+    }
+
+    #[test]
+    fn display() {
+        let annotation = DebugAnnotation {
+            name: IString::Plain("request".to_string()),
+            value: DebugValue::Dict(vec![
+                DebugAnnotation {
+                    name: IString::Interned(3),
+                    value: DebugValue::Array(vec![DebugValue::Uint(1), DebugValue::Bool(true)]),
+                },
+                DebugAnnotation {
+                    name: IString::Plain("path".to_string()),
+                    value: DebugValue::String("/a \"b\"".to_string()),
+                },
+            ]),
+        };
+        assert_eq!(
+            annotation.to_string(),
+            r#"request={#3: [1, true], path: "/a \"b\""}"#
+        );
+        assert_eq!(annotation.clone(), annotation);
+    }
+
+    #[test]
+    fn constructors() {
+        assert_eq!(
+            DebugAnnotation::new("count", 3u32),
+            DebugAnnotation {
+                name: IString::Plain("count".to_string()),
+                value: DebugValue::Uint(3),
+            }
+        );
+        assert_eq!(DebugValue::from(-1i8), DebugValue::Int(-1));
+        assert_eq!(DebugValue::from(0.5f32), DebugValue::Double(0.5));
+        assert_eq!(DebugValue::from("a"), DebugValue::String("a".to_string()));
+        assert_eq!(
+            DebugValue::from(vec![1u64, 2]),
+            DebugValue::Array(vec![DebugValue::Uint(1), DebugValue::Uint(2)])
+        );
+        assert_eq!(
+            DebugValue::from(vec![DebugAnnotation::new("ok", true)]).to_string(),
+            "{ok: true}"
+        );
     }
 }
@@ -0,0 +1,138 @@
+//! Hardware counters of the current thread, see
+//! [`PerfettoLayerBuilder::perf_counter`](crate::PerfettoLayerBuilder::perf_counter).
+
+/// A hardware performance counter to sample at span entry and exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfCounter {
+    /// Instructions retired.
+    Instructions,
+    /// CPU cycles.
+    Cycles,
+    /// A raw, CPU specific event (`PERF_TYPE_RAW`), e.g. `0x1c2` for
+    /// uops retired on Intel CPUs.
+    Raw(u64),
+}
+
+impl PerfCounter {
+    /// The name of the counter tracks.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PerfCounter::Instructions => "instructions",
+            PerfCounter::Cycles => "cycles",
+            PerfCounter::Raw(_) => "perf counter",
+        }
+    }
+}
+
+/// Read the counter for the calling thread, opening it on first use.
+///
+/// Returns `None` if the counter is not available, e.g. on platforms other
+/// than Linux, in virtual machines without a PMU or if
+/// `/proc/sys/kernel/perf_event_paranoid` forbids it.
+#[cfg(target_os = "linux")]
+pub(crate) fn read(counter: PerfCounter) -> Option<u64> {
+    use std::{cell::OnceCell, fs::File, io::Read};
+
+    thread_local! {
+        static COUNTER: OnceCell<Option<File>> = const { OnceCell::new() };
+    }
+    COUNTER.with(|file| {
+        let mut file = file.get_or_init(|| linux::open(counter)).as_ref()?;
+        let mut value = [0; 8];
+        file.read_exact(&mut value).ok()?;
+        Some(u64::from_ne_bytes(value))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read(_counter: PerfCounter) -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{fs::File, os::fd::FromRawFd};
+
+    use super::PerfCounter;
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_TYPE_RAW: u32 = 4;
+    const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+    const EXCLUDE_KERNEL: u64 = 1 << 5;
+    const EXCLUDE_HV: u64 = 1 << 6;
+    const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+    /// The first version of `struct perf_event_attr`, which every kernel
+    /// accepts. libc doesn't define it.
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        type_: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+    }
+
+    pub fn open(counter: PerfCounter) -> Option<File> {
+        let (type_, config) = match counter {
+            PerfCounter::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+            PerfCounter::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+            PerfCounter::Raw(config) => (PERF_TYPE_RAW, config),
+        };
+        let attr = PerfEventAttr {
+            type_,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            // Counting only user space needs no privileges with the
+            // default `perf_event_paranoid` of 2.
+            flags: EXCLUDE_KERNEL | EXCLUDE_HV,
+            ..PerfEventAttr::default()
+        };
+        // SAFETY: `attr` outlives the call, and pid 0 with cpu -1 counts the
+        // calling thread on any CPU.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0,
+                -1,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        // SAFETY: The kernel just handed us the descriptor.
+        Some(unsafe { File::from_raw_fd(fd as i32) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_this_thread() {
+        // Only where the kernel lets us open the counter.
+        let Some(before) = read(PerfCounter::Instructions) else {
+            return;
+        };
+        std::hint::black_box((0..10_000u64).sum::<u64>());
+        let after = read(PerfCounter::Instructions).unwrap();
+        assert!(after - before >= 10_000, "{} instructions", after - before);
+
+        // Other threads have counters of their own, which start at zero.
+        let other = std::thread::spawn(|| read(PerfCounter::Instructions).unwrap())
+            .join()
+            .unwrap();
+        assert!(other < after);
+    }
+}
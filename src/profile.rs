@@ -0,0 +1,68 @@
+//! Combinations of builder options for common uses, see [`Profile`].
+
+use std::time::Duration;
+
+use crate::PerfettoLayerBuilder;
+
+/// A combination of options for a common use, see
+/// [`PerfettoLayerBuilder::profile`].
+///
+/// Each profile sets the same options, so the last profile applied wins:
+/// [`include_args`](PerfettoLayerBuilder::include_args),
+/// [`args_budget`](PerfettoLayerBuilder::args_budget),
+/// [`max_arg_len`](PerfettoLayerBuilder::max_arg_len),
+/// [`min_span_duration`](PerfettoLayerBuilder::min_span_duration),
+/// [`source_locations`](PerfettoLayerBuilder::source_locations),
+/// [`include_thread_info`](PerfettoLayerBuilder::include_thread_info) and
+/// [`os_thread_ids`](PerfettoLayerBuilder::os_thread_ids).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// For tracing in production all the time: no arguments, and only
+    /// span entries that last at least 10µs.
+    LowOverhead,
+    /// For everyday use: arguments of at most 4KiB each, and 1MiB per
+    /// second of trace time at most.
+    Balanced,
+    /// For digging into a problem: all arguments, source locations, and
+    /// the scheduling info and OS thread id of each thread.
+    Verbose,
+}
+
+impl Profile {
+    pub(crate) fn apply<S>(self, mut builder: PerfettoLayerBuilder<S>) -> PerfettoLayerBuilder<S> {
+        let verbose = self == Profile::Verbose;
+        builder.include_args = self != Profile::LowOverhead;
+        builder.args_budget = (self == Profile::Balanced).then_some(1024 * 1024);
+        builder.limits.max_value_len = match self {
+            Profile::Balanced => 4 * 1024,
+            Profile::LowOverhead | Profile::Verbose => 64 * 1024,
+        };
+        builder.min_span_duration =
+            (self == Profile::LowOverhead).then_some(Duration::from_micros(10));
+        builder.source_locations = verbose;
+        builder.include_thread_info = verbose;
+        builder.os_thread_ids = verbose;
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_one_wins() {
+        let builder = PerfettoLayerBuilder::<tracing_subscriber::Registry>::new()
+            .profile(Profile::LowOverhead)
+            .profile(Profile::Balanced);
+        assert!(builder.include_args);
+        assert_eq!(builder.args_budget, Some(1024 * 1024));
+        assert_eq!(builder.min_span_duration, None);
+
+        let builder = builder.profile(Profile::Verbose).include_args(false);
+        assert!(!builder.include_args);
+        assert_eq!(builder.args_budget, None);
+        assert_eq!(builder.limits.max_value_len, 64 * 1024);
+        assert!(builder.source_locations);
+    }
+}
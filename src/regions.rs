@@ -0,0 +1,119 @@
+//! Time ranges marked for whoever reads the trace, see [`region`].
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::Clock, queue_message, stats::Counters, Message};
+
+/// Name of the track of the regions. Regions that overlap without nesting
+/// go onto further tracks, "Regions 2" and so on.
+const REGIONS_TRACK: &str = "Regions";
+
+/// Sends regions to the writer of a layer. [`region`] finds it through
+/// the current dispatcher.
+#[derive(Clone)]
+pub(crate) struct RegionSink {
+    pub sender: Sender<Message>,
+    pub clock: Clock,
+    pub counters: Arc<Counters>,
+}
+
+impl RegionSink {
+    fn send(&self, id: u64, name: Option<String>) {
+        if self.counters.stopped() {
+            return;
+        }
+        let msg = Message::Region {
+            timestamp: self.clock.now(),
+            id,
+            name,
+        };
+        queue_message(&self.sender, &self.counters, msg);
+    }
+}
+
+/// Mark a region of interest, e.g. the steps to reproduce a bug, on the
+/// "Regions" track of the trace. The region lasts until the guard is
+/// dropped, whichever threads are involved in the meantime.
+///
+/// Regions are recorded by the [`PerfettoLayer`](crate::PerfettoLayer) of
+/// the current default subscriber; without one, this does nothing.
+pub fn region(name: impl Into<String>) -> RegionGuard {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let sink =
+        tracing::dispatcher::get_default(|dispatch| dispatch.downcast_ref::<RegionSink>().cloned());
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    if let Some(sink) = &sink {
+        sink.send(id, Some(name.into()));
+    }
+    RegionGuard { sink, id }
+}
+
+/// Ends a region when dropped, see [`region`].
+#[must_use = "the region ends when the guard is dropped"]
+pub struct RegionGuard {
+    sink: Option<RegionSink>,
+    id: u64,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        if let Some(sink) = &self.sink {
+            sink.send(self.id, None);
+        }
+    }
+}
+
+/// The region shown on each of the regions tracks.
+#[derive(Default)]
+pub(crate) struct RegionLanes {
+    lanes: Vec<Option<u64>>,
+}
+
+impl RegionLanes {
+    /// Put a region onto the first free track, returning its name.
+    pub fn begin(&mut self, id: u64) -> Arc<str> {
+        let lane = match self.lanes.iter().position(Option::is_none) {
+            Some(lane) => lane,
+            None => {
+                self.lanes.push(None);
+                self.lanes.len() - 1
+            }
+        };
+        self.lanes[lane] = Some(id);
+        lane_name(lane)
+    }
+
+    /// Free the track of a region, returning its name.
+    pub fn end(&mut self, id: u64) -> Option<Arc<str>> {
+        let lane = self.lanes.iter().position(|region| *region == Some(id))?;
+        self.lanes[lane] = None;
+        Some(lane_name(lane))
+    }
+}
+
+fn lane_name(lane: usize) -> Arc<str> {
+    match lane {
+        0 => REGIONS_TRACK.into(),
+        lane => format!("{} {}", REGIONS_TRACK, lane + 1).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lanes() {
+        let mut lanes = RegionLanes::default();
+        assert_eq!(&*lanes.begin(0), "Regions");
+        assert_eq!(&*lanes.begin(1), "Regions 2");
+        assert_eq!(lanes.end(0).as_deref(), Some("Regions"));
+        assert_eq!(&*lanes.begin(2), "Regions");
+        assert_eq!(lanes.end(0), None);
+    }
+}
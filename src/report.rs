@@ -0,0 +1,112 @@
+//! A summary of how the trace went, written next to it when it ends, see
+//! [`PerfettoLayerBuilder::shutdown_report`](crate::PerfettoLayerBuilder::shutdown_report).
+
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{summary::json_string, TraceStats};
+
+/// Shared between the writer thread, which writes the report, and the
+/// writer, which opens the files.
+pub(crate) type SharedReport = Arc<Mutex<ShutdownReport>>;
+
+pub(crate) struct ShutdownReport {
+    path: PathBuf,
+    started: Instant,
+    /// Every file written, in order.
+    files: Vec<PathBuf>,
+}
+
+impl ShutdownReport {
+    /// A report on the trace written to `trace`, which ends up in
+    /// `<trace>.meta.json`.
+    pub fn new(trace: &Path) -> Self {
+        let mut path = OsString::from(trace);
+        path.push(".meta.json");
+        ShutdownReport {
+            path: path.into(),
+            started: Instant::now(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn file_opened(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    fn render(&self, stats: &TraceStats, error: Option<&str>) -> String {
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"complete\": {},", error.is_none());
+        let _ = writeln!(
+            out,
+            "  \"error\": {},",
+            error.map_or_else(|| "null".to_string(), json_string)
+        );
+        let _ = writeln!(
+            out,
+            "  \"duration_ns\": {},",
+            self.started.elapsed().as_nanos()
+        );
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| json_string(&file.to_string_lossy()))
+            .collect();
+        let _ = writeln!(out, "  \"files\": [{}],", files.join(", "));
+        let _ = writeln!(out, "  \"segments\": {},", self.files.len());
+        for (name, value) in [
+            ("packets", stats.packets_written),
+            ("events", stats.events_written),
+            ("bytes", stats.bytes_written),
+            ("packets_dropped", stats.packets_dropped),
+            ("messages_dropped", stats.messages_dropped),
+            ("violations", stats.violations),
+            ("write_stalls", stats.write_stalls),
+            ("args_omitted", stats.args_omitted),
+        ] {
+            let _ = writeln!(out, "  \"{}\": {},", name, value);
+        }
+        let _ = writeln!(out, "  \"file_size\": {}", stats.file_size);
+        out.push_str("}\n");
+        out
+    }
+
+    /// Write the report, with the `error` that ended the trace early, if
+    /// any.
+    pub fn write(&self, stats: &TraceStats, error: Option<&str>) -> io::Result<()> {
+        fs::write(&self.path, self.render(stats, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let mut report = ShutdownReport::new(Path::new("trace.pftrace"));
+        assert_eq!(report.path, PathBuf::from("trace.pftrace.meta.json"));
+        report.file_opened("trace.0.pftrace".into());
+        report.file_opened("trace.1.pftrace".into());
+        let stats = TraceStats {
+            packets_written: 12,
+            events_written: 8,
+            packets_dropped: 1,
+            ..TraceStats::default()
+        };
+        let json = report.render(&stats, Some("I/O error: \"disk full\""));
+        assert!(json.contains("\"complete\": false,\n"));
+        assert!(json.contains("\"error\": \"I/O error: \\\"disk full\\\"\",\n"));
+        assert!(json.contains("\"files\": [\"trace.0.pftrace\", \"trace.1.pftrace\"],\n"));
+        assert!(json.contains("\"segments\": 2,\n"));
+        assert!(json.contains("\"events\": 8,\n"));
+        assert!(json.contains("\"packets_dropped\": 1,\n"));
+        assert!(json.ends_with("\"file_size\": 0\n}\n"));
+    }
+}
@@ -0,0 +1,112 @@
+//! Keeping only the most recent packets in memory, see
+//! [`PerfettoLayerBuilder::ring_buffer`](crate::PerfettoLayerBuilder::ring_buffer).
+//!
+//! Packets are kept encoded, so the ring holds about as many bytes as the
+//! trace it is written to. Evicting packets loses the track descriptors,
+//! sequence resets and clock snapshots written at the start of the trace,
+//! so the latest of these are pinned and written before the packets.
+//!
+//! Names are interned per sequence until the sequence is reset, so packets
+//! whose reset was evicted are useless. The writer resets each sequence
+//! once a quarter of the ring was written since its last reset, which
+//! limits those packets to the oldest quarter.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+};
+
+/// A packet that stays in the ring while the packets around it are evicted.
+/// The order is the order in which they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Pin {
+    /// The latest reset of one of the writer's own sequences, which don't
+    /// intern anything.
+    Sequence(u32),
+    Clock,
+    /// The latest descriptor of a track.
+    Track(u64),
+}
+
+pub(crate) struct Ring {
+    max_bytes: usize,
+    packets: VecDeque<Vec<u8>>,
+    /// Size of `packets` in bytes.
+    len: usize,
+    /// Number of bytes pushed so far.
+    pushed: u64,
+    pinned: BTreeMap<Pin, Vec<u8>>,
+}
+
+impl Ring {
+    pub fn new(max_bytes: usize) -> Self {
+        Ring {
+            max_bytes,
+            packets: VecDeque::new(),
+            len: 0,
+            pushed: 0,
+            pinned: BTreeMap::new(),
+        }
+    }
+
+    /// Add an encoded packet, evicting the oldest ones to make room.
+    pub fn push(&mut self, packet: Vec<u8>) {
+        self.len += packet.len();
+        self.pushed += packet.len() as u64;
+        self.packets.push_back(packet);
+        while self.len > self.max_bytes {
+            let Some(evicted) = self.packets.pop_front() else {
+                break;
+            };
+            self.len -= evicted.len();
+        }
+    }
+
+    pub fn pin(&mut self, pin: Pin, packet: Vec<u8>) {
+        self.pinned.insert(pin, packet);
+    }
+
+    /// The position of the next packet pushed, in bytes since the start.
+    pub fn position(&self) -> u64 {
+        self.pushed
+    }
+
+    /// Whether a sequence reset at `position` is due for another reset.
+    pub fn reset_due(&self, position: u64) -> bool {
+        self.pushed - position >= self.max_bytes as u64 / 4
+    }
+
+    /// Write the pinned packets and then the ring, returning the number of
+    /// bytes written.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<u64> {
+        let mut written = 0;
+        for packet in self.pinned.values().chain(&self.packets) {
+            out.write_all(packet)?;
+            written += packet.len() as u64;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest() {
+        let mut ring = Ring::new(10);
+        ring.pin(Pin::Track(1), b"t".to_vec());
+        ring.pin(Pin::Sequence(7), b"s".to_vec());
+        for packet in ["aaaa", "bbbb", "cccc"] {
+            ring.push(packet.as_bytes().to_vec());
+        }
+        assert_eq!(ring.position(), 12);
+        assert!(!ring.reset_due(11));
+        assert!(ring.reset_due(10));
+        ring.pin(Pin::Track(1), b"T".to_vec());
+
+        let mut out = Vec::new();
+        assert_eq!(ring.write_to(&mut out).unwrap(), 10);
+        assert_eq!(out, b"sTbbbbcccc");
+    }
+}
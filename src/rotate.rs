@@ -0,0 +1,179 @@
+//! Splitting a long-running trace into several files.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Called with the path of each trace file once it is complete.
+pub(crate) type RotateCallback = Box<dyn FnMut(&Path) + Send>;
+
+/// When to make sure the trace file is on disk rather than just in the
+/// operating system's cache, see
+/// [`PerfettoLayerBuilder::sync_policy`](crate::PerfettoLayerBuilder::sync_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Leave it to the operating system.
+    #[default]
+    Never,
+    /// Whenever a file is complete, i.e. on rotation and at the end of the
+    /// trace.
+    OnRotate,
+    /// Also after every flush that brings at least this many new bytes.
+    EveryNBytes(u64),
+}
+
+/// Numbering of the trace files and the callback for finished ones.
+pub(crate) struct Rotation {
+    base: PathBuf,
+    /// Start a new file once the current one is this large.
+    pub max_file_size: Option<u64>,
+    index: u32,
+    on_rotate: Option<RotateCallback>,
+}
+
+impl Rotation {
+    pub fn new(
+        base: PathBuf,
+        max_file_size: Option<u64>,
+        on_rotate: Option<RotateCallback>,
+    ) -> Self {
+        Rotation {
+            base,
+            max_file_size,
+            index: 0,
+            on_rotate,
+        }
+    }
+
+    /// The path of the current file. Without a size limit, this is just the
+    /// configured path. Otherwise the file index is inserted before the
+    /// extension, e.g. `trace.3.perfetto-trace`.
+    pub fn current_path(&self) -> PathBuf {
+        self.path(self.index)
+    }
+
+    /// The path of the file with the given index.
+    pub fn path(&self, index: u32) -> PathBuf {
+        if self.max_file_size.is_none() {
+            return self.base.clone();
+        }
+        let (stem, ext) = self.stem_and_extension();
+        let name = match ext {
+            Some(ext) => format!("{}.{}.{}", stem, index, ext),
+            None => format!("{}.{}", stem, index),
+        };
+        self.base.with_file_name(name)
+    }
+
+    fn stem_and_extension(&self) -> (String, Option<String>) {
+        let stem = self
+            .base
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let ext = self
+            .base
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned());
+        (stem, ext)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Continue with the highest numbered file that exists, when appending
+    /// to the trace of an earlier run. Files before it may have been
+    /// removed, e.g. by the rotation callback.
+    pub fn resume(&mut self) -> io::Result<()> {
+        if self.max_file_size.is_none() {
+            return Ok(());
+        }
+        let (stem, ext) = self.stem_and_extension();
+        let dir = match self.base.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let index = name
+                .strip_prefix(&stem)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .and_then(|rest| match &ext {
+                    Some(ext) => rest.strip_suffix(ext.as_str())?.strip_suffix('.'),
+                    None => Some(rest),
+                })
+                .and_then(|index| index.parse::<u32>().ok());
+            if let Some(index) = index {
+                self.index = self.index.max(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move on to the next file, returning the path of the current one.
+    pub fn next_file(&mut self) -> PathBuf {
+        let path = self.current_path();
+        self.index += 1;
+        path
+    }
+
+    /// Report a file as complete.
+    pub fn finished(&mut self, path: &Path) {
+        if let Some(on_rotate) = &mut self.on_rotate {
+            on_rotate(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbered_paths() {
+        let mut rotation = Rotation::new("out/trace.perfetto-trace".into(), Some(1), None);
+        assert_eq!(
+            rotation.current_path(),
+            Path::new("out/trace.0.perfetto-trace")
+        );
+        assert_eq!(
+            rotation.next_file(),
+            Path::new("out/trace.0.perfetto-trace")
+        );
+        assert_eq!(
+            rotation.current_path(),
+            Path::new("out/trace.1.perfetto-trace")
+        );
+
+        let rotation = Rotation::new("trace".into(), None, None);
+        assert_eq!(rotation.current_path(), Path::new("trace"));
+    }
+
+    #[test]
+    fn resume() {
+        let dir = std::env::temp_dir().join(format!("rotate-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "trace.2.pftrace",
+            "trace.10.pftrace",
+            "trace.11.json",
+            "other.12.pftrace",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let mut rotation = Rotation::new(dir.join("trace.pftrace"), Some(1), None);
+        rotation.resume().unwrap();
+        assert_eq!(rotation.current_path(), dir.join("trace.10.pftrace"));
+        rotation.next_file();
+        assert_eq!(rotation.current_path(), dir.join("trace.11.pftrace"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! Rules deciding where spans and events go, see
+//! [`PerfettoLayerBuilder::route`](crate::PerfettoLayerBuilder::route).
+//!
+//! The rules only look at the static metadata of a callsite, so they are
+//! evaluated once per callsite and the outcome is cached.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use tracing::{callsite::Identifier, Level, Metadata};
+
+use crate::Kinds;
+
+/// A routing rule: which spans and events it matches, and what happens to
+/// them.
+///
+/// A rule without conditions matches everything. Each condition narrows it
+/// down, e.g. `Rule::new().target("my_crate::db").field("rows").counter("rows")`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    target: Option<String>,
+    level: Option<Level>,
+    name: Option<String>,
+    fields: Vec<String>,
+    kinds: Kinds,
+    track: Option<Arc<str>>,
+    category: Option<Arc<str>>,
+    counters: Vec<String>,
+    discard: bool,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Rule {
+            target: None,
+            level: None,
+            name: None,
+            fields: Vec::new(),
+            kinds: Kinds::ALL,
+            track: None,
+            category: None,
+            counters: Vec::new(),
+            discard: false,
+        }
+    }
+
+    /// Only match targets starting with `prefix`.
+    pub fn target(mut self, prefix: impl Into<String>) -> Self {
+        self.target = Some(prefix.into());
+        self
+    }
+
+    /// Only match `level` and more severe levels.
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Only match spans and events with this name.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Only match callsites that have this field. Can be given several
+    /// times, in which case all fields must be there.
+    pub fn field(mut self, name: impl Into<String>) -> Self {
+        self.fields.push(name.into());
+        self
+    }
+
+    /// Only match spans, or only events.
+    pub fn kinds(mut self, kinds: Kinds) -> Self {
+        self.kinds = kinds;
+        self
+    }
+
+    /// Put the slices and instants onto the custom track `track`, unless a
+    /// `perfetto.track` field says otherwise.
+    pub fn to_track(mut self, track: &str) -> Self {
+        self.track = Some(track.into());
+        self
+    }
+
+    /// Give the slices and instants the category `category`, which the
+    /// Perfetto UI and trace_processor can filter on.
+    ///
+    /// The UI shows the category in the details of a slice, and
+    /// trace_processor has it in the `category` column of the `slice`
+    /// table. The UI colors slices by their name, not their category, so
+    /// categories don't change the colors; they are for finding and
+    /// filtering the slices of a subsystem.
+    pub fn category(mut self, category: &str) -> Self {
+        self.category = Some(category.into());
+        self
+    }
+
+    /// Record the field `field` of events as a counter, as with
+    /// [`PerfettoLayerBuilder::counter_field`](crate::PerfettoLayerBuilder::counter_field).
+    pub fn counter(mut self, field: impl Into<String>) -> Self {
+        self.counters.push(field.into());
+        self
+    }
+
+    /// Don't record the spans and events at all.
+    pub fn discard(mut self) -> Self {
+        self.discard = true;
+        self
+    }
+
+    fn matches(&self, metadata: &Metadata<'_>) -> bool {
+        let kind = if metadata.is_span() {
+            Kinds::SPANS
+        } else {
+            Kinds::EVENTS
+        };
+        self.kinds.contains(kind)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|prefix| metadata.target().starts_with(prefix.as_str()))
+            && self.level.is_none_or(|level| *metadata.level() <= level)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| metadata.name() == name)
+            && self
+                .fields
+                .iter()
+                .all(|field| metadata.fields().field(field).is_some())
+    }
+}
+
+/// What the matching rules decided for a callsite. Of the tracks and
+/// categories, the first one set wins.
+#[derive(Debug, Default)]
+pub(crate) struct Route {
+    pub discard: bool,
+    pub track: Option<Arc<str>>,
+    pub category: Option<Arc<str>>,
+    /// Fields recorded as counters.
+    pub counters: Vec<&'static str>,
+}
+
+/// The rules and their outcome for each callsite seen so far.
+pub(crate) struct Router {
+    rules: Vec<Rule>,
+    routes: RwLock<HashMap<Identifier, Arc<Route>>>,
+}
+
+impl Router {
+    /// `None` if there are no rules, so routing costs nothing.
+    pub fn new(rules: Vec<Rule>) -> Option<Router> {
+        (!rules.is_empty()).then(|| Router {
+            rules,
+            routes: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn route(&self, metadata: &'static Metadata<'static>) -> Arc<Route> {
+        let id = metadata.callsite();
+        if let Some(route) = self.routes.read().unwrap().get(&id) {
+            return route.clone();
+        }
+        let route = Arc::new(self.resolve(metadata));
+        self.routes
+            .write()
+            .unwrap()
+            .entry(id)
+            .or_insert(route)
+            .clone()
+    }
+
+    fn resolve(&self, metadata: &'static Metadata<'static>) -> Route {
+        let mut route = Route::default();
+        for rule in self.rules.iter().filter(|rule| rule.matches(metadata)) {
+            route.discard |= rule.discard;
+            route.track = route.track.or_else(|| rule.track.clone());
+            route.category = route.category.or_else(|| rule.category.clone());
+            for field in metadata.fields() {
+                if rule.counters.iter().any(|counter| counter == field.name())
+                    && !route.counters.contains(&field.name())
+                {
+                    route.counters.push(field.name());
+                }
+            }
+        }
+        route
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_match_wins() {
+        let router = Router::new(vec![
+            Rule::new().target("app::db").to_track("db"),
+            Rule::new().level(Level::WARN).category("problems"),
+            Rule::new().field("rows").counter("rows").to_track("rows"),
+            Rule::new().name("noise").discard(),
+        ])
+        .unwrap();
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let span = tracing::warn_span!(target: "app::db", "query", rows = 3);
+        let route = router.route(span.metadata().unwrap());
+        assert_eq!(route.track.as_deref(), Some("db"));
+        assert_eq!(route.category.as_deref(), Some("problems"));
+        assert_eq!(route.counters, ["rows"]);
+        assert!(!route.discard);
+        // Cached.
+        assert!(Arc::ptr_eq(&route, &router.route(span.metadata().unwrap())));
+
+        let span = tracing::info_span!("noise");
+        let route = router.route(span.metadata().unwrap());
+        assert!(route.discard);
+        assert_eq!(route.category, None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Cleaning up names and argument values before they go into the trace.
+//!
+//! `Debug` output and log messages can contain newlines, terminal escape
+//! sequences or whole serialized documents, which make slices hard to read
+//! and confuse tools that process the trace line by line.
+
+use std::borrow::Cow;
+
+use crate::packet::{DebugAnnotation, DebugValue, IString};
+
+/// Appended to strings that were cut off.
+const ELLIPSIS: &str = "…";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum length in bytes of slice, event and argument names.
+    pub max_name_len: usize,
+    /// Maximum length in bytes of string argument values.
+    pub max_value_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_name_len: 1024,
+            max_value_len: 64 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        sanitize(name, self.max_name_len)
+    }
+
+    pub fn annotations(&self, annotations: &mut [DebugAnnotation]) {
+        for annotation in annotations {
+            if let IString::Plain(name) = &mut annotation.name {
+                replace(name, self.max_name_len);
+            }
+            self.value(&mut annotation.value);
+        }
+    }
+
+    fn value(&self, value: &mut DebugValue) {
+        match value {
+            DebugValue::String(s) => replace(s, self.max_value_len),
+            DebugValue::Dict(entries) => self.annotations(entries),
+            DebugValue::Array(values) => {
+                for value in values {
+                    self.value(value);
+                }
+            }
+            #[cfg(feature = "backtrace")]
+            DebugValue::Backtrace(trace) => {
+                *value = trace.resolve();
+                self.value(value);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn replace(s: &mut String, max_len: usize) {
+    if let Cow::Owned(sanitized) = sanitize(s, max_len) {
+        *s = sanitized;
+    }
+}
+
+/// Escape control characters in `s` and cut it off at `max_len` bytes
+/// (including the ellipsis marking the cut), at a character boundary.
+pub fn sanitize(s: &str, max_len: usize) -> Cow<'_, str> {
+    if s.len() <= max_len && !s.chars().any(char::is_control) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len().min(max_len));
+    let mut truncated = false;
+    let mut buf = [0; 4];
+    for c in s.chars() {
+        let escaped;
+        let piece: &str = match c {
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            c if c.is_control() => {
+                escaped = format!("\\u{{{:x}}}", c as u32);
+                &escaped
+            }
+            c => c.encode_utf8(&mut buf),
+        };
+        if out.len() + piece.len() > max_len {
+            truncated = true;
+            break;
+        }
+        out.push_str(piece);
+    }
+    if truncated && max_len >= ELLIPSIS.len() {
+        while out.len() + ELLIPSIS.len() > max_len {
+            out.pop();
+        }
+        out.push_str(ELLIPSIS);
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_control_chars() {
+        assert_eq!(sanitize("plain", 100), "plain");
+        assert!(matches!(sanitize("plain", 100), Cow::Borrowed(_)));
+        assert_eq!(sanitize("a\nb\tc\u{1b}[0m", 100), "a\\nb\\tc\\u{1b}[0m");
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        assert_eq!(sanitize("abcdefgh", 6), "abc…");
+        // "é" is two bytes, so only two of them fit next to the ellipsis.
+        assert_eq!(sanitize("éééé", 7), "éé…");
+        assert_eq!(sanitize("line\nbreak", 7), "line…");
+    }
+}
@@ -0,0 +1,141 @@
+//! Scheduling information about the current thread.
+
+use crate::packet::DebugAnnotation;
+
+/// Scheduling policy, priority and CPU affinity of a thread.
+#[derive(Debug, Clone)]
+pub struct SchedInfo {
+    pub policy: &'static str,
+    /// Static priority (`sched_priority`), only meaningful for real-time
+    /// policies.
+    pub priority: i32,
+    pub nice: i32,
+    /// CPUs the thread may run on.
+    pub affinity: Vec<usize>,
+}
+
+impl SchedInfo {
+    /// Query the scheduling info of the calling thread.
+    ///
+    /// Returns `None` on platforms where we don't know how to get it.
+    #[cfg(target_os = "linux")]
+    pub fn current() -> Option<SchedInfo> {
+        // SAFETY: All calls only write into the locally owned structs and
+        // `0` refers to the calling thread.
+        unsafe {
+            let policy = match libc::sched_getscheduler(0) {
+                libc::SCHED_OTHER => "SCHED_OTHER",
+                libc::SCHED_FIFO => "SCHED_FIFO",
+                libc::SCHED_RR => "SCHED_RR",
+                libc::SCHED_BATCH => "SCHED_BATCH",
+                libc::SCHED_IDLE => "SCHED_IDLE",
+                6 => "SCHED_DEADLINE",
+                _ => "unknown",
+            };
+
+            let mut param: libc::sched_param = std::mem::zeroed();
+            let priority = if libc::sched_getparam(0, &mut param) == 0 {
+                param.sched_priority
+            } else {
+                0
+            };
+
+            // On Linux, `PRIO_PROCESS` with `who == 0` refers to the calling
+            // thread, not the whole process.
+            let nice = libc::getpriority(libc::PRIO_PROCESS, 0);
+
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            let mut affinity = Vec::new();
+            if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) == 0 {
+                for cpu in 0..libc::CPU_SETSIZE as usize {
+                    if libc::CPU_ISSET(cpu, &set) {
+                        affinity.push(cpu);
+                    }
+                }
+            }
+
+            Some(SchedInfo {
+                policy,
+                priority,
+                nice,
+                affinity,
+            })
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn current() -> Option<SchedInfo> {
+        None
+    }
+
+    pub fn debug_annotations(&self) -> Vec<DebugAnnotation> {
+        vec![
+            DebugAnnotation::new("sched_policy", self.policy),
+            DebugAnnotation::new("sched_priority", self.priority),
+            DebugAnnotation::new("nice", self.nice),
+            DebugAnnotation::new("cpu_affinity", cpu_list(&self.affinity)),
+        ]
+    }
+}
+
+/// The id the OS gives the calling thread, as shown by `perf`, `gdb` or
+/// `top -H`.
+///
+/// Returns `None` on platforms where we don't know how to get it.
+#[cfg(target_os = "linux")]
+pub fn os_thread_id() -> Option<u32> {
+    // SAFETY: gettid cannot fail.
+    Some(unsafe { libc::gettid() } as u32)
+}
+
+#[cfg(windows)]
+pub fn os_thread_id() -> Option<u32> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+    // SAFETY: GetCurrentThreadId cannot fail.
+    Some(unsafe { GetCurrentThreadId() })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn os_thread_id() -> Option<u32> {
+    None
+}
+
+/// Format a sorted list of CPUs in the compact form used by the kernel, e.g.
+/// `0-3,6`.
+fn cpu_list(cpus: &[usize]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let first = cpus[i];
+        let mut last = first;
+        while i + 1 < cpus.len() && cpus[i + 1] == last + 1 {
+            i += 1;
+            last = cpus[i];
+        }
+        if !out.is_empty() {
+            out.push(',');
+        }
+        if first == last {
+            out.push_str(&first.to_string());
+        } else {
+            out.push_str(&format!("{}-{}", first, last));
+        }
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cpu_list;
+
+    #[test]
+    fn cpu_list_ranges() {
+        assert_eq!(cpu_list(&[]), "");
+        assert_eq!(cpu_list(&[2]), "2");
+        assert_eq!(cpu_list(&[0, 1, 2, 3, 6, 8, 9]), "0-3,6,8-9");
+    }
+}
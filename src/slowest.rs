@@ -0,0 +1,117 @@
+//! Keeping only the slowest entries of each span, see
+//! [`PerfettoLayerBuilder::slowest_spans`](crate::PerfettoLayerBuilder::slowest_spans).
+
+use std::{borrow::Cow, collections::HashMap};
+
+use tracing::callsite::Identifier;
+
+use crate::{Message, ThreadId, Timestamp};
+
+/// The slowest entries per span callsite seen so far.
+pub struct Reservoirs {
+    max_kept: usize,
+    callsites: HashMap<Identifier, Reservoir>,
+    /// Callsites in the order they were first seen, so the output does not
+    /// depend on hashing.
+    order: Vec<Identifier>,
+}
+
+pub struct Reservoir {
+    pub name: Cow<'static, str>,
+    /// Thread that last entered the span.
+    pub thread_id: ThreadId,
+    pub kept: Vec<KeptSlice>,
+    /// Number and total duration of the entries that were not kept.
+    pub dropped: u64,
+    pub dropped_ns: u64,
+}
+
+pub struct KeptSlice {
+    duration: u64,
+    pub enter: Message,
+    pub exit: Message,
+}
+
+impl Reservoirs {
+    pub fn new(max_kept: usize) -> Self {
+        Reservoirs {
+            max_kept,
+            callsites: HashMap::new(),
+            order: Vec::new(),
+        }
+    }
+
+    /// Add a complete span entry, given by the message beginning its slice
+    /// and the time and perf counter sample at its end.
+    pub fn add(
+        &mut self,
+        callsite: Identifier,
+        enter: Message,
+        end: Timestamp,
+        end_perf_count: Option<u64>,
+    ) {
+        let Message::Enter {
+            timestamp,
+            ref name,
+            thread_id,
+            ref track,
+            ..
+        } = enter
+        else {
+            return;
+        };
+        let exit = Message::Exit {
+            timestamp: end,
+            name: name.clone(),
+            thread_id,
+            track: track.clone(),
+            perf_count: end_perf_count,
+            args: None,
+            unwound: false,
+        };
+        let duration = end.saturating_sub(timestamp);
+
+        let reservoir = self.callsites.entry(callsite.clone()).or_insert_with(|| {
+            self.order.push(callsite);
+            Reservoir {
+                name: name.clone(),
+                thread_id,
+                kept: Vec::new(),
+                dropped: 0,
+                dropped_ns: 0,
+            }
+        });
+        reservoir.thread_id = thread_id;
+        let slice = KeptSlice {
+            duration,
+            enter,
+            exit,
+        };
+        if reservoir.kept.len() < self.max_kept {
+            reservoir.kept.push(slice);
+            return;
+        }
+        // Only a handful of slices are kept, so a linear search is fine.
+        let fastest = reservoir
+            .kept
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, kept)| kept.duration)
+            .map(|(i, kept)| (i, kept.duration));
+        let dropped_duration = match fastest {
+            Some((i, fastest)) if fastest < duration => {
+                reservoir.kept[i] = slice;
+                fastest
+            }
+            _ => duration,
+        };
+        reservoir.dropped += 1;
+        reservoir.dropped_ns += dropped_duration;
+    }
+
+    pub fn into_reservoirs(mut self) -> impl Iterator<Item = Reservoir> {
+        self.order
+            .into_iter()
+            .filter_map(move |callsite| self.callsites.remove(&callsite))
+    }
+}
@@ -0,0 +1,152 @@
+//! Black box recording: keep the recent past in memory, and only write it
+//! out around interesting events, see
+//! [`PerfettoLayerBuilder::snapshot`](crate::PerfettoLayerBuilder::snapshot).
+
+use std::{collections::VecDeque, time::Duration};
+
+use tracing::{Level, Metadata};
+
+use crate::{Message, Timestamp};
+
+/// The events that write a snapshot.
+#[derive(Debug, Clone)]
+pub enum SnapshotTrigger {
+    /// Events at this level or a more severe one, e.g. `Level::ERROR`.
+    Level(Level),
+    /// Events of this name, e.g. from `tracing::warn!(name: "slow_frame", ...)`.
+    EventNamed(String),
+}
+
+impl SnapshotTrigger {
+    pub(crate) fn matches(&self, metadata: &Metadata<'_>) -> bool {
+        match self {
+            // More verbose levels compare greater.
+            SnapshotTrigger::Level(level) => metadata.level() <= level,
+            SnapshotTrigger::EventNamed(name) => metadata.name() == name,
+        }
+    }
+}
+
+/// Messages of the last `before` nanoseconds, waiting for a trigger.
+pub(crate) struct SnapshotBuffer {
+    before: u64,
+    after: u64,
+    buffer: VecDeque<Message>,
+    /// End of the window after the last trigger, while it is open.
+    recording_until: Option<Timestamp>,
+    /// Messages to write now.
+    ready: Vec<Message>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(before: Duration, after: Duration) -> Self {
+        SnapshotBuffer {
+            before: before.as_nanos() as u64,
+            after: after.as_nanos() as u64,
+            buffer: VecDeque::new(),
+            recording_until: None,
+            ready: Vec::new(),
+        }
+    }
+
+    /// Take a message from the layer, and return the messages that should
+    /// be written now.
+    pub fn process(&mut self, msg: Message) -> std::vec::Drain<'_, Message> {
+        if let Message::Snapshot { timestamp } = msg {
+            self.ready.extend(self.buffer.drain(..));
+            self.recording_until = Some(timestamp.saturating_add(self.after));
+            return self.ready.drain(..);
+        }
+        let timestamp = timestamp(&msg);
+        if let (Some(until), Some(timestamp)) = (self.recording_until, timestamp) {
+            if timestamp > until {
+                self.recording_until = None;
+            }
+        }
+        if self.recording_until.is_some() {
+            self.ready.push(msg);
+        } else if timestamp.is_some() || matches!(msg, Message::NewThread(..)) {
+            self.buffer.push_back(msg);
+            if let Some(now) = timestamp {
+                self.prune(now);
+            }
+        } else {
+            self.ready.push(msg);
+        }
+        self.ready.drain(..)
+    }
+
+    /// Drop the messages that are too old to be part of a snapshot.
+    fn prune(&mut self, now: Timestamp) {
+        while let Some(front) = self.buffer.front() {
+            match timestamp(front) {
+                Some(timestamp) if timestamp.saturating_add(self.before) >= now => break,
+                Some(_) => {
+                    self.buffer.pop_front();
+                }
+                // The thread's later messages need its sequence.
+                None => self.ready.extend(self.buffer.pop_front()),
+            }
+        }
+    }
+}
+
+fn timestamp(msg: &Message) -> Option<Timestamp> {
+    match msg {
+        Message::ThreadExit(_, timestamp)
+        | Message::Enter { timestamp, .. }
+        | Message::Exit { timestamp, .. }
+        | Message::Event { timestamp, .. }
+        | Message::Counters { timestamp, .. }
+        | Message::AndroidLog { timestamp, .. }
+        | Message::State { timestamp, .. }
+        | Message::Region { timestamp, .. } => Some(*timestamp),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn event(timestamp: Timestamp, name: &'static str) -> Message {
+        Message::Event {
+            timestamp,
+            name: Cow::Borrowed(name),
+            args: None,
+            thread_id: 0,
+            track: None,
+            flow_ids: Vec::new(),
+            terminating_flow_ids: Vec::new(),
+            location: None,
+            category: None,
+        }
+    }
+
+    fn names(messages: std::vec::Drain<'_, Message>) -> Vec<String> {
+        messages
+            .map(|msg| match msg {
+                Message::Event { name, .. } => name.into_owned(),
+                Message::NewThread(_, name, _) => name,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn windows() {
+        let mut snapshot = SnapshotBuffer::new(Duration::from_nanos(100), Duration::from_nanos(50));
+        assert!(names(snapshot.process(Message::NewThread(0, "main".into(), None))).is_empty());
+        // Thread registrations are written once nothing is before them.
+        assert_eq!(names(snapshot.process(event(10, "old"))), ["main"]);
+        assert!(names(snapshot.process(event(150, "recent"))).is_empty());
+        assert!(names(snapshot.process(event(200, "error"))).is_empty());
+
+        let written = names(snapshot.process(Message::Snapshot { timestamp: 200 }));
+        assert_eq!(written, ["recent", "error"]);
+        assert_eq!(names(snapshot.process(event(250, "after"))), ["after"]);
+        assert!(names(snapshot.process(event(251, "later"))).is_empty());
+    }
+}
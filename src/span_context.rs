@@ -0,0 +1,121 @@
+//! Linking the trace of a process to those of the subprocesses it spawns,
+//! see [`SpanContext`].
+
+use std::{fmt, str::FromStr};
+
+use crate::new_flow_id;
+
+/// Environment variable that passes a [`SpanContext`] to a subprocess.
+pub const SPAN_CONTEXT_ENV: &str = "TRACING_PERFETTO_SPAN_CONTEXT";
+
+/// Command line flag that passes a [`SpanContext`] to a subprocess, as
+/// `--perfetto-span-context=<context>`.
+pub const SPAN_CONTEXT_ARG: &str = "--perfetto-span-context";
+
+/// A point in the trace of a parent process that the root slices of a
+/// subprocess link back to with a flow.
+///
+/// The parent calls [`export`](Self::export) within the span the
+/// subprocess belongs to, and passes the context on with [`env`](Self::env)
+/// or [`arg`](Self::arg). The subprocess reads it with
+/// [`from_env`](Self::from_env) or [`from_args`](Self::from_args) and hands
+/// it to
+/// [`PerfettoLayerBuilder::parent_span_context`](crate::PerfettoLayerBuilder::parent_span_context).
+/// The flow shows up when both traces are opened together, ideally with
+/// timestamps from [`Origin::SystemBoot`](crate::Origin::SystemBoot).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanContext {
+    pub flow_id: u64,
+}
+
+impl SpanContext {
+    /// Start a flow at the current span.
+    pub fn export() -> SpanContext {
+        let flow_id = new_flow_id();
+        // Flows attach to slices, so this is a short slice within the
+        // current span.
+        let span = tracing::info_span!(
+            target: "tracing_perfetto",
+            "span context exported",
+            "perfetto.flow" = flow_id
+        );
+        span.in_scope(|| {});
+        SpanContext { flow_id }
+    }
+
+    /// The environment variable to set for the subprocess, e.g. with
+    /// [`Command::env`](std::process::Command::env).
+    pub fn env(&self) -> (&'static str, String) {
+        (SPAN_CONTEXT_ENV, self.to_string())
+    }
+
+    /// The command line argument to pass to the subprocess.
+    pub fn arg(&self) -> String {
+        format!("{}={}", SPAN_CONTEXT_ARG, self)
+    }
+
+    /// The context passed with [`env`](Self::env), if any.
+    pub fn from_env() -> Option<SpanContext> {
+        std::env::var(SPAN_CONTEXT_ENV).ok()?.parse().ok()
+    }
+
+    /// The context passed with [`arg`](Self::arg), if any, e.g. from
+    /// `std::env::args()`.
+    pub fn from_args<I>(args: I) -> Option<SpanContext>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        args.into_iter().find_map(|arg| {
+            let value = arg
+                .as_ref()
+                .strip_prefix(SPAN_CONTEXT_ARG)?
+                .strip_prefix('=')?;
+            value.parse().ok()
+        })
+    }
+}
+
+impl fmt::Display for SpanContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.flow_id)
+    }
+}
+
+/// Error parsing a [`SpanContext`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSpanContextError;
+
+impl fmt::Display for ParseSpanContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid span context")
+    }
+}
+
+impl std::error::Error for ParseSpanContextError {}
+
+impl FromStr for SpanContext {
+    type Err = ParseSpanContextError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let flow_id = u64::from_str_radix(s.trim(), 16).map_err(|_| ParseSpanContextError)?;
+        Ok(SpanContext { flow_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let context = SpanContext {
+            flow_id: 0x1234_0000_0007,
+        };
+        assert_eq!(context.to_string().parse(), Ok(context));
+        let args = ["child".to_string(), context.arg()];
+        assert_eq!(SpanContext::from_args(&args), Some(context));
+        assert_eq!(SpanContext::from_args(["--perfetto-span-context"]), None);
+        assert_eq!("not hex".parse::<SpanContext>(), Err(ParseSpanContextError));
+    }
+}
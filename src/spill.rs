@@ -0,0 +1,136 @@
+//! Keeping the queue of the writer short while the output is slow, see
+//! [`PerfettoLayerBuilder::spill_to_disk`](crate::PerfettoLayerBuilder::spill_to_disk).
+//!
+//! The writer encodes packets into a spill file instead of the output while
+//! the queue is long, and copies them over once it has caught up. The
+//! encoded packets take far less memory than the queued messages, and
+//! they are the cheapest format to copy back.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+/// How much to copy to the output at once, between looking at the queue.
+const CHUNK_LEN: usize = 256 * 1024;
+
+pub(crate) struct Spill {
+    dir: PathBuf,
+    /// Number of queued messages above which packets are spilled.
+    max_queued: usize,
+    file: Option<SpillFile>,
+    /// Number of spill files created, to name the next one.
+    files: u32,
+}
+
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: File,
+    /// Bytes written but not read yet.
+    pending: u64,
+}
+
+impl Spill {
+    pub fn new(dir: PathBuf, max_queued: usize) -> Self {
+        Spill {
+            dir,
+            max_queued,
+            file: None,
+            files: 0,
+        }
+    }
+
+    /// Whether packets go to the spill file rather than the output.
+    pub fn active(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Start spilling if `queued` messages are too many.
+    pub fn check(&mut self, queued: usize) -> io::Result<()> {
+        if queued <= self.max_queued || self.file.is_some() {
+            return Ok(());
+        }
+        let path = self.dir.join(format!(
+            "tracing-perfetto-spill-{}-{}",
+            std::process::id(),
+            self.files
+        ));
+        self.files += 1;
+        let writer = BufWriter::new(File::create(&path)?);
+        let reader = File::open(&path)?;
+        self.file = Some(SpillFile {
+            path,
+            writer,
+            reader,
+            pending: 0,
+        });
+        Ok(())
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let file = self.file.as_mut().expect("not spilling");
+        file.writer.write_all(bytes)?;
+        file.pending += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Copy the next chunk of spilled packets to `out`, returning the
+    /// number of bytes copied. Spilling stops once all are copied.
+    pub fn unspill(&mut self, out: &mut impl Write) -> io::Result<u64> {
+        let Some(file) = &mut self.file else {
+            return Ok(0);
+        };
+        file.writer.flush()?;
+        let len = file.pending.min(CHUNK_LEN as u64);
+        let copied = io::copy(&mut (&mut file.reader).take(len), out)?;
+        if copied < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "spill file is shorter than written",
+            ));
+        }
+        file.pending -= copied;
+        if file.pending == 0 {
+            let file = self.file.take().unwrap();
+            drop((file.writer, file.reader));
+            std::fs::remove_file(file.path)?;
+        }
+        Ok(copied)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            drop((file.writer, file.reader));
+            let _ignore_err = std::fs::remove_file(file.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir();
+        let mut spill = Spill::new(dir, 2);
+        spill.check(2).unwrap();
+        assert!(!spill.active());
+        spill.check(3).unwrap();
+        assert!(spill.active());
+        let data: Vec<u8> = (0..CHUNK_LEN + 100).map(|i| i as u8).collect();
+        spill.write(&data).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(spill.unspill(&mut out).unwrap(), CHUNK_LEN as u64);
+        assert!(spill.active());
+        spill.write(b"more").unwrap();
+        assert_eq!(spill.unspill(&mut out).unwrap(), 104);
+        assert!(!spill.active());
+        assert_eq!(&out[..data.len()], &data[..]);
+        assert_eq!(&out[data.len()..], b"more");
+    }
+}
@@ -0,0 +1,42 @@
+//! Tracks showing the state of a component over time, see
+//! [`PerfettoLayer::state_track`](crate::PerfettoLayer::state_track).
+
+use std::sync::Arc;
+
+use crossbeam_channel::Sender;
+
+use crate::{clock::Clock, queue_message, stats::Counters, Message};
+
+/// A track on which each state is a slice, lasting until the next state is
+/// set. Cheap to clone; all clones set the state of the same track.
+#[derive(Debug, Clone)]
+pub struct StateTrack {
+    pub(crate) name: Arc<str>,
+    pub(crate) sender: Sender<Message>,
+    pub(crate) clock: Clock,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl StateTrack {
+    /// End the current state, if any, and enter `state`.
+    pub fn set_state(&self, state: impl Into<String>) {
+        self.send(Some(state.into()));
+    }
+
+    /// End the current state, leaving a gap until the next one.
+    pub fn clear(&self) {
+        self.send(None);
+    }
+
+    fn send(&self, state: Option<String>) {
+        if self.counters.stopped() {
+            return;
+        }
+        let msg = Message::State {
+            timestamp: self.clock.now(),
+            track: self.name.clone(),
+            state,
+        };
+        queue_message(&self.sender, &self.counters, msg);
+    }
+}
@@ -0,0 +1,67 @@
+//! Fixed limits for the `static-config` feature.
+//!
+//! With the feature enabled, the writer keeps its per-thread state in an
+//! array instead of a growing vector, and the queue between the traced
+//! threads and the writer has a fixed capacity, so memory use is bounded
+//! up front. The limits can be changed when building the crate through the
+//! `TRACING_PERFETTO_MAX_THREADS` and `TRACING_PERFETTO_QUEUE_CAPACITY`
+//! environment variables.
+
+use crate::ThreadId;
+
+/// Number of threads that get a track of their own. Further threads share
+/// one track, as with [`PerfettoLayerBuilder::max_threads`], which can only
+/// lower this limit.
+///
+/// [`PerfettoLayerBuilder::max_threads`]: crate::PerfettoLayerBuilder::max_threads
+pub const MAX_THREADS: usize = parse(option_env!("TRACING_PERFETTO_MAX_THREADS"), 16);
+
+/// Number of messages that can be queued for the writer. When the queue is
+/// full, traced threads wait for the writer to catch up. Events recorded on
+/// the writer thread itself, e.g. by an `on_rotate` callback, are dropped
+/// instead, and counted in [`TraceStats::messages_dropped`].
+///
+/// [`TraceStats::messages_dropped`]: crate::TraceStats::messages_dropped
+pub const QUEUE_CAPACITY: usize = parse(option_env!("TRACING_PERFETTO_QUEUE_CAPACITY"), 64);
+
+/// Number of per-thread slots in the writer: one per thread with a track of
+/// its own, plus the shared one.
+pub(crate) const SEQUENCE_SLOTS: usize = MAX_THREADS + 1;
+
+/// The thread limit to use, given the one set on the builder.
+pub(crate) fn max_threads(configured: Option<ThreadId>) -> ThreadId {
+    let max = MAX_THREADS as ThreadId;
+    configured.map_or(max, |configured| configured.min(max))
+}
+
+/// Parse a decimal number at compile time.
+const fn parse(value: Option<&str>, default: usize) -> usize {
+    let Some(value) = value else {
+        return default;
+    };
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "limit must not be empty");
+    let mut result = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "limit must be a decimal number");
+        result = result * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(result > 0, "limit must be positive");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse(None, 16), 16);
+        assert_eq!(parse(Some("128"), 16), 128);
+        assert_eq!(max_threads(None), MAX_THREADS as ThreadId);
+        assert_eq!(max_threads(Some(2)), 2);
+        assert_eq!(max_threads(Some(u32::MAX)), MAX_THREADS as ThreadId);
+    }
+}
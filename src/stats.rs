@@ -0,0 +1,125 @@
+//! Live counters about the trace being written.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use crossbeam_channel::Sender;
+
+use crate::Message;
+
+/// Counters updated by the writer thread.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub packets_written: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub bytes_flushed: AtomicU64,
+    /// See [`TraceStats::events_written`].
+    pub events_written: AtomicU64,
+    /// See [`TraceStats::packets_dropped`].
+    pub packets_dropped: AtomicU64,
+    /// See [`TraceStats::messages_dropped`].
+    pub messages_dropped: AtomicU64,
+    /// See [`TraceStats::violations`].
+    pub violations: AtomicU64,
+    /// See [`TraceStats::write_stalls`].
+    pub write_stalls: AtomicU64,
+    /// See [`TraceStats::args_omitted`].
+    pub args_omitted: AtomicU64,
+    /// See [`TraceStats::bytes_spilled`].
+    pub bytes_spilled: AtomicU64,
+    /// Set when the writer has finished the trace, so the layer can stop
+    /// recording.
+    pub stopped: AtomicBool,
+}
+
+impl Counters {
+    pub(crate) fn add_packet(&self, bytes: usize) {
+        self.packets_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn stats(&self, messages_queued: usize) -> TraceStats {
+        TraceStats {
+            packets_written: self.packets_written.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            file_size: self.bytes_flushed.load(Ordering::Relaxed),
+            messages_queued,
+            events_written: self.events_written.load(Ordering::Relaxed),
+            packets_dropped: self.packets_dropped.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            violations: self.violations.load(Ordering::Relaxed),
+            write_stalls: self.write_stalls.load(Ordering::Relaxed),
+            args_omitted: self.args_omitted.load(Ordering::Relaxed),
+            bytes_spilled: self.bytes_spilled.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn flushed(&self) {
+        self.bytes_flushed.store(
+            self.bytes_written.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// A snapshot of the counters of a trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceStats {
+    /// Number of packets encoded by the writer.
+    pub packets_written: u64,
+    /// Number of bytes encoded by the writer, including data that is still
+    /// buffered in memory.
+    pub bytes_written: u64,
+    /// Number of bytes handed to the operating system, i.e., the current
+    /// size of the trace file.
+    pub file_size: u64,
+    /// Number of messages waiting for the writer thread.
+    pub messages_queued: usize,
+    /// Number of slices, instants and counter values among the packets
+    /// written.
+    pub events_written: u64,
+    /// Number of packets left out as they could not be encoded.
+    pub packets_dropped: u64,
+    /// Number of messages still queued when the trace ended, which never
+    /// made it into the trace, and, with the `static-config` feature, of
+    /// messages the writer thread dropped because the queue was full.
+    pub messages_dropped: u64,
+    /// Number of problems found in the trace in strict mode, see
+    /// [`PerfettoLayerBuilder::strict`](crate::PerfettoLayerBuilder::strict).
+    pub violations: u64,
+    /// Number of writes that took longer than the
+    /// [`write_stall_threshold`](crate::PerfettoLayerBuilder::write_stall_threshold).
+    pub write_stalls: u64,
+    /// Number of slices and instants written without their arguments, as
+    /// the [`args_budget`](crate::PerfettoLayerBuilder::args_budget) was
+    /// used up. Updated at the end of each second.
+    pub args_omitted: u64,
+    /// Number of bytes that went through a spill file, see
+    /// [`PerfettoLayerBuilder::spill_to_disk`](crate::PerfettoLayerBuilder::spill_to_disk).
+    pub bytes_spilled: u64,
+}
+
+/// A cheap handle to poll the [`TraceStats`] of a running trace, e.g. from a
+/// metrics exporter.
+#[derive(Debug, Clone)]
+pub struct StatsHandle {
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) queue: Sender<Message>,
+}
+
+impl StatsHandle {
+    pub fn stats(&self) -> TraceStats {
+        self.counters.stats(self.queue.len())
+    }
+}
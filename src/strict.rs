@@ -0,0 +1,265 @@
+//! Checks of the packets the writer produces, see
+//! [`PerfettoLayerBuilder::strict`](crate::PerfettoLayerBuilder::strict).
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{atomic::Ordering, Arc},
+};
+
+use crate::{
+    packet::{
+        DebugAnnotation, DebugValue, EventType, IString, PacketData, TracePacket,
+        SEQ_INCREMENTAL_STATE_CLEARED,
+    },
+    stats::Counters,
+    writer::ErrorHook,
+};
+
+/// What a reader knows about a sequence at its current packet.
+#[derive(Default)]
+struct SequenceCheck {
+    last_timestamp: u64,
+    event_names: HashSet<u64>,
+    annotation_names: HashSet<u64>,
+    string_values: HashSet<u64>,
+    /// Number of open slices of each track. `None` is the sequence's
+    /// default track.
+    open_slices: HashMap<Option<u64>, usize>,
+}
+
+/// Finds packets that trace processors would misread or drop.
+pub(crate) struct Validator {
+    sequences: HashMap<u32, SequenceCheck>,
+    /// Where the violations are counted.
+    counters: Arc<Counters>,
+    /// Whether slices are written once the span is exited, so their
+    /// timestamps lag behind those of the packets around them.
+    deferred_slices: bool,
+    /// Where they are reported.
+    on_error: ErrorHook,
+}
+
+impl Validator {
+    pub fn new(counters: Arc<Counters>, deferred_slices: bool, on_error: ErrorHook) -> Self {
+        Validator {
+            sequences: HashMap::new(),
+            counters,
+            deferred_slices,
+            on_error,
+        }
+    }
+
+    pub fn check(&mut self, packet: &TracePacket) {
+        let sequence_id = packet.trusted_packet_sequence_id;
+        let sequence = self.sequences.entry(sequence_id).or_default();
+        let mut violations = Vec::new();
+
+        let deferred = self.deferred_slices
+            && matches!(
+                &packet.data,
+                PacketData::TrackEvent(event)
+                    if matches!(event.event_type, EventType::SliceBegin | EventType::SliceEnd)
+            );
+        if !deferred {
+            if packet.timestamp < sequence.last_timestamp {
+                violations.push(format!(
+                    "timestamp {} before the previous one, {}",
+                    packet.timestamp, sequence.last_timestamp
+                ));
+            }
+            sequence.last_timestamp = sequence.last_timestamp.max(packet.timestamp);
+        }
+
+        if packet.sequence_flags & SEQ_INCREMENTAL_STATE_CLEARED != 0 {
+            sequence.event_names.clear();
+            sequence.annotation_names.clear();
+            sequence.string_values.clear();
+        }
+        if let Some(interned) = &packet.interned_data {
+            sequence
+                .event_names
+                .extend(interned.event_names.iter().map(|name| name.iid));
+            sequence
+                .annotation_names
+                .extend(interned.debug_annotation_names.iter().map(|name| name.iid));
+            sequence.string_values.extend(
+                interned
+                    .debug_annotation_string_values
+                    .iter()
+                    .map(|value| value.iid),
+            );
+        }
+
+        if let PacketData::TrackEvent(event) = &packet.data {
+            if let IString::Interned(iid) = event.name {
+                if !sequence.event_names.contains(&iid) {
+                    violations.push(format!("event name {} used before it is interned", iid));
+                }
+            }
+            sequence.check_annotations(&event.debug_annotations, &mut violations);
+
+            let open = sequence.open_slices.entry(event.track_uuid).or_default();
+            match event.event_type {
+                EventType::SliceBegin => *open += 1,
+                EventType::SliceEnd => {
+                    if *open > 0 {
+                        *open -= 1;
+                    } else {
+                        violations.push(format!(
+                            "slice end without a begin on track {}",
+                            track_name(event.track_uuid)
+                        ));
+                    }
+                }
+                EventType::Instant | EventType::Counter => {}
+            }
+        }
+
+        for violation in violations {
+            self.report(sequence_id, &violation);
+        }
+    }
+
+    /// Report the slices that never ended, and return the total number of
+    /// violations.
+    pub fn finish(&mut self) -> u64 {
+        let mut unclosed = Vec::new();
+        for (sequence_id, sequence) in &self.sequences {
+            for (track, open) in &sequence.open_slices {
+                if *open > 0 {
+                    unclosed.push((*sequence_id, *track, *open));
+                }
+            }
+        }
+        unclosed.sort();
+        for (sequence_id, track, count) in unclosed {
+            self.report(
+                sequence_id,
+                &format!(
+                    "{} slices never ended on track {}",
+                    count,
+                    track_name(track)
+                ),
+            );
+        }
+        let violations = self.counters.violations.load(Ordering::Relaxed);
+        if violations > 0 {
+            self.on_error.report_partial(
+                "strict",
+                io::ErrorKind::InvalidData,
+                &format_args!("{} violations in the trace", violations),
+            );
+        }
+        violations
+    }
+
+    fn report(&mut self, sequence_id: u32, violation: &str) {
+        self.counters.violations.fetch_add(1, Ordering::Relaxed);
+        self.on_error.report_partial(
+            "strict",
+            io::ErrorKind::InvalidData,
+            &format_args!("sequence {}: {}", sequence_id, violation),
+        );
+    }
+}
+
+impl SequenceCheck {
+    fn check_annotations(&self, annotations: &[DebugAnnotation], violations: &mut Vec<String>) {
+        for annotation in annotations {
+            if let IString::Interned(iid) = annotation.name {
+                if !self.annotation_names.contains(&iid) {
+                    violations.push(format!(
+                        "annotation name {} used before it is interned",
+                        iid
+                    ));
+                }
+            }
+            self.check_value(&annotation.value, violations);
+        }
+    }
+
+    fn check_value(&self, value: &DebugValue, violations: &mut Vec<String>) {
+        match value {
+            DebugValue::InternedString(iid) if !self.string_values.contains(iid) => {
+                violations.push(format!("string value {} used before it is interned", iid));
+            }
+            DebugValue::Dict(entries) => self.check_annotations(entries, violations),
+            DebugValue::Array(values) => {
+                for value in values {
+                    self.check_value(value, violations);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn track_name(track_uuid: Option<u64>) -> String {
+    match track_uuid {
+        Some(uuid) => uuid.to_string(),
+        None => "of the sequence".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{EventName, InternedData, TrackEvent};
+
+    fn slice(event_type: EventType, timestamp: u64, interned: bool) -> TracePacket {
+        TracePacket {
+            timestamp,
+            data: PacketData::TrackEvent(TrackEvent {
+                event_type,
+                name: IString::Interned(1),
+                debug_annotations: Vec::new(),
+                track_uuid: None,
+                category_iids: Vec::new(),
+                source_location_iid: None,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                counter_value: None,
+                double_counter_value: None,
+                extra_counters: Vec::new(),
+                extra_double_counters: Vec::new(),
+            }),
+            sequence_flags: 0,
+            trusted_uid: 0,
+            trusted_packet_sequence_id: 1,
+            interned_data: interned.then(|| InternedData {
+                event_names: vec![EventName {
+                    iid: 1,
+                    name: "work".to_string(),
+                }],
+                ..InternedData::default()
+            }),
+            trace_packet_defaults: None,
+        }
+    }
+
+    #[test]
+    fn violations() {
+        let mut validator = Validator::new(Arc::default(), false, ErrorHook::default());
+        validator.check(&slice(EventType::SliceBegin, 10, true));
+        validator.check(&slice(EventType::SliceEnd, 20, false));
+        assert_eq!(validator.finish(), 0);
+
+        // Out of order, and an end without a begin.
+        validator.check(&slice(EventType::SliceEnd, 15, false));
+        assert_eq!(validator.finish(), 2);
+
+        let mut validator = Validator::new(Arc::default(), false, ErrorHook::default());
+        // Not interned, and never ended.
+        validator.check(&slice(EventType::SliceBegin, 10, false));
+        assert_eq!(validator.finish(), 2);
+
+        // Slices written once the span is exited lag behind.
+        let mut validator = Validator::new(Arc::default(), true, ErrorHook::default());
+        validator.check(&slice(EventType::SliceBegin, 20, true));
+        validator.check(&slice(EventType::SliceEnd, 30, false));
+        validator.check(&slice(EventType::SliceBegin, 10, false));
+        validator.check(&slice(EventType::SliceEnd, 15, false));
+        assert_eq!(validator.finish(), 0);
+    }
+}
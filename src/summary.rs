@@ -0,0 +1,216 @@
+//! Statistics of the span durations, written next to the trace, see
+//! [`PerfettoLayerBuilder::span_summary`](crate::PerfettoLayerBuilder::span_summary).
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::{ThreadId, Timestamp};
+
+/// The file format of the span summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// An array of objects with `name`, `count`, `total_ns`, `mean_ns` and
+    /// `p95_ns` fields.
+    Json,
+    /// A header line and a line per span name, with the same columns.
+    Csv,
+}
+
+/// Histogram buckets per power of two, which bounds the error of the
+/// percentile to about 9%.
+const BUCKETS_PER_OCTAVE: f64 = 8.0;
+
+#[derive(Default)]
+struct SpanStats {
+    count: u64,
+    total: u64,
+    max: u64,
+    /// Number of durations in each bucket.
+    histogram: Vec<u64>,
+}
+
+impl SpanStats {
+    fn add(&mut self, duration: u64) {
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+        let bucket = bucket(duration);
+        if self.histogram.len() <= bucket {
+            self.histogram.resize(bucket + 1, 0);
+        }
+        self.histogram[bucket] += 1;
+    }
+
+    /// The upper end of the bucket holding the 95th percentile.
+    fn p95(&self) -> u64 {
+        let rank = (self.count * 95).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.histogram.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = 2f64.powf((bucket + 1) as f64 / BUCKETS_PER_OCTAVE) as u64;
+                return upper.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn bucket(duration: u64) -> usize {
+    if duration == 0 {
+        return 0;
+    }
+    ((duration as f64).log2() * BUCKETS_PER_OCTAVE) as usize
+}
+
+/// Collects the durations of span entries while the trace is written.
+pub(crate) struct SpanSummary {
+    path: PathBuf,
+    format: SummaryFormat,
+    /// Entry timestamps of the open slices of each thread and track.
+    open: HashMap<(ThreadId, Option<Arc<str>>), Vec<Timestamp>>,
+    spans: BTreeMap<Cow<'static, str>, SpanStats>,
+}
+
+impl SpanSummary {
+    pub fn new(path: PathBuf, format: SummaryFormat) -> Self {
+        SpanSummary {
+            path,
+            format,
+            open: HashMap::new(),
+            spans: BTreeMap::new(),
+        }
+    }
+
+    pub fn enter(&mut self, thread_id: ThreadId, track: Option<&Arc<str>>, timestamp: Timestamp) {
+        self.open
+            .entry((thread_id, track.cloned()))
+            .or_default()
+            .push(timestamp);
+    }
+
+    pub fn exit(
+        &mut self,
+        thread_id: ThreadId,
+        track: Option<&Arc<str>>,
+        name: Cow<'static, str>,
+        timestamp: Timestamp,
+    ) {
+        let entered_at = self
+            .open
+            .get_mut(&(thread_id, track.cloned()))
+            .and_then(Vec::pop);
+        if let Some(entered_at) = entered_at {
+            self.add(name, timestamp.saturating_sub(entered_at));
+        }
+    }
+
+    /// Count a complete span entry.
+    pub fn add(&mut self, name: impl Into<Cow<'static, str>>, duration: u64) {
+        self.spans.entry(name.into()).or_default().add(duration);
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        fs::write(&self.path, self.render())
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        match self.format {
+            SummaryFormat::Json => {
+                out.push('[');
+                for (i, (name, stats)) in self.spans.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    let _ = write!(
+                        out,
+                        "\n  {{\"name\": {}, \"count\": {}, \"total_ns\": {}, \"mean_ns\": {}, \"p95_ns\": {}}}",
+                        json_string(name),
+                        stats.count,
+                        stats.total,
+                        stats.total / stats.count,
+                        stats.p95()
+                    );
+                }
+                out.push_str("\n]\n");
+            }
+            SummaryFormat::Csv => {
+                out.push_str("name,count,total_ns,mean_ns,p95_ns\n");
+                for (name, stats) in &self.spans {
+                    let _ = writeln!(
+                        out,
+                        "{},{},{},{},{}",
+                        csv_field(name),
+                        stats.count,
+                        stats.total,
+                        stats.total / stats.count,
+                        stats.p95()
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let mut summary = SpanSummary::new(PathBuf::new(), SummaryFormat::Csv);
+        let track: Arc<str> = "db".into();
+        summary.enter(0, None, 0);
+        summary.enter(0, Some(&track), 10);
+        summary.exit(0, Some(&track), "query, slow".into(), 30);
+        summary.exit(0, None, "request".into(), 100);
+        for duration in 1..=100 {
+            summary.add("poll", duration * 1000);
+        }
+        assert_eq!(
+            summary.render(),
+            "name,count,total_ns,mean_ns,p95_ns\n\
+             poll,100,5050000,50500,100000\n\
+             \"query, slow\",1,20,20,20\n\
+             request,1,100,100,100\n"
+        );
+
+        summary.format = SummaryFormat::Json;
+        assert!(summary
+            .render()
+            .contains("{\"name\": \"request\", \"count\": 1, \"total_ns\": 100, \"mean_ns\": 100, \"p95_ns\": 100}"));
+    }
+}
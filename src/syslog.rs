@@ -0,0 +1,147 @@
+//! Pointing operators from error logs to the trace.
+//!
+//! Error events are logged to syslog (and thereby to the systemd journal)
+//! together with the path of the trace file that records them, so an alert
+//! on the log line leads straight to the right trace.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tracing::field::Visit;
+
+use crate::writer::ErrorHook;
+
+/// The path of the trace file currently being written, if the trace goes
+/// to a known path. Updated by the writer thread when files are rotated.
+pub type CurrentPath = Arc<Mutex<Option<PathBuf>>>;
+
+/// `LOG_USER | LOG_ERR`
+const PRIORITY: u8 = 8 + 3;
+
+/// A connection to the local syslog daemon.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct Syslog {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    tag: String,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Connect to `/dev/log`, which journald listens on as well.
+    ///
+    /// Returns `None` (reporting the error) if that fails, and on platforms
+    /// other than Unix.
+    #[cfg(unix)]
+    pub fn connect(on_error: &ErrorHook) -> Option<Syslog> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|socket| socket.connect("/dev/log").map(|()| socket));
+        match socket {
+            Ok(socket) => Some(Syslog {
+                socket,
+                tag: program_name(),
+                pid: std::process::id(),
+            }),
+            Err(err) => {
+                on_error.report_partial("cannot connect to syslog", err.kind(), &err);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_on_error: &ErrorHook) -> Option<Syslog> {
+        None
+    }
+
+    /// Log an error event along with the trace file.
+    pub fn error(&self, event: &tracing::Event<'_>, trace_path: Option<&Path>) {
+        let mut v = MessageVisitor::default();
+        event.record(&mut v);
+        let line = format_line(
+            &self.tag,
+            self.pid,
+            event.metadata().name(),
+            v.message.as_deref(),
+            trace_path,
+        );
+        self.send(&line);
+    }
+
+    #[cfg(unix)]
+    fn send(&self, line: &str) {
+        // Best effort: the daemon may be gone or its queue full.
+        let _ignore_err = self.socket.send(line.as_bytes());
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _line: &str) {}
+}
+
+fn program_name() -> String {
+    std::env::args()
+        .next()
+        .as_deref()
+        .map(Path::new)
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "tracing-perfetto".to_string())
+}
+
+fn format_line(
+    tag: &str,
+    pid: u32,
+    name: &str,
+    message: Option<&str>,
+    trace_path: Option<&Path>,
+) -> String {
+    let mut line = format!("<{}>{}[{}]: {}", PRIORITY, tag, pid, name);
+    if let Some(message) = message {
+        line.push_str(": ");
+        line.push_str(message);
+    }
+    if let Some(path) = trace_path {
+        line.push_str(&format!(" (trace: {})", path.display()));
+    }
+    // One event, one line.
+    line.replace('\n', " ")
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+    message: Option<String>,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_format() {
+        assert_eq!(
+            format_line(
+                "server",
+                7,
+                "event src/main.rs:3",
+                Some("request failed\nretrying"),
+                Some(Path::new("/var/trace.perfetto-trace"))
+            ),
+            "<11>server[7]: event src/main.rs:3: request failed retrying \
+             (trace: /var/trace.perfetto-trace)"
+        );
+        assert_eq!(
+            format_line("server", 7, "oops", None, None),
+            "<11>server[7]: oops"
+        );
+    }
+}
@@ -0,0 +1,747 @@
+//! Helpers for testing instrumentation: record a closure into an in-memory
+//! trace, and check which slices and instants ended up in it.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! let trace = tracing_perfetto::test::capture(|| {
+//!     tracing::info_span!("load", user = "alice").in_scope(|| {
+//!         tracing::info!(items = 3, "loaded");
+//!     });
+//! });
+//! trace
+//!     .slice("load")
+//!     .assert_arg("user", "alice")
+//!     .assert_duration(..Duration::from_secs(1));
+//! trace.instant("loaded").assert_arg("items", 3);
+//! ```
+//!
+//! Only spans and events of the thread running the closure are recorded,
+//! unless other threads are given the subscriber, e.g. with
+//! [`tracing::Dispatch`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    ops::RangeBounds,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+use crate::{MessagePolicy, PerfettoLayerBuilder, SEQ_INCREMENTAL_STATE_CLEARED};
+
+/// Run `f` with a subscriber that records into a trace, and return the
+/// trace. Arguments are included, and events are named by their messages.
+pub fn capture<F: FnOnce()>(f: F) -> Trace {
+    let builder = PerfettoLayerBuilder::new()
+        .include_args(true)
+        .message_policy(MessagePolicy::Name);
+    capture_with(builder, f)
+}
+
+/// Like [`capture`], but with a layer configured by `builder`. The output
+/// set on the builder is replaced.
+pub fn capture_with<F: FnOnce()>(builder: PerfettoLayerBuilder<Registry>, f: F) -> Trace {
+    Trace::parse(&capture_bytes(builder, f))
+}
+
+/// Like [`capture_with`], but return the encoded trace, for checks of what
+/// [`Trace`] doesn't decode, such as track descriptors.
+pub(crate) fn capture_bytes<F: FnOnce()>(builder: PerfettoLayerBuilder<Registry>, f: F) -> Vec<u8> {
+    let out = SharedBuffer::default();
+    let (layer, guard) = builder.writer(out.clone()).build();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), f);
+    drop(guard);
+
+    let data = out.0.lock().unwrap();
+    data.clone()
+}
+
+/// An output that stays readable after the writer thread is done with it.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An argument value.
+///
+/// Numbers compare equal regardless of their type, so `assert_arg("n", 3)`
+/// matches both signed and unsigned fields.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    Double(f64),
+    String(String),
+    Dict(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Uint(n) => Some(n as f64),
+            Value::Int(n) => Some(n as f64),
+            Value::Double(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Dict(a), Value::Dict(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value {
+        Value::Uint(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(d: f64) -> Value {
+        Value::Double(d)
+    }
+}
+
+/// A span entry.
+#[derive(Debug, Clone)]
+pub struct Slice {
+    pub name: String,
+    pub track: String,
+    /// Timestamps in nanoseconds.
+    pub start: u64,
+    /// `None` if the span was still entered when the trace ended.
+    pub end: Option<u64>,
+    /// The arguments of the begin and end of the slice.
+    pub args: Vec<(String, Value)>,
+    pub categories: Vec<String>,
+    /// Flows the slice starts or continues, and those it terminates.
+    pub flows: Vec<u64>,
+    pub terminating_flows: Vec<u64>,
+}
+
+impl Slice {
+    pub fn duration(&self) -> Option<Duration> {
+        self.end
+            .map(|end| Duration::from_nanos(end.saturating_sub(self.start)))
+    }
+
+    pub fn arg(&self, name: &str) -> Option<&Value> {
+        find_arg(&self.args, name)
+    }
+
+    /// Panic unless the slice has the argument `name` with value
+    /// `expected`.
+    #[track_caller]
+    pub fn assert_arg<V: Into<Value>>(&self, name: &str, expected: V) -> &Self {
+        check_arg("slice", &self.name, &self.args, name, expected.into());
+        self
+    }
+
+    /// Panic unless the slice has ended, and took a duration in `range`.
+    #[track_caller]
+    pub fn assert_duration<R: RangeBounds<Duration> + fmt::Debug>(&self, range: R) -> &Self {
+        match self.duration() {
+            Some(duration) if range.contains(&duration) => {}
+            Some(duration) => panic!(
+                "slice {:?} took {:?}, expected {:?}",
+                self.name, duration, range
+            ),
+            None => panic!("slice {:?} did not end", self.name),
+        }
+        self
+    }
+}
+
+/// An event.
+#[derive(Debug, Clone)]
+pub struct Instant {
+    pub name: String,
+    pub track: String,
+    pub timestamp: u64,
+    pub args: Vec<(String, Value)>,
+    pub categories: Vec<String>,
+    pub flows: Vec<u64>,
+    pub terminating_flows: Vec<u64>,
+}
+
+impl Instant {
+    pub fn arg(&self, name: &str) -> Option<&Value> {
+        find_arg(&self.args, name)
+    }
+
+    /// Panic unless the instant has the argument `name` with value
+    /// `expected`.
+    #[track_caller]
+    pub fn assert_arg<V: Into<Value>>(&self, name: &str, expected: V) -> &Self {
+        check_arg("instant", &self.name, &self.args, name, expected.into());
+        self
+    }
+}
+
+fn find_arg<'a>(args: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    args.iter()
+        .find(|(arg, _)| arg == name)
+        .map(|(_, value)| value)
+}
+
+#[track_caller]
+fn check_arg(kind: &str, owner: &str, args: &[(String, Value)], name: &str, expected: Value) {
+    match find_arg(args, name) {
+        Some(value) if *value == expected => {}
+        Some(value) => panic!(
+            "{} {:?} has {} = {:?}, expected {:?}",
+            kind, owner, name, value, expected
+        ),
+        None => panic!(
+            "{} {:?} has no argument {:?}, only {:?}",
+            kind,
+            owner,
+            name,
+            args.iter().map(|(arg, _)| arg).collect::<Vec<_>>()
+        ),
+    }
+}
+
+/// A sample of a counter.
+#[derive(Debug, Clone)]
+pub struct CounterSample {
+    pub track: String,
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+/// The slices, instants and counter samples of a trace, in the order they
+/// were written.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub slices: Vec<Slice>,
+    pub instants: Vec<Instant>,
+    pub counters: Vec<CounterSample>,
+}
+
+impl Trace {
+    /// Read a trace written by this crate.
+    ///
+    /// Panics if it cannot be decoded.
+    pub fn parse(data: &[u8]) -> Trace {
+        let mut parser = Parser::default();
+        for (field, value) in Fields(data) {
+            if let (1, Wire::Bytes(packet)) = (field, value) {
+                parser.packet(packet);
+            }
+        }
+        parser.finish()
+    }
+
+    pub fn slices_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Slice> + 'a {
+        self.slices.iter().filter(move |slice| slice.name == name)
+    }
+
+    pub fn instants_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Instant> + 'a {
+        self.instants
+            .iter()
+            .filter(move |instant| instant.name == name)
+    }
+
+    /// The first slice named `name`. Panics if there is none.
+    #[track_caller]
+    pub fn slice(&self, name: &str) -> &Slice {
+        match self.slices.iter().find(|slice| slice.name == name) {
+            Some(slice) => slice,
+            None => panic!(
+                "no slice {:?}, only {:?}",
+                name,
+                self.slices.iter().map(|s| &s.name).collect::<Vec<_>>()
+            ),
+        }
+    }
+
+    /// The first instant named `name`. Panics if there is none.
+    #[track_caller]
+    pub fn instant(&self, name: &str) -> &Instant {
+        match self.instants.iter().find(|instant| instant.name == name) {
+            Some(instant) => instant,
+            None => panic!(
+                "no instant {:?}, only {:?}",
+                name,
+                self.instants.iter().map(|i| &i.name).collect::<Vec<_>>()
+            ),
+        }
+    }
+
+    /// Panic if there is a slice named `name`.
+    #[track_caller]
+    pub fn assert_no_slice(&self, name: &str) {
+        let count = self.slices_named(name).count();
+        assert!(count == 0, "found {} slices {:?}", count, name);
+    }
+}
+
+/// Panic unless `trace` has a slice named `name` with the given arguments,
+/// and evaluate to the slice.
+///
+/// ```
+/// # let trace = tracing_perfetto::test::capture(|| {
+/// #     tracing::info_span!("load", user = "alice", attempt = 2).in_scope(|| {});
+/// # });
+/// tracing_perfetto::assert_slice!(trace, "load", user = "alice", attempt = 2);
+/// ```
+#[macro_export]
+macro_rules! assert_slice {
+    ($trace:expr, $name:expr $(, $arg:ident = $value:expr)* $(,)?) => {{
+        let slice = $trace.slice($name);
+        $(slice.assert_arg(stringify!($arg), $value);)*
+        slice
+    }};
+}
+
+/// Panic unless `trace` has an instant named `name` with the given
+/// arguments, and evaluate to the instant.
+#[macro_export]
+macro_rules! assert_instant {
+    ($trace:expr, $name:expr $(, $arg:ident = $value:expr)* $(,)?) => {{
+        let instant = $trace.instant($name);
+        $(instant.assert_arg(stringify!($arg), $value);)*
+        instant
+    }};
+}
+
+/// A field value in protobuf wire format.
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+/// The fields of an encoded message.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> u64 {
+        let mut value = 0;
+        for (i, byte) in self.0.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.0 = &self.0[i + 1..];
+                return value;
+            }
+        }
+        panic!("truncated varint");
+    }
+
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        assert!(self.0.len() >= len, "truncated field");
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        data
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (u64, Wire<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let tag = self.varint();
+        let value = match tag & 7 {
+            0 => Wire::Varint(self.varint()),
+            1 => Wire::Fixed64(u64::from_le_bytes(self.take(8).try_into().unwrap())),
+            2 => {
+                let len = self.varint() as usize;
+                Wire::Bytes(self.take(len))
+            }
+            5 => {
+                self.take(4);
+                Wire::Fixed32
+            }
+            wire_type => panic!("unexpected wire type {}", wire_type),
+        };
+        Some((tag >> 3, value))
+    }
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+/// The interning tables and defaults of a packet sequence.
+#[derive(Default)]
+struct Sequence {
+    event_names: HashMap<u64, String>,
+    categories: HashMap<u64, String>,
+    annotation_names: HashMap<u64, String>,
+    strings: HashMap<u64, String>,
+    default_track: Option<u64>,
+}
+
+#[derive(Default)]
+struct Parser {
+    sequences: HashMap<u64, Sequence>,
+    track_names: HashMap<u64, String>,
+    /// Indices of the open slices of each track.
+    open: HashMap<u64, Vec<usize>>,
+    slices: Vec<(u64, Slice)>,
+    instants: Vec<(u64, Instant)>,
+    counters: Vec<(u64, CounterSample)>,
+}
+
+impl Parser {
+    fn packet(&mut self, data: &[u8]) {
+        let mut timestamp = 0;
+        let mut sequence_id = 0;
+        let mut flags = 0;
+        let mut event = None;
+        let mut descriptor = None;
+        let mut interned = None;
+        let mut defaults = None;
+        for (field, value) in Fields(data) {
+            match (field, value) {
+                (8, Wire::Varint(n)) => timestamp = n,
+                (10, Wire::Varint(n)) => sequence_id = n,
+                (13, Wire::Varint(n)) => flags = n,
+                (11, Wire::Bytes(b)) => event = Some(b),
+                (60, Wire::Bytes(b)) => descriptor = Some(b),
+                (12, Wire::Bytes(b)) => interned = Some(b),
+                (59, Wire::Bytes(b)) => defaults = Some(b),
+                _ => {}
+            }
+        }
+
+        if flags & SEQ_INCREMENTAL_STATE_CLEARED as u64 != 0 {
+            self.sequences.remove(&sequence_id);
+        }
+        let sequence = self.sequences.entry(sequence_id).or_default();
+        if let Some(defaults) = defaults {
+            for (field, value) in Fields(defaults) {
+                if let (11, Wire::Bytes(track_defaults)) = (field, value) {
+                    for (field, value) in Fields(track_defaults) {
+                        if let (11, Wire::Varint(uuid)) = (field, value) {
+                            sequence.default_track = Some(uuid);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(interned) = interned {
+            for (field, value) in Fields(interned) {
+                let Wire::Bytes(entry) = value else { continue };
+                let table = match field {
+                    1 => &mut sequence.categories,
+                    2 => &mut sequence.event_names,
+                    3 => &mut sequence.annotation_names,
+                    29 => &mut sequence.strings,
+                    _ => continue,
+                };
+                let (mut iid, mut name) = (0, String::new());
+                for (field, value) in Fields(entry) {
+                    match (field, value) {
+                        (1, Wire::Varint(n)) => iid = n,
+                        (2, Wire::Bytes(b)) => name = string(b),
+                        _ => {}
+                    }
+                }
+                table.insert(iid, name);
+            }
+        }
+        if let Some(descriptor) = descriptor {
+            let (mut uuid, mut name) = (0, String::new());
+            for (field, value) in Fields(descriptor) {
+                match (field, value) {
+                    (1, Wire::Varint(n)) => uuid = n,
+                    (2, Wire::Bytes(b)) => name = string(b),
+                    _ => {}
+                }
+            }
+            self.track_names.insert(uuid, name);
+        }
+        if let Some(event) = event {
+            self.track_event(sequence_id, timestamp, event);
+        }
+    }
+
+    fn track_event(&mut self, sequence_id: u64, timestamp: u64, data: &[u8]) {
+        let sequence = &self.sequences[&sequence_id];
+        let mut event_type = 0;
+        let mut name = String::new();
+        let mut track = sequence.default_track.unwrap_or(0);
+        let mut args = Vec::new();
+        let mut categories = Vec::new();
+        let (mut flows, mut terminating_flows) = (Vec::new(), Vec::new());
+        let mut value = 0.0;
+        let (mut extra_tracks, mut extra_values) = (Vec::new(), Vec::new());
+        let (mut extra_double_tracks, mut extra_double_values) = (Vec::new(), Vec::new());
+        for (field, wire) in Fields(data) {
+            match (field, wire) {
+                (9, Wire::Varint(n)) => event_type = n,
+                (10, Wire::Varint(iid)) => {
+                    name = sequence.event_names.get(&iid).cloned().unwrap_or_default()
+                }
+                (23, Wire::Bytes(b)) => name = string(b),
+                (11, Wire::Varint(uuid)) => track = uuid,
+                (4, Wire::Bytes(b)) => args.push(annotation(sequence, b)),
+                (3, Wire::Varint(iid)) => {
+                    categories.push(sequence.categories.get(&iid).cloned().unwrap_or_default())
+                }
+                (22, Wire::Bytes(b)) => categories.push(string(b)),
+                (47, Wire::Fixed64(id)) => flows.push(id),
+                (48, Wire::Fixed64(id)) => terminating_flows.push(id),
+                (30, Wire::Varint(n)) => value = n as i64 as f64,
+                (44, Wire::Fixed64(bits)) => value = f64::from_bits(bits),
+                (31, Wire::Varint(uuid)) => extra_tracks.push(uuid),
+                (12, Wire::Varint(n)) => extra_values.push(n as i64 as f64),
+                (45, Wire::Varint(uuid)) => extra_double_tracks.push(uuid),
+                (46, Wire::Fixed64(bits)) => extra_double_values.push(f64::from_bits(bits)),
+                _ => {}
+            }
+        }
+        match event_type {
+            // Slice begin
+            1 => {
+                self.open.entry(track).or_default().push(self.slices.len());
+                let slice = Slice {
+                    name,
+                    track: String::new(),
+                    start: timestamp,
+                    end: None,
+                    args,
+                    categories,
+                    flows,
+                    terminating_flows,
+                };
+                self.slices.push((track, slice));
+            }
+            // Slice end
+            2 => {
+                if let Some(i) = self.open.get_mut(&track).and_then(|open| open.pop()) {
+                    let slice = &mut self.slices[i].1;
+                    slice.end = Some(timestamp);
+                    slice.args.extend(args);
+                }
+            }
+            // Instant
+            3 => {
+                let instant = Instant {
+                    name,
+                    track: String::new(),
+                    timestamp,
+                    args,
+                    categories,
+                    flows,
+                    terminating_flows,
+                };
+                self.instants.push((track, instant));
+            }
+            // Counter
+            4 => {
+                let sample = CounterSample {
+                    track: String::new(),
+                    timestamp,
+                    value,
+                };
+                self.counters.push((track, sample));
+            }
+            _ => {}
+        }
+        let extras = extra_tracks.into_iter().zip(extra_values);
+        let extra_doubles = extra_double_tracks.into_iter().zip(extra_double_values);
+        for (track, value) in extras.chain(extra_doubles) {
+            let sample = CounterSample {
+                track: String::new(),
+                timestamp,
+                value,
+            };
+            self.counters.push((track, sample));
+        }
+    }
+
+    /// Name the tracks, which may have been renamed after their first use.
+    fn finish(self) -> Trace {
+        let names = self.track_names;
+        let name = |uuid: u64| names.get(&uuid).cloned().unwrap_or_default();
+        Trace {
+            slices: self
+                .slices
+                .into_iter()
+                .map(|(uuid, slice)| Slice {
+                    track: name(uuid),
+                    ..slice
+                })
+                .collect(),
+            instants: self
+                .instants
+                .into_iter()
+                .map(|(uuid, instant)| Instant {
+                    track: name(uuid),
+                    ..instant
+                })
+                .collect(),
+            counters: self
+                .counters
+                .into_iter()
+                .map(|(uuid, sample)| CounterSample {
+                    track: name(uuid),
+                    ..sample
+                })
+                .collect(),
+        }
+    }
+}
+
+fn annotation(sequence: &Sequence, data: &[u8]) -> (String, Value) {
+    let mut name = String::new();
+    for (field, wire) in Fields(data) {
+        match (field, wire) {
+            (1, Wire::Varint(iid)) => {
+                name = sequence
+                    .annotation_names
+                    .get(&iid)
+                    .cloned()
+                    .unwrap_or_default()
+            }
+            (10, Wire::Bytes(b)) => name = string(b),
+            _ => {}
+        }
+    }
+    (name, value(sequence, data))
+}
+
+fn value(sequence: &Sequence, data: &[u8]) -> Value {
+    let mut dict = Vec::new();
+    let mut array = Vec::new();
+    for (field, wire) in Fields(data) {
+        match (field, wire) {
+            (2, Wire::Varint(n)) => return Value::Bool(n != 0),
+            (3, Wire::Varint(n)) => return Value::Uint(n),
+            (4, Wire::Varint(n)) => return Value::Int(n as i64),
+            (5, Wire::Fixed64(bits)) => return Value::Double(f64::from_bits(bits)),
+            (6, Wire::Bytes(b)) => return Value::String(string(b)),
+            (17, Wire::Varint(iid)) => {
+                return Value::String(sequence.strings.get(&iid).cloned().unwrap_or_default())
+            }
+            (11, Wire::Bytes(b)) => dict.push(annotation(sequence, b)),
+            (12, Wire::Bytes(b)) => array.push(value(sequence, b)),
+            _ => {}
+        }
+    }
+    if array.is_empty() {
+        Value::Dict(dict)
+    } else {
+        Value::Array(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_slices_and_instants() {
+        let trace = capture(|| {
+            tracing::info_span!("outer", user = "alice").in_scope(|| {
+                tracing::info_span!("inner", attempt = 2u64).in_scope(|| {});
+                tracing::info!(target: "test", items = 3, ratio = 0.5, "loaded");
+            });
+            tracing::info_span!("inner", attempt = 3u64).in_scope(|| {});
+        });
+
+        trace
+            .slice("outer")
+            .assert_arg("user", "alice")
+            .assert_duration(..Duration::from_secs(10));
+        let attempts: Vec<_> = trace
+            .slices_named("inner")
+            .map(|slice| slice.arg("attempt").cloned())
+            .collect();
+        assert_eq!(attempts, [Some(Value::Uint(2)), Some(Value::Uint(3))]);
+        let outer = trace.slice("outer");
+        let inner = trace.slice("inner");
+        assert!(outer.start <= inner.start && inner.end <= outer.end);
+        assert_eq!(outer.track, inner.track);
+
+        trace
+            .instant("loaded")
+            .assert_arg("items", 3)
+            .assert_arg("ratio", 0.5);
+        trace.assert_no_slice("loaded");
+    }
+
+    #[test]
+    fn assertion_macros() {
+        let trace = capture(|| {
+            tracing::info_span!("request", user = "alice", retry = false).in_scope(|| {
+                tracing::info!(code = 404u64, "not found");
+            });
+        });
+        let slice = crate::assert_slice!(trace, "request", user = "alice", retry = false);
+        let instant = crate::assert_instant!(trace, "not found", code = 404);
+        assert_eq!(instant.track, slice.track);
+    }
+
+    #[test]
+    #[should_panic(expected = "has user = String(\"alice\"), expected String(\"bob\")")]
+    fn failed_assertion() {
+        let trace = capture(|| {
+            tracing::info_span!("outer", user = "alice").in_scope(|| {});
+        });
+        trace.slice("outer").assert_arg("user", "bob");
+    }
+}
@@ -0,0 +1,69 @@
+//! Support for tokio's task instrumentation.
+//!
+//! With `RUSTFLAGS="--cfg tokio_unstable"` and tokio's `tracing` feature,
+//! tokio creates a `runtime.spawn` span for every spawned task and enters it
+//! whenever the task is polled. Because of work stealing, the polls of one
+//! task are spread across all worker threads. We put each task on a track of
+//! its own instead, with a "task alive" slice from spawn until the task is
+//! dropped and the polls nested inside of it.
+
+use std::sync::Arc;
+
+use tracing::{field::Visit, span};
+
+const TASK_TARGET: &str = "tokio::task";
+const TASK_SPAN_NAME: &str = "runtime.spawn";
+
+/// Name of the slice covering the lifetime of a task.
+pub const TASK_ALIVE_NAME: &str = "task alive";
+
+/// Marks the span of a tokio task, so we can end the "task alive" slice when
+/// the span is closed.
+pub struct TaskExt;
+
+/// The name of the track for a tokio task span, or `None` if the span is not
+/// a task span.
+pub fn task_track(attrs: &span::Attributes<'_>) -> Option<Arc<str>> {
+    let metadata = attrs.metadata();
+    if metadata.target() != TASK_TARGET || metadata.name() != TASK_SPAN_NAME {
+        return None;
+    }
+    let mut v = TaskFieldVisitor::default();
+    attrs.record(&mut v);
+    let id = v.id?;
+    let name = match v.name {
+        Some(name) => format!("{} (task {})", name, id),
+        None => format!("task {}", id),
+    };
+    Some(name.into())
+}
+
+#[derive(Default)]
+struct TaskFieldVisitor {
+    id: Option<String>,
+    name: Option<String>,
+}
+
+impl Visit for TaskFieldVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "task.id" => self.id = Some(format!("{:?}", value)),
+            "task.name" => self.name = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        if field.name() == "task.id" {
+            self.id = Some(value.to_string());
+        }
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            "task.id" => self.id = Some(value.to_string()),
+            "task.name" if !value.is_empty() => self.name = Some(value.to_string()),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Mirroring spans to the ftrace `trace_marker` file.
+//!
+//! Slices written there in the atrace format show up in system traces
+//! recorded with Perfetto or trace-cmd on the thread that entered the span,
+//! right next to the kernel's scheduling data.
+
+use std::fs::File;
+
+use crate::{sanitize::sanitize, writer::ErrorHook};
+
+/// Writes to `trace_marker` are cut off at a few KiB, so we keep the names
+/// much shorter.
+const MAX_NAME_LEN: usize = 512;
+
+/// An open `trace_marker` file.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct TraceMarker {
+    file: File,
+    pid: u32,
+}
+
+impl TraceMarker {
+    /// Open `trace_marker` in tracefs, or in debugfs on older kernels.
+    ///
+    /// Returns `None` (reporting the error) if neither can be opened, e.g.
+    /// for lack of permissions, and on platforms other than Linux.
+    #[cfg(target_os = "linux")]
+    pub fn open(on_error: &ErrorHook) -> Option<TraceMarker> {
+        let paths = [
+            "/sys/kernel/tracing/trace_marker",
+            "/sys/kernel/debug/tracing/trace_marker",
+        ];
+        let mut last_err = None;
+        for path in paths {
+            match std::fs::OpenOptions::new().write(true).open(path) {
+                Ok(file) => {
+                    return Some(TraceMarker {
+                        file,
+                        pid: std::process::id(),
+                    })
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if let Some(err) = last_err {
+            on_error.report_partial("cannot open trace_marker", err.kind(), &err);
+        }
+        None
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_on_error: &ErrorHook) -> Option<TraceMarker> {
+        None
+    }
+
+    pub fn begin(&self, name: &str) {
+        self.write(&begin_marker(self.pid, name));
+    }
+
+    pub fn end(&self) {
+        self.write(&end_marker(self.pid));
+    }
+
+    /// Each marker has to be written with a single `write`, so that markers
+    /// of different threads don't get mixed up.
+    #[cfg(target_os = "linux")]
+    fn write(&self, marker: &str) {
+        use std::io::Write;
+
+        // Markers are best effort, e.g. tracing may be off.
+        let _ignore_err = (&self.file).write(marker.as_bytes());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write(&self, _marker: &str) {}
+}
+
+fn begin_marker(pid: u32, name: &str) -> String {
+    format!("B|{}|{}\n", pid, sanitize(name, MAX_NAME_LEN))
+}
+
+fn end_marker(pid: u32) -> String {
+    format!("E|{}\n", pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atrace_format() {
+        assert_eq!(begin_marker(42, "query"), "B|42|query\n");
+        assert_eq!(begin_marker(42, "two\nlines"), "B|42|two\\nlines\n");
+        assert_eq!(end_marker(42), "E|42\n");
+    }
+}
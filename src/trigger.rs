@@ -0,0 +1,113 @@
+//! Starting to record only after a trigger, see
+//! [`PerfettoLayerBuilder::start_trigger`](crate::PerfettoLayerBuilder::start_trigger).
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::clock::Clock;
+
+/// When to start recording.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// Once this much time has passed since the layer was built.
+    AfterDuration(Duration),
+    /// With the first event of this name, e.g. from
+    /// `tracing::info!(name: "warmup_done", ...)`. The event itself is
+    /// recorded.
+    OnEventNamed(String),
+    /// When the handle is fired.
+    Manual(TriggerHandle),
+}
+
+/// Starts recording when fired, see [`Trigger::Manual`].
+#[derive(Debug, Clone, Default)]
+pub struct TriggerHandle(Arc<AtomicBool>);
+
+impl TriggerHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fire(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn fired(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The trigger of a layer, and whether it has fired.
+pub(crate) struct StartTrigger {
+    trigger: Trigger,
+    /// Timestamp at which [`Trigger::AfterDuration`] fires.
+    deadline: u64,
+    started: AtomicBool,
+}
+
+impl StartTrigger {
+    pub fn new(trigger: Trigger, clock: &Clock) -> Self {
+        let deadline = match &trigger {
+            Trigger::AfterDuration(duration) => clock.now() + duration.as_nanos() as u64,
+            _ => 0,
+        };
+        StartTrigger {
+            trigger,
+            deadline,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    pub fn started(&self, clock: &Clock) -> bool {
+        if self.started.load(Ordering::Relaxed) {
+            return true;
+        }
+        let fired = match &self.trigger {
+            Trigger::AfterDuration(_) => clock.now() >= self.deadline,
+            Trigger::OnEventNamed(_) => false,
+            Trigger::Manual(handle) => handle.fired(),
+        };
+        if fired {
+            self.started.store(true, Ordering::Relaxed);
+        }
+        fired
+    }
+
+    /// Start if `name` is the name of the trigger event.
+    pub fn on_event(&self, name: &str) {
+        if matches!(&self.trigger, Trigger::OnEventNamed(trigger) if trigger == name) {
+            self.started.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers() {
+        let clock = Clock::default();
+        let after = StartTrigger::new(Trigger::AfterDuration(Duration::from_millis(20)), &clock);
+        assert!(!after.started(&clock));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(after.started(&clock));
+
+        let named = StartTrigger::new(Trigger::OnEventNamed("warmup_done".into()), &clock);
+        named.on_event("warmup");
+        assert!(!named.started(&clock));
+        named.on_event("warmup_done");
+        assert!(named.started(&clock));
+
+        let handle = TriggerHandle::new();
+        let manual = StartTrigger::new(Trigger::Manual(handle.clone()), &clock);
+        assert!(!manual.started(&clock));
+        handle.fire();
+        assert!(manual.started(&clock));
+    }
+}
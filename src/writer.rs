@@ -0,0 +1,2208 @@
+//! The writer thread, which encodes the messages from the layer into trace
+//! packets and writes them to the output file.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::Receiver;
+
+use crate::{
+    append,
+    args_budget::{ArgsBudget, Overrun},
+    base64::ChunkWriter,
+    callsite::callsite_hash,
+    clock::Clock,
+    container::ContainerInfo,
+    emit::{EmitError, ProtoEmitter},
+    framing::{ChunkTransform, FrameWriter},
+    ids::{
+        thread_sequence_id, thread_track_uuid, DYNAMIC_TRACK_UUID_BASE, HEADER_SEQUENCE_ID,
+        PROCESS_INFO_SEQUENCE_ID, PROCESS_INFO_TRACK_UUID, PROCESS_TRACK_UUID, STATE_SEQUENCE_ID,
+        WRITER_BYTES_TRACK_UUID, WRITER_QUEUE_TRACK_UUID, WRITER_SEQUENCE_ID, WRITER_TRACK_UUID,
+    },
+    intern::Interned,
+    packet::{
+        self, AndroidLogEvent, AndroidLogPacket, ClockId, ClockSnapshot, CounterUnit,
+        DebugAnnotation, DebugAnnotationName, Emit, EventCategory, EventName, InternedData,
+        PacketData, ProcessDescriptor, SourceLocation, ThreadDescriptor, TracePacket,
+        TracePacketDefaults, TrackDescriptor, TrackEvent, TrackEventDefaults,
+        SEQ_INCREMENTAL_STATE_CLEARED, SEQ_NEEDS_INCREMENTAL_STATE,
+    },
+    regions::RegionLanes,
+    report::{SharedReport, ShutdownReport},
+    ring::{Pin, Ring},
+    rotate::{RotateCallback, Rotation, SyncPolicy},
+    sanitize::Limits,
+    slowest::Reservoirs,
+    snapshot::SnapshotBuffer,
+    spill::Spill,
+    stats::Counters,
+    strict::Validator,
+    summary::{SpanSummary, SummaryFormat},
+    syslog::CurrentPath,
+    CounterScope, CounterValue, Message, PerfCounter, ProcessInfo, ThreadId, Timestamp, Unit,
+};
+
+/// Where errors go, see
+/// [`PerfettoLayerBuilder::on_error`](crate::PerfettoLayerBuilder::on_error).
+/// Shared by the layer, the writer thread and the flush guard.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorHook(Option<Arc<Mutex<ErrorCallback>>>);
+
+type ErrorCallback = dyn FnMut(&io::Error) + Send;
+
+impl ErrorHook {
+    pub fn new<F>(on_error: F) -> Self
+    where
+        F: FnMut(&io::Error) + Send + 'static,
+    {
+        ErrorHook(Some(Arc::new(Mutex::new(on_error))))
+    }
+
+    /// Pass `err` to the hook, or print it to stderr, prefixed with
+    /// `context`, if there is none.
+    pub fn report(&self, context: &str, err: &io::Error) {
+        match &self.0 {
+            // A hook that panicked once still gets the later errors.
+            Some(hook) => (hook.lock().unwrap_or_else(PoisonError::into_inner))(err),
+            None => eprintln!("tracing_perfetto: {}: {}", context, err),
+        }
+    }
+
+    /// Like [`report`](Self::report), for errors that only affect part of
+    /// the trace. The hook gets an error of `kind`, with the context as
+    /// part of its message.
+    pub fn report_partial(&self, context: &str, kind: io::ErrorKind, err: &dyn fmt::Display) {
+        match &self.0 {
+            Some(_) => self.report(
+                context,
+                &io::Error::new(kind, format!("{}: {}", context, err)),
+            ),
+            None => eprintln!("tracing_perfetto: {}: {}", context, err),
+        }
+    }
+}
+
+/// Errors that stop the writer thread, or (for encoding errors) drop a
+/// single packet.
+#[derive(Debug)]
+pub(crate) enum WriterError {
+    Io(io::Error),
+    Emit(EmitError),
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriterError::Io(err) => write!(f, "I/O error: {}", err),
+            WriterError::Emit(err) => write!(f, "encoding error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WriterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriterError::Io(err) => Some(err),
+            WriterError::Emit(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for WriterError {
+    fn from(err: io::Error) -> Self {
+        WriterError::Io(err)
+    }
+}
+
+impl From<EmitError> for WriterError {
+    fn from(err: EmitError) -> Self {
+        WriterError::Emit(err)
+    }
+}
+
+/// Track and name of the instants summarizing the entries of a span that
+/// were not among the slowest.
+const SPAN_SUMMARY_TRACK: &str = "span summaries";
+const SPAN_SUMMARY_NAME: &str = "dropped entries";
+
+fn packet_defaults(track_uuid: u64, clock_id: ClockId) -> TracePacketDefaults {
+    TracePacketDefaults {
+        timestamp_clock_id: clock_id,
+        track_event_defaults: Some(TrackEventDefaults { track_uuid }),
+    }
+}
+
+/// The two packets that start a new sequence with its own track.
+///
+/// The first packet is needed so we can use string interning. It also
+/// defines the default track uuid for the sequence, so we never have to
+/// override the track uuid in a packet. The second packet is the track
+/// descriptor, which defines the track uuid and track name.
+fn sequence_header(
+    trusted_uid: i32,
+    sequence_id: u32,
+    track_uuid: u64,
+    track_name: String,
+    clock_id: ClockId,
+) -> [TracePacket; 2] {
+    [
+        TracePacket {
+            timestamp: 1,
+            data: PacketData::None,
+            sequence_flags: SEQ_INCREMENTAL_STATE_CLEARED,
+            trusted_uid,
+            trusted_packet_sequence_id: sequence_id,
+            interned_data: None,
+            trace_packet_defaults: Some(packet_defaults(track_uuid, clock_id)),
+        },
+        TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid: track_uuid,
+                parent_uuid: None,
+                name: track_name,
+                counter: None,
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid,
+            trusted_packet_sequence_id: sequence_id,
+            interned_data: None,
+            trace_packet_defaults: None,
+        },
+    ]
+}
+
+/// Fresh interning tables holding the seeded event names, and the interned
+/// data announcing them, if any.
+fn seeded_interned(seed: &[String]) -> (Interned, Option<InternedData>) {
+    let mut interned = Interned::new();
+    let mut data = InternedData::default();
+    for name in seed {
+        let (iid, added) = interned.event_name(name);
+        if added {
+            data.event_names.push(EventName {
+                iid,
+                name: name.clone(),
+            });
+        }
+    }
+    let data = (!data.is_empty()).then_some(data);
+    (interned, data)
+}
+
+/// The file name of the executable, or "process" if it is unknown.
+fn process_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "process".to_string())
+}
+
+/// A slice on the process info track, which carries the build information as
+/// debug annotations on its begin event.
+fn process_info_packet(
+    trusted_uid: i32,
+    timestamp: Timestamp,
+    event_type: packet::EventType,
+    info: &ProcessInfo,
+) -> TracePacket {
+    let debug_annotations = match event_type {
+        packet::EventType::SliceBegin => {
+            let mut annotations = vec![
+                ("version", info.version.clone()),
+                ("profile", info.profile.clone()),
+            ];
+            if let Some(git_hash) = &info.git_hash {
+                annotations.push(("git_hash", git_hash.clone()));
+            }
+            annotations
+                .into_iter()
+                .map(|(name, value)| DebugAnnotation {
+                    name: packet::IString::Plain(name.to_string()),
+                    value: packet::DebugValue::String(value),
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    TracePacket {
+        timestamp,
+        sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+        data: PacketData::TrackEvent(TrackEvent {
+            event_type,
+            name: packet::IString::Plain(format!("{} {}", info.name, info.version)),
+            debug_annotations,
+            track_uuid: None,
+            category_iids: Vec::new(),
+            source_location_iid: None,
+            flow_ids: Vec::new(),
+            terminating_flow_ids: Vec::new(),
+            counter_value: None,
+            double_counter_value: None,
+            extra_counters: Vec::new(),
+            extra_double_counters: Vec::new(),
+        }),
+        trusted_uid,
+        trusted_packet_sequence_id: PROCESS_INFO_SEQUENCE_ID,
+        interned_data: None,
+        trace_packet_defaults: None,
+    }
+}
+
+/// A packet on the sequence of the writer's own activity.
+fn writer_packet(
+    trusted_uid: i32,
+    timestamp: Timestamp,
+    event_type: packet::EventType,
+    track_uuid: Option<u64>,
+    counter_value: Option<i64>,
+    debug_annotations: Vec<DebugAnnotation>,
+) -> TracePacket {
+    TracePacket {
+        timestamp,
+        sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+        data: PacketData::TrackEvent(TrackEvent {
+            event_type,
+            name: packet::IString::Plain(WRITER_SLICE_NAME.to_string()),
+            debug_annotations,
+            track_uuid,
+            category_iids: Vec::new(),
+            source_location_iid: None,
+            flow_ids: Vec::new(),
+            terminating_flow_ids: Vec::new(),
+            counter_value,
+            double_counter_value: None,
+            extra_counters: Vec::new(),
+            extra_double_counters: Vec::new(),
+        }),
+        trusted_uid,
+        trusted_packet_sequence_id: WRITER_SEQUENCE_ID,
+        interned_data: None,
+        trace_packet_defaults: None,
+    }
+}
+
+const WRITER_SLICE_NAME: &str = "process messages";
+const WRITE_STALL_NAME: &str = "write stall";
+/// Counter track of the packets written without their arguments, see
+/// [`PerfettoLayerBuilder::args_budget`](crate::PerfettoLayerBuilder::args_budget).
+const OMITTED_ARGS_NAME: &str = "omitted args";
+
+/// Number of bytes `annotations` take up in a packet, before their names
+/// are interned.
+fn encoded_len(annotations: &[DebugAnnotation]) -> Result<usize, EmitError> {
+    let mut out = ProtoEmitter::new();
+    for annotation in annotations {
+        annotation.emit(&mut out)?;
+    }
+    Ok(out.as_bytes().len())
+}
+
+/// Replace the plain names of `annotations` (including nested ones) by
+/// interned ones, adding newly interned names to `interned_data`.
+fn intern_debug_annotations(
+    interned: &mut Interned,
+    interned_data: &mut InternedData,
+    annotations: &mut [DebugAnnotation],
+) {
+    for annotation in annotations {
+        if let packet::IString::Plain(name) = &annotation.name {
+            let (iid, added) = interned.debug_annotation_name(name);
+            if added {
+                interned_data
+                    .debug_annotation_names
+                    .push(DebugAnnotationName {
+                        iid,
+                        name: name.clone(),
+                    });
+            }
+            annotation.name = packet::IString::Interned(iid);
+        }
+        if let packet::DebugValue::Dict(entries) = &mut annotation.value {
+            intern_debug_annotations(interned, interned_data, entries);
+        }
+    }
+}
+
+#[cfg(not(feature = "static-config"))]
+type Sequences = Vec<SequenceState>;
+#[cfg(feature = "static-config")]
+type Sequences = Box<[SequenceState; crate::static_config::SEQUENCE_SLOTS]>;
+
+#[derive(Default)]
+struct SequenceState {
+    /// Whether a thread has used this sequence before.
+    started: bool,
+    /// Whether the track got its name from a `NewThread` message, rather
+    /// than a placeholder.
+    named: bool,
+    /// Uuid of the thread track of the thread currently using the sequence.
+    track_uuid: u64,
+    /// Name of the thread track.
+    name: String,
+    /// The OS thread the track belongs to, see
+    /// [`PerfettoLayerBuilder::os_thread_ids`](crate::PerfettoLayerBuilder::os_thread_ids).
+    os_thread: Option<ThreadDescriptor>,
+    interned: Interned,
+    /// Timestamp at which the incremental state was last cleared.
+    cleared_at: Timestamp,
+    /// Position of the packet that cleared it in the ring buffer, if any.
+    cleared_position: u64,
+    /// Whether a packet was dropped since then, which may have taken
+    /// interned data with it.
+    dropped: bool,
+}
+
+/// The writer's own activity, see [`PerfettoLayerBuilder::trace_writer`].
+struct SelfTrace {
+    clock: Clock,
+    /// When the current batch of messages started, and how many messages
+    /// were queued at that point.
+    batch: Option<(Timestamp, usize)>,
+    /// Number of messages in the current batch.
+    messages: u64,
+}
+
+struct Writer {
+    out: BufWriter<Sink>,
+    em: ProtoEmitter,
+    trusted_uid: i32,
+    /// Per-sequence state, indexed by thread id.
+    sequences: Sequences,
+    /// How often to clear the incremental state of each sequence, see
+    /// [`PerfettoLayerBuilder::incremental_state_interval`].
+    clear_interval: Option<u64>,
+    flush_hint_threshold: u8,
+    /// The clock of the layer.
+    clock: Clock,
+    /// Uuids of the custom tracks for which we have emitted a descriptor.
+    custom_tracks: HashMap<Arc<str>, u64>,
+    /// Uuids of the counter tracks of fields, see
+    /// [`PerfettoLayerBuilder::unit_hint`] and
+    /// [`PerfettoLayerBuilder::counter_field`], by field and the uuid of
+    /// the process or thread track they are nested below.
+    counter_tracks: HashMap<(&'static str, u64), u64>,
+    /// The counter sampled at span entry and exit, and the uuids of its
+    /// tracks by the uuid of their thread track.
+    perf_counter: Option<PerfCounter>,
+    perf_counter_tracks: HashMap<u64, u64>,
+    /// Uuid and current state of each state track.
+    state_tracks: HashMap<Arc<str>, (u64, Option<String>)>,
+    /// Whether the sequence of the state tracks has started in this file.
+    state_sequence_started: bool,
+    region_lanes: RegionLanes,
+    next_track_uuid: u64,
+    /// Timestamp of the most recent event, used to close the process info
+    /// slice when the trace ends.
+    last_timestamp: Timestamp,
+    counters: Arc<Counters>,
+    limits: Limits,
+    process_info: Option<ProcessInfo>,
+    container_info: Option<ContainerInfo>,
+    /// Set when writing to a path, in which case the trace can be split
+    /// into several files.
+    rotation: Option<Rotation>,
+    /// Number of bytes written to the current file.
+    file_size: u64,
+    /// The slowest span entries, if only those are written.
+    reservoirs: Option<Reservoirs>,
+    current_path: CurrentPath,
+    /// Where to record the files opened, for the shutdown report.
+    report: Option<SharedReport>,
+    on_error: ErrorHook,
+    self_trace: Option<SelfTrace>,
+    write_stall_threshold: Duration,
+    /// Start and duration of the slow writes that are not in the trace yet.
+    write_stalls: Vec<(Timestamp, Duration)>,
+    /// Whether the writer's track was emitted for the write stalls, if it
+    /// is not there for the self trace anyway.
+    stall_track_started: bool,
+    args_budget: Option<ArgsBudget>,
+    spill: Option<Spill>,
+    /// The most recent packets, if the output is only written on demand.
+    ring: Option<Ring>,
+    validator: Option<Validator>,
+    sync_policy: SyncPolicy,
+    /// The size of the current file when it was last synced.
+    synced_size: u64,
+    span_summary: Option<SpanSummary>,
+    /// Whether to attach the callsites of slices and instants.
+    source_locations: bool,
+    /// Event names to intern at the start of each sequence, see
+    /// [`PerfettoLayerBuilder::intern_seed`](crate::PerfettoLayerBuilder::intern_seed).
+    intern_seed: Vec<String>,
+    export_intern_table: Option<PathBuf>,
+    /// Sequences whose next packet is marked with `previous_packet_dropped`.
+    dropped_packets: HashSet<u32>,
+    /// Number of the run when appending to a trace file, by which the
+    /// sequence ids and track uuids are moved, see
+    /// [`PerfettoLayerBuilder::append`](crate::PerfettoLayerBuilder::append).
+    run: u32,
+}
+
+impl Writer {
+    /// Encode and write a packet. Nothing is written if the packet cannot
+    /// be encoded; the next packet of its sequence then tells readers that
+    /// data is missing.
+    fn write_packet(&mut self, packet: &TracePacket) -> Result<(), WriterError> {
+        let sequence_id = packet.trusted_packet_sequence_id;
+        let after_drop = self.dropped_packets.contains(&sequence_id);
+        let shifted;
+        let emitted_packet = if self.run > 0 {
+            let mut packet = packet.clone();
+            append::shift_ids(&mut packet, self.run);
+            shifted = packet;
+            &shifted
+        } else {
+            packet
+        };
+        self.em.clear();
+        let emitted = self.em.nested(1, |out| {
+            emitted_packet.emit(out)?;
+            if after_drop {
+                // TracePacket.previous_packet_dropped
+                out.varint_field(42, 1);
+            }
+            Ok(())
+        });
+        if let Err(err) = emitted {
+            self.dropped_packets.insert(sequence_id);
+            self.counters
+                .packets_dropped
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            // Interned data of a thread may be lost, so its sequence has to
+            // start over.
+            let thread = sequence_id.checked_sub(1).map(|i| i as usize);
+            if let Some(sequence) = thread.and_then(|i| self.sequences.get_mut(i)) {
+                sequence.dropped = true;
+            }
+            return Err(err.into());
+        }
+        if after_drop {
+            self.dropped_packets.remove(&sequence_id);
+        }
+        if let Some(validator) = &mut self.validator {
+            validator.check(packet);
+        }
+        let len = self.em.as_bytes().len();
+        self.counters.add_packet(len);
+        if matches!(packet.data, PacketData::TrackEvent(_)) {
+            self.counters
+                .events_written
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        if let Some(ring) = &mut self.ring {
+            ring.push(self.em.as_bytes().to_vec());
+            let pin = match &emitted_packet.data {
+                PacketData::TrackDescriptor(track) => Some(Pin::Track(track.uuid)),
+                PacketData::ClockSnapshot(_) => Some(Pin::Clock),
+                _ if emitted_packet.sequence_flags & SEQ_INCREMENTAL_STATE_CLEARED != 0
+                    && sequence_id >= STATE_SEQUENCE_ID =>
+                {
+                    Some(Pin::Sequence(sequence_id))
+                }
+                _ => None,
+            };
+            if let Some(pin) = pin {
+                // A descriptor may come before the reset of its sequence
+                // when dumped, which it doesn't need anyway.
+                let mut pinned = emitted_packet.clone();
+                if let Pin::Track(_) = pin {
+                    pinned.sequence_flags = 0;
+                }
+                self.em.clear();
+                self.em.nested(1, |out| pinned.emit(out))?;
+                ring.pin(pin, self.em.as_bytes().to_vec());
+            }
+            return Ok(());
+        }
+        if let Some(spill) = self.spill.as_mut().filter(|spill| spill.active()) {
+            spill.write(self.em.as_bytes())?;
+            self.counters
+                .bytes_spilled
+                .fetch_add(len as u64, std::sync::atomic::Ordering::Relaxed);
+            return Ok(());
+        }
+        // Only time the writes that reach the sink.
+        let reaches_sink = self.out.buffer().len() + len > self.out.capacity();
+        let started = reaches_sink.then(|| (self.clock.now(), Instant::now()));
+        self.out.write_all(self.em.as_bytes())?;
+        if let Some(started) = started {
+            self.check_stall(started);
+        }
+        self.file_size += len as u64;
+        Ok(())
+    }
+
+    /// Emit the process info track and begin its slice.
+    fn begin_process_info(&mut self) -> Result<(), WriterError> {
+        let Some(info) = &self.process_info else {
+            return Ok(());
+        };
+        let [defaults, descriptor] = sequence_header(
+            self.trusted_uid,
+            PROCESS_INFO_SEQUENCE_ID,
+            PROCESS_INFO_TRACK_UUID,
+            "process info".to_string(),
+            self.clock.clock_id(),
+        );
+        let begin = process_info_packet(
+            self.trusted_uid,
+            self.last_timestamp,
+            packet::EventType::SliceBegin,
+            info,
+        );
+        for packet in [defaults, descriptor, begin] {
+            self.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Describe the process and relate our clock to the wall clock, so
+    /// that even a trace without events is a valid record of the run.
+    fn write_header(&mut self) -> Result<(), WriterError> {
+        let [defaults, mut descriptor] = sequence_header(
+            self.trusted_uid,
+            HEADER_SEQUENCE_ID,
+            PROCESS_TRACK_UUID,
+            process_name(),
+            self.clock.clock_id(),
+        );
+        if let PacketData::TrackDescriptor(track) = &mut descriptor.data {
+            track.process = Some(ProcessDescriptor {
+                pid: std::process::id(),
+                process_name: track.name.clone(),
+            });
+        }
+        let timestamp = self.clock.now();
+        let snapshot = TracePacket {
+            timestamp,
+            data: PacketData::ClockSnapshot(ClockSnapshot {
+                clocks: self.clock.snapshot(timestamp),
+                primary_trace_clock: self.clock.clock_id(),
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: HEADER_SEQUENCE_ID,
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        for packet in [defaults, descriptor, snapshot] {
+            self.write_packet(&packet)?;
+        }
+        if let Some(info) = &self.container_info {
+            let instant =
+                self.process_instant(timestamp, "container info", info.debug_annotations());
+            self.write_packet(&instant)?;
+        }
+        Ok(())
+    }
+
+    /// Mark where a run appended to the trace file starts, see
+    /// [`PerfettoLayerBuilder::append`](crate::PerfettoLayerBuilder::append).
+    fn write_restart_marker(&mut self) -> Result<(), WriterError> {
+        let annotations = vec![DebugAnnotation::new("run", self.run)];
+        let instant = self.process_instant(self.clock.now(), "restart", annotations);
+        self.write_packet(&instant)
+    }
+
+    /// An instant on the process track, after [`write_header`](Self::write_header).
+    fn process_instant(
+        &self,
+        timestamp: Timestamp,
+        name: &str,
+        debug_annotations: Vec<DebugAnnotation>,
+    ) -> TracePacket {
+        TracePacket {
+            timestamp,
+            data: PacketData::TrackEvent(TrackEvent {
+                event_type: packet::EventType::Instant,
+                name: packet::IString::Plain(name.to_string()),
+                debug_annotations,
+                track_uuid: None,
+                category_iids: Vec::new(),
+                source_location_iid: None,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                counter_value: None,
+                double_counter_value: None,
+                extra_counters: Vec::new(),
+                extra_double_counters: Vec::new(),
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: HEADER_SEQUENCE_ID,
+            interned_data: None,
+            trace_packet_defaults: None,
+        }
+    }
+
+    /// End the process info slice.
+    fn end_process_info(&mut self) -> Result<(), WriterError> {
+        let Some(info) = &self.process_info else {
+            return Ok(());
+        };
+        let end = process_info_packet(
+            self.trusted_uid,
+            self.last_timestamp,
+            packet::EventType::SliceEnd,
+            info,
+        );
+        self.write_packet(&end)
+    }
+
+    /// Emit the tracks of the writer's own activity.
+    fn begin_self_trace(&mut self) -> Result<(), WriterError> {
+        if self.self_trace.is_none() {
+            return Ok(());
+        }
+        let [defaults, descriptor] = sequence_header(
+            self.trusted_uid,
+            WRITER_SEQUENCE_ID,
+            WRITER_TRACK_UUID,
+            "tracing-perfetto".to_string(),
+            self.clock.clock_id(),
+        );
+        let counters = [
+            (
+                WRITER_BYTES_TRACK_UUID,
+                "bytes written",
+                CounterUnit::SizeBytes,
+            ),
+            (
+                WRITER_QUEUE_TRACK_UUID,
+                "queued messages",
+                CounterUnit::Count,
+            ),
+        ]
+        .map(|(uuid, name, unit)| TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid,
+                parent_uuid: Some(WRITER_TRACK_UUID),
+                name: name.to_string(),
+                counter: Some(unit),
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: WRITER_SEQUENCE_ID,
+            interned_data: None,
+            trace_packet_defaults: None,
+        });
+        for packet in [defaults, descriptor].into_iter().chain(counters) {
+            self.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Note that a message is being processed, with `queued` messages
+    /// waiting (including this one).
+    fn message_started(&mut self, queued: usize) {
+        if let Some(self_trace) = &mut self.self_trace {
+            if self_trace.batch.is_none() {
+                self_trace.batch = Some((self_trace.clock.now(), queued));
+            }
+            self_trace.messages += 1;
+        }
+    }
+
+    /// Record the batch of messages processed since the last flush.
+    fn batch_finished(&mut self) -> Result<(), WriterError> {
+        let Some(self_trace) = &mut self.self_trace else {
+            return Ok(());
+        };
+        let Some((start, queued)) = self_trace.batch.take() else {
+            return Ok(());
+        };
+        let messages = std::mem::take(&mut self_trace.messages);
+        let end = self_trace.clock.now();
+        let bytes_written = self
+            .counters
+            .bytes_written
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let args = vec![DebugAnnotation {
+            name: packet::IString::Plain("messages".to_string()),
+            value: packet::DebugValue::Uint(messages),
+        }];
+        let uid = self.trusted_uid;
+        let packets = [
+            writer_packet(uid, start, packet::EventType::SliceBegin, None, None, args),
+            writer_packet(
+                uid,
+                end,
+                packet::EventType::SliceEnd,
+                None,
+                None,
+                Vec::new(),
+            ),
+            writer_packet(
+                uid,
+                start,
+                packet::EventType::Counter,
+                Some(WRITER_QUEUE_TRACK_UUID),
+                Some(queued as i64),
+                Vec::new(),
+            ),
+            writer_packet(
+                uid,
+                end,
+                packet::EventType::Counter,
+                Some(WRITER_BYTES_TRACK_UUID),
+                Some(bytes_written as i64),
+                Vec::new(),
+            ),
+        ];
+        for packet in &packets {
+            self.write_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    fn should_rotate(&self) -> bool {
+        // The spilled packets belong to the current file.
+        !self.spilling()
+            && self
+                .rotation
+                .as_ref()
+                .and_then(|rotation| rotation.max_file_size)
+                .is_some_and(|max_file_size| self.file_size >= max_file_size)
+    }
+
+    /// Finish the current file and continue in a new one, which starts with
+    /// everything needed to read it on its own.
+    ///
+    /// Slices that are open during the rotation are cut in two.
+    fn rotate(&mut self) -> Result<(), WriterError> {
+        self.end_process_info()?;
+        self.flush()?;
+        if self.sync_policy != SyncPolicy::Never {
+            self.sync()?;
+        }
+        let Some(rotation) = &mut self.rotation else {
+            return Ok(());
+        };
+        let finished = rotation.next_file();
+        let path = rotation.current_path();
+        let file = File::create(&path)?;
+        if let Some(report) = &self.report {
+            report.lock().unwrap().file_opened(path.clone());
+        }
+        *self.current_path.lock().unwrap() = Some(path);
+        // The buffer is empty after the flush, so this closes the old file.
+        self.out.get_mut().replace_file(file);
+        rotation.finished(&finished);
+        self.file_size = 0;
+        self.synced_size = 0;
+        // Drops in the old file don't concern the new one.
+        self.dropped_packets.clear();
+
+        self.write_header()?;
+        self.begin_process_info()?;
+        self.begin_self_trace()?;
+        self.stall_track_started = false;
+        // Readers of the new file have not seen any track descriptors or
+        // interned data, so start all sequences over.
+        for thread_id in 0..self.sequences.len() {
+            let sequence = &mut self.sequences[thread_id];
+            if !sequence.started {
+                continue;
+            }
+            let (interned, seed_data) = seeded_interned(&self.intern_seed);
+            sequence.interned = interned;
+            sequence.cleared_at = self.last_timestamp;
+            sequence.dropped = false;
+            let mut header = sequence_header(
+                self.trusted_uid,
+                thread_sequence_id(thread_id as ThreadId),
+                sequence.track_uuid,
+                sequence.name.clone(),
+                self.clock.clock_id(),
+            );
+            header[0].interned_data = seed_data;
+            if let PacketData::TrackDescriptor(track) = &mut header[1].data {
+                track.thread = sequence.os_thread.clone();
+            }
+            for packet in &header {
+                self.write_packet(packet)?;
+            }
+        }
+        self.custom_tracks.clear();
+        self.counter_tracks.clear();
+        self.perf_counter_tracks.clear();
+        // Continue the current states in the new file.
+        self.state_sequence_started = false;
+        let states: Vec<_> = self
+            .state_tracks
+            .iter()
+            .map(|(track, (uuid, state))| (track.clone(), *uuid, state.clone()))
+            .collect();
+        for (track, uuid, state) in states {
+            self.describe_state_track(uuid, &track)?;
+            if let Some(state) = state {
+                let begin = self.state_packet(packet::EventType::SliceBegin, uuid, state);
+                self.write_packet(&begin)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// End the current state of a state track and begin the next one.
+    fn set_state(
+        &mut self,
+        timestamp: Timestamp,
+        track: Arc<str>,
+        state: Option<String>,
+    ) -> Result<(), WriterError> {
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let (uuid, previous) = match self.state_tracks.get_mut(&track) {
+            Some((uuid, current)) => (*uuid, std::mem::replace(current, state.clone())),
+            None => {
+                let uuid = self.allocate_track_uuid();
+                self.state_tracks
+                    .insert(track.clone(), (uuid, state.clone()));
+                self.describe_state_track(uuid, &track)?;
+                (uuid, None)
+            }
+        };
+        if let Some(previous) = previous {
+            let end = self.state_packet(packet::EventType::SliceEnd, uuid, previous);
+            self.write_packet(&end)?;
+        }
+        if let Some(state) = state {
+            let begin = self.state_packet(packet::EventType::SliceBegin, uuid, state);
+            self.write_packet(&begin)?;
+        }
+        Ok(())
+    }
+
+    fn describe_state_track(&mut self, uuid: u64, track: &str) -> Result<(), WriterError> {
+        if !self.state_sequence_started {
+            self.state_sequence_started = true;
+            let clear = TracePacket {
+                timestamp: 1,
+                data: PacketData::None,
+                sequence_flags: SEQ_INCREMENTAL_STATE_CLEARED,
+                trusted_uid: self.trusted_uid,
+                trusted_packet_sequence_id: STATE_SEQUENCE_ID,
+                interned_data: None,
+                trace_packet_defaults: Some(packet_defaults(
+                    PROCESS_TRACK_UUID,
+                    self.clock.clock_id(),
+                )),
+            };
+            self.write_packet(&clear)?;
+        }
+        let descriptor = TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid,
+                parent_uuid: None,
+                name: track.to_string(),
+                counter: None,
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: STATE_SEQUENCE_ID,
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        self.write_packet(&descriptor)
+    }
+
+    /// A slice begin or end of a state, at the latest timestamp.
+    fn state_packet(
+        &self,
+        event_type: packet::EventType,
+        track_uuid: u64,
+        state: String,
+    ) -> TracePacket {
+        let name = self.limits.name(&state).into_owned();
+        TracePacket {
+            timestamp: self.last_timestamp,
+            data: PacketData::TrackEvent(TrackEvent {
+                event_type,
+                name: packet::IString::Plain(name),
+                debug_annotations: Vec::new(),
+                track_uuid: Some(track_uuid),
+                category_iids: Vec::new(),
+                source_location_iid: None,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                counter_value: None,
+                double_counter_value: None,
+                extra_counters: Vec::new(),
+                extra_double_counters: Vec::new(),
+            }),
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: STATE_SEQUENCE_ID,
+            interned_data: None,
+            trace_packet_defaults: None,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let started = (self.clock.now(), Instant::now());
+        self.out.flush()?;
+        self.check_stall(started);
+        self.counters.flushed();
+        if let SyncPolicy::EveryNBytes(n) = self.sync_policy {
+            if self.file_size - self.synced_size >= n {
+                self.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Note the write that started at `started` if it took too long.
+    fn check_stall(&mut self, (timestamp, started): (Timestamp, Instant)) {
+        let elapsed = started.elapsed();
+        if elapsed >= self.write_stall_threshold {
+            self.counters
+                .write_stalls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.write_stalls.push((timestamp, elapsed));
+        }
+    }
+
+    /// Write an instant on the writer's track for each slow write since
+    /// the last call. They are not written right away, so that a stall
+    /// while writing them is not reported in turn.
+    fn report_stalls(&mut self) -> Result<(), WriterError> {
+        if self.write_stalls.is_empty() {
+            return Ok(());
+        }
+        if self.self_trace.is_none() && !self.stall_track_started {
+            let header = sequence_header(
+                self.trusted_uid,
+                WRITER_SEQUENCE_ID,
+                WRITER_TRACK_UUID,
+                "tracing-perfetto".to_string(),
+                self.clock.clock_id(),
+            );
+            for packet in &header {
+                self.write_packet(packet)?;
+            }
+            self.stall_track_started = true;
+        }
+        for (timestamp, duration) in std::mem::take(&mut self.write_stalls) {
+            let mut packet = writer_packet(
+                self.trusted_uid,
+                timestamp,
+                packet::EventType::Instant,
+                None,
+                None,
+                vec![DebugAnnotation::new(
+                    "duration_ns",
+                    duration.as_nanos() as u64,
+                )],
+            );
+            if let PacketData::TrackEvent(event) = &mut packet.data {
+                event.name = packet::IString::Plain(WRITE_STALL_NAME.to_string());
+            }
+            self.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Count the packets whose arguments were omitted in a second on the
+    /// "omitted args" counter track, and in [`TraceStats::args_omitted`].
+    ///
+    /// [`TraceStats::args_omitted`]: crate::TraceStats::args_omitted
+    fn write_overrun(&mut self, overrun: Overrun) -> Result<(), WriterError> {
+        self.counters
+            .args_omitted
+            .fetch_add(overrun.omitted, std::sync::atomic::Ordering::Relaxed);
+        let sample = |value| {
+            vec![(
+                OMITTED_ARGS_NAME,
+                None,
+                CounterScope::Process,
+                CounterValue::Int(value),
+            )]
+        };
+        self.counter_samples(
+            overrun.thread_id,
+            overrun.start,
+            sample(overrun.omitted as i64),
+        )?;
+        self.counter_samples(overrun.thread_id, overrun.end, sample(0))
+    }
+
+    /// Write the event names interned on any sequence to `path`, one per
+    /// line, for [`PerfettoLayerBuilder::intern_seed`](crate::PerfettoLayerBuilder::intern_seed).
+    fn export_intern_table(&self, path: &Path) -> io::Result<()> {
+        let mut seen = HashSet::new();
+        let mut out = String::new();
+        for sequence in self.sequences.iter().filter(|sequence| sequence.started) {
+            for name in sequence.interned.event_names.values() {
+                // Names with line breaks can't be read back.
+                if !name.contains('\n') && seen.insert(name) {
+                    out.push_str(name);
+                    out.push('\n');
+                }
+            }
+        }
+        std::fs::write(path, out)
+    }
+
+    /// Write the ring buffer to `path`.
+    fn dump(&mut self, path: &Path) -> io::Result<()> {
+        let Some(ring) = &self.ring else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the trace is not kept in a ring buffer",
+            ));
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        ring.write_to(&mut out)?;
+        out.into_inner().map_err(io::IntoInnerError::into_error)?;
+        Ok(())
+    }
+
+    /// Whether packets go to the spill file rather than the output.
+    fn spilling(&self) -> bool {
+        self.spill.as_ref().is_some_and(Spill::active)
+    }
+
+    /// Start spilling if too many messages are queued.
+    fn check_queue(&mut self, queued: usize) -> io::Result<()> {
+        match &mut self.spill {
+            Some(spill) => spill.check(queued),
+            None => Ok(()),
+        }
+    }
+
+    /// Copy the next chunk of spilled packets to the output.
+    fn unspill(&mut self) -> io::Result<()> {
+        if let Some(spill) = &mut self.spill {
+            self.file_size += spill.unspill(&mut self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Make sure the flushed data survives a power failure, see
+    /// [`PerfettoLayerBuilder::sync_policy`](crate::PerfettoLayerBuilder::sync_policy).
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(file) = self.out.get_ref().file() {
+            file.sync_data()?;
+        }
+        self.synced_size = self.file_size;
+        Ok(())
+    }
+
+    /// Flush if the output buffer is fuller than the threshold.
+    fn flush_hint(&mut self) -> io::Result<()> {
+        let threshold = self.out.capacity() * self.flush_hint_threshold as usize / 100;
+        if self.out.buffer().len() >= threshold {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn new_thread(
+        &mut self,
+        thread_id: ThreadId,
+        thread_name: String,
+        os_thread: Option<ThreadDescriptor>,
+    ) -> Result<(), WriterError> {
+        self.grow_sequences(thread_id);
+
+        let sequence = &mut self.sequences[thread_id as usize];
+        if sequence.started && !sequence.named {
+            // The thread's events arrived before its introduction, so the
+            // track already exists. Re-emitting the descriptor renames it.
+            sequence.named = true;
+            sequence.name = thread_name.clone();
+            sequence.os_thread = os_thread.clone();
+            let descriptor = TracePacket {
+                timestamp: 1,
+                data: PacketData::TrackDescriptor(TrackDescriptor {
+                    uuid: sequence.track_uuid,
+                    parent_uuid: None,
+                    name: thread_name,
+                    counter: None,
+                    process: None,
+                    thread: os_thread,
+                }),
+                sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+                trusted_uid: self.trusted_uid,
+                trusted_packet_sequence_id: thread_sequence_id(thread_id),
+                interned_data: None,
+                trace_packet_defaults: None,
+            };
+            return self.write_packet(&descriptor);
+        }
+        self.start_sequence(thread_id, thread_name, os_thread, true)
+    }
+
+    /// Make sure the sequence of `thread_id` has been started, so events can
+    /// be emitted on it even if no `NewThread` message was seen yet.
+    fn ensure_thread(&mut self, thread_id: ThreadId) -> Result<(), WriterError> {
+        match self.sequences.get(thread_id as usize) {
+            Some(sequence) if sequence.started => Ok(()),
+            _ => {
+                self.grow_sequences(thread_id);
+                self.start_sequence(thread_id, format!("thread {}", thread_id), None, false)
+            }
+        }
+    }
+
+    /// Make room for the sequence of `thread_id`.
+    #[cfg(not(feature = "static-config"))]
+    fn grow_sequences(&mut self, thread_id: ThreadId) {
+        if self.sequences.len() <= thread_id as usize {
+            self.sequences
+                .resize_with((thread_id + 1) as usize, SequenceState::default);
+        }
+    }
+
+    /// The layer caps thread ids so that every sequence has a slot.
+    #[cfg(feature = "static-config")]
+    fn grow_sequences(&mut self, _thread_id: ThreadId) {}
+
+    fn start_sequence(
+        &mut self,
+        thread_id: ThreadId,
+        thread_name: String,
+        os_thread: Option<ThreadDescriptor>,
+        named: bool,
+    ) -> Result<(), WriterError> {
+        // A recycled thread id reuses the sequence, but gets a new track so
+        // that the old thread's slices stay separate.
+        let track_uuid = if self.sequences[thread_id as usize].started {
+            self.allocate_track_uuid()
+        } else {
+            thread_track_uuid(thread_id)
+        };
+        self.sequences[thread_id as usize] = SequenceState {
+            started: true,
+            named,
+            track_uuid,
+            name: thread_name.clone(),
+            os_thread: os_thread.clone(),
+            cleared_position: self.ring.as_ref().map_or(0, Ring::position),
+            ..SequenceState::default()
+        };
+        let (interned, seed_data) = seeded_interned(&self.intern_seed);
+        self.sequences[thread_id as usize].interned = interned;
+
+        // Because we use one trusted sequence id per thread, we only have to
+        // override the track uuid in a packet for custom tracks.
+        let mut header = sequence_header(
+            self.trusted_uid,
+            thread_sequence_id(thread_id),
+            track_uuid,
+            thread_name,
+            self.clock.clock_id(),
+        );
+        header[0].interned_data = seed_data;
+        if let PacketData::TrackDescriptor(track) = &mut header[1].data {
+            track.thread = os_thread;
+        }
+        for packet in &header {
+            self.write_packet(packet)?;
+        }
+        Ok(())
+    }
+
+    fn allocate_track_uuid(&mut self) -> u64 {
+        let uuid = self.next_track_uuid;
+        self.next_track_uuid += 1;
+        uuid
+    }
+
+    /// Get the uuid of the custom track with the given name, emitting its
+    /// descriptor on the given thread's sequence if it is new.
+    fn custom_track_uuid(
+        &mut self,
+        thread_id: ThreadId,
+        name: &Arc<str>,
+    ) -> Result<u64, WriterError> {
+        if let Some(uuid) = self.custom_tracks.get(name) {
+            return Ok(*uuid);
+        }
+        let uuid = self.allocate_track_uuid();
+        self.custom_tracks.insert(name.clone(), uuid);
+        let descriptor = TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid,
+                parent_uuid: None,
+                name: name.to_string(),
+                counter: None,
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: 0,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: thread_sequence_id(thread_id),
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        self.write_packet(&descriptor)?;
+        Ok(uuid)
+    }
+
+    /// Write samples of the counters of fields in a single packet, creating
+    /// their tracks on the given thread's sequence if they are new.
+    fn counter_samples(
+        &mut self,
+        thread_id: ThreadId,
+        timestamp: Timestamp,
+        samples: Vec<(&'static str, Option<Unit>, CounterScope, CounterValue)>,
+    ) -> Result<(), WriterError> {
+        self.ensure_thread(thread_id)?;
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let mut values = Vec::with_capacity(samples.len());
+        for (field, unit, scope, value) in samples {
+            let (trace_unit, factor) = unit.map_or((CounterUnit::Unspecified, 1), Unit::trace_unit);
+            let track_uuid = self.counter_track_uuid(thread_id, field, trace_unit, scope)?;
+            values.push((track_uuid, value.scaled(factor)));
+        }
+        let Some(((track_uuid, first), rest)) = values.split_first() else {
+            return Ok(());
+        };
+        let (counter_value, double_counter_value) = match *first {
+            CounterValue::Int(value) => (Some(value), None),
+            CounterValue::Double(value) => (None, Some(value)),
+        };
+        // The other counters ride along on the first one's event.
+        let mut extra_counters = Vec::new();
+        let mut extra_double_counters = Vec::new();
+        for (track_uuid, value) in rest {
+            match *value {
+                CounterValue::Int(value) => extra_counters.push((*track_uuid, value)),
+                CounterValue::Double(value) => extra_double_counters.push((*track_uuid, value)),
+            }
+        }
+        let sample = TracePacket {
+            timestamp,
+            sequence_flags: SEQ_NEEDS_INCREMENTAL_STATE,
+            data: PacketData::TrackEvent(TrackEvent {
+                event_type: packet::EventType::Counter,
+                name: packet::IString::Plain(String::new()),
+                debug_annotations: Vec::new(),
+                track_uuid: Some(*track_uuid),
+                category_iids: Vec::new(),
+                source_location_iid: None,
+                flow_ids: Vec::new(),
+                terminating_flow_ids: Vec::new(),
+                counter_value,
+                double_counter_value,
+                extra_counters,
+                extra_double_counters,
+            }),
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: thread_sequence_id(thread_id),
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        self.write_packet(&sample)
+    }
+
+    /// Get the uuid of the counter track of a field, of the process or of
+    /// the given thread, emitting its descriptor on the thread's sequence if
+    /// it is new.
+    fn counter_track_uuid(
+        &mut self,
+        thread_id: ThreadId,
+        field: &'static str,
+        unit: CounterUnit,
+        scope: CounterScope,
+    ) -> Result<u64, WriterError> {
+        let parent_uuid = match scope {
+            CounterScope::Process => PROCESS_TRACK_UUID,
+            CounterScope::Thread => self.sequences[thread_id as usize].track_uuid,
+        };
+        if let Some(uuid) = self.counter_tracks.get(&(field, parent_uuid)) {
+            return Ok(*uuid);
+        }
+        let uuid = self.allocate_track_uuid();
+        self.counter_tracks.insert((field, parent_uuid), uuid);
+        let descriptor = TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid,
+                parent_uuid: Some(parent_uuid),
+                name: field.to_string(),
+                counter: Some(unit),
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: 0,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: thread_sequence_id(thread_id),
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        self.write_packet(&descriptor)?;
+        Ok(uuid)
+    }
+
+    /// Get the uuid of the thread's track of the perf counter, emitting its
+    /// descriptor if it is new.
+    fn perf_counter_track_uuid(
+        &mut self,
+        thread_id: ThreadId,
+        counter: PerfCounter,
+    ) -> Result<u64, WriterError> {
+        let thread_track_uuid = self.sequences[thread_id as usize].track_uuid;
+        if let Some(uuid) = self.perf_counter_tracks.get(&thread_track_uuid) {
+            return Ok(*uuid);
+        }
+        let uuid = self.allocate_track_uuid();
+        self.perf_counter_tracks.insert(thread_track_uuid, uuid);
+        let descriptor = TracePacket {
+            timestamp: 1,
+            data: PacketData::TrackDescriptor(TrackDescriptor {
+                uuid,
+                parent_uuid: Some(thread_track_uuid),
+                name: counter.name().to_string(),
+                counter: Some(CounterUnit::Count),
+                process: None,
+                thread: None,
+            }),
+            sequence_flags: 0,
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: thread_sequence_id(thread_id),
+            interned_data: None,
+            trace_packet_defaults: None,
+        };
+        self.write_packet(&descriptor)?;
+        Ok(uuid)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn track_event(
+        &mut self,
+        thread_id: ThreadId,
+        timestamp: Timestamp,
+        event_type: packet::EventType,
+        name: &str,
+        mut debug_annotations: Vec<DebugAnnotation>,
+        track: Option<&Arc<str>>,
+        flow_ids: Vec<u64>,
+        terminating_flow_ids: Vec<u64>,
+        location: Option<&'static tracing::Metadata<'static>>,
+        category: Option<&str>,
+        perf_count: Option<u64>,
+    ) -> Result<(), WriterError> {
+        self.ensure_thread(thread_id)?;
+        // Slice begins can be deferred, so timestamps may go backwards.
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let track_uuid = match track {
+            Some(track) => Some(self.custom_track_uuid(thread_id, track)?),
+            None => None,
+        };
+        if let Some(overrun) = self
+            .args_budget
+            .as_mut()
+            .and_then(|budget| budget.advance(timestamp))
+        {
+            self.write_overrun(overrun)?;
+        }
+        let mut extra_counters = Vec::new();
+        if let (Some(counter), Some(count)) = (self.perf_counter, perf_count) {
+            let uuid = self.perf_counter_track_uuid(thread_id, counter)?;
+            extra_counters.push((uuid, count as i64));
+        }
+
+        let sequence = &mut self.sequences[thread_id as usize];
+        let mut sequence_flags = SEQ_NEEDS_INCREMENTAL_STATE;
+        let mut trace_packet_defaults = None;
+        let mut seed_data = None;
+        let interval_passed = self
+            .clear_interval
+            .is_some_and(|interval| timestamp.saturating_sub(sequence.cleared_at) >= interval);
+        // Packets can only be parsed from the last reset still in the ring
+        // buffer.
+        let ring_reset_due = self
+            .ring
+            .as_ref()
+            .is_some_and(|ring| ring.reset_due(sequence.cleared_position));
+        if interval_passed || sequence.dropped || ring_reset_due {
+            // Start over with fresh interning tables, so readers can start
+            // parsing the sequence from this packet.
+            (sequence.interned, seed_data) = seeded_interned(&self.intern_seed);
+            sequence.cleared_at = timestamp;
+            sequence.cleared_position = self.ring.as_ref().map_or(0, Ring::position);
+            sequence.dropped = false;
+            sequence_flags |= SEQ_INCREMENTAL_STATE_CLEARED;
+            trace_packet_defaults =
+                Some(packet_defaults(sequence.track_uuid, self.clock.clock_id()));
+        }
+
+        let location = location.filter(|_| self.source_locations);
+        if let Some(metadata) = location {
+            debug_annotations.push(DebugAnnotation {
+                name: packet::IString::Plain("callsite".to_string()),
+                value: packet::DebugValue::Uint(callsite_hash(metadata)),
+            });
+        }
+        let name = self.limits.name(name);
+        self.limits.annotations(&mut debug_annotations);
+        if let Some(budget) = &mut self.args_budget {
+            if !debug_annotations.is_empty()
+                && !budget.spend(encoded_len(&debug_annotations)?, thread_id)
+            {
+                debug_annotations.clear();
+            }
+        }
+
+        let mut interned_data = seed_data.unwrap_or_default();
+        let (name_iid, added) = sequence.interned.event_name(&name);
+        if added {
+            interned_data.event_names.push(EventName {
+                iid: name_iid,
+                name: name.to_string(),
+            });
+        }
+        let mut source_location_iid = None;
+        if let Some((metadata, file, line)) =
+            location.and_then(|metadata| Some((metadata, metadata.file()?, metadata.line()?)))
+        {
+            let (iid, added) = sequence.interned.source_location(file, line);
+            if added {
+                interned_data.source_locations.push(SourceLocation {
+                    iid,
+                    file_name: file.to_string(),
+                    function_name: metadata.module_path().map(str::to_string),
+                    line_number: line,
+                });
+            }
+            source_location_iid = Some(iid);
+        }
+        let mut category_iids = Vec::new();
+        if let Some(category) = category {
+            let (iid, added) = sequence.interned.event_category(category);
+            if added {
+                interned_data.event_categories.push(EventCategory {
+                    iid,
+                    name: category.to_string(),
+                });
+            }
+            category_iids.push(iid);
+        }
+        intern_debug_annotations(
+            &mut sequence.interned,
+            &mut interned_data,
+            &mut debug_annotations,
+        );
+        let interned_data = if interned_data.is_empty() {
+            None
+        } else {
+            Some(interned_data)
+        };
+
+        let msg = TracePacket {
+            timestamp,
+            sequence_flags,
+            data: PacketData::TrackEvent(TrackEvent {
+                event_type,
+                name: packet::IString::Interned(name_iid),
+                debug_annotations,
+                track_uuid,
+                category_iids,
+                source_location_iid,
+                flow_ids,
+                terminating_flow_ids,
+                counter_value: None,
+                double_counter_value: None,
+                extra_counters,
+                extra_double_counters: Vec::new(),
+            }),
+            trusted_uid: self.trusted_uid,
+            trusted_packet_sequence_id: thread_sequence_id(thread_id),
+            interned_data,
+            trace_packet_defaults,
+        };
+        self.write_packet(&msg)
+    }
+
+    fn handle_message(&mut self, msg: Message) -> Result<(), WriterError> {
+        match msg {
+            Message::NewThread(thread_id, thread_name, os_thread) => {
+                self.new_thread(thread_id, thread_name, os_thread)
+            }
+            Message::ThreadExit(thread_id, timestamp) => {
+                // Perfetto has no notion of a finished track, so mark the
+                // end with an instant.
+                self.track_event(
+                    thread_id,
+                    timestamp,
+                    packet::EventType::Instant,
+                    "thread exited",
+                    Vec::new(),
+                    None,
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    None,
+                    None,
+                )
+            }
+            Message::Enter {
+                timestamp,
+                name,
+                args,
+                thread_id,
+                track,
+                flow_ids,
+                terminating_flow_ids,
+                location,
+                category,
+                perf_count,
+            } => {
+                if let Some(summary) = &mut self.span_summary {
+                    summary.enter(thread_id, track.as_ref(), timestamp);
+                }
+                let debug_annotations = if let Some(info) = args {
+                    info.deref().to_vec()
+                } else {
+                    Vec::new()
+                };
+                self.track_event(
+                    thread_id,
+                    timestamp,
+                    packet::EventType::SliceBegin,
+                    &name,
+                    debug_annotations,
+                    track.as_ref(),
+                    flow_ids,
+                    terminating_flow_ids,
+                    location,
+                    category.as_deref(),
+                    perf_count,
+                )
+            }
+            Message::Exit {
+                timestamp,
+                name,
+                thread_id,
+                track,
+                perf_count,
+                args,
+                unwound,
+            } => {
+                if let Some(summary) = &mut self.span_summary {
+                    summary.exit(thread_id, track.as_ref(), name.clone(), timestamp);
+                }
+                // Arguments of the end are added to those of the slice.
+                let mut args = args.map_or_else(Vec::new, |args| args.to_vec());
+                if unwound {
+                    args.push(DebugAnnotation::new("unwound", true));
+                }
+                self.track_event(
+                    thread_id,
+                    timestamp,
+                    packet::EventType::SliceEnd,
+                    &name,
+                    args,
+                    track.as_ref(),
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    None,
+                    perf_count,
+                )
+            }
+            Message::Event {
+                timestamp,
+                name,
+                args,
+                thread_id,
+                track,
+                flow_ids,
+                terminating_flow_ids,
+                location,
+                category,
+            } => {
+                let debug_annotations = if let Some(info) = args {
+                    info.deref().to_vec()
+                } else {
+                    Vec::new()
+                };
+                self.track_event(
+                    thread_id,
+                    timestamp,
+                    packet::EventType::Instant,
+                    &name,
+                    debug_annotations,
+                    track.as_ref(),
+                    flow_ids,
+                    terminating_flow_ids,
+                    location,
+                    category.as_deref(),
+                    None,
+                )
+            }
+            Message::AndroidLog {
+                timestamp,
+                prio,
+                tag,
+                message,
+                thread_id,
+            } => {
+                let packet = TracePacket {
+                    timestamp,
+                    data: PacketData::AndroidLog(AndroidLogPacket {
+                        events: vec![AndroidLogEvent {
+                            pid: std::process::id() as i32,
+                            timestamp,
+                            tag,
+                            prio,
+                            message,
+                        }],
+                    }),
+                    sequence_flags: 0,
+                    trusted_uid: self.trusted_uid,
+                    trusted_packet_sequence_id: thread_sequence_id(thread_id),
+                    interned_data: None,
+                    trace_packet_defaults: None,
+                };
+                self.write_packet(&packet)
+            }
+            Message::Counters {
+                timestamp,
+                samples,
+                thread_id,
+            } => self.counter_samples(thread_id, timestamp, samples),
+            // Handled by the writer loop.
+            Message::FlushHint => Ok(self.flush_hint()?),
+            Message::Flush => Ok(self.flush()?),
+            Message::State {
+                timestamp,
+                track,
+                state,
+            } => self.set_state(timestamp, track, state),
+            Message::Region {
+                timestamp,
+                id,
+                name,
+            } => {
+                // Regions are states of the regions tracks.
+                let track = match &name {
+                    Some(_) => Some(self.region_lanes.begin(id)),
+                    None => self.region_lanes.end(id),
+                };
+                match track {
+                    Some(track) => self.set_state(timestamp, track, name),
+                    None => Ok(()),
+                }
+            }
+            Message::Rotate => {
+                let numbered = self
+                    .rotation
+                    .as_ref()
+                    .is_some_and(|rotation| rotation.max_file_size.is_some());
+                if numbered {
+                    self.rotate()?;
+                }
+                Ok(())
+            }
+            Message::Slice { .. }
+            | Message::Snapshot { .. }
+            | Message::Dump { .. }
+            | Message::Drop => Ok(()),
+        }
+    }
+
+    /// Write the slices kept by `reservoirs`, and for each callsite an
+    /// instant summarizing the slices that were dropped.
+    fn write_slowest(&mut self, reservoirs: Reservoirs) -> Result<(), WriterError> {
+        let summary_track: Arc<str> = SPAN_SUMMARY_TRACK.into();
+        for reservoir in reservoirs.into_reservoirs() {
+            for slice in reservoir.kept {
+                skip_oversized(self.handle_message(slice.enter), &self.on_error)?;
+                skip_oversized(self.handle_message(slice.exit), &self.on_error)?;
+            }
+            if reservoir.dropped == 0 {
+                continue;
+            }
+            let args = [
+                (
+                    "span",
+                    packet::DebugValue::String(reservoir.name.to_string()),
+                ),
+                ("dropped", packet::DebugValue::Uint(reservoir.dropped)),
+                (
+                    "dropped_total_ns",
+                    packet::DebugValue::Uint(reservoir.dropped_ns),
+                ),
+            ];
+            let debug_annotations = args
+                .into_iter()
+                .map(|(name, value)| DebugAnnotation {
+                    name: packet::IString::Plain(name.to_string()),
+                    value,
+                })
+                .collect();
+            skip_oversized(
+                self.track_event(
+                    reservoir.thread_id,
+                    self.last_timestamp,
+                    packet::EventType::Instant,
+                    SPAN_SUMMARY_NAME,
+                    debug_annotations,
+                    Some(&summary_track),
+                    Vec::new(),
+                    Vec::new(),
+                    None,
+                    None,
+                    None,
+                ),
+                &self.on_error,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the trace is written to.
+pub(crate) enum Output {
+    Path(PathBuf),
+    File(File),
+    /// Base64 chunk lines written to a stream.
+    Base64(Box<dyn Write + Send>),
+    /// The binary trace written to a stream.
+    Writer(Box<dyn Write + Send>),
+}
+
+/// The destination of the encoded packets.
+enum Sink {
+    File(File),
+    Chunks(ChunkWriter),
+    Writer(Box<dyn Write + Send>),
+    /// Transformed frames written to another sink.
+    Framed(Box<FrameWriter<Sink>>),
+}
+
+impl Sink {
+    /// The file written to, if any.
+    fn file(&self) -> Option<&File> {
+        match self {
+            Sink::File(file) => Some(file),
+            Sink::Chunks(_) | Sink::Writer(_) => None,
+            Sink::Framed(framed) => framed.inner.file(),
+        }
+    }
+
+    /// Write to `file` from now on, keeping the transform, if any.
+    fn replace_file(&mut self, file: File) {
+        match self {
+            Sink::Framed(framed) => framed.inner.replace_file(file),
+            sink => *sink = Sink::File(file),
+        }
+    }
+
+    fn into_file(self) -> Option<File> {
+        match self {
+            Sink::File(file) => Some(file),
+            Sink::Chunks(_) | Sink::Writer(_) => None,
+            Sink::Framed(framed) => framed.inner.into_file(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::File(file) => file.write(data),
+            Sink::Chunks(chunks) => chunks.write(data),
+            Sink::Writer(out) => out.write(data),
+            Sink::Framed(framed) => framed.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Chunks(chunks) => chunks.flush(),
+            Sink::Writer(out) => out.flush(),
+            Sink::Framed(framed) => framed.flush(),
+        }
+    }
+}
+
+/// Builder settings that are needed by the writer thread.
+pub(crate) struct WriterConfig {
+    pub output: Option<Output>,
+    /// Applied to the output before it is written.
+    pub transform: Option<ChunkTransform>,
+    pub counters: Arc<Counters>,
+    pub process_info: Option<ProcessInfo>,
+    /// Written into the header of each file.
+    pub container_info: Option<ContainerInfo>,
+    pub incremental_state_interval: Option<Duration>,
+    /// The clock of the layer.
+    pub clock: Clock,
+    pub limits: Limits,
+    pub max_file_size: Option<u64>,
+    pub on_rotate: Option<RotateCallback>,
+    pub slowest_spans: Option<usize>,
+    /// Where to publish the path of the file being written.
+    pub current_path: CurrentPath,
+    /// Whether the writer should record its own activity.
+    pub self_trace: bool,
+    /// How full the output buffer must be, in percent, to flush it on
+    /// [`Message::FlushHint`].
+    pub flush_hint_threshold: u8,
+    /// Finish the trace once this many bytes are written.
+    pub stop_after_bytes: Option<u64>,
+    /// Finish the trace after this long.
+    pub stop_after: Option<Duration>,
+    /// See [`PerfettoLayerBuilder::write_stall_threshold`](crate::PerfettoLayerBuilder::write_stall_threshold).
+    pub write_stall_threshold: Duration,
+    /// How much to write before and after a snapshot trigger, if only
+    /// snapshots are written.
+    pub snapshot: Option<(Duration, Duration)>,
+    /// Whether to check the packets written, see
+    /// [`PerfettoLayerBuilder::strict`](crate::PerfettoLayerBuilder::strict).
+    pub strict: bool,
+    /// Whether the layer only sends slices once the span is exited.
+    pub defers_slices: bool,
+    pub sync_policy: SyncPolicy,
+    /// Where and how to write the statistics of the spans.
+    pub span_summary: Option<(PathBuf, SummaryFormat)>,
+    pub source_locations: bool,
+    pub perf_counter: Option<PerfCounter>,
+    pub intern_seed: Vec<String>,
+    /// Where to write the event names interned in the trace.
+    pub export_intern_table: Option<PathBuf>,
+    /// Whether to continue an existing trace file.
+    pub append: bool,
+    /// See [`PerfettoLayerBuilder::args_budget`](crate::PerfettoLayerBuilder::args_budget).
+    pub args_budget: Option<u64>,
+    /// Where to spill packets, and above how many queued messages, see
+    /// [`PerfettoLayerBuilder::spill_to_disk`](crate::PerfettoLayerBuilder::spill_to_disk).
+    pub spill: Option<(PathBuf, usize)>,
+    /// See [`PerfettoLayerBuilder::ring_buffer`](crate::PerfettoLayerBuilder::ring_buffer).
+    pub ring_buffer: Option<usize>,
+    /// See [`PerfettoLayerBuilder::on_error`](crate::PerfettoLayerBuilder::on_error).
+    pub on_error: ErrorHook,
+    /// See [`PerfettoLayerBuilder::shutdown_report`](crate::PerfettoLayerBuilder::shutdown_report).
+    pub shutdown_report: bool,
+}
+
+/// Drop packets that are too large to encode, as the rest of the trace is
+/// still fine, but pass on other errors.
+fn skip_oversized(
+    result: Result<(), WriterError>,
+    on_error: &ErrorHook,
+) -> Result<(), WriterError> {
+    match result {
+        Err(WriterError::Emit(err)) => {
+            on_error.report_partial("dropping packet", io::ErrorKind::InvalidData, &err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+pub(crate) fn writer_thread(
+    rx: Receiver<Message>,
+    mut config: WriterConfig,
+) -> Result<Option<File>, WriterError> {
+    crate::ON_WRITER_THREAD.with(|on_writer_thread| on_writer_thread.set(true));
+    let counters = config.counters.clone();
+    let on_error = config.on_error.clone();
+    let output = config.output.take().unwrap_or_else(|| {
+        Output::Path(PathBuf::from(format!(
+            "trace-{}.perfetto-trace",
+            std::time::SystemTime::UNIX_EPOCH
+                .elapsed()
+                .unwrap_or_default()
+                .as_secs()
+        )))
+    });
+    let report = match &output {
+        Output::Path(path) if config.shutdown_report => {
+            Some(Arc::new(Mutex::new(ShutdownReport::new(path))))
+        }
+        _ => None,
+    };
+    let result = write_trace(rx, output, config, report.clone());
+    if let Err(err) = &result {
+        // Without a writer, the layer would keep recording into a queue
+        // that nobody reads.
+        counters.stop();
+        let encoding_err;
+        let err = match err {
+            WriterError::Io(err) => err,
+            WriterError::Emit(err) => {
+                encoding_err = io::Error::other(err.to_string());
+                &encoding_err
+            }
+        };
+        on_error.report("writer thread failed", err);
+    }
+    if let Some(report) = report {
+        let error = result.as_ref().err().map(ToString::to_string);
+        let written = report
+            .lock()
+            .unwrap()
+            .write(&counters.stats(0), error.as_deref());
+        if let Err(err) = written {
+            on_error.report("cannot write shutdown report", &err);
+        }
+    }
+    result
+}
+
+fn write_trace(
+    rx: Receiver<Message>,
+    output: Output,
+    config: WriterConfig,
+    report: Option<SharedReport>,
+) -> Result<Option<File>, WriterError> {
+    let mut rotation = None;
+    let mut existing = append::Existing { len: 0, run: 0 };
+    let sink = match output {
+        Output::File(file) => Sink::File(file),
+        Output::Path(path) => {
+            let mut r = Rotation::new(path, config.max_file_size, config.on_rotate);
+            if config.append {
+                r.resume()?;
+            }
+            let path = r.current_path();
+            let file = if config.append {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)?;
+                existing = append::scan(&file)?;
+                // The last file may have been created just before a crash,
+                // so the earlier run's ids are in the one before it.
+                let mut index = r.index();
+                while existing.run == 0 && index > 0 {
+                    index -= 1;
+                    if let Ok(earlier) = File::open(r.path(index)) {
+                        existing.run = append::scan(&earlier)?.run;
+                    }
+                }
+                file.set_len(existing.len)?;
+                file.seek(SeekFrom::End(0))?;
+                file
+            } else {
+                File::create(&path)?
+            };
+            if let Some(report) = &report {
+                report.lock().unwrap().file_opened(path.clone());
+            }
+            *config.current_path.lock().unwrap() = Some(path);
+            rotation = Some(r);
+            Sink::File(file)
+        }
+        Output::Base64(out) => Sink::Chunks(ChunkWriter::new(out)),
+        Output::Writer(out) => Sink::Writer(out),
+    };
+    let sink = match config.transform {
+        Some(transform) => Sink::Framed(Box::new(FrameWriter::new(sink, transform))),
+        None => sink,
+    };
+
+    let mut writer = Writer {
+        out: BufWriter::with_capacity(64 * 1024, sink),
+        em: ProtoEmitter::new(),
+        trusted_uid: 42,
+        #[cfg(not(feature = "static-config"))]
+        sequences: vec![SequenceState::default()],
+        #[cfg(feature = "static-config")]
+        sequences: Box::new(std::array::from_fn(|_| SequenceState::default())),
+        clear_interval: config
+            .incremental_state_interval
+            .map(|d| d.as_nanos() as u64),
+        clock: config.clock,
+        flush_hint_threshold: config.flush_hint_threshold,
+        custom_tracks: HashMap::new(),
+        counter_tracks: HashMap::new(),
+        perf_counter: config.perf_counter,
+        perf_counter_tracks: HashMap::new(),
+        state_tracks: HashMap::new(),
+        state_sequence_started: false,
+        region_lanes: RegionLanes::default(),
+        next_track_uuid: DYNAMIC_TRACK_UUID_BASE,
+        last_timestamp: config.clock.now(),
+        validator: config.strict.then(|| {
+            Validator::new(
+                config.counters.clone(),
+                config.defers_slices,
+                config.on_error.clone(),
+            )
+        }),
+        counters: config.counters,
+        limits: config.limits,
+        process_info: config.process_info,
+        container_info: config.container_info,
+        rotation,
+        file_size: existing.len,
+        sync_policy: config.sync_policy,
+        synced_size: 0,
+        span_summary: config
+            .span_summary
+            .map(|(path, format)| SpanSummary::new(path, format)),
+        source_locations: config.source_locations,
+        intern_seed: config.intern_seed,
+        export_intern_table: config.export_intern_table,
+        dropped_packets: HashSet::new(),
+        run: existing.run,
+        reservoirs: config.slowest_spans.map(Reservoirs::new),
+        current_path: config.current_path,
+        report,
+        on_error: config.on_error,
+        write_stall_threshold: config.write_stall_threshold,
+        write_stalls: Vec::new(),
+        stall_track_started: false,
+        args_budget: config.args_budget.map(ArgsBudget::new),
+        spill: config
+            .spill
+            .map(|(dir, max_queued)| Spill::new(dir, max_queued)),
+        ring: config.ring_buffer.map(Ring::new),
+        self_trace: config.self_trace.then_some(SelfTrace {
+            clock: config.clock,
+            batch: None,
+            messages: 0,
+        }),
+    };
+
+    writer.write_header()?;
+    if writer.run > 0 {
+        writer.write_restart_marker()?;
+    }
+    writer.begin_process_info()?;
+    writer.begin_self_trace()?;
+
+    let mut snapshot = config
+        .snapshot
+        .map(|(before, after)| SnapshotBuffer::new(before, after));
+    let deadline = config
+        .stop_after
+        .map(|duration| std::time::Instant::now() + duration);
+    loop {
+        // Copy spilled packets over while there is nothing else to do.
+        while writer.spilling() && rx.is_empty() {
+            writer.unspill()?;
+        }
+        let received = match deadline {
+            Some(deadline) => rx.recv_deadline(deadline).ok(),
+            None => rx.recv().ok(),
+        };
+        let Some(msg) = received else {
+            break;
+        };
+        // Rotate before writing rather than after, so the last file is never
+        // left without events.
+        if writer.should_rotate() {
+            writer.rotate()?;
+        }
+        writer.message_started(rx.len() + 1);
+        writer.check_queue(rx.len())?;
+        // Make sure everything an exited thread recorded ends up on disk,
+        // even if the trace keeps running for a long time.
+        let flush = matches!(msg, Message::ThreadExit(..));
+        match msg {
+            Message::Drop => break,
+            Message::Dump { path, done } => {
+                let _ignore_err = done.send(writer.dump(&path));
+            }
+            Message::Slice {
+                callsite,
+                enter,
+                end,
+                end_perf_count,
+            } => {
+                if let Some(reservoirs) = &mut writer.reservoirs {
+                    reservoirs.add(callsite, *enter, end, end_perf_count);
+                }
+            }
+            msg => match &mut snapshot {
+                Some(snapshot) => {
+                    for msg in snapshot.process(msg) {
+                        skip_oversized(writer.handle_message(msg), &writer.on_error)?;
+                    }
+                }
+                None => skip_oversized(writer.handle_message(msg), &writer.on_error)?,
+            },
+        }
+        // Flushing after every message is slow, so only do it once we've
+        // caught up with the producers.
+        if flush || rx.is_empty() {
+            writer.flush()?;
+            writer.batch_finished()?;
+            writer.report_stalls()?;
+        }
+        if config.stop_after_bytes.is_some_and(|limit| {
+            writer
+                .counters
+                .bytes_written
+                .load(std::sync::atomic::Ordering::Relaxed)
+                >= limit
+        }) {
+            break;
+        }
+    }
+    // Producers stop sending once they see this; anything still queued is
+    // dropped along with the receiver.
+    writer.counters.stop();
+    writer
+        .counters
+        .messages_dropped
+        .fetch_add(rx.len() as u64, std::sync::atomic::Ordering::Relaxed);
+
+    writer.batch_finished()?;
+    writer.report_stalls()?;
+    if let Some(overrun) = writer.args_budget.as_mut().and_then(ArgsBudget::finish) {
+        writer.write_overrun(overrun)?;
+    }
+
+    if let Some(reservoirs) = writer.reservoirs.take() {
+        writer.write_slowest(reservoirs)?;
+    }
+    writer.end_process_info()?;
+    if let Some(ring) = writer.ring.take() {
+        writer.file_size += ring.write_to(&mut writer.out)?;
+    }
+    while writer.spilling() {
+        writer.unspill()?;
+    }
+    writer.flush()?;
+    if writer.sync_policy != SyncPolicy::Never {
+        writer.sync()?;
+    }
+    if let Some(summary) = &writer.span_summary {
+        summary.write()?;
+    }
+    if let Some(path) = &writer.export_intern_table {
+        writer.export_intern_table(path)?;
+    }
+    if let Some(validator) = &mut writer.validator {
+        validator.finish();
+    }
+    let sink = writer
+        .out
+        .into_inner()
+        .map_err(|err| WriterError::Io(err.into_error()))?;
+    if let Some(rotation) = &mut writer.rotation {
+        let path = rotation.current_path();
+        rotation.finished(&path);
+    }
+    Ok(sink.into_file())
+}
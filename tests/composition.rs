@@ -0,0 +1,143 @@
+//! The layer combined with the layers and filters of tracing-subscriber
+//! that it is most often used with.
+//!
+//! Layers in one stack share the registry's span data and the callsite
+//! interest cache, so a filter or a layer that also visits fields must not
+//! change what ends up in the trace, and the other way around.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{debug, info, info_span, warn};
+use tracing_perfetto::PerfettoLayerBuilder;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// Collects the output of a fmt layer.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn fmt_layer<S>(captured: &Captured) -> impl tracing_subscriber::Layer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let captured = captured.clone();
+    fmt::layer()
+        .with_ansi(false)
+        .with_writer(move || captured.clone())
+}
+
+fn contains(trace: &[u8], needle: &str) -> bool {
+    trace
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+fn workload() {
+    info_span!("request", user = "alice").in_scope(|| {
+        info!(items = 3, "loaded cart");
+        debug!("cache details");
+        warn!(target: "payments", "card declined");
+    });
+}
+
+#[test]
+fn global_env_filter() {
+    let path = "test-composition-global.perfetto-trace";
+    let captured = Captured::default();
+    let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .build();
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::new("info"))
+        .with(fmt_layer(&captured))
+        .with(perfetto_layer);
+    tracing::subscriber::with_default(subscriber, workload);
+    drop(handle);
+
+    let text = captured.text();
+    let trace = std::fs::read(path).unwrap();
+    // Both layers see the fields, even though both visit them.
+    assert!(text.contains("request{user=\"alice\"}"));
+    assert!(text.contains("loaded cart items=3"));
+    assert!(text.contains("card declined"));
+    assert!(contains(&trace, "request"));
+    assert!(contains(&trace, "alice"));
+    assert!(contains(&trace, "items"));
+    assert!(contains(&trace, "card declined"));
+    // The filter applies to both.
+    assert!(!text.contains("cache details"));
+    assert!(!contains(&trace, "cache details"));
+}
+
+#[test]
+fn per_layer_filters() {
+    let path = "test-composition-per-layer.perfetto-trace";
+    let captured = Captured::default();
+    let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .build();
+    // The trace gets everything, the log only warnings, so the interest
+    // cache must not let the log's filter decide for the trace.
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt_layer(&captured).with_filter(EnvFilter::new("warn")))
+        .with(perfetto_layer.with_filter(EnvFilter::new("debug")));
+    tracing::subscriber::with_default(subscriber, workload);
+    drop(handle);
+
+    let text = captured.text();
+    let trace = std::fs::read(path).unwrap();
+    assert!(text.contains("card declined"));
+    assert!(!text.contains("loaded cart"));
+    assert!(!text.contains("cache details"));
+    assert!(contains(&trace, "request"));
+    assert!(contains(&trace, "loaded cart"));
+    assert!(contains(&trace, "cache details"));
+    assert!(contains(&trace, "card declined"));
+}
+
+#[test]
+fn target_directives() {
+    let path = "test-composition-targets.perfetto-trace";
+    let captured = Captured::default();
+    let (perfetto_layer, handle) = PerfettoLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .build();
+    // Only the payments target is recorded; the span around its event is
+    // filtered out, so the event has no slice to go into.
+    let subscriber = tracing_subscriber::registry()
+        .with(perfetto_layer)
+        .with(fmt_layer(&captured))
+        .with(EnvFilter::new("payments=warn"));
+    tracing::subscriber::with_default(subscriber, workload);
+    drop(handle);
+
+    let text = captured.text();
+    let trace = std::fs::read(path).unwrap();
+    assert!(text.contains("card declined"));
+    assert!(!text.contains("request"));
+    assert!(contains(&trace, "card declined"));
+    assert!(!contains(&trace, "request"));
+    assert!(!contains(&trace, "loaded cart"));
+}
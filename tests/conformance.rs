@@ -0,0 +1,224 @@
+//! Checks that the traces we write load cleanly in released versions of
+//! Perfetto's trace_processor, which is also what the UI runs.
+//!
+//! The UI's tolerance for quirks in the encoding changes between releases,
+//! so we test against a pinned set of versions, listed in `VERSIONS`. Run
+//! with
+//!
+//! ```text
+//! TRACE_PROCESSOR_DIR=/path/to/binaries cargo test --features conformance --test conformance
+//! ```
+//!
+//! where the directory contains a `<version>/trace_processor_shell` binary
+//! for each pinned version. Binaries are published for every release, e.g.
+//! at `https://commondatastorage.googleapis.com/perfetto-luci-artifacts/<version>/linux-amd64/trace_processor_shell`.
+//!
+//! When adding a version, keep the oldest one that the UI still accepts
+//! traces from, and note in the changelog when support for one is dropped.
+#![cfg(feature = "conformance")]
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use tracing::{info, info_span};
+use tracing_perfetto::PerfettoLayerBuilder;
+use tracing_subscriber::prelude::*;
+
+/// The trace_processor versions we promise to be compatible with.
+const VERSIONS: &[&str] = &["v40.0", "v45.0", "v49.0"];
+
+/// The binaries of all pinned versions, or `None` if `TRACE_PROCESSOR_DIR`
+/// is not set, so `cargo test --all-features` works without them.
+fn trace_processors() -> Option<Vec<(&'static str, PathBuf)>> {
+    let Some(dir) = std::env::var_os("TRACE_PROCESSOR_DIR") else {
+        eprintln!("TRACE_PROCESSOR_DIR not set, skipping conformance test");
+        return None;
+    };
+    let binaries = VERSIONS
+        .iter()
+        .map(|version| {
+            let path = Path::new(&dir).join(version).join("trace_processor_shell");
+            assert!(
+                path.exists(),
+                "trace_processor {} not found at {}",
+                version,
+                path.display()
+            );
+            (*version, path)
+        })
+        .collect();
+    Some(binaries)
+}
+
+fn trace_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("conformance-{}.perfetto-trace", name))
+}
+
+/// Run a query returning a single value and return that value.
+fn query(trace_processor: &Path, trace: &Path, sql: &str) -> String {
+    let query_file = trace.with_extension("sql");
+    std::fs::write(&query_file, sql).unwrap();
+    let output = Command::new(trace_processor)
+        .arg("--query-file")
+        .arg(&query_file)
+        .arg(trace)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{} failed on {}:\n{}",
+        trace_processor.display(),
+        trace.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // The output is a header line with the column name followed by the row.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let value = stdout.lines().rfind(|line| !line.is_empty());
+    value.unwrap_or_default().trim_matches('"').to_string()
+}
+
+/// Check a trace against all pinned versions: it must import without errors
+/// or data losses, and contain `slices` slices with a name.
+fn check(trace: &Path, slices: usize) {
+    let Some(trace_processors) = trace_processors() else {
+        return;
+    };
+    for (version, tp) in trace_processors {
+        let errors = query(
+            &tp,
+            trace,
+            "select coalesce(group_concat(name, ' '), '') from stats \
+             where severity in ('error', 'data_loss') and value > 0",
+        );
+        assert_eq!(
+            errors,
+            "",
+            "{}: import errors in {}",
+            version,
+            trace.display()
+        );
+        let count = query(
+            &tp,
+            trace,
+            "select count(*) from slice where name is not null",
+        );
+        assert_eq!(
+            count,
+            slices.to_string(),
+            "{}: wrong number of slices in {}",
+            version,
+            trace.display()
+        );
+    }
+}
+
+fn record(builder: PerfettoLayerBuilder<tracing_subscriber::Registry>, f: impl FnOnce()) {
+    let (layer, guard) = builder.build();
+    let default = tracing_subscriber::registry().with(layer).set_default();
+    f();
+    drop(default);
+    drop(guard);
+}
+
+#[test]
+fn nested_spans_and_events() {
+    let path = trace_path("nested");
+    record(
+        PerfettoLayerBuilder::new().file(&path).include_args(true),
+        || {
+            info_span!("outer", n = 1).in_scope(|| {
+                info_span!("inner", s = "text\nwith newline").in_scope(|| {
+                    info!(answer = 42, "hello");
+                });
+            });
+        },
+    );
+    check(&path, 3);
+}
+
+#[test]
+fn threads_and_custom_tracks() {
+    let path = trace_path("tracks");
+    record(
+        PerfettoLayerBuilder::new()
+            .file(&path)
+            .inherit_tracks(true)
+            .process_info(tracing_perfetto::process_info!()),
+        || {
+            let dispatch = tracing::dispatcher::get_default(|d| d.clone());
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    info_span!("worker").in_scope(|| {});
+                })
+            })
+            .join()
+            .unwrap();
+            info_span!("query", perfetto.track = "db").in_scope(|| {
+                info_span!("fetch").in_scope(|| {});
+            });
+        },
+    );
+    // The process info slice counts as well.
+    check(&path, 4);
+}
+
+#[test]
+fn incremental_state_cleared() {
+    let path = trace_path("incremental");
+    record(
+        PerfettoLayerBuilder::new()
+            .file(&path)
+            .incremental_state_interval(Duration::from_millis(1)),
+        || {
+            for _ in 0..10 {
+                info_span!("repeated").in_scope(|| {
+                    std::thread::sleep(Duration::from_millis(1));
+                });
+            }
+        },
+    );
+    check(&path, 10);
+}
+
+#[test]
+fn rotated_files() {
+    let path = trace_path("rotated");
+    let finished = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    record(
+        PerfettoLayerBuilder::new()
+            .file(&path)
+            .max_file_size(256)
+            .on_rotate({
+                let finished = finished.clone();
+                move |path| finished.lock().unwrap().push(path.to_path_buf())
+            }),
+        || {
+            for _ in 0..20 {
+                info_span!("rotating").in_scope(|| {});
+            }
+        },
+    );
+    let finished = finished.lock().unwrap();
+    assert!(finished.len() > 1);
+    let Some(trace_processors) = trace_processors() else {
+        return;
+    };
+    for (version, tp) in trace_processors {
+        let total: usize = finished
+            .iter()
+            .map(|file| {
+                query(
+                    &tp,
+                    file,
+                    "select count(*) from slice where name is not null",
+                )
+                .parse::<usize>()
+                .unwrap()
+            })
+            .sum();
+        assert_eq!(total, 20, "{}: slices lost across files", version);
+    }
+}